@@ -0,0 +1,239 @@
+//! For a summary of the message calls made during a transaction.
+//!
+//! CALL, CALLCODE, DELEGATECALL and STATICCALL are classified by value and target.
+
+use alloy_primitives::U256;
+use serde::Serialize;
+use std::{collections::HashSet, fmt::Display, str::FromStr};
+
+use crate::{
+    context::{Address, Context},
+    processed::ProcessedStep,
+};
+
+/// The opcode used to make a message call.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum CallKind {
+    Call,
+    CallCode,
+    DelegateCall,
+    StaticCall,
+}
+
+impl CallKind {
+    fn from_opcode(opcode: &str) -> Option<Self> {
+        match opcode {
+            "CALL" => Some(Self::Call),
+            "CALLCODE" => Some(Self::CallCode),
+            "DELEGATECALL" => Some(Self::DelegateCall),
+            "STATICCALL" => Some(Self::StaticCall),
+            _ => None,
+        }
+    }
+}
+
+/// A single message call.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CallEdge {
+    pub kind: CallKind,
+    /// Address of the code making the call.
+    pub from: Address,
+    pub to: String,
+    /// Whether ether is transferred by the call.
+    pub value_bearing: bool,
+    /// Whether the target has no code (a payment).
+    pub to_codeless: bool,
+    /// Depth of the caller (1 = the transaction recipient).
+    pub depth: u64,
+}
+
+/// All message calls made, in order.
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct CallGraph {
+    pub edges: Vec<CallEdge>,
+}
+
+impl CallGraph {
+    /// Records a processed step if it is a message call. Other steps are ignored.
+    pub fn record(&mut self, step: &ProcessedStep, context: &Context, depth: u64) {
+        let (kind, to, value, to_codeless) = match step {
+            ProcessedStep::Call { to, value } => (CallKind::Call, to, Some(value.as_str()), false),
+            ProcessedStep::CallCode { to, value } => {
+                (CallKind::CallCode, to, Some(value.as_str()), false)
+            }
+            // DELEGATECALL and STATICCALL do not transfer value.
+            ProcessedStep::DelegateCall { to, value: _ } => {
+                (CallKind::DelegateCall, to, None, false)
+            }
+            ProcessedStep::StaticCall { to } => (CallKind::StaticCall, to, None, false),
+            ProcessedStep::PayCall { to, value, opcode } => {
+                let Some(kind) = CallKind::from_opcode(opcode) else {
+                    return;
+                };
+                let value = match kind {
+                    CallKind::Call | CallKind::CallCode => Some(value.0.as_str()),
+                    CallKind::DelegateCall | CallKind::StaticCall => None,
+                };
+                (kind, to, value, true)
+            }
+            _ => return,
+        };
+        self.edges.push(CallEdge {
+            kind,
+            from: context.code_address.clone(),
+            to: to.clone(),
+            value_bearing: value.map(is_non_zero).unwrap_or(false),
+            to_codeless,
+            depth,
+        });
+    }
+    /// Counts of the calls made.
+    pub fn summary(&self) -> CallGraphSummary {
+        let mut summary = CallGraphSummary {
+            total: self.edges.len(),
+            ..Default::default()
+        };
+        let mut targets = HashSet::new();
+        for edge in &self.edges {
+            match edge.value_bearing {
+                true => summary.value_bearing += 1,
+                false => summary.zero_value += 1,
+            }
+            match edge.kind {
+                CallKind::Call => summary.call += 1,
+                CallKind::CallCode => summary.call_code += 1,
+                CallKind::DelegateCall => summary.delegate_call += 1,
+                CallKind::StaticCall => summary.static_call += 1,
+            }
+            if edge.to_codeless {
+                summary.to_codeless += 1;
+            }
+            if edge.depth > 1 {
+                summary.nested += 1;
+            }
+            targets.insert(edge.to.as_str());
+        }
+        summary.unique_targets = targets.len();
+        summary
+    }
+}
+
+/// Counts of message calls by value and target.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CallGraphSummary {
+    pub total: usize,
+    pub value_bearing: usize,
+    pub zero_value: usize,
+    pub call: usize,
+    pub call_code: usize,
+    pub delegate_call: usize,
+    pub static_call: usize,
+    /// Calls to accounts without code.
+    pub to_codeless: usize,
+    /// Calls made by a contract that was itself called by a contract.
+    pub nested: usize,
+    pub unique_targets: usize,
+}
+
+impl Display for CallGraphSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Call graph: {} calls ({} value-bearing, {} zero-value) to {} accounts. \
+            CALL {}, CALLCODE {}, DELEGATECALL {}, STATICCALL {}. {} to codeless accounts, {} nested",
+            self.total,
+            self.value_bearing,
+            self.zero_value,
+            self.unique_targets,
+            self.call,
+            self.call_code,
+            self.delegate_call,
+            self.static_call,
+            self.to_codeless,
+            self.nested
+        )
+    }
+}
+
+/// Whether a hex-string value (0x-prefix) is non-zero.
+fn is_non_zero(hex_value: &str) -> bool {
+    U256::from_str(hex_value)
+        .map(|value| value != U256::ZERO)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        filter::process_lines,
+        opcode::{EvmStepDebug, TraceLine},
+    };
+
+    fn step(op: &str, depth: u64, stack: &[&str]) -> TraceLine {
+        TraceLine::StepDebug(EvmStepDebug {
+            pc: 0,
+            op: op.to_string(),
+            gas: 0,
+            gas_cost: 0,
+            depth,
+            stack: stack.iter().map(|s| s.to_string()).collect(),
+            memory: None,
+        })
+    }
+
+    /// Stack for a call variant, top of stack last: [.., value, to, gas].
+    fn call_stack<'a>(to: &'a str, value: &'a str) -> Vec<&'a str> {
+        vec!["0x0", "0x0", "0x0", "0x0", value, to, "0xffff"]
+    }
+
+    /// Stack for a call variant without a value: [.., to, gas].
+    fn no_value_call_stack(to: &str) -> Vec<&str> {
+        vec!["0x0", "0x0", "0x0", "0x0", to, "0xffff"]
+    }
+
+    /// Transaction recipient calls A with value, A delegatecalls B, B staticcalls C.
+    /// Then A pays a codeless account and makes a zero-value call to C.
+    #[test]
+    fn test_nested_call_graph_counts() {
+        let trace = vec![
+            step("CALL", 1, &call_stack("0xaa", "0x64")),
+            step("DELEGATECALL", 2, &no_value_call_stack("0xbb")),
+            step("STATICCALL", 3, &no_value_call_stack("0xcc")),
+            step("STOP", 4, &[]),
+            step("RETURN", 3, &["0x0", "0x0"]),
+            step("CALL", 2, &call_stack("0xdd", "0x1")),
+            // Same depth after CALL, so the target has no code.
+            step("CALL", 2, &call_stack("0xcc", "0x0")),
+            step("STOP", 3, &[]),
+            step("STOP", 2, &[]),
+            step("STOP", 1, &[]),
+        ];
+        let mut out: Vec<u8> = vec![];
+        let graph = process_lines(trace.into_iter(), &mut out);
+        let summary = graph.summary();
+        assert_eq!(summary.total, 5);
+        assert_eq!(summary.value_bearing, 2);
+        assert_eq!(summary.zero_value, 3);
+        assert_eq!(summary.call, 3);
+        assert_eq!(summary.delegate_call, 1);
+        assert_eq!(summary.static_call, 1);
+        assert_eq!(summary.to_codeless, 1);
+        assert_eq!(summary.nested, 4);
+        assert_eq!(summary.unique_targets, 4);
+
+        let payment = &graph.edges[3];
+        assert!(payment.to_codeless);
+        assert!(payment.value_bearing);
+        assert_eq!(payment.from, Address::Standard("0xaa".to_string()));
+    }
+
+    #[test]
+    fn test_is_non_zero() {
+        assert!(is_non_zero("0x1"));
+        assert!(!is_non_zero("0x0"));
+    }
+}
@@ -18,6 +18,9 @@ use clap::{Parser, ValueEnum};
 pub struct AppArgs {
     #[clap(value_enum, default_value_t=ModeFlag::Debug)]
     pub trace_style: ModeFlag,
+    /// Print a summary of message calls (CALL, DELEGATECALL, ...) after the trace
+    #[clap(long)]
+    pub call_graph: bool,
 }
 
 /// Different traces have different fields (e.g., op vs opName)
@@ -31,4 +34,6 @@ pub enum ModeFlag {
     Eip3155,
     /// For debug_traceBlockByNumber or debug_traceTransaction (see NDJSON instructions)
     Debug,
+    /// Struct logs (as for Debug), converted to EIP-3155 steps before processing
+    StructLog,
 }
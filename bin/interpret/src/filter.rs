@@ -14,6 +14,7 @@ use std::io::{BufRead, Write};
 use thiserror::Error;
 
 use crate::{
+    callgraph::CallGraph,
     cli::ModeFlag,
     context::{apply_pending_context, get_pending_context_update, Context, ContextUpdate},
     juncture::Juncture,
@@ -27,56 +28,94 @@ pub enum FilterError {
     SerdeJson(#[from] serde_json::Error),
 }
 
-pub fn process_trace(trace_style: ModeFlag) {
+/// Reads a trace from stdin and writes a summary to stdout.
+///
+/// If `call_graph` is set, a summary of the message calls is written at the end.
+pub fn process_trace(trace_style: ModeFlag, call_graph: bool) {
     let stdin = std::io::stdin();
     let reader = stdin.lock();
 
-    let mut transaction_counter = 0;
-
-    let mut peekable_lines = reader
+    let lines = reader
         .lines()
         .filter_map(|line| match line {
             Ok(l) => Some(l),
             Err(_) => None, // Bad stdin line
         })
-        .filter_map(|line| {
-            match trace_style {
-                ModeFlag::Eip3155 => {
-                    let json = serde_json::from_str::<EvmStepEip3155>(&line);
-                    match json {
-                        Ok(step) => Some(TraceLine::StepEip3155(step)),
-                        Err(_) => {
-                            // Not an EvmStep (e.g., output)
-                            match serde_json::from_str::<EvmOutput>(&line) {
-                                Ok(output) => Some(TraceLine::Output(output)),
-                                Err(_) => None, // Not an EvmStep or Output
-                            }
-                        }
-                    }
-                }
-                ModeFlag::Debug => {
-                    let json = serde_json::from_str::<EvmStepDebug>(&line);
-                    match json {
-                        Ok(step) => Some(TraceLine::StepDebug(step)),
-                        Err(_) => {
-                            // Not an EvmStep (e.g., output)
-                            match serde_json::from_str::<EvmOutput>(&line) {
-                                Ok(output) => Some(TraceLine::Output(output)),
-                                Err(_) => None, // Not an EvmStep or Output
-                            }
-                        }
+        .filter_map(|line| parse_line(&trace_style, &line));
+
+    let mut stdout = Box::new(std::io::stdout());
+    let graph = process_lines(lines, &mut stdout);
+    if call_graph {
+        let _ = writeln!(stdout, "{}", graph.summary());
+    }
+}
+
+/// Reads a line of the trace in the given style. Lines that are neither an EVM step nor
+/// output are skipped (None).
+fn parse_line(trace_style: &ModeFlag, line: &str) -> Option<TraceLine> {
+    match trace_style {
+        ModeFlag::Eip3155 => parse_eip3155_line(line),
+        ModeFlag::Debug => {
+            let json = serde_json::from_str::<EvmStepDebug>(line);
+            match json {
+                Ok(step) => Some(TraceLine::StepDebug(step)),
+                Err(_) => {
+                    // Not an EvmStep (e.g., output)
+                    match serde_json::from_str::<EvmOutput>(line) {
+                        Ok(output) => Some(TraceLine::Output(output)),
+                        Err(_) => None, // Not an EvmStep or Output
                     }
                 }
             }
-        })
-        .peekable();
+        }
+        ModeFlag::StructLog => parse_eip3155_line(&struct_log_to_eip3155(line).ok()?),
+    }
+}
+
+/// Reads an EIP-3155 line (step or output).
+fn parse_eip3155_line(line: &str) -> Option<TraceLine> {
+    let json = serde_json::from_str::<EvmStepEip3155>(line);
+    match json {
+        Ok(step) => Some(TraceLine::StepEip3155(step)),
+        Err(_) => {
+            // Not an EvmStep (e.g., output)
+            match serde_json::from_str::<EvmOutput>(line) {
+                Ok(output) => Some(TraceLine::Output(output)),
+                Err(_) => None, // Not an EvmStep or Output
+            }
+        }
+    }
+}
+
+/// Converts one struct log line (debug_traceTransaction default tracer) into an
+/// EIP-3155 line. Used for the struct log trace style, so that archived struct logs can be
+/// piped to `process_trace` without re-executing the block.
+///
+/// Lines that are not struct logs (e.g., output) are returned unchanged.
+fn struct_log_to_eip3155(line: &str) -> Result<String, FilterError> {
+    match serde_json::from_str::<EvmStepDebug>(line) {
+        Ok(step) => Ok(serde_json::to_string(&EvmStepEip3155::from(&step))?),
+        Err(_) => match serde_json::from_str::<EvmOutput>(line) {
+            Ok(_) => Ok(line.to_string()),
+            Err(e) => Err(FilterError::SerdeJson(e)),
+        },
+    }
+}
+
+/// Writes a summary of each noteworthy step in the trace. Returns the message calls made.
+pub(crate) fn process_lines<I, W>(lines: I, stdout: &mut W) -> CallGraph
+where
+    I: Iterator<Item = TraceLine>,
+    W: Write,
+{
+    let mut peekable_lines = lines.peekable();
+    let mut transaction_counter = 0;
+    let mut call_graph = CallGraph::default();
 
     let mut context: Vec<Context> = vec![Context::default()];
     let mut pending_context = ContextUpdate::None;
     let mut create_counter: usize = 0;
 
-    let mut stdout = Box::new(std::io::stdout());
-
     while let Some(unprocessed_step) = peekable_lines.next() {
         // Add processed information to step.
         // Exclude uninteresting steps (ADD, ISZERO, ...)
@@ -96,6 +135,9 @@ pub fn process_trace(trace_style: ModeFlag) {
 
         // Update context
         apply_pending_context(&mut context, &mut pending_context);
+        if let Some(current) = context.last() {
+            call_graph.record(&processed, current, unprocessed_step.depth());
+        }
         pending_context =
             get_pending_context_update(&context, &processed, &mut create_counter).unwrap();
 
@@ -112,6 +154,7 @@ pub fn process_trace(trace_style: ModeFlag) {
             }
         }
     }
+    call_graph
 }
 
 /// If a line from the trace is of interest, a new representation is created.
@@ -130,3 +173,52 @@ fn process_step(step: &TraceLine) -> Option<ProcessedStep> {
         TraceLine::Output(evm_output) => Some(ProcessedStep::from(evm_output)),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Struct logs for: CALL to 0xaa with value, which SSTOREs and returns.
+    const STRUCT_LOGS: &str = r#"{"pc":0,"op":"PUSH1","gas":100000,"gasCost":3,"depth":1,"stack":[]}
+{"pc":2,"op":"CALL","gas":99997,"gasCost":2600,"depth":1,"stack":["0x0","0x0","0x0","0x0","0x64","0xaa","0xffff"],"memory":["0000000000000000000000000000000000000000000000000000000000000000"]}
+{"pc":0,"op":"SSTORE","gas":60000,"gasCost":20000,"depth":2,"stack":["0x1","0x2"]}
+{"pc":1,"op":"STOP","gas":40000,"gasCost":0,"depth":2,"stack":[]}
+{"pc":3,"op":"STOP","gas":57397,"gasCost":0,"depth":1,"stack":["0x1"]}
+{"output":"","gasUsed":"0xa0ab"}"#;
+
+    #[test]
+    fn test_struct_logs_processed_as_eip3155() {
+        let converted: Vec<String> = STRUCT_LOGS
+            .lines()
+            .map(|line| struct_log_to_eip3155(line).unwrap())
+            .collect();
+
+        let call: EvmStepEip3155 = serde_json::from_str(&converted[1]).unwrap();
+        assert_eq!(call.op, 0xf1);
+        assert_eq!(call.op_name, "CALL");
+        assert_eq!(call.gas, "0x1869d");
+        assert_eq!(call.gas_cost, "0xa28");
+        assert_eq!(call.mem_size, 32);
+        assert_eq!(converted[5], STRUCT_LOGS.lines().last().unwrap());
+
+        let eip3155_lines = STRUCT_LOGS
+            .lines()
+            .map(|line| parse_line(&ModeFlag::StructLog, line).unwrap());
+        let debug_lines = STRUCT_LOGS
+            .lines()
+            .map(|line| parse_line(&ModeFlag::Debug, line).unwrap());
+        let mut from_eip3155: Vec<u8> = vec![];
+        let mut from_debug: Vec<u8> = vec![];
+        let graph = process_lines(eip3155_lines, &mut from_eip3155);
+        process_lines(debug_lines, &mut from_debug);
+        assert!(!from_eip3155.is_empty());
+        assert_eq!(from_eip3155, from_debug);
+        assert_eq!(graph.summary().call, 1);
+    }
+
+    #[test]
+    fn test_struct_log_conversion_rejects_other_json() {
+        assert!(struct_log_to_eip3155(r#"{"unrelated":1}"#).is_err());
+        assert!(parse_line(&ModeFlag::StructLog, r#"{"unrelated":1}"#).is_none());
+    }
+}
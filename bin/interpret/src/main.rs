@@ -1,5 +1,6 @@
 use anyhow::Result;
 
+pub(crate) mod callgraph;
 pub(crate) mod cli;
 pub(crate) mod context;
 pub(crate) mod ether;
@@ -17,6 +18,6 @@ pub use filter::process_trace;
 /// ```
 fn main() -> Result<()> {
     let args = AppArgs::parse();
-    process_trace(args.trace_style);
+    process_trace(args.trace_style, args.call_graph);
     Ok(())
 }
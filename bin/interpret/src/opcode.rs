@@ -91,3 +91,144 @@ impl EvmStep for EvmStepEip3155 {
         &self.depth
     }
 }
+
+impl From<&EvmStepDebug> for EvmStepEip3155 {
+    /// Converts a struct log step (debug_traceTransaction) into an EIP-3155 step.
+    ///
+    /// Struct logs do not include the opcode number, so it is derived from the name.
+    /// Unrecognised names are represented as INVALID (0xfe).
+    fn from(step: &EvmStepDebug) -> Self {
+        let mem_size = step
+            .memory
+            .as_ref()
+            .map(|words| words.len() as u64 * 32)
+            .unwrap_or_default();
+        EvmStepEip3155 {
+            pc: step.pc,
+            op: opcode_value(&step.op).unwrap_or(0xfe) as u64,
+            gas: format!("0x{:x}", step.gas),
+            gas_cost: format!("0x{:x}", step.gas_cost),
+            mem_size,
+            stack: step.stack.clone(),
+            depth: step.depth,
+            op_name: step.op.clone(),
+            memory: step.memory.clone(),
+        }
+    }
+}
+
+/// Returns the opcode number for an opcode name (e.g., "SSTORE" -> 0x55).
+pub(crate) fn opcode_value(name: &str) -> Option<u8> {
+    // Numbered families.
+    let numbered = [
+        ("PUSH", 0x5f, 0, 32),
+        ("DUP", 0x7f, 1, 16),
+        ("SWAP", 0x8f, 1, 16),
+        ("LOG", 0xa0, 0, 4),
+    ];
+    for (prefix, base, min, max) in numbered {
+        if let Some(n) = name.strip_prefix(prefix).and_then(|n| n.parse::<u8>().ok()) {
+            if (min..=max).contains(&n) {
+                return Some(base + n - min);
+            }
+            return None;
+        }
+    }
+    let value = match name {
+        "STOP" => 0x00,
+        "ADD" => 0x01,
+        "MUL" => 0x02,
+        "SUB" => 0x03,
+        "DIV" => 0x04,
+        "SDIV" => 0x05,
+        "MOD" => 0x06,
+        "SMOD" => 0x07,
+        "ADDMOD" => 0x08,
+        "MULMOD" => 0x09,
+        "EXP" => 0x0a,
+        "SIGNEXTEND" => 0x0b,
+        "LT" => 0x10,
+        "GT" => 0x11,
+        "SLT" => 0x12,
+        "SGT" => 0x13,
+        "EQ" => 0x14,
+        "ISZERO" => 0x15,
+        "AND" => 0x16,
+        "OR" => 0x17,
+        "XOR" => 0x18,
+        "NOT" => 0x19,
+        "BYTE" => 0x1a,
+        "SHL" => 0x1b,
+        "SHR" => 0x1c,
+        "SAR" => 0x1d,
+        "KECCAK256" | "SHA3" => 0x20,
+        "ADDRESS" => 0x30,
+        "BALANCE" => 0x31,
+        "ORIGIN" => 0x32,
+        "CALLER" => 0x33,
+        "CALLVALUE" => 0x34,
+        "CALLDATALOAD" => 0x35,
+        "CALLDATASIZE" => 0x36,
+        "CALLDATACOPY" => 0x37,
+        "CODESIZE" => 0x38,
+        "CODECOPY" => 0x39,
+        "GASPRICE" => 0x3a,
+        "EXTCODESIZE" => 0x3b,
+        "EXTCODECOPY" => 0x3c,
+        "RETURNDATASIZE" => 0x3d,
+        "RETURNDATACOPY" => 0x3e,
+        "EXTCODEHASH" => 0x3f,
+        "BLOCKHASH" => 0x40,
+        "COINBASE" => 0x41,
+        "TIMESTAMP" => 0x42,
+        "NUMBER" => 0x43,
+        "DIFFICULTY" | "PREVRANDAO" => 0x44,
+        "GASLIMIT" => 0x45,
+        "CHAINID" => 0x46,
+        "SELFBALANCE" => 0x47,
+        "BASEFEE" => 0x48,
+        "POP" => 0x50,
+        "MLOAD" => 0x51,
+        "MSTORE" => 0x52,
+        "MSTORE8" => 0x53,
+        "SLOAD" => 0x54,
+        "SSTORE" => 0x55,
+        "JUMP" => 0x56,
+        "JUMPI" => 0x57,
+        "PC" => 0x58,
+        "MSIZE" => 0x59,
+        "GAS" => 0x5a,
+        "JUMPDEST" => 0x5b,
+        "CREATE" => 0xf0,
+        "CALL" => 0xf1,
+        "CALLCODE" => 0xf2,
+        "RETURN" => 0xf3,
+        "DELEGATECALL" => 0xf4,
+        "CREATE2" => 0xf5,
+        "STATICCALL" => 0xfa,
+        "REVERT" => 0xfd,
+        "INVALID" => 0xfe,
+        "SELFDESTRUCT" => 0xff,
+        _ => return None,
+    };
+    Some(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_opcode_value() {
+        assert_eq!(opcode_value("STOP"), Some(0x00));
+        assert_eq!(opcode_value("SSTORE"), Some(0x55));
+        assert_eq!(opcode_value("PUSH0"), Some(0x5f));
+        assert_eq!(opcode_value("PUSH32"), Some(0x7f));
+        assert_eq!(opcode_value("DUP1"), Some(0x80));
+        assert_eq!(opcode_value("SWAP16"), Some(0x9f));
+        assert_eq!(opcode_value("LOG4"), Some(0xa4));
+        assert_eq!(opcode_value("PUSH33"), None);
+        assert_eq!(opcode_value("DUP0"), None);
+        assert_eq!(opcode_value("NOTANOPCODE"), None);
+    }
+}
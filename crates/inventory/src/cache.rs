@@ -1,9 +1,14 @@
 //! Used for calling a node and storing the result locally for testing.
+//!
+//! Progress and the duration of each step are reported with the `log` crate, which is
+//! silent unless the application installs a logger (e.g., `env_logger`).
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap},
     fs::{self, File},
-    io::{self, BufReader, Write},
-    path::PathBuf,
+    io::{self, BufReader, BufWriter, Write},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    time::Instant,
 };
 
 use archors_types::{
@@ -12,28 +17,35 @@ use archors_types::{
 };
 use ethers::{
     abi::ethereum_types::FromStrRadixErr,
-    types::{Block, Transaction, H160, H256, U64},
+    types::{Block, EIP1186ProofResponse, Transaction, H160, H256, U64},
     utils::keccak256,
 };
-use futures::stream::StreamExt;
-use log::{debug, info};
+use futures::stream::{self, StreamExt};
+use log::{debug, info, warn};
 use reqwest::Client;
+use serde::{de::DeserializeOwned, Serialize};
 use thiserror::Error;
 use url::{ParseError, Url};
 
 use crate::{
     oracle::{oracle_from_simulated_state_update, OracleError},
     rpc::{
-        debug_trace_block_default, debug_trace_block_prestate, eth_get_proof, get_block_by_number,
-        AccountProofResponse, BlockDefaultTraceResponse, BlockPrestateResponse,
+        debug_trace_block_default, debug_trace_block_prestate, eth_get_proof, eth_get_proof_batch,
+        get_block_by_number, get_block_header_by_number, AccountProofResponse,
+        BlockDefaultTraceResponse, BlockHeaderResponse, BlockPrestateResponse,
         BlockPrestateTransactions, BlockResponse, JsonRpcRequest,
     },
+    store::{ArtifactKey, BlockStore, FileStore, StoreError},
     transferrable::{state_from_parts, TransferrableError},
-    types::{BlockHashAccess, BlockHashAccesses, BlockProofs, BlockStateAccesses},
+    types::{
+        AccountToProve, BlockCompressionStat, BlockHashAccess, BlockHashAccesses, BlockProofs,
+        BlockProofsError, BlockStateAccesses, CompressionRatio, ParcelMetadata, ParcelStats,
+        StateDiscrepancy,
+    },
     utils::{compress, decompress, hex_decode, string_to_h256, UtilsError},
 };
 
-static CACHE_DIR: &str = "data/blocks";
+pub(crate) static CACHE_DIR: &str = "data/blocks";
 
 #[derive(Debug, Error)]
 pub enum CacheError {
@@ -44,8 +56,12 @@ pub enum CacheError {
     },
     #[error("Block retrieved does not yet have a number")]
     NoBlockNumber,
+    #[error("Block {0} has no prior block")]
+    NoPriorBlock(u64),
     #[error("Node oracle error {0}")]
     OracleError(#[from] OracleError),
+    #[error("Block proofs error {0}")]
+    BlockProofsError(#[from] BlockProofsError),
     #[error("Reqwest error {0}")]
     ReqwestError(#[from] reqwest::Error),
     #[error("IO error {0}")]
@@ -60,39 +76,71 @@ pub enum CacheError {
     StackEmpty,
     #[error("State type error {0}")]
     StateError(#[from] StateError),
+    #[error("Store error {0}")]
+    StoreError(#[from] StoreError),
     #[error("Transferrable error {0}")]
     TransferrableError(#[from] TransferrableError),
     #[error("Url error {0}")]
     UrlError(#[from] ParseError),
     #[error("Utils error {0}")]
     UtilsError(#[from] UtilsError),
+    #[error("JSON-RPC batch response has {received} responses for {expected} requests")]
+    IncompleteBatchResponse { expected: usize, received: usize },
+    #[error("Node returned no proof for account {0:?}")]
+    NoProofReturned(H160),
+    #[error("Node returned a proof for account {found:?}, expected {expected:?}")]
+    ProofAddressMismatch { expected: H160, found: H160 },
     #[error("File {filename} could not be opened {source}")]
     FileOpener {
         source: io::Error,
         filename: PathBuf,
     },
+    #[error("Parcel metadata {found:?} does not match the expected {expected:?}")]
+    ParcelMetadataMismatch {
+        expected: ParcelMetadata,
+        found: ParcelMetadata,
+    },
 }
 
 pub async fn store_block_with_transactions(url: &str, target_block: u64) -> Result<(), CacheError> {
+    store_block_with_transactions_in(&FileStore::default(), url, target_block).await
+}
+
+/// Obtains and stores a block with transactions in the given store.
+pub async fn store_block_with_transactions_in<S: BlockStore>(
+    store: &S,
+    url: &str,
+    target_block: u64,
+) -> Result<(), CacheError> {
+    let block = fetch_block_with_transactions(url, target_block).await?;
+    let Some(block_number) = block.number else {
+        return Err(CacheError::NoBlockNumber);
+    };
+    let names = CacheFileNames::new(block_number.as_u64());
+    write_json(store, &names.block_with_transactions(), &block)
+}
+
+/// Calls eth_getBlockByNumber for a block with transactions.
+///
+/// Transactions are sorted by index, ready for execution.
+pub async fn fetch_block_with_transactions(
+    url: &str,
+    target_block: u64,
+) -> Result<Block<Transaction>, CacheError> {
     let block_number_hex = format!("0x{:x}", target_block);
     let client = Client::new();
-    // Get a block.
-    let block = client
+    let request = get_block_by_number(&block_number_hex);
+    let response: BlockResponse = client
         .post(Url::parse(url)?)
-        .json(&get_block_by_number(&block_number_hex))
+        .json(&request)
         .send()
         .await?
-        .json::<BlockResponse>()
-        .await?;
-
-    let Some(block_number) = block.result.number else {
-        return Err(CacheError::NoBlockNumber);
-    };
-    let names = CacheFileNames::new(block_number.as_u64());
-    fs::create_dir_all(names.dirname())?;
-    let mut block_file = File::create(names.block_with_transactions())?;
-    block_file.write_all(serde_json::to_string_pretty(&block.result)?.as_bytes())?;
-    Ok(())
+        .json()
+        .await
+        .map_err(|e| CacheError::DecodingFailed { source: e, request })?;
+    let mut block = response.result;
+    block.transactions.sort_by_key(|tx| tx.transaction_index);
+    Ok(block)
 }
 
 /// Retrieves required state for a particular cached block.
@@ -106,23 +154,50 @@ pub async fn fetch_required_block_state(
     // Prestate-trace the block. Then deduplicate. Then getProof for prior block.
     info!("1/6 requesting debug_traceBlock with prestate tracer");
     let tx_prestates = request_prestate_tracer(url, target_block).await?;
-    info!("2/6 extracting unique state accesses");
-    let accesses = BlockStateAccesses::from_prestate_accesses(tx_prestates);
+    // Trace (no-memory) the block. Then filter for BLOCKHASH, EXTCODEHASH and EXTCODESIZE.
+    info!("2/6 requesting debug_traceBlock with default trace (for BLOCKHASH opcode)");
+    let trace_accesses = fetch_default_trace_accesses(url, target_block).await?;
+    info!("3/6 extracting unique state accesses");
+    let mut accesses = BlockStateAccesses::from_prestate_accesses(tx_prestates);
+    accesses.include_accounts(&trace_accesses.code_inspected_accounts);
     let account_num = accesses.access_data.len();
-    info!("3/6 requesting eth_getProof for accessed states ({account_num} separate calls)");
-    let proofs = request_proofs(get_proof_url, &accesses, target_block).await?;
+    info!("4/6 requesting eth_getProof for accessed states ({account_num} separate calls)");
+    let proofs = request_proofs(get_proof_url, &accesses, prior_block(target_block)?).await?;
     // Parse from prestate-trace.
-    info!("4/6 extracting contract bytecode from state access response");
+    info!("5/6 extracting contract bytecode from state access response");
     let mut contracts: Vec<ContractBytes> = contracts_from_state(accesses)?.into_values().collect();
     contracts.sort();
-    // Trace (no-memory) the block. Then filter for BLOCKHASH opcode.
-    info!("5/6 requesting debug_traceBlock with default trace (for BLOCKHASH opcode)");
-    let blockhashes = fetch_blockhashes(url, target_block).await?;
     info!("6/6 constructing RequiredBlockState");
-    let required_block_state = state_from_parts(proofs, contracts, blockhashes)?;
+    let required_block_state = state_from_parts(proofs, contracts, trace_accesses.blockhashes)?;
     Ok(required_block_state)
 }
 
+/// Retrieves required state for each block in a range, preparing up to `concurrency`
+/// blocks at a time.
+///
+/// The cap bounds the number of blocks with requests in flight, which keeps the load on
+/// the node within rate limits. A failure for one block does not stop the others.
+/// Results are returned in block order.
+pub async fn prepare_blocks(
+    url: &str,
+    range: RangeInclusive<u64>,
+    concurrency: usize,
+) -> Vec<(u64, Result<RequiredBlockState, CacheError>)> {
+    let mut results: Vec<(u64, Result<RequiredBlockState, CacheError>)> = stream::iter(range)
+        .map(|block| async move {
+            let state = fetch_required_block_state(url, url, block).await;
+            if let Err(e) = &state {
+                debug!("Unable to prepare block {block}: {e}");
+            }
+            (block, state)
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+    results.sort_by_key(|(block, _)| *block);
+    results
+}
+
 /// Retrieves and stores required state for a particular cached block.
 ///
 /// Creates a transferrable state parcel without the creation of intermediate cache files.
@@ -130,9 +205,19 @@ pub async fn store_required_state(
     url: &str,
     get_proof_url: &str,
     target_block: u64,
+) -> Result<(), CacheError> {
+    store_required_state_in(&FileStore::default(), url, get_proof_url, target_block).await
+}
+
+/// Retrieves required state for a particular block and stores it in the given store.
+pub async fn store_required_state_in<S: BlockStore>(
+    store: &S,
+    url: &str,
+    get_proof_url: &str,
+    target_block: u64,
 ) -> Result<(), CacheError> {
     let data = fetch_required_block_state(url, get_proof_url, target_block).await?;
-    save_transferrable_data(target_block, data)?;
+    save_transferrable_data(store, target_block, data)?;
     Ok(())
 }
 
@@ -150,12 +235,18 @@ pub async fn store_required_state(
 ///     - Composed of (key, value).
 ///     - Will be used with eth_getProof.
 pub async fn store_block_prestate_tracer(url: &str, target_block: u64) -> Result<(), CacheError> {
+    store_block_prestate_tracer_in(&FileStore::default(), url, target_block).await
+}
+
+/// Calls debug trace transaction with prestate tracer and stores the result in the given store.
+pub async fn store_block_prestate_tracer_in<S: BlockStore>(
+    store: &S,
+    url: &str,
+    target_block: u64,
+) -> Result<(), CacheError> {
     let tx_prestates = request_prestate_tracer(url, target_block).await?;
     let names = CacheFileNames::new(target_block);
-    fs::create_dir_all(names.dirname())?;
-    let mut block_file = File::create(names.block_prestate_trace())?;
-    block_file.write_all(serde_json::to_string_pretty(&tx_prestates)?.as_bytes())?;
-    Ok(())
+    write_json(store, &names.block_prestate_trace(), &tx_prestates)
 }
 
 async fn request_prestate_tracer(
@@ -177,34 +268,62 @@ async fn request_prestate_tracer(
 }
 
 /// Obtains and stores BLOCKHASH opcode use as cache file.
+///
+/// Accounts inspected by EXTCODEHASH or EXTCODESIZE are also stored, so that they are
+/// included in `store_state_proofs`.
 pub async fn store_blockhash_opcode_reads(url: &str, target_block: u64) -> Result<(), CacheError> {
-    let hashes = fetch_blockhashes(url, target_block).await?;
+    store_blockhash_opcode_reads_in(&FileStore::default(), url, target_block).await
+}
 
+/// Obtains and stores BLOCKHASH opcode use in the given store.
+pub async fn store_blockhash_opcode_reads_in<S: BlockStore>(
+    store: &S,
+    url: &str,
+    target_block: u64,
+) -> Result<(), CacheError> {
+    let accesses = fetch_default_trace_accesses(url, target_block).await?;
     let names = CacheFileNames::new(target_block);
-    let dir = names.dirname();
-    fs::create_dir_all(dir)?;
-    let mut blockhash_file = File::create(names.blockhashes())?;
-    blockhash_file.write_all(serde_json::to_string_pretty(&hashes)?.as_bytes())?;
+    write_json(store, &names.blockhashes(), &accesses.blockhashes)?;
+    write_json(
+        store,
+        &names.code_inspected_accounts(),
+        &accesses.code_inspected_accounts,
+    )
+}
 
-    Ok(())
+/// Number of recent blocks whose hash is available to the BLOCKHASH opcode.
+const BLOCKHASH_WINDOW: u64 = 256;
+
+/// State read by opcodes in a default trace, that is not necessarily present in the
+/// prestate tracer output.
+pub(crate) struct DefaultTraceAccesses {
+    /// BLOCKHASH opcode use (up to 256 pairs of block number / blockhash pairs).
+    pub(crate) blockhashes: BlockHashAccesses,
+    /// Block numbers read by BLOCKHASH outside the window of the 256 prior blocks.
+    /// These are not included in `blockhashes`, as the EVM does not look them up.
+    pub(crate) out_of_range_blockhashes: Vec<u64>,
+    /// Accounts whose code was inspected with EXTCODEHASH or EXTCODESIZE. The prestate
+    /// tracer may omit these if the account is not otherwise accessed.
+    pub(crate) code_inspected_accounts: Vec<H160>,
 }
 
 /// Calls debug_traceBlock with the default tracer and filters the result
-/// for BLOCKHASH opcode use.
-///
-/// The results (up to 256 pairs of block number / blockhash pairs) are stored.
+/// for BLOCKHASH, EXTCODEHASH and EXTCODESIZE opcode use.
 ///
 /// Uses a temp file to store the trace instead of holding in memory.
 ///
 /// Alternative, use terminal and use grep/jq to avoid disk write.
-async fn fetch_blockhashes(url: &str, target_block: u64) -> Result<BlockHashAccesses, CacheError> {
-    let names = CacheFileNames::new(target_block);
-    let dir = names.dirname();
-    fs::create_dir_all(dir)?;
+async fn fetch_default_trace_accesses(
+    url: &str,
+    target_block: u64,
+) -> Result<DefaultTraceAccesses, CacheError> {
+    let dir = FileStore::default().block_dir(target_block);
+    fs::create_dir_all(&dir)?;
 
-    let mut trace_filename = names.dirname();
-    trace_filename.push("temp_trace_for_blockhash_opcode.txt");
-    let mut trace_file = File::create(&trace_filename)?;
+    let trace_filename = dir.join("temp_trace_for_blockhash_opcode.txt");
+    // Removed when dropped, including if an error occurs.
+    let (temp_trace, trace_file) = TempFile::create(trace_filename)?;
+    let mut trace_file = BufWriter::new(trace_file);
     // Get the trace from the node and store temporarily.
     let client = Client::new();
     let block_number_hex = format!("0x{:x}", target_block);
@@ -219,10 +338,22 @@ async fn fetch_blockhashes(url: &str, target_block: u64) -> Result<BlockHashAcce
     while let Some(chunk) = stream.next().await {
         trace_file.write_all(&chunk?)?;
     }
+    trace_file.flush()?;
     drop(trace_file);
 
-    // Read the trace from file and filter for blockhash opcode.
-    let file = File::open(&trace_filename).map_err(|e| CacheError::FileOpener {
+    default_trace_accesses_from_file(temp_trace.path(), target_block)
+}
+
+/// Reads a stored debug_traceBlock (default tracer) response and filters for BLOCKHASH,
+/// EXTCODEHASH and EXTCODESIZE opcode use.
+///
+/// BLOCKHASH reads outside of `[target_block - 256, target_block - 1]` are dropped
+/// and flagged with a warning.
+fn default_trace_accesses_from_file(
+    trace_filename: &Path,
+    target_block: u64,
+) -> Result<DefaultTraceAccesses, CacheError> {
+    let file = File::open(trace_filename).map_err(|e| CacheError::FileOpener {
         source: e,
         filename: trace_filename.to_owned(),
     })?;
@@ -230,7 +361,10 @@ async fn fetch_blockhashes(url: &str, target_block: u64) -> Result<BlockHashAcce
     let stream =
         serde_json::Deserializer::from_reader(&mut reader).into_iter::<BlockDefaultTraceResponse>();
 
+    let window = target_block.saturating_sub(BLOCKHASH_WINDOW)..target_block;
     let mut blockhash_reads: HashMap<U64, H256> = HashMap::new();
+    let mut out_of_range_blockhashes: BTreeSet<u64> = BTreeSet::new();
+    let mut code_inspected_accounts: BTreeSet<H160> = BTreeSet::new();
     for response in stream {
         for tx in response?.result {
             let mut steps = tx.result.struct_logs.iter().peekable();
@@ -246,8 +380,21 @@ async fn fetch_blockhashes(url: &str, target_block: u64) -> Result<BlockHashAcce
                         .last()
                         .ok_or(CacheError::StackEmpty)?;
                     let block_hash = string_to_h256(block_hash_string)?;
+                    if !window.contains(&block_number.as_u64()) {
+                        warn!(
+                            "Block {target_block} has BLOCKHASH read of block {block_number} \
+                            outside the 256 block window, ignoring"
+                        );
+                        out_of_range_blockhashes.insert(block_number.as_u64());
+                        continue;
+                    }
                     blockhash_reads.insert(block_number.to_owned(), block_hash.to_owned());
                 }
+                if step.op == "EXTCODEHASH" || step.op == "EXTCODESIZE" {
+                    let address_string = step.stack.last().ok_or(CacheError::StackEmpty)?;
+                    let address = H160::from(string_to_h256(address_string)?);
+                    code_inspected_accounts.insert(address);
+                }
             }
         }
     }
@@ -261,11 +408,37 @@ async fn fetch_blockhashes(url: &str, target_block: u64) -> Result<BlockHashAcce
             })
             .collect::<Vec<BlockHashAccess>>(),
     };
+    Ok(DefaultTraceAccesses {
+        blockhashes: hashes,
+        out_of_range_blockhashes: out_of_range_blockhashes.into_iter().collect(),
+        code_inspected_accounts: code_inspected_accounts.into_iter().collect(),
+    })
+}
+
+/// A file that is removed when dropped.
+///
+/// Traces can be hundreds of MB, so they must not be left behind if processing fails.
+struct TempFile {
+    path: PathBuf,
+}
 
-    // Remove the temp trace file
-    fs::remove_file(trace_filename)?;
+impl TempFile {
+    /// Creates the file, returning the guard and the file handle for writing.
+    fn create(path: PathBuf) -> Result<(Self, File), io::Error> {
+        let file = File::create(&path)?;
+        Ok((Self { path }, file))
+    }
+    fn path(&self) -> &Path {
+        &self.path
+    }
+}
 
-    Ok(hashes)
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            debug!("Unable to remove temp file {}: {e}", self.path.display());
+        }
+    }
 }
 
 /// Uses a cached block prestate and groups account state data when it is accessed
@@ -274,18 +447,27 @@ async fn fetch_blockhashes(url: &str, target_block: u64) -> Result<BlockHashAcce
 /// Note that accounts can have the same bytecode (e.g., redeployments) and this
 /// represent duplication that can be resolved with compression.
 pub fn store_deduplicated_state(target_block: u64) -> Result<(), CacheError> {
+    store_deduplicated_state_in(&FileStore::default(), target_block)
+}
+
+/// Groups account state data from a stored block prestate, using the given store.
+pub fn store_deduplicated_state_in<S: BlockStore>(
+    store: &S,
+    target_block: u64,
+) -> Result<(), CacheError> {
     let names = CacheFileNames::new(target_block);
-    let filename = names.block_prestate_trace();
-    let data = fs::read_to_string(&filename).map_err(|e| CacheError::FileOpener {
-        source: e,
-        filename,
-    })?;
-    let block: Vec<BlockPrestateTransactions> = serde_json::from_str(&data)?;
+    let block: Vec<BlockPrestateTransactions> = read_json(store, &names.block_prestate_trace())?;
+    let start = Instant::now();
     let state_accesses = BlockStateAccesses::from_prestate_accesses(block);
-    fs::create_dir_all(names.dirname())?;
-    let mut block_file = File::create(names.block_accessed_state_deduplicated())?;
-    block_file.write_all(serde_json::to_string_pretty(&state_accesses)?.as_bytes())?;
-    Ok(())
+    debug!(
+        "Deduplicated state accesses for block {target_block} in {:?}",
+        start.elapsed()
+    );
+    write_json(
+        store,
+        &names.block_accessed_state_deduplicated(),
+        &state_accesses,
+    )
 }
 
 /// Uses a cached record of accounts and storage slots and for each account calls
@@ -302,37 +484,233 @@ pub fn store_deduplicated_state(target_block: u64) -> Result<(), CacheError> {
 /// state root computation. This doubles the calls to get_proof, and this second set of
 /// calls is not required if the post-execution root is not required.
 pub async fn store_state_proofs(url: &str, target_block: u64) -> Result<(), CacheError> {
-    let prior_block = target_block - 1;
+    store_state_proofs_in(&FileStore::default(), url, target_block).await
+}
+
+/// Obtains and stores proofs for a stored record of accounts and storage slots, using the
+/// given store.
+pub async fn store_state_proofs_in<S: BlockStore>(
+    store: &S,
+    url: &str,
+    target_block: u64,
+) -> Result<(), CacheError> {
+    let prior_block = prior_block(target_block)?;
     let names = CacheFileNames::new(target_block);
-    let filename = names.block_accessed_state_deduplicated();
-    let data = fs::read_to_string(&filename).map_err(|e| CacheError::FileOpener {
-        source: e,
-        filename,
-    })?;
-    let state_accesses: BlockStateAccesses = serde_json::from_str(&data)?;
+    let mut state_accesses: BlockStateAccesses =
+        read_json(store, &names.block_accessed_state_deduplicated())?;
+    // Accounts only inspected by EXTCODEHASH/EXTCODESIZE may be absent from the prestate.
+    if store.exists(&names.code_inspected_accounts()) {
+        let accounts: Vec<H160> = read_json(store, &names.code_inspected_accounts())?;
+        state_accesses.include_accounts(&accounts);
+    }
 
     // Get proofs for prior block
-    let block_proofs = request_proofs(url, &state_accesses, prior_block).await?;
-    fs::create_dir_all(names.dirname())?;
-    let mut block_file = File::create(names.prior_block_state_proofs())?;
-    block_file.write_all(serde_json::to_string_pretty(&block_proofs)?.as_bytes())?;
+    let partial = names.prior_block_state_proofs_partial();
+    let block_proofs =
+        request_proofs_resumable(store, &partial, url, &state_accesses, prior_block).await?;
+    write_json(store, &names.prior_block_state_proofs(), &block_proofs)?;
+    store.remove(&partial)?;
+    for discrepancy in state_accesses.discrepancies_with_proofs(&block_proofs)? {
+        warn!("Prestate tracer and eth_getProof disagree for block {prior_block}: {discrepancy:?}");
+    }
 
     // Get proofs for block. These are used for debugging post-execution proofs.
-    let block_proofs = request_proofs(url, &state_accesses, target_block).await?;
-    fs::create_dir_all(names.dirname())?;
-    let mut block_file = File::create(names.block_state_proofs())?;
-    block_file.write_all(serde_json::to_string_pretty(&block_proofs)?.as_bytes())?;
+    let partial = names.block_state_proofs_partial();
+    let block_proofs =
+        request_proofs_resumable(store, &partial, url, &state_accesses, target_block).await?;
+    write_json(store, &names.block_state_proofs(), &block_proofs)?;
+    store.remove(&partial)?;
     // They could also be used for internal nodes.
     Ok(())
 }
 
+/// Returns the proof (without storage) for an account at a block, calling eth_getProof only
+/// if the proof is not already cached.
+///
+/// For obtaining proofs on demand, rather than for every account accessed in a block.
+pub async fn get_or_fetch_account_proof(
+    url: &str,
+    block: u64,
+    address: H160,
+    base: PathBuf,
+) -> Result<EIP1186ProofResponse, CacheError> {
+    get_or_fetch_account_proof_in(&FileStore::new(base), url, block, address).await
+}
+
+/// Returns a cached account proof from the given store, or fetches and caches it.
+///
+/// Proofs are cached for the block they are requested at (not the block after).
+pub async fn get_or_fetch_account_proof_in<S: BlockStore>(
+    store: &S,
+    url: &str,
+    block: u64,
+    address: H160,
+) -> Result<EIP1186ProofResponse, CacheError> {
+    let key = CacheFileNames::new(block).account_proofs();
+    let mut cached = match store.exists(&key) {
+        true => read_json(store, &key)?,
+        false => BlockProofs {
+            proofs: HashMap::new(),
+        },
+    };
+    if let Some(proof) = cached.proofs.get(&address) {
+        return Ok(proof.clone());
+    }
+    let account = AccountToProve {
+        address: format!("{address:?}"),
+        slots: vec![],
+    };
+    let block_number_hex = format!("0x{:x}", block);
+    let proof = request_proofs_individually(&Client::new(), url, &[account], &block_number_hex)
+        .await?
+        .pop()
+        .ok_or(CacheError::NoProofReturned(address))?;
+    if proof.address != address {
+        return Err(CacheError::ProofAddressMismatch {
+            expected: address,
+            found: proof.address,
+        });
+    }
+    cached.proofs.insert(address, proof.clone());
+    write_json(store, &key, &cached)?;
+    Ok(proof)
+}
+
+/// Compares the stored deduplicated prestate with the stored proofs for the prior block.
+///
+/// Both are the state of accounts prior to the target block, from independent calls to
+/// a node. Any discrepancy indicates that the node returned inconsistent data.
+pub fn prestate_discrepancies_in<S: BlockStore>(
+    store: &S,
+    target_block: u64,
+) -> Result<Vec<StateDiscrepancy>, CacheError> {
+    let names = CacheFileNames::new(target_block);
+    let state_accesses: BlockStateAccesses =
+        read_json(store, &names.block_accessed_state_deduplicated())?;
+    let block_proofs: BlockProofs = read_json(store, &names.prior_block_state_proofs())?;
+    Ok(state_accesses.discrepancies_with_proofs(&block_proofs)?)
+}
+
+/// Calls eth_getProof for every given accessed state, like `request_proofs`, recording
+/// progress in the store after every batch.
+///
+/// If a partial record is present (e.g., from an interrupted run), accounts in it are
+/// not requested again.
+async fn request_proofs_resumable<S: BlockStore>(
+    store: &S,
+    partial: &ArtifactKey,
+    url: &str,
+    accesses: &BlockStateAccesses,
+    target_block: u64,
+) -> Result<BlockProofs, CacheError> {
+    let (mut block_proofs, remaining) = resume_proofs(store, partial, accesses)?;
+    if !block_proofs.proofs.is_empty() {
+        info!(
+            "Resuming eth_getProof for block {target_block}: {} accounts stored, {} remaining",
+            block_proofs.proofs.len(),
+            remaining.len()
+        );
+    }
+    let client = Client::new();
+    let block_number_hex = format!("0x{:x}", target_block);
+    let start = Instant::now();
+    for accounts in remaining.chunks(PROOFS_PER_BATCH) {
+        request_proof_chunk(&client, url, accounts, &block_number_hex, &mut block_proofs).await?;
+        write_json(store, partial, &block_proofs)?;
+    }
+    debug!(
+        "Fetched {} proofs for block {target_block} in {:?}",
+        remaining.len(),
+        start.elapsed()
+    );
+    Ok(block_proofs)
+}
+
+/// Returns the proofs already stored in a partial record (if any) and the accounts that
+/// do not yet have a proof.
+fn resume_proofs<S: BlockStore>(
+    store: &S,
+    partial: &ArtifactKey,
+    accesses: &BlockStateAccesses,
+) -> Result<(BlockProofs, Vec<AccountToProve>), CacheError> {
+    let block_proofs = match store.exists(partial) {
+        true => read_json(store, partial)?,
+        false => BlockProofs {
+            proofs: HashMap::new(),
+        },
+    };
+    let mut remaining = vec![];
+    for account in accesses.get_all_accounts_to_prove() {
+        let address = H160::from_slice(&hex_decode(&account.address)?);
+        if !block_proofs.proofs.contains_key(&address) {
+            remaining.push(account);
+        }
+    }
+    Ok((block_proofs, remaining))
+}
+
+/// Returns the number of the block whose post-state the target block is applied to.
+///
+/// Block 0 (genesis) has no prior block.
+pub fn prior_block(target_block: u64) -> Result<u64, CacheError> {
+    target_block
+        .checked_sub(1)
+        .ok_or(CacheError::NoPriorBlock(target_block))
+}
+
+/// Calls eth_getBlockByNumber for the block prior to the target block and returns
+/// its state root.
+///
+/// This is the root that proofs for the target block (requested at the prior block)
+/// are verified against.
+pub async fn fetch_prior_block_state_root(
+    url: &str,
+    target_block: u64,
+) -> Result<H256, CacheError> {
+    let block_number_hex = format!("0x{:x}", prior_block(target_block)?);
+    let client = Client::new();
+    let request = get_block_header_by_number(&block_number_hex);
+    let response: BlockHeaderResponse = client
+        .post(Url::parse(url)?)
+        .json(&request)
+        .send()
+        .await?
+        .json()
+        .await
+        .map_err(|e| CacheError::DecodingFailed { source: e, request })?;
+    Ok(response.result.state_root)
+}
+
+/// Obtains and stores the state root of the block prior to the target block as a cache file.
+pub async fn store_prior_block_state_root(url: &str, target_block: u64) -> Result<(), CacheError> {
+    store_prior_block_state_root_in(&FileStore::default(), url, target_block).await
+}
+
+/// Obtains and stores the state root of the block prior to the target block in the given store.
+pub async fn store_prior_block_state_root_in<S: BlockStore>(
+    store: &S,
+    url: &str,
+    target_block: u64,
+) -> Result<(), CacheError> {
+    let state_root = fetch_prior_block_state_root(url, target_block).await?;
+    let names = CacheFileNames::new(target_block);
+    write_json(store, &names.prior_block_state_root(), &state_root)
+}
+
+/// Number of eth_getProof calls sent in a single JSON-RPC batch request.
+const PROOFS_PER_BATCH: usize = 100;
+
 /// Calls a node eth_getProof endpoint for every given accessed state.
 ///
+/// Calls are sent as JSON-RPC batch requests to reduce round trips. If a batch is not
+/// answered (e.g., the node does not support batching), the calls in that batch are
+/// sent individually.
+///
 /// Note that proofs are post-execution.
 ///
 /// To get the proofs for "state ready to trace the target block", the target
 /// block should be the block prior to the one that will be traced.
-async fn request_proofs(
+pub async fn request_proofs(
     url: &str,
     accesses: &BlockStateAccesses,
     target_block: u64,
@@ -343,10 +721,83 @@ async fn request_proofs(
     let mut block_proofs = BlockProofs {
         proofs: HashMap::new(),
     };
-    let prior_block_number_hex = format!("0x{:x}", target_block);
-    for account in accounts_to_prove {
-        let proof_request = eth_get_proof(&account, &prior_block_number_hex);
-        let account = H160::from_slice(&hex_decode(account.address)?);
+    let block_number_hex = format!("0x{:x}", target_block);
+    for accounts in accounts_to_prove.chunks(PROOFS_PER_BATCH) {
+        request_proof_chunk(&client, url, accounts, &block_number_hex, &mut block_proofs).await?;
+    }
+    Ok(block_proofs)
+}
+
+/// Requests proofs for a chunk of accounts as a batch, falling back to individual
+/// requests, and adds them to the block proofs.
+async fn request_proof_chunk(
+    client: &Client,
+    url: &str,
+    accounts: &[AccountToProve],
+    block_number_hex: &str,
+    block_proofs: &mut BlockProofs,
+) -> Result<(), CacheError> {
+    let proofs = match request_proof_batch(client, url, accounts, block_number_hex).await {
+        Ok(proofs) => proofs,
+        Err(e) => {
+            debug!("Batch eth_getProof failed ({e}), sending requests individually");
+            request_proofs_individually(client, url, accounts, block_number_hex).await?
+        }
+    };
+    for (account, proof) in accounts.iter().zip(proofs) {
+        let account = H160::from_slice(&hex_decode(&account.address)?);
+        block_proofs.proofs.insert(account, proof);
+    }
+    Ok(())
+}
+
+/// Sends eth_getProof calls for the accounts as a single batch request.
+///
+/// Proofs are returned in the same order as the accounts.
+async fn request_proof_batch(
+    client: &Client,
+    url: &str,
+    accounts: &[AccountToProve],
+    block_number_hex: &str,
+) -> Result<Vec<EIP1186ProofResponse>, CacheError> {
+    let batch = eth_get_proof_batch(accounts, block_number_hex);
+    let mut responses: Vec<AccountProofResponse> = client
+        .post(Url::parse(url)?)
+        .json(&batch)
+        .send()
+        .await?
+        .json()
+        .await?;
+    // Responses to a batch may be in any order.
+    responses.sort_by_key(|response| response.id);
+    let ids_match = responses
+        .iter()
+        .enumerate()
+        .all(|(index, response)| response.id as usize == index);
+    if responses.len() != batch.len() || !ids_match {
+        return Err(CacheError::IncompleteBatchResponse {
+            expected: batch.len(),
+            received: responses.len(),
+        });
+    }
+    Ok(responses
+        .into_iter()
+        .map(|response| response.result)
+        .collect())
+}
+
+/// Sends one eth_getProof request per account.
+///
+/// Proofs are returned in the same order as the accounts.
+async fn request_proofs_individually(
+    client: &Client,
+    url: &str,
+    accounts: &[AccountToProve],
+    block_number_hex: &str,
+) -> Result<Vec<EIP1186ProofResponse>, CacheError> {
+    let mut proofs = vec![];
+    for account in accounts {
+        let proof_request = eth_get_proof(account, block_number_hex);
         let response: AccountProofResponse = client
             .post(Url::parse(url)?)
             .json(&proof_request)
@@ -358,9 +809,9 @@ async fn request_proofs(
                 source: e,
                 request: proof_request,
             })?;
-        block_proofs.proofs.insert(account, response.result);
+        proofs.push(response.result);
     }
-    Ok(block_proofs)
+    Ok(proofs)
 }
 
 /// Uses a cached deduplicated block prestate compresses the data.
@@ -368,15 +819,14 @@ async fn request_proofs(
 /// This is important because some bytecode may exist multiple times
 /// at different addresses.
 pub fn compress_deduplicated_state(target_block: u64) -> Result<(), CacheError> {
+    let store = FileStore::default();
     let names = CacheFileNames::new(target_block);
-    let filename = names.block_accessed_state_deduplicated();
-    let data = fs::read(&filename).map_err(|e| CacheError::FileOpener {
-        source: e,
-        filename,
-    })?;
+    let data = store.read(&names.block_accessed_state_deduplicated())?;
     let compressed = compress(data)?;
-    let mut file = File::create(names.block_accessed_state_deduplicated_compressed())?;
-    file.write_all(&compressed)?;
+    store.write(
+        &names.block_accessed_state_deduplicated_compressed(),
+        &compressed,
+    )?;
     Ok(())
 }
 
@@ -401,46 +851,227 @@ pub fn compress_deduplicated_state(target_block: u64) -> Result<(), CacheError>
 /// If compression is done on a per-block level then inter-block duplicates
 /// are not efficiently compressed.
 pub fn compress_proofs(target_block: u64) -> Result<(), CacheError> {
+    let store = FileStore::default();
     let names = CacheFileNames::new(target_block);
-    let block_file = names.prior_block_state_proofs();
-    let data = fs::read(&block_file).map_err(|e| CacheError::FileOpener {
-        source: e,
-        filename: block_file,
-    })?;
+    let data = store.read(&names.prior_block_state_proofs())?;
     let compressed = compress(data)?;
-    let mut file = File::create(names.prior_block_state_proofs_compressed())?;
-    file.write_all(&compressed)?;
+    store.write(&names.prior_block_state_proofs_compressed(), &compressed)?;
     Ok(())
 }
 
+/// Reports the sizes of the cached proofs and deduplicated state for each block, before
+/// and after compression (e.g., as documented for `compress_proofs`).
+///
+/// The base is a cache directory (e.g., "data/blocks").
+pub fn compression_stats(base: PathBuf, blocks: &[u64]) -> Vec<BlockCompressionStat> {
+    let store = FileStore::new(base);
+    let size = |key: ArtifactKey| fs::metadata(store.path(&key)).ok().map(|m| m.len());
+    let ratio = |uncompressed: ArtifactKey, compressed: ArtifactKey| {
+        Some(CompressionRatio {
+            uncompressed: size(uncompressed)?,
+            compressed: size(compressed)?,
+        })
+    };
+    blocks
+        .iter()
+        .map(|&block| {
+            let names = CacheFileNames::new(block);
+            BlockCompressionStat {
+                block,
+                proofs: ratio(
+                    names.prior_block_state_proofs(),
+                    names.prior_block_state_proofs_compressed(),
+                ),
+                state: ratio(
+                    names.block_accessed_state_deduplicated(),
+                    names.block_accessed_state_deduplicated_compressed(),
+                ),
+            }
+        })
+        .collect()
+}
+
 /// Retrieves all state data required for a block and creates and stores
-/// an SSZ+snappy encoded format redy for P2P transfer.
-pub fn create_transferrable_proof(target_block: u64) -> Result<(), CacheError> {
-    let proofs = get_proofs_from_cache(target_block)?;
-    let mut contracts: Vec<ContractBytes> = get_contracts_from_cache(target_block)?
+/// an SSZ+snappy encoded format redy for P2P transfer. The metadata of the parcel (see
+/// `store_parcel_metadata_in`) is stored alongside it.
+///
+/// Returns the sizes and counts of the stored parcel.
+pub fn create_transferrable_proof(target_block: u64) -> Result<ParcelStats, CacheError> {
+    create_transferrable_proof_with_callback(target_block, None)
+}
+
+/// As for `create_transferrable_proof`, with an optional callback that receives the
+/// stats of the parcel once it is stored (e.g., for CLI output).
+///
+/// The parcel is compressed as it is written to the file.
+pub fn create_transferrable_proof_with_callback(
+    target_block: u64,
+    on_stats: Option<&dyn Fn(&ParcelStats)>,
+) -> Result<ParcelStats, CacheError> {
+    let store = FileStore::default();
+    let transferrable = transferrable_from_store(&store, target_block)?;
+    write_parcel_metadata(&store, target_block, &transferrable)?;
+    let stats = write_transferrable_file(&store, target_block, transferrable)?;
+    if let Some(callback) = on_stats {
+        callback(&stats);
+    }
+    Ok(stats)
+}
+
+/// As for `create_transferrable_proof_with_callback`, using the given store.
+pub fn create_transferrable_proof_in<S: BlockStore>(
+    store: &S,
+    target_block: u64,
+    on_stats: Option<&dyn Fn(&ParcelStats)>,
+) -> Result<ParcelStats, CacheError> {
+    let transferrable = transferrable_from_store(store, target_block)?;
+    write_parcel_metadata(store, target_block, &transferrable)?;
+    let stats = save_transferrable_data(store, target_block, transferrable)?;
+    if let Some(callback) = on_stats {
+        callback(&stats);
+    }
+    Ok(stats)
+}
+
+/// Constructs the parcel from the stored proofs, contracts and blockhashes.
+pub fn transferrable_from_store<S: BlockStore>(
+    store: &S,
+    target_block: u64,
+) -> Result<RequiredBlockState, CacheError> {
+    let proofs = get_proofs_from_store(store, target_block)?;
+    let mut contracts: Vec<ContractBytes> = get_contracts_from_store(store, target_block)?
         .into_values()
         .collect();
     contracts.sort();
-    let blockhashes = get_blockhashes_from_cache(target_block)?;
+    let blockhashes = get_blockhashes_from_store(store, target_block)?;
+    let start = Instant::now();
+    let state = state_from_parts(proofs, contracts, blockhashes)?;
+    debug!(
+        "Constructed parcel for block {target_block} ({} nodes, {} contracts) in {:?}",
+        state.trie_nodes.len(),
+        state.contracts.len(),
+        start.elapsed()
+    );
+    Ok(state)
+}
 
-    let transferrable = state_from_parts(proofs, contracts, blockhashes)?;
-    save_transferrable_data(target_block, transferrable)?;
-    Ok(())
+/// Streams the parcel as .ssz_snappy to its file, without holding the compressed parcel
+/// in memory.
+fn write_transferrable_file(
+    store: &FileStore,
+    target_block: u64,
+    data: RequiredBlockState,
+) -> Result<ParcelStats, CacheError> {
+    let node_count = data.trie_nodes.len();
+    let contract_count = data.contracts.len();
+    let path =
+        store.path(&CacheFileNames::new(target_block).prior_block_transferrable_state_proofs());
+    fs::create_dir_all(store.block_dir(target_block))?;
+    let file = File::create(&path)?;
+    let start = Instant::now();
+    let ssz_size = data.write_ssz_snappy(BufWriter::new(file))?;
+    let stats = ParcelStats {
+        ssz_size,
+        compressed_size: fs::metadata(&path)?.len() as usize,
+        node_count,
+        contract_count,
+    };
+    log_compression(target_block, &stats, start);
+    Ok(stats)
 }
 
-fn save_transferrable_data(target_block: u64, data: RequiredBlockState) -> Result<(), CacheError> {
+/// Stores the parcel as .ssz_snappy, returning the sizes and counts of the parcel.
+pub fn save_transferrable_data<S: BlockStore>(
+    store: &S,
+    target_block: u64,
+    data: RequiredBlockState,
+) -> Result<ParcelStats, CacheError> {
     let names = CacheFileNames::new(target_block);
+    let start = Instant::now();
+    let (bytes, stats) = encode_transferrable_data(data)?;
+    log_compression(target_block, &stats, start);
+    store.write(&names.prior_block_transferrable_state_proofs(), &bytes)?;
+    Ok(stats)
+}
+
+/// Reports the sizes of an encoded parcel and the time taken to encode it.
+fn log_compression(target_block: u64, stats: &ParcelStats, start: Instant) {
+    debug!(
+        "Compressed parcel for block {target_block} from {} to {} bytes in {:?}",
+        stats.ssz_size,
+        stats.compressed_size,
+        start.elapsed()
+    );
+}
+
+/// Encodes a parcel as .ssz_snappy bytes, recording the sizes and counts involved.
+pub fn encode_transferrable_data(
+    data: RequiredBlockState,
+) -> Result<(Vec<u8>, ParcelStats), CacheError> {
+    let node_count = data.trie_nodes.len();
+    let contract_count = data.contracts.len();
     let ssz = data.to_ssz_bytes()?;
+    let ssz_size = ssz.len();
     let bytes = compress(ssz)?;
-    let mut file = File::create(names.prior_block_transferrable_state_proofs())?;
-    file.write_all(&bytes)?;
+    let stats = ParcelStats {
+        ssz_size,
+        compressed_size: bytes.len(),
+        node_count,
+        contract_count,
+    };
+    Ok((bytes, stats))
+}
+
+/// Computes the node oracle from the cached pre- and post-state proofs and stores it, so
+/// that it is not recomputed each time it is used.
+pub fn store_node_oracle(target_block: u64) -> Result<(), CacheError> {
+    store_node_oracle_in(&FileStore::default(), target_block)
+}
+
+/// Computes the node oracle from the pre- and post-state proofs and stores it in the given
+/// store.
+pub fn store_node_oracle_in<S: BlockStore>(store: &S, target_block: u64) -> Result<(), CacheError> {
+    let oracle = compute_node_oracle(store, target_block)?;
+    save_node_oracle(store, target_block, &oracle)
+}
+
+/// Stores a node oracle for a block as .ssz_snappy.
+pub fn save_node_oracle<S: BlockStore>(
+    store: &S,
+    target_block: u64,
+    oracle: &TrieNodeOracle,
+) -> Result<(), CacheError> {
+    let bytes = compress(oracle.to_bytes()?)?;
+    store.write(&CacheFileNames::new(target_block).node_oracle(), &bytes)?;
     Ok(())
 }
 
-/// Retrieves the node oracle from the cached pre- and post-state proofs.
+/// Retrieves the node oracle for a block from cache.
+///
+/// A stored oracle is used if present, otherwise the oracle is computed from the cached
+/// pre- and post-state proofs.
 pub fn get_node_oracle_from_cache(block: u64) -> Result<TrieNodeOracle, CacheError> {
-    let post = get_post_state_proofs_from_cache(block)?;
-    let pre = get_proofs_from_cache(block)?;
+    get_node_oracle_from_store(&FileStore::default(), block)
+}
+
+/// Retrieves the node oracle for a block from the given store, computing it if it is not
+/// stored.
+pub fn get_node_oracle_from_store<S: BlockStore>(
+    store: &S,
+    block: u64,
+) -> Result<TrieNodeOracle, CacheError> {
+    let key = CacheFileNames::new(block).node_oracle();
+    if store.exists(&key) {
+        let bytes = decompress(store.read(&key)?)?;
+        return Ok(TrieNodeOracle::from_bytes(&bytes)?);
+    }
+    compute_node_oracle(store, block)
+}
+
+/// Constructs the node oracle from the stored pre- and post-state proofs.
+fn compute_node_oracle<S: BlockStore>(store: &S, block: u64) -> Result<TrieNodeOracle, CacheError> {
+    let post = get_post_state_proofs_from_store(store, block)?;
+    let pre = get_proofs_from_store(store, block)?;
     //let oracle = demo_detect_removed_storage(pre, post);
     let oracle = oracle_from_simulated_state_update(pre, post)?;
     debug!("oracle has been constructed");
@@ -449,80 +1080,191 @@ pub fn get_node_oracle_from_cache(block: u64) -> Result<TrieNodeOracle, CacheErr
 
 /// Retrieves the accessed-state proofs for a single block from cache.
 pub fn get_proofs_from_cache(block: u64) -> Result<BlockProofs, CacheError> {
-    let proof_cache_path = CacheFileNames::new(block).prior_block_state_proofs();
-    let file = File::open(&proof_cache_path).map_err(|e| CacheError::FileOpener {
-        source: e,
-        filename: proof_cache_path,
-    })?;
-    let reader = BufReader::new(file);
-    let block_proofs = serde_json::from_reader(reader)?;
-    Ok(block_proofs)
+    get_proofs_from_store(&FileStore::default(), block)
+}
+
+/// Retrieves the accessed-state proofs for a single block from the given store.
+pub fn get_proofs_from_store<S: BlockStore>(
+    store: &S,
+    block: u64,
+) -> Result<BlockProofs, CacheError> {
+    read_json(
+        store,
+        &CacheFileNames::new(block).prior_block_state_proofs(),
+    )
 }
 
 /// Retrieves the post-state accessed-state proofs for a single block from cache.
 /// This can be used to debug the block executor and proof update mechanisms.
 pub fn get_post_state_proofs_from_cache(block: u64) -> Result<BlockProofs, CacheError> {
-    let proof_cache_path = CacheFileNames::new(block).block_state_proofs();
-    let file = File::open(&proof_cache_path).map_err(|e| CacheError::FileOpener {
-        source: e,
-        filename: proof_cache_path,
-    })?;
-    let reader = BufReader::new(file);
-    let block_proofs = serde_json::from_reader(reader)?;
-    Ok(block_proofs)
+    get_post_state_proofs_from_store(&FileStore::default(), block)
+}
+
+/// Retrieves the post-state accessed-state proofs for a single block from the given store.
+pub fn get_post_state_proofs_from_store<S: BlockStore>(
+    store: &S,
+    block: u64,
+) -> Result<BlockProofs, CacheError> {
+    read_json(store, &CacheFileNames::new(block).block_state_proofs())
 }
 
 /// Retrieves the transferrable (ssz+snappy) proofs for a single block from cache.
 pub fn get_required_state_from_cache(block: u64) -> Result<RequiredBlockState, CacheError> {
-    let proof_cache_path = CacheFileNames::new(block).prior_block_transferrable_state_proofs();
-    let data = fs::read(&proof_cache_path).map_err(|e| CacheError::FileOpener {
-        source: e,
-        filename: proof_cache_path,
-    })?;
+    get_required_state_from_store(&FileStore::default(), block)
+}
+
+/// Retrieves the transferrable (ssz+snappy) proofs for a single block from the given store.
+pub fn get_required_state_from_store<S: BlockStore>(
+    store: &S,
+    block: u64,
+) -> Result<RequiredBlockState, CacheError> {
+    let data = store.read(&CacheFileNames::new(block).prior_block_transferrable_state_proofs())?;
     let ssz = decompress(data)?;
     let block_proofs = RequiredBlockState::from_ssz_bytes(ssz)?;
     Ok(block_proofs)
 }
 
+/// Records the target block and parent state root of a stored parcel, alongside the parcel.
+///
+/// The parent state root is that of the account proofs in the parcel. If the state root of
+/// the prior block has been stored, the parcel must be against that root.
+pub fn store_parcel_metadata_in<S: BlockStore>(
+    store: &S,
+    target_block: u64,
+) -> Result<ParcelMetadata, CacheError> {
+    let state = get_required_state_from_store(store, target_block)?;
+    write_parcel_metadata(store, target_block, &state)
+}
+
+/// Records the metadata of a parcel (see `store_parcel_metadata_in`).
+fn write_parcel_metadata<S: BlockStore>(
+    store: &S,
+    target_block: u64,
+    state: &RequiredBlockState,
+) -> Result<ParcelMetadata, CacheError> {
+    let names = CacheFileNames::new(target_block);
+    let metadata = ParcelMetadata {
+        target_block,
+        parent_state_root: state.state_root()?,
+    };
+    if store.exists(&names.prior_block_state_root()) {
+        let parent_state_root = get_prior_block_state_root_from_store(store, target_block)?;
+        check_parcel_metadata(&metadata, target_block, parent_state_root)?;
+    }
+    write_json(store, &names.parcel_metadata(), &metadata)?;
+    Ok(metadata)
+}
+
+/// Retrieves the metadata of the parcel for a single block from the given store.
+pub fn get_parcel_metadata_from_store<S: BlockStore>(
+    store: &S,
+    block: u64,
+) -> Result<ParcelMetadata, CacheError> {
+    read_json(store, &CacheFileNames::new(block).parcel_metadata())
+}
+
+/// Checks that parcel metadata is for the intended block, and that the parcel is against
+/// the state root of the block before it (from that block header).
+pub fn check_parcel_metadata(
+    metadata: &ParcelMetadata,
+    target_block: u64,
+    parent_state_root: H256,
+) -> Result<(), CacheError> {
+    let expected = ParcelMetadata {
+        target_block,
+        parent_state_root,
+    };
+    if *metadata != expected {
+        return Err(CacheError::ParcelMetadataMismatch {
+            expected,
+            found: *metadata,
+        });
+    }
+    Ok(())
+}
+
+/// Retrieves the state root of the block prior to the target block from cache.
+///
+/// Proofs for the target block can be verified against this root.
+pub fn get_prior_block_state_root_from_cache(block: u64) -> Result<H256, CacheError> {
+    get_prior_block_state_root_from_store(&FileStore::default(), block)
+}
+
+/// Retrieves the state root of the block prior to the target block from the given store.
+pub fn get_prior_block_state_root_from_store<S: BlockStore>(
+    store: &S,
+    block: u64,
+) -> Result<H256, CacheError> {
+    read_json(store, &CacheFileNames::new(block).prior_block_state_root())
+}
+
 /// Retrieves a single block that has been stored.
 pub fn get_block_from_cache(block: u64) -> Result<Block<Transaction>, CacheError> {
-    let block_cache_path = CacheFileNames::new(block).block_with_transactions();
-    let file = File::open(&block_cache_path).map_err(|e| CacheError::FileOpener {
-        source: e,
-        filename: block_cache_path,
-    })?;
-    let reader = BufReader::new(file);
-    let mut block: Block<Transaction> = serde_json::from_reader(reader)?;
+    get_block_from_store(&FileStore::default(), block)
+}
+
+/// Retrieves a single block from the given store.
+pub fn get_block_from_store<S: BlockStore>(
+    store: &S,
+    block: u64,
+) -> Result<Block<Transaction>, CacheError> {
+    let mut block: Block<Transaction> =
+        read_json(store, &CacheFileNames::new(block).block_with_transactions())?;
     block.transactions.sort_by_key(|tx| tx.transaction_index);
     Ok(block)
 }
 
 /// Retrieves all BLOCKHASH use values for a single block.
 pub fn get_blockhashes_from_cache(block: u64) -> Result<BlockHashAccesses, CacheError> {
-    let blockhash_path = CacheFileNames::new(block).blockhashes();
-    let file = File::open(&blockhash_path).map_err(|e| CacheError::FileOpener {
-        source: e,
-        filename: blockhash_path,
-    })?;
-    let reader = BufReader::new(file);
-    let accesses: BlockHashAccesses = serde_json::from_reader(reader)?;
-    Ok(accesses)
+    get_blockhashes_from_store(&FileStore::default(), block)
+}
+
+/// Retrieves all BLOCKHASH use values for a single block from the given store.
+pub fn get_blockhashes_from_store<S: BlockStore>(
+    store: &S,
+    block: u64,
+) -> Result<BlockHashAccesses, CacheError> {
+    read_json(store, &CacheFileNames::new(block).blockhashes())
 }
 
 pub(crate) type ContractBytes = Vec<u8>;
 
 /// Retrieves the contract code for a particular cached block.
 pub fn get_contracts_from_cache(block: u64) -> Result<HashMap<H256, ContractBytes>, CacheError> {
-    let block_state_path = CacheFileNames::new(block).block_accessed_state_deduplicated();
-    let file = File::open(&block_state_path).map_err(|e| CacheError::FileOpener {
-        source: e,
-        filename: block_state_path,
-    })?;
-    let reader = BufReader::new(file);
-    let state: BlockStateAccesses = serde_json::from_reader(reader)?;
+    get_contracts_from_store(&FileStore::default(), block)
+}
+
+/// Retrieves the contract code for a particular block from the given store.
+pub fn get_contracts_from_store<S: BlockStore>(
+    store: &S,
+    block: u64,
+) -> Result<HashMap<H256, ContractBytes>, CacheError> {
+    let state: BlockStateAccesses = read_json(
+        store,
+        &CacheFileNames::new(block).block_accessed_state_deduplicated(),
+    )?;
     contracts_from_state(state)
 }
 
+/// Serializes a value as JSON and writes it to the store.
+fn write_json<S: BlockStore, T: Serialize>(
+    store: &S,
+    key: &ArtifactKey,
+    value: &T,
+) -> Result<(), CacheError> {
+    store.write(key, serde_json::to_string_pretty(value)?.as_bytes())?;
+    Ok(())
+}
+
+/// Reads an artifact from the store and deserializes it from JSON.
+fn read_json<S: BlockStore, T: DeserializeOwned>(
+    store: &S,
+    key: &ArtifactKey,
+) -> Result<T, CacheError> {
+    let data = store.read(key)?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
 /// Extracts contract bytecode from accessed state.
 fn contracts_from_state(
     state: BlockStateAccesses,
@@ -538,7 +1280,7 @@ fn contracts_from_state(
     Ok(code_map)
 }
 
-/// Helper for consistent cached file and directory names.
+/// Helper for consistent cached artifact names.
 struct CacheFileNames {
     block: u64,
 }
@@ -547,38 +1289,569 @@ impl CacheFileNames {
     fn new(block: u64) -> Self {
         Self { block }
     }
-    fn dirname(&self) -> PathBuf {
-        PathBuf::from(format!("{CACHE_DIR}/{}", self.block))
+    fn block_accessed_state_deduplicated(&self) -> ArtifactKey {
+        ArtifactKey::new(self.block, "block_accessed_state_deduplicated.json")
     }
-    fn block_accessed_state_deduplicated(&self) -> PathBuf {
-        self.dirname()
-            .join("block_accessed_state_deduplicated.json")
+    fn block_accessed_state_deduplicated_compressed(&self) -> ArtifactKey {
+        ArtifactKey::new(self.block, "block_accessed_state_deduplicated.snappy")
     }
-    fn block_accessed_state_deduplicated_compressed(&self) -> PathBuf {
-        self.dirname()
-            .join("block_accessed_state_deduplicated.snappy")
-    }
-    fn block_prestate_trace(&self) -> PathBuf {
-        self.dirname().join("block_prestate_trace.json")
+    fn block_prestate_trace(&self) -> ArtifactKey {
+        ArtifactKey::new(self.block, "block_prestate_trace.json")
     }
     /// The state proof is eth_getProof for the prior block.
-    fn prior_block_state_proofs(&self) -> PathBuf {
-        self.dirname().join("prior_block_state_proofs.json")
+    fn prior_block_state_proofs(&self) -> ArtifactKey {
+        ArtifactKey::new(self.block, "prior_block_state_proofs.json")
+    }
+    /// The state root of the prior block, against which prior block proofs are verified.
+    fn prior_block_state_root(&self) -> ArtifactKey {
+        ArtifactKey::new(self.block, "prior_block_state_root.json")
+    }
+    fn block_state_proofs(&self) -> ArtifactKey {
+        ArtifactKey::new(self.block, "block_state_proofs.json")
+    }
+    /// Account proofs at this block obtained on demand (`get_or_fetch_account_proof`).
+    fn account_proofs(&self) -> ArtifactKey {
+        ArtifactKey::new(self.block, "account_proofs.json")
+    }
+    /// Proofs obtained so far by an unfinished `store_state_proofs`.
+    fn prior_block_state_proofs_partial(&self) -> ArtifactKey {
+        ArtifactKey::new(self.block, "prior_block_state_proofs.partial.json")
+    }
+    fn block_state_proofs_partial(&self) -> ArtifactKey {
+        ArtifactKey::new(self.block, "block_state_proofs.partial.json")
     }
-    fn block_state_proofs(&self) -> PathBuf {
-        self.dirname().join("block_state_proofs.json")
+    fn prior_block_state_proofs_compressed(&self) -> ArtifactKey {
+        ArtifactKey::new(self.block, "prior_block_state_proofs.snappy")
     }
-    fn prior_block_state_proofs_compressed(&self) -> PathBuf {
-        self.dirname().join("prior_block_state_proofs.snappy")
+    fn prior_block_transferrable_state_proofs(&self) -> ArtifactKey {
+        ArtifactKey::new(
+            self.block,
+            "prior_block_transferrable_state_proofs.ssz_snappy",
+        )
     }
-    fn prior_block_transferrable_state_proofs(&self) -> PathBuf {
-        self.dirname()
-            .join("prior_block_transferrable_state_proofs.ssz_snappy")
+    /// The block and parent state root that the transferrable parcel is for.
+    fn parcel_metadata(&self) -> ArtifactKey {
+        ArtifactKey::new(
+            self.block,
+            "prior_block_transferrable_state_proofs.metadata.json",
+        )
     }
-    fn block_with_transactions(&self) -> PathBuf {
-        self.dirname().join("block_with_transactions.json")
+    fn block_with_transactions(&self) -> ArtifactKey {
+        ArtifactKey::new(self.block, "block_with_transactions.json")
     }
-    fn blockhashes(&self) -> PathBuf {
-        self.dirname().join("blockhash_opcode_use.json")
+    fn blockhashes(&self) -> ArtifactKey {
+        ArtifactKey::new(self.block, "blockhash_opcode_use.json")
+    }
+    /// Accounts inspected by EXTCODEHASH or EXTCODESIZE.
+    fn code_inspected_accounts(&self) -> ArtifactKey {
+        ArtifactKey::new(self.block, "code_inspected_accounts.json")
+    }
+    /// Trie nodes for post-state proof updates that remove nodes.
+    fn node_oracle(&self) -> ArtifactKey {
+        ArtifactKey::new(self.block, "node_oracle.ssz_snappy")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::Read,
+        net::TcpListener,
+        str::FromStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+    };
+
+    use archors_types::fixture::{parcel_bytes, prior_block_proofs, FIXTURE_BLOCK};
+
+    use crate::{store::MemoryStore, types::AccountState};
+
+    use super::*;
+
+    #[test]
+    fn test_prior_block() {
+        assert_eq!(prior_block(17190873).unwrap(), 17190872);
+        assert_eq!(prior_block(1).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_prior_block_of_genesis_is_error() {
+        assert!(matches!(prior_block(0), Err(CacheError::NoPriorBlock(0))));
+    }
+
+    /// Tests that the stats for a re-encoded fixture parcel match its actual sizes.
+    #[test]
+    fn test_parcel_stats_match_fixture() {
+        let ssz = decompress(parcel_bytes()).unwrap();
+        let state = RequiredBlockState::from_ssz_bytes(ssz.clone()).unwrap();
+        let node_count = state.trie_nodes.len();
+        let contract_count = state.contracts.len();
+
+        let (bytes, stats) = encode_transferrable_data(state).unwrap();
+        assert_eq!(stats.ssz_size, ssz.len());
+        assert_eq!(stats.compressed_size, bytes.len());
+        assert_eq!(stats.compressed_size, compress(ssz).unwrap().len());
+        assert_eq!(stats.node_count, node_count);
+        assert_eq!(stats.contract_count, contract_count);
+        assert!(stats.compressed_size < stats.ssz_size);
+    }
+
+    /// Serves a single eth_getProof response on a local port, then stops. Returns the url
+    /// and a count of requests received.
+    fn serve_one_proof(proof: &EIP1186ProofResponse) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let body = serde_json::json!({"jsonrpc": "2.0", "id": 1, "result": proof}).to_string();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            // Read the headers and body before responding.
+            let mut request = vec![];
+            let mut buf = [0u8; 4096];
+            loop {
+                let read = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..read]);
+                let text = String::from_utf8_lossy(&request).to_lowercase();
+                if let Some(header_end) = text.find("\r\n\r\n") {
+                    let content_length = text
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length:"))
+                        .map(|len| len.trim().parse::<usize>().unwrap())
+                        .unwrap_or(0);
+                    if request.len() >= header_end + 4 + content_length {
+                        break;
+                    }
+                }
+                if read == 0 {
+                    break;
+                }
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        (url, requests)
+    }
+
+    /// Tests that an account proof is fetched once, then read from the cache.
+    #[tokio::test]
+    async fn test_get_or_fetch_account_proof() {
+        let address = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let proof = EIP1186ProofResponse {
+            address,
+            balance: 7u64.into(),
+            nonce: 2u64.into(),
+            ..Default::default()
+        };
+        let (url, requests) = serve_one_proof(&proof);
+        let store = MemoryStore::default();
+
+        let fetched = get_or_fetch_account_proof_in(&store, &url, 17190872, address)
+            .await
+            .unwrap();
+        assert_eq!(fetched, proof);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // The server has stopped, so this must be read from the cache.
+        let cached = get_or_fetch_account_proof_in(&store, &url, 17190872, address)
+            .await
+            .unwrap();
+        assert_eq!(cached, proof);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    /// Tests that a proof for a different account is not cached.
+    #[tokio::test]
+    async fn test_get_or_fetch_account_proof_address_mismatch() {
+        let address = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let other = H160::from_str("0x0400000000000000000000000000000000000000").unwrap();
+        let proof = EIP1186ProofResponse {
+            address: other,
+            ..Default::default()
+        };
+        let (url, _) = serve_one_proof(&proof);
+        let store = MemoryStore::default();
+
+        let result = get_or_fetch_account_proof_in(&store, &url, 17190872, address).await;
+        assert!(matches!(
+            result,
+            Err(CacheError::ProofAddressMismatch { expected, found })
+                if expected == address && found == other
+        ));
+        assert!(!store.exists(&CacheFileNames::new(17190872).account_proofs()));
+    }
+
+    #[test]
+    fn test_compression_stats_for_fixture() {
+        let stats = compression_stats(PathBuf::from("../../data/blocks"), &[17190873, 1]);
+        assert_eq!(
+            stats[0].proofs,
+            Some(CompressionRatio {
+                uncompressed: 8937136,
+                compressed: 6379521
+            })
+        );
+        assert_eq!(
+            stats[0].state,
+            Some(CompressionRatio {
+                uncompressed: 4377677,
+                compressed: 1573767
+            })
+        );
+        // The -28% documented for compress_proofs.
+        assert_eq!(stats[0].proofs.unwrap().change_percent().trunc(), -28.0);
+        assert_eq!(
+            stats[1],
+            BlockCompressionStat {
+                block: 1,
+                proofs: None,
+                state: None
+            }
+        );
+    }
+
+    /// Tests that a parcel streamed to a sink roundtrips.
+    #[test]
+    fn test_write_ssz_snappy_roundtrip() {
+        let ssz = decompress(parcel_bytes()).unwrap();
+        let state = RequiredBlockState::from_ssz_bytes(ssz.clone()).unwrap();
+        let expected = RequiredBlockState::from_ssz_bytes(ssz.clone()).unwrap();
+
+        let mut sink: Vec<u8> = vec![];
+        let ssz_size = state.write_ssz_snappy(&mut sink).unwrap();
+        assert_eq!(ssz_size, ssz.len());
+        assert_eq!(
+            RequiredBlockState::from_ssz_snappy_bytes(&sink).unwrap(),
+            expected
+        );
+    }
+
+    /// Tests that a stored oracle is used without the proofs it is computed from.
+    #[test]
+    fn test_stored_node_oracle_roundtrip() {
+        let store = MemoryStore::default();
+        let block = 1000;
+        let address = H160::from_low_u64_be(1);
+        let mut oracle = TrieNodeOracle::default();
+        oracle.insert_nodes(address, vec![0x1, 0x2], vec![vec![0xc2, 0x80, 0x80]]);
+        oracle.insert_nodes(address, vec![0x3], vec![vec![0xc0], vec![0xe1, 0x01]]);
+        // Without proofs or a stored oracle there is nothing to compute from.
+        assert!(get_node_oracle_from_store(&store, block).is_err());
+
+        save_node_oracle(&store, block, &oracle).unwrap();
+        let retrieved = get_node_oracle_from_store(&store, block).unwrap();
+        assert_eq!(retrieved.len(), 2);
+        assert_eq!(
+            retrieved.lookup(address, vec![0x1, 0x2]),
+            oracle.lookup(address, vec![0x1, 0x2])
+        );
+        assert_eq!(
+            retrieved.lookup(address, vec![0x3]),
+            oracle.lookup(address, vec![0x3])
+        );
+    }
+
+    /// Tests that proofs recorded before an interruption are not requested again.
+    #[test]
+    fn test_resume_skips_proven_accounts() {
+        let store = MemoryStore::default();
+        let names = CacheFileNames::new(1000);
+        let partial = names.prior_block_state_proofs_partial();
+        let proven = H160::repeat_byte(1);
+        let unproven = H160::repeat_byte(2);
+        let mut accesses = BlockStateAccesses::new();
+        accesses.include_accounts(&[proven, unproven]);
+
+        // Nothing recorded yet.
+        let (block_proofs, remaining) = resume_proofs(&store, &partial, &accesses).unwrap();
+        assert!(block_proofs.proofs.is_empty());
+        assert_eq!(remaining.len(), 2);
+
+        // Interrupted after the first account was proven.
+        let proof = EIP1186ProofResponse {
+            address: proven,
+            ..Default::default()
+        };
+        let recorded = BlockProofs {
+            proofs: HashMap::from([(proven, proof)]),
+        };
+        write_json(&store, &partial, &recorded).unwrap();
+        let (block_proofs, remaining) = resume_proofs(&store, &partial, &accesses).unwrap();
+        assert!(block_proofs.proofs.contains_key(&proven));
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(
+            H160::from_slice(&hex_decode(&remaining[0].address).unwrap()),
+            unproven
+        );
+
+        store.remove(&partial).unwrap();
+        assert!(!store.exists(&partial));
+        // Removing again is not an error.
+        store.remove(&partial).unwrap();
+    }
+
+    /// Tests that cached artifacts can be stored and retrieved without a disk, from
+    /// prestate trace through to a transferrable parcel.
+    #[test]
+    fn test_memory_store_roundtrip() {
+        let store = MemoryStore::default();
+        let block = 1000;
+        let names = CacheFileNames::new(block);
+
+        let code = vec![0x60, 0x00];
+        let code_hash = H256::from(keccak256(&code));
+        let address = "0x0300000000000000000000000000000000000000";
+        let account = AccountState {
+            balance: "0x0".to_string(),
+            code: Some("0x6000".to_string()),
+            nonce: Some(1),
+            storage: None,
+        };
+        let prestate = vec![BlockPrestateTransactions {
+            result: HashMap::from([(address.to_string(), account)]),
+        }];
+        write_json(&store, &names.block_prestate_trace(), &prestate).unwrap();
+        store_deduplicated_state_in(&store, block).unwrap();
+        let contracts = get_contracts_from_store(&store, block).unwrap();
+        assert_eq!(contracts.get(&code_hash), Some(&code));
+
+        let address = H160::from_slice(&hex_decode(address).unwrap());
+        let proof = EIP1186ProofResponse {
+            address,
+            code_hash,
+            account_proof: vec![state_root_node()],
+            ..Default::default()
+        };
+        let proofs = BlockProofs {
+            proofs: HashMap::from([(address, proof)]),
+        };
+        write_json(&store, &names.prior_block_state_proofs(), &proofs).unwrap();
+        let blockhashes = BlockHashAccesses {
+            blockhash_accesses: vec![BlockHashAccess {
+                block_number: U64::from(block - 1),
+                block_hash: H256::repeat_byte(1),
+            }],
+        };
+        write_json(&store, &names.blockhashes(), &blockhashes).unwrap();
+
+        let stats = create_transferrable_proof_in(&store, block, None).unwrap();
+        assert_eq!(stats.contract_count, 1);
+        let state = get_required_state_from_store(&store, block).unwrap();
+        assert_eq!(state.compact_eip1186_proofs.len(), 1);
+        assert_eq!(state.contracts.len(), 1);
+        assert_eq!(state.blockhashes.len(), 1);
+        let metadata = get_parcel_metadata_from_store(&store, block).unwrap();
+        assert_eq!(
+            metadata.parent_state_root,
+            H256::from(keccak256(state_root_node()))
+        );
+        assert!(!FileStore::default().exists(&names.prior_block_transferrable_state_proofs()));
+    }
+
+    /// A leaf with an empty path and value, used as the only node of an account proof.
+    fn state_root_node() -> ethers::types::Bytes {
+        vec![0xc2, 0x20, 0x80].into()
+    }
+
+    /// Records messages logged by this crate.
+    struct CaptureLogger;
+
+    static CAPTURED_LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.target().starts_with("archors_inventory")
+        }
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                CAPTURED_LOGS
+                    .lock()
+                    .unwrap()
+                    .push(record.args().to_string());
+            }
+        }
+        fn flush(&self) {}
+    }
+
+    /// Tests that the deduplication, construction and compression steps of parcel creation
+    /// are logged.
+    #[test]
+    fn test_parcel_creation_logs_steps() {
+        static LOGGER: CaptureLogger = CaptureLogger;
+        // Only one logger can be set per process.
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Debug);
+        let store = MemoryStore::default();
+        let block = 1001;
+        let names = CacheFileNames::new(block);
+
+        let address = H160::repeat_byte(3);
+        let account = AccountState {
+            balance: "0x0".to_string(),
+            code: None,
+            nonce: Some(1),
+            storage: None,
+        };
+        let prestate = vec![BlockPrestateTransactions {
+            result: HashMap::from([(format!("{address:?}"), account)]),
+        }];
+        write_json(&store, &names.block_prestate_trace(), &prestate).unwrap();
+        store_deduplicated_state_in(&store, block).unwrap();
+        let proof = EIP1186ProofResponse {
+            address,
+            account_proof: vec![state_root_node()],
+            ..Default::default()
+        };
+        let proofs = BlockProofs {
+            proofs: HashMap::from([(address, proof)]),
+        };
+        write_json(&store, &names.prior_block_state_proofs(), &proofs).unwrap();
+        let blockhashes = BlockHashAccesses {
+            blockhash_accesses: vec![],
+        };
+        write_json(&store, &names.blockhashes(), &blockhashes).unwrap();
+        create_transferrable_proof_in(&store, block, None).unwrap();
+
+        let logs = CAPTURED_LOGS.lock().unwrap();
+        for step in [
+            "Deduplicated state accesses for block 1001",
+            "Constructed parcel for block 1001",
+            "Compressed parcel for block 1001",
+        ] {
+            assert!(
+                logs.iter().any(|message| message.starts_with(step)),
+                "no log for: {step}"
+            );
+        }
+    }
+
+    /// Tests that the metadata recorded for a fixture parcel is for the source block and
+    /// the root of the fixture proofs.
+    #[test]
+    fn test_parcel_metadata_matches_source_block() {
+        let store = MemoryStore::default();
+        let block: Block<Transaction> = serde_json::from_slice(
+            &fs::read("../../data/blocks/17190873/block_with_transactions.json").unwrap(),
+        )
+        .unwrap();
+        let target_block = block.number.unwrap().as_u64();
+        let names = CacheFileNames::new(target_block);
+        store
+            .write(
+                &names.prior_block_transferrable_state_proofs(),
+                &parcel_bytes(),
+            )
+            .unwrap();
+        let proofs = prior_block_proofs();
+        let proof = proofs.values().next().unwrap();
+        let parent_state_root = H256::from(keccak256(&proof.account_proof[0]));
+
+        let metadata = store_parcel_metadata_in(&store, target_block).unwrap();
+        assert_eq!(
+            metadata,
+            get_parcel_metadata_from_store(&store, FIXTURE_BLOCK).unwrap()
+        );
+        assert_eq!(metadata.target_block, FIXTURE_BLOCK);
+        assert_eq!(metadata.parent_state_root, parent_state_root);
+        check_parcel_metadata(&metadata, target_block, parent_state_root).unwrap();
+        assert!(matches!(
+            check_parcel_metadata(&metadata, target_block + 1, parent_state_root),
+            Err(CacheError::ParcelMetadataMismatch { .. })
+        ));
+
+        // A stored prior block root that differs from the parcel is an error.
+        write_json(
+            &store,
+            &names.prior_block_state_root(),
+            &H256::repeat_byte(1),
+        )
+        .unwrap();
+        assert!(matches!(
+            store_parcel_metadata_in(&store, target_block),
+            Err(CacheError::ParcelMetadataMismatch { .. })
+        ));
+    }
+
+    /// Tests that the temp trace file is removed if reading the trace fails.
+    #[test]
+    fn test_temp_trace_file_removed_on_error() {
+        let path = std::env::temp_dir().join("archors_temp_trace_for_blockhash_opcode.txt");
+        let result = || -> Result<DefaultTraceAccesses, CacheError> {
+            let (temp_trace, mut file) = TempFile::create(path.clone())?;
+            file.write_all(b"not a trace")?;
+            drop(file);
+            assert!(path.exists());
+            default_trace_accesses_from_file(temp_trace.path(), 1)
+        };
+        assert!(matches!(result(), Err(CacheError::SerdeJsonError(_))));
+        assert!(!path.exists());
+    }
+
+    /// Tests that an account only inspected by EXTCODEHASH is included in the accounts
+    /// for which proofs are requested.
+    #[test]
+    fn test_extcodehash_account_is_proved() {
+        let untouched = "0x00000000000000adc04c56bf30ac9d3c0aaf14dc";
+        let trace = serde_json::json!({"id": 1, "jsonrpc": "2.0", "result": [{"result": {"structLogs": [
+            {"pc": 0, "op": "PUSH20", "gas": 100, "gasCost": 3, "depth": 1, "stack": []},
+            {"pc": 21, "op": "EXTCODEHASH", "gas": 97, "gasCost": 2600, "depth": 1,
+                "stack": [format!("0x{:0>64}", &untouched[2..])]},
+            {"pc": 22, "op": "STOP", "gas": 0, "gasCost": 0, "depth": 1,
+                "stack": [format!("0x{:064x}", 0)]},
+        ]}}]});
+        let path = std::env::temp_dir().join("archors_test_trace_for_extcodehash.txt");
+        let (temp_trace, mut file) = TempFile::create(path).unwrap();
+        file.write_all(trace.to_string().as_bytes()).unwrap();
+        drop(file);
+        let trace_accesses = default_trace_accesses_from_file(temp_trace.path(), 1).unwrap();
+        let address = H160::from_str(untouched).unwrap();
+        assert_eq!(trace_accesses.code_inspected_accounts, vec![address]);
+
+        let mut accesses = BlockStateAccesses::new();
+        accesses.include_accounts(&trace_accesses.code_inspected_accounts);
+        let to_prove = accesses.get_all_accounts_to_prove();
+        assert_eq!(to_prove.len(), 1);
+        assert_eq!(H160::from_str(&to_prove[0].address).unwrap(), address);
+        assert!(to_prove[0].slots.is_empty());
+    }
+
+    /// Tests that a BLOCKHASH read of a block outside the 256 prior blocks is flagged
+    /// and not recorded.
+    #[test]
+    fn test_out_of_range_blockhash_read_flagged() {
+        let target_block = 1000;
+        let hash = format!("0x{:064x}", 0xabc);
+        let read = |pc: u64, block_number: u64| {
+            [
+                serde_json::json!({"pc": pc, "op": "BLOCKHASH", "gas": 100, "gasCost": 20,
+                    "depth": 1, "stack": [format!("0x{block_number:x}")]}),
+                serde_json::json!({"pc": pc + 1, "op": "POP", "gas": 80, "gasCost": 2,
+                    "depth": 1, "stack": [hash]}),
+            ]
+        };
+        let mut struct_logs = vec![];
+        struct_logs.extend(read(0, 999));
+        struct_logs.extend(read(2, 1000));
+        struct_logs.extend(read(4, 743));
+        let trace = serde_json::json!({"id": 1, "jsonrpc": "2.0",
+            "result": [{"result": {"structLogs": struct_logs}}]});
+        let path = std::env::temp_dir().join("archors_test_trace_for_blockhash_window.txt");
+        let (temp_trace, mut file) = TempFile::create(path).unwrap();
+        file.write_all(trace.to_string().as_bytes()).unwrap();
+        drop(file);
+        let trace_accesses =
+            default_trace_accesses_from_file(temp_trace.path(), target_block).unwrap();
+        assert_eq!(trace_accesses.out_of_range_blockhashes, vec![743, 1000]);
+        let accesses = trace_accesses.blockhashes.blockhash_accesses;
+        assert_eq!(accesses.len(), 1);
+        assert_eq!(accesses[0].block_number, U64::from(999));
+        assert_eq!(accesses[0].block_hash, H256::from_low_u64_be(0xabc));
     }
 }
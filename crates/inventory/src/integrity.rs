@@ -0,0 +1,297 @@
+//! Checks that the artifacts cached for a block are consistent with each other and with
+//! the state root of the prior block. This is the integrity gate for an archived block.
+
+use std::{collections::HashSet, fmt::Display, path::PathBuf};
+
+use archors_verify::{eip1186::verify_proof, ordered::ordered_trie_root, path::PathError};
+use ethers::types::{Block, Transaction, H160, H256};
+use thiserror::Error;
+
+use crate::{
+    cache::{
+        get_block_from_store, get_blockhashes_from_store, get_prior_block_state_root_from_store,
+        get_proofs_from_store, get_required_state_from_store, CacheError,
+    },
+    store::{BlockStore, FileStore},
+    transferrable::{check_blockhash_coverage, TransferrableError},
+};
+
+#[derive(Debug, Error)]
+pub enum IntegrityError {
+    #[error("CacheError {0}")]
+    CacheError(#[from] CacheError),
+    #[error("Cached block has number {found:?}, expected {expected}")]
+    BlockNumberMismatch { expected: u64, found: Option<u64> },
+    #[error("PathError {0}")]
+    PathError(#[from] PathError),
+    #[error("Block has transactions root {expected:?}, computed {computed:?}")]
+    TransactionsRootMismatch { expected: H256, computed: H256 },
+    #[error("TransferrableError {0}")]
+    TransferrableError(#[from] TransferrableError),
+}
+
+/// Outcome of the integrity checks for a cached block.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlockReport {
+    pub block: u64,
+    /// State root of the prior block, that the proofs are verified against.
+    pub state_root: H256,
+    /// Number of account proofs that were verified.
+    pub accounts_verified: usize,
+    /// Accounts with a proof that is not valid for the state root.
+    pub invalid_proofs: Vec<H160>,
+    /// Accounts in the parcel that do not have a valid proof.
+    pub unproven_parcel_accounts: Vec<H160>,
+    /// Description of a contract that does not match any code hash, if present.
+    pub contract_mismatch: Option<String>,
+    /// Blocks whose hash is read by the block (BLOCKHASH) but absent from the parcel.
+    pub missing_blockhashes: Vec<u64>,
+    /// Transactions root computed from the cached transactions, if it differs from the
+    /// header.
+    pub transactions_root_mismatch: Option<H256>,
+}
+
+impl BlockReport {
+    /// Whether all checks passed.
+    pub fn is_valid(&self) -> bool {
+        self.invalid_proofs.is_empty()
+            && self.unproven_parcel_accounts.is_empty()
+            && self.contract_mismatch.is_none()
+            && self.missing_blockhashes.is_empty()
+            && self.transactions_root_mismatch.is_none()
+    }
+}
+
+impl Display for BlockReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Block {}: {} accounts verified against state root {:?}. {} invalid proofs, \
+            {} unproven parcel accounts, contracts {}, {} missing blockhashes, \
+            transactions root {}",
+            self.block,
+            self.accounts_verified,
+            self.state_root,
+            self.invalid_proofs.len(),
+            self.unproven_parcel_accounts.len(),
+            match &self.contract_mismatch {
+                Some(_) => "mismatched",
+                None => "ok",
+            },
+            self.missing_blockhashes.len(),
+            match self.transactions_root_mismatch {
+                Some(_) => "mismatched",
+                None => "ok",
+            }
+        )
+    }
+}
+
+/// Checks the cached artifacts of a block in a cache directory (e.g., "data/blocks").
+pub fn verify_cached_block(base: PathBuf, block: u64) -> Result<BlockReport, IntegrityError> {
+    verify_cached_block_in(&FileStore::new(base), block)
+}
+
+/// Checks the cached artifacts of a block in the given store.
+///
+/// Loads the block, prior block state root, proofs, parcel and blockhashes. Then checks
+/// that:
+/// - Every proof is valid for the prior block state root.
+/// - Every account in the parcel has a valid proof.
+/// - Every contract in the parcel matches the code hash of an account.
+/// - Every blockhash read by the block is in the parcel.
+/// - The transactions of the block match the transactions root of the header.
+///
+/// Failed checks are recorded in the report. Missing or malformed artifacts are an error.
+pub fn verify_cached_block_in<S: BlockStore>(
+    store: &S,
+    block: u64,
+) -> Result<BlockReport, IntegrityError> {
+    let header = get_block_from_store(store, block)?;
+    let found = header.number.map(|number| number.as_u64());
+    if found != Some(block) {
+        return Err(IntegrityError::BlockNumberMismatch {
+            expected: block,
+            found,
+        });
+    }
+    let state_root = get_prior_block_state_root_from_store(store, block)?;
+    let proofs = get_proofs_from_store(store, block)?;
+    let state = get_required_state_from_store(store, block)?;
+    let blockhash_reads = get_blockhashes_from_store(store, block)?;
+
+    let mut report = BlockReport {
+        block,
+        state_root,
+        ..Default::default()
+    };
+    match verify_transactions_root(&header) {
+        Ok(()) => {}
+        Err(IntegrityError::TransactionsRootMismatch { computed, .. }) => {
+            report.transactions_root_mismatch = Some(computed);
+        }
+        Err(e) => return Err(e),
+    }
+    let mut proven = HashSet::new();
+    for (address, proof) in &proofs.proofs {
+        match verify_proof(state_root.as_bytes(), proof) {
+            Ok(()) => {
+                proven.insert(*address);
+            }
+            Err(_) => report.invalid_proofs.push(*address),
+        }
+    }
+    report.accounts_verified = proven.len();
+    report.invalid_proofs.sort();
+
+    report.unproven_parcel_accounts = state
+        .parcel_addresses()
+        .into_iter()
+        .filter(|address| !proven.contains(address))
+        .collect();
+    report.contract_mismatch = state.verify().err().map(|e| e.to_string());
+    match check_blockhash_coverage(&state, &blockhash_reads) {
+        Ok(()) => {}
+        Err(TransferrableError::MissingBlockHashes(missing)) => {
+            report.missing_blockhashes = missing;
+        }
+        Err(e) => return Err(e.into()),
+    }
+    Ok(report)
+}
+
+/// Computes the root of the transactions trie, where each transaction is keyed by its
+/// index in the block.
+///
+/// Typed transactions are prefixed with their type (EIP-2718), as by `Transaction::rlp`.
+pub fn transactions_root(transactions: &[Transaction]) -> Result<H256, IntegrityError> {
+    let encoded: Vec<Vec<u8>> = transactions.iter().map(|tx| tx.rlp().to_vec()).collect();
+    Ok(ordered_trie_root(&encoded)?)
+}
+
+/// Checks that the transactions of a block match the transactions root in its header.
+///
+/// Catches a cached block whose transactions were altered, before it is traced.
+pub fn verify_transactions_root(block: &Block<Transaction>) -> Result<(), IntegrityError> {
+    let computed = transactions_root(&block.transactions)?;
+    if computed != block.transactions_root {
+        return Err(IntegrityError::TransactionsRootMismatch {
+            expected: block.transactions_root,
+            computed,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use ethers::utils::keccak256;
+
+    use crate::{
+        cache::encode_transferrable_data,
+        store::{ArtifactKey, MemoryStore},
+    };
+
+    use super::*;
+
+    const BLOCK: u64 = 17190873;
+
+    /// Copies the cached fixture artifacts for the block into memory, with the prior
+    /// block state root.
+    fn fixture_store() -> MemoryStore {
+        let store = MemoryStore::default();
+        for name in [
+            "block_with_transactions.json",
+            "prior_block_state_proofs.json",
+            "prior_block_transferrable_state_proofs.ssz_snappy",
+            "blockhash_opcode_use.json",
+        ] {
+            let data = fs::read(format!("../../data/blocks/{BLOCK}/{name}")).unwrap();
+            store.write(&ArtifactKey::new(BLOCK, name), &data).unwrap();
+        }
+        // The root is the hash of the first node of any account proof.
+        let proofs = get_proofs_from_store(&store, BLOCK).unwrap();
+        let proof = proofs.proofs.values().next().unwrap();
+        let state_root = H256::from(keccak256(&proof.account_proof[0]));
+        store
+            .write(
+                &ArtifactKey::new(BLOCK, "prior_block_state_root.json"),
+                serde_json::to_string(&state_root).unwrap().as_bytes(),
+            )
+            .unwrap();
+        store
+    }
+
+    #[test]
+    fn test_verify_known_good_block() {
+        let store = fixture_store();
+        let report = verify_cached_block_in(&store, BLOCK).unwrap();
+        assert!(report.is_valid(), "{report}");
+        assert!(report.accounts_verified > 0);
+    }
+
+    #[test]
+    fn test_verify_corrupted_block() {
+        let store = fixture_store();
+        // Tamper with a contract in the parcel.
+        let mut state = get_required_state_from_store(&store, BLOCK).unwrap();
+        state.contracts[0][0] ^= 0xff;
+        let (bytes, _) = encode_transferrable_data(state).unwrap();
+        store
+            .write(
+                &ArtifactKey::new(BLOCK, "prior_block_transferrable_state_proofs.ssz_snappy"),
+                &bytes,
+            )
+            .unwrap();
+        // Use a state root that the proofs do not belong to.
+        store
+            .write(
+                &ArtifactKey::new(BLOCK, "prior_block_state_root.json"),
+                serde_json::to_string(&H256::repeat_byte(1))
+                    .unwrap()
+                    .as_bytes(),
+            )
+            .unwrap();
+
+        let report = verify_cached_block_in(&store, BLOCK).unwrap();
+        assert!(!report.is_valid());
+        assert_eq!(report.accounts_verified, 0);
+        assert!(!report.invalid_proofs.is_empty());
+        assert!(!report.unproven_parcel_accounts.is_empty());
+        assert!(report.contract_mismatch.is_some());
+
+        // A block that is not cached is an error, rather than a report.
+        assert!(verify_cached_block_in(&store, BLOCK + 1).is_err());
+    }
+
+    /// Tests that the transactions root computed for a cached block matches the header,
+    /// and that an altered transaction is detected.
+    #[test]
+    fn test_transactions_root_matches_header() {
+        let store = fixture_store();
+        let mut block = get_block_from_store(&store, BLOCK).unwrap();
+        assert_eq!(
+            transactions_root(&block.transactions).unwrap(),
+            block.transactions_root
+        );
+        verify_transactions_root(&block).unwrap();
+
+        block.transactions[0].value += ethers::types::U256::one();
+        assert!(matches!(
+            verify_transactions_root(&block),
+            Err(IntegrityError::TransactionsRootMismatch { .. })
+        ));
+        let tampered = serde_json::to_vec(&block).unwrap();
+        store
+            .write(
+                &ArtifactKey::new(BLOCK, "block_with_transactions.json"),
+                &tampered,
+            )
+            .unwrap();
+        let report = verify_cached_block_in(&store, BLOCK).unwrap();
+        assert!(report.transactions_root_mismatch.is_some());
+        assert!(!report.is_valid());
+    }
+}
@@ -1,7 +1,9 @@
 pub mod cache;
+pub mod integrity;
 pub mod oracle;
 pub mod overlap;
 pub mod rpc;
+pub mod store;
 pub mod transferrable;
 pub mod types;
 pub mod utils;
@@ -3,7 +3,10 @@
 //! The oracle provides nodes for scenarios where key deletion involves node removal and trie
 //! rearrangements that are otherwise incomputable.
 
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    str::FromStr,
+};
 
 use archors_multiproof::{
     eip1186::MultiProofError,
@@ -11,10 +14,10 @@ use archors_multiproof::{
     proof::{Intent, MultiProof, ProofError, ProofOutcome},
     EIP1186MultiProof,
 };
-use archors_types::oracle::TrieNodeOracle;
+use archors_types::{constants::EMPTY_CODE_HASH, oracle::TrieNodeOracle};
 use archors_verify::path::{NibblePath, PathError};
 use ethers::{
-    types::{H160, H256, U256},
+    types::{EIP1186ProofResponse, H160, H256, U256},
     utils::{keccak256, rlp},
 };
 use thiserror::Error;
@@ -123,6 +126,62 @@ pub fn demo_detect_removed_storage(_pre: BlockProofs, _post: BlockProofs) -> Tri
     oracle
 }
 
+/// A key or account that a block removes from the state.
+///
+/// Removal may delete trie nodes and restructure the trie, in which case the post-state
+/// proof update requires an oracle.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Deletion {
+    /// A storage slot that goes from a nonzero value to zero.
+    Storage { address: H160, key: H256 },
+    /// An account that is not empty before the block and is empty after it.
+    Account(H160),
+}
+
+/// Detects the storage keys and accounts that a block deletes, using pre- and post-block
+/// state proofs.
+///
+/// This is cheaper than `oracle_from_simulated_state_update` and can be used to decide if
+/// an oracle may be needed for a block. Keys without a value in both proofs are ignored.
+/// Storage deletions are listed before account deletions, each ordered by address.
+pub fn detect_deletions(pre: &BlockProofs, post: &BlockProofs) -> Vec<Deletion> {
+    let mut deletions = vec![];
+    for (address, account_post) in &post.proofs {
+        let Some(account_pre) = pre.proofs.get(address) else {
+            continue;
+        };
+        if !account_is_empty(account_pre) && account_is_empty(account_post) {
+            deletions.push(Deletion::Account(*address));
+        }
+        for storage_proof_post in &account_post.storage_proof {
+            let Some(storage_proof_pre) = account_pre
+                .storage_proof
+                .iter()
+                .find(|x| x.key == storage_proof_post.key)
+            else {
+                continue;
+            };
+            let val_pre = storage_proof_pre.value;
+            let val_post = storage_proof_post.value;
+            if storage_created_or_destroyed(&val_pre, &val_post) && val_post.is_zero() {
+                deletions.push(Deletion::Storage {
+                    address: *address,
+                    key: storage_proof_post.key,
+                });
+            }
+        }
+    }
+    deletions.sort();
+    deletions
+}
+
+/// An account with no nonce, balance or code is empty (EIP-161), and is absent from the
+/// account trie.
+fn account_is_empty(account: &EIP1186ProofResponse) -> bool {
+    let no_code = account.code_hash == H256(EMPTY_CODE_HASH) || account.code_hash.is_zero();
+    account.nonce.is_zero() && account.balance.is_zero() && no_code
+}
+
 /// Tries to apply known state transition to a multiproof. The oracle is then constructed from
 /// the known values.
 ///
@@ -134,14 +193,16 @@ pub fn oracle_from_simulated_state_update(
 ) -> Result<TrieNodeOracle, OracleError> {
     // Detect places where an oracle is required.
     let mut updates: Vec<InterestingUpdate> = vec![];
+    let pre_by_path = pre.by_path();
     for (address, account) in post.proofs.iter() {
+        if account.storage_proof.is_empty() {
+            continue;
+        }
+        let acc_proof_pre = pre_by_path
+            .get(&H256::from(keccak256(address)))
+            .ok_or_else(|| OracleError::NoPreStateAddress(hex_encode(address)))?;
         for storage_proof_post in &account.storage_proof {
             let key = storage_proof_post.key;
-
-            let acc_proof_pre = pre
-                .proofs
-                .get(address)
-                .ok_or_else(|| OracleError::NoPreStateAddress(hex_encode(address)))?;
             let val_pre: U256 = acc_proof_pre
                 .storage_proof
                 .iter()
@@ -194,9 +255,20 @@ pub fn oracle_from_simulated_state_update(
         }
     }
 
+    oracle_from_tasks(&tasks, &post)
+}
+
+/// Populates an oracle with the post-block nodes required by each task.
+///
+/// All the post-block storage proofs of an account are combined into one multiproof,
+/// so the tasks of an account are resolved against a single storage trie.
+fn oracle_from_tasks(
+    tasks: &[OracleTask],
+    post: &BlockProofs,
+) -> Result<TrieNodeOracle, OracleError> {
     let mut oracle = TrieNodeOracle::default();
-    // Populate the oracle
-    for task in &tasks {
+    let mut storage_multiproofs: HashMap<H160, MultiProof> = HashMap::new();
+    for task in tasks {
         let account = post
             .proofs
             .get(&task.address)
@@ -209,11 +281,10 @@ pub fn oracle_from_simulated_state_update(
                 address: hex_encode(task.address),
                 key: hex_encode(task.key),
             })?;
-        // Create a representation of the proof that is easy to traverse.
-        let mut proof = MultiProof::init(account.storage_hash);
-        proof
-            .insert_proof(storage.proof.to_owned())
-            .expect("Cachecd proof from RPC expected to be valid.");
+        let proof = match storage_multiproofs.entry(task.address) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(storage_multiproof(account)?),
+        };
         let path = keccak256(task.key);
         // Verify that the oracle-based update resulted in a valid proof.
         let intent = match storage.value.is_zero() {
@@ -240,6 +311,15 @@ pub fn oracle_from_simulated_state_update(
     Ok(oracle)
 }
 
+/// Combines all the storage proofs of an account into one multiproof for the storage trie.
+fn storage_multiproof(account: &EIP1186ProofResponse) -> Result<MultiProof, OracleError> {
+    let mut multiproof = MultiProof::init(account.storage_hash);
+    for storage_proof in &account.storage_proof {
+        multiproof.insert_proof(storage_proof.proof.to_owned())?;
+    }
+    Ok(multiproof)
+}
+
 /// Detects if storage went (pre- and post- block) from absent to present, or from present to absent. That is, from exclusion proof to inclusion proof or vice versa.
 fn storage_created_or_destroyed(val_pre: &U256, val_post: &U256) -> bool {
     let e_to_i = val_pre.is_zero() && !val_post.is_zero();
@@ -258,6 +338,8 @@ struct InterestingUpdate {
 
 #[cfg(test)]
 mod test {
+    use ethers::types::StorageProof;
+
     use super::*;
 
     #[test]
@@ -273,4 +355,130 @@ mod test {
         assert!(!storage_created_or_destroyed(&one, &one));
         assert!(!storage_created_or_destroyed(&one, &two));
     }
+
+    #[test]
+    fn test_detect_deletions() {
+        let contract = H160::from_low_u64_be(1);
+        let destroyed = H160::from_low_u64_be(2);
+        let proof = |address: H160, nonce: u64, slots: &[(u64, u64)]| EIP1186ProofResponse {
+            address,
+            nonce: nonce.into(),
+            code_hash: H256(EMPTY_CODE_HASH),
+            storage_proof: slots
+                .iter()
+                .map(|(key, value)| StorageProof {
+                    key: H256::from_low_u64_be(*key),
+                    value: (*value).into(),
+                    proof: vec![],
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let pre = BlockProofs {
+            proofs: HashMap::from([
+                (
+                    contract,
+                    proof(contract, 1, &[(1, 5), (2, 0), (3, 5), (4, 7)]),
+                ),
+                (destroyed, proof(destroyed, 1, &[])),
+            ]),
+        };
+        let post = BlockProofs {
+            proofs: HashMap::from([
+                // Key 1 deleted, key 2 created, key 3 modified, key 4 deleted.
+                (
+                    contract,
+                    proof(contract, 1, &[(1, 0), (2, 5), (3, 6), (4, 0)]),
+                ),
+                (destroyed, proof(destroyed, 0, &[])),
+            ]),
+        };
+        assert_eq!(
+            detect_deletions(&pre, &post),
+            vec![
+                Deletion::Storage {
+                    address: contract,
+                    key: H256::from_low_u64_be(1)
+                },
+                Deletion::Storage {
+                    address: contract,
+                    key: H256::from_low_u64_be(4)
+                },
+                Deletion::Account(destroyed),
+            ]
+        );
+    }
+
+    /// Tests that the tasks for two keys of one account are resolved against a single
+    /// storage multiproof with both keys.
+    #[test]
+    fn test_two_keys_share_storage_multiproof() {
+        // Two keys whose paths diverge at the first nibble: a branch with two leaves.
+        let first_key = H256::zero();
+        let first_nibble = keccak256(first_key)[0] >> 4;
+        let second_key = (1..)
+            .map(H256::from_low_u64_be)
+            .find(|key| keccak256(key)[0] >> 4 != first_nibble)
+            .unwrap();
+        let value = U256::from(5);
+        let leaf = |key: H256| {
+            let path = keccak256(key);
+            // Leaf with odd remaining path length (63 nibbles).
+            let mut encoded_path = vec![0x30 | (path[0] & 0x0f)];
+            encoded_path.extend_from_slice(&path[1..]);
+            let mut stream = rlp::RlpStream::new_list(2);
+            stream.append(&encoded_path);
+            stream.append(&rlp::encode(&value).to_vec());
+            stream.out().to_vec()
+        };
+        let mut branch = rlp::RlpStream::new_list(17);
+        let children: HashMap<u8, Vec<u8>> = [first_key, second_key]
+            .into_iter()
+            .map(|key| (keccak256(key)[0] >> 4, leaf(key)))
+            .collect();
+        for nibble in 0..17u8 {
+            match children.get(&nibble) {
+                Some(child) => branch.append(&keccak256(child).to_vec()),
+                None => branch.append_empty_data(),
+            };
+        }
+        let branch = branch.out().to_vec();
+        let address = H160::from_low_u64_be(1);
+        let account = EIP1186ProofResponse {
+            address,
+            storage_hash: H256::from(keccak256(&branch)),
+            storage_proof: [first_key, second_key]
+                .into_iter()
+                .map(|key| StorageProof {
+                    key,
+                    value,
+                    proof: vec![branch.clone().into(), leaf(key).into()],
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let multiproof = storage_multiproof(&account).unwrap();
+        assert_eq!(multiproof.dump_sorted().len(), 3);
+
+        let post = BlockProofs {
+            proofs: HashMap::from([(address, account)]),
+        };
+        let tasks: Vec<OracleTask> = [first_key, second_key]
+            .into_iter()
+            .map(|key| OracleTask {
+                address,
+                key,
+                traversal_index: 1,
+                purpose: TaskType::ForInclusion(rlp::encode(&value).to_vec()),
+            })
+            .collect();
+        let oracle = oracle_from_tasks(&tasks, &post).unwrap();
+        assert_eq!(oracle.len(), 2);
+        // Each oracle target is the leaf, reached by the path up to the leaf (index 1).
+        for key in [first_key, second_key] {
+            let path = keccak256(key);
+            let traversal = vec![path[0] >> 4, path[0] & 0x0f];
+            assert_eq!(oracle.lookup(address, traversal), Some(vec![leaf(key)]));
+        }
+    }
 }
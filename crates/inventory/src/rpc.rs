@@ -1,6 +1,6 @@
 use std::fmt::Display;
 
-use ethers::types::{Block, EIP1186ProofResponse, Transaction};
+use ethers::types::{Block, EIP1186ProofResponse, Transaction, H256};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
@@ -8,7 +8,7 @@ use crate::types::{AccountToProve, TransactionAccountStates};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct AccountProofResponse {
-    id: u32,
+    pub(crate) id: u32,
     jsonrpc: String,
     pub(crate) result: EIP1186ProofResponse,
 }
@@ -34,6 +34,13 @@ pub(crate) struct BlockResponse {
     pub(crate) result: Block<Transaction>,
 }
 
+#[derive(Deserialize, Serialize)]
+pub(crate) struct BlockHeaderResponse {
+    id: u32,
+    jsonrpc: String,
+    pub(crate) result: Block<H256>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct TxPrestateResponse {
     id: u32,
@@ -86,6 +93,17 @@ pub(crate) fn get_block_by_number(block: &str) -> JsonRpcRequest {
     }
 }
 
+/// Generates a JSON-RPC request for eth_getBlockByNumber for
+/// the specified block, with transaction hashes only.
+pub(crate) fn get_block_header_by_number(block: &str) -> JsonRpcRequest {
+    JsonRpcRequest {
+        jsonrpc: "2.0".to_owned(),
+        method: "eth_getBlockByNumber".to_owned(),
+        params: vec![json!(block), Value::Bool(false)],
+        id: 1,
+    }
+}
+
 /// Generates a JSON-RPC request for eth_getProof for
 /// the given account and storage slots at the specified block.
 pub(crate) fn eth_get_proof(account: &AccountToProve, block_number: &str) -> JsonRpcRequest {
@@ -101,6 +119,22 @@ pub(crate) fn eth_get_proof(account: &AccountToProve, block_number: &str) -> Jso
     }
 }
 
+/// Generates a JSON-RPC batch of eth_getProof requests, one per account, at the
+/// specified block. Each request id is the index of the account.
+pub(crate) fn eth_get_proof_batch(
+    accounts: &[AccountToProve],
+    block_number: &str,
+) -> Vec<JsonRpcRequest> {
+    accounts
+        .iter()
+        .enumerate()
+        .map(|(index, account)| JsonRpcRequest {
+            id: index as u64,
+            ..eth_get_proof(account, block_number)
+        })
+        .collect()
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct BlockDefaultTraceResponse {
     id: u32,
@@ -0,0 +1,168 @@
+//! Backends for storing cached block artifacts (filesystem, memory).
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("Artifact {0} not found in store")]
+    NotFound(ArtifactKey),
+    #[error("IO error for artifact {key} {source}")]
+    Io { source: io::Error, key: ArtifactKey },
+    #[error("Memory store lock was poisoned")]
+    LockPoisoned,
+}
+
+/// Identifies a single cached artifact for a block (e.g., the block with transactions).
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ArtifactKey {
+    pub block: u64,
+    /// Name of the artifact, including any extension.
+    pub name: &'static str,
+}
+
+impl ArtifactKey {
+    pub fn new(block: u64, name: &'static str) -> Self {
+        Self { block, name }
+    }
+}
+
+impl Display for ArtifactKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.block, self.name)
+    }
+}
+
+/// A place where block artifacts can be written to and read from.
+pub trait BlockStore {
+    /// Returns the bytes of an artifact.
+    fn read(&self, key: &ArtifactKey) -> Result<Vec<u8>, StoreError>;
+    /// Stores the bytes of an artifact, replacing any existing artifact.
+    fn write(&self, key: &ArtifactKey, data: &[u8]) -> Result<(), StoreError>;
+    /// Whether the artifact is present in the store.
+    fn exists(&self, key: &ArtifactKey) -> bool;
+    /// Removes an artifact. Removing an absent artifact is not an error.
+    fn remove(&self, key: &ArtifactKey) -> Result<(), StoreError>;
+}
+
+/// Stores artifacts as files, one directory per block.
+#[derive(Clone, Debug)]
+pub struct FileStore {
+    root: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+    /// Directory for the artifacts of a block.
+    pub fn block_dir(&self, block: u64) -> PathBuf {
+        self.root.join(block.to_string())
+    }
+    /// Path of the file for an artifact.
+    pub(crate) fn path(&self, key: &ArtifactKey) -> PathBuf {
+        self.block_dir(key.block).join(key.name)
+    }
+}
+
+impl Default for FileStore {
+    fn default() -> Self {
+        Self::new(PathBuf::from(crate::cache::CACHE_DIR))
+    }
+}
+
+impl BlockStore for FileStore {
+    fn read(&self, key: &ArtifactKey) -> Result<Vec<u8>, StoreError> {
+        fs::read(self.path(key)).map_err(|source| match source.kind() {
+            io::ErrorKind::NotFound => StoreError::NotFound(key.clone()),
+            _ => StoreError::Io {
+                source,
+                key: key.clone(),
+            },
+        })
+    }
+    fn write(&self, key: &ArtifactKey, data: &[u8]) -> Result<(), StoreError> {
+        let to_store_error = |source| StoreError::Io {
+            source,
+            key: key.clone(),
+        };
+        fs::create_dir_all(self.block_dir(key.block)).map_err(to_store_error)?;
+        let mut file = File::create(self.path(key)).map_err(to_store_error)?;
+        file.write_all(data).map_err(to_store_error)?;
+        Ok(())
+    }
+    fn exists(&self, key: &ArtifactKey) -> bool {
+        self.path(key).exists()
+    }
+    fn remove(&self, key: &ArtifactKey) -> Result<(), StoreError> {
+        match fs::remove_file(self.path(key)) {
+            Err(source) if source.kind() != io::ErrorKind::NotFound => Err(StoreError::Io {
+                source,
+                key: key.clone(),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Stores artifacts in memory. Useful for tests and contexts without a disk.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    artifacts: Mutex<HashMap<ArtifactKey, Vec<u8>>>,
+}
+
+impl BlockStore for MemoryStore {
+    fn read(&self, key: &ArtifactKey) -> Result<Vec<u8>, StoreError> {
+        self.artifacts
+            .lock()
+            .map_err(|_| StoreError::LockPoisoned)?
+            .get(key)
+            .cloned()
+            .ok_or_else(|| StoreError::NotFound(key.clone()))
+    }
+    fn write(&self, key: &ArtifactKey, data: &[u8]) -> Result<(), StoreError> {
+        self.artifacts
+            .lock()
+            .map_err(|_| StoreError::LockPoisoned)?
+            .insert(key.clone(), data.to_vec());
+        Ok(())
+    }
+    fn exists(&self, key: &ArtifactKey) -> bool {
+        self.artifacts
+            .lock()
+            .map(|artifacts| artifacts.contains_key(key))
+            .unwrap_or(false)
+    }
+    fn remove(&self, key: &ArtifactKey) -> Result<(), StoreError> {
+        self.artifacts
+            .lock()
+            .map_err(|_| StoreError::LockPoisoned)?
+            .remove(key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_memory_store_roundtrip() {
+        let store = MemoryStore::default();
+        let key = ArtifactKey::new(17190873, "artifact.json");
+        assert!(!store.exists(&key));
+        assert!(matches!(store.read(&key), Err(StoreError::NotFound(_))));
+        store.write(&key, &[1, 2, 3]).unwrap();
+        assert!(store.exists(&key));
+        assert_eq!(store.read(&key).unwrap(), vec![1, 2, 3]);
+        // Same artifact name for a different block is distinct.
+        assert!(!store.exists(&ArtifactKey::new(17190874, "artifact.json")));
+    }
+}
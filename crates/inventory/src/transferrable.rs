@@ -14,23 +14,31 @@
 //!
 //! Such an item can be referred to by the position in a separate list.
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
-use archors_types::state::{
-    BlockHashes, CompactEip1186Proof, CompactEip1186Proofs, CompactStorageProof,
-    CompactStorageProofs, Contract, Contracts, NodeIndices, RecentBlockHash, RequiredBlockState,
+use archors_types::{
+    constants::{
+        MAX_ACCOUNT_PROOFS_PER_BLOCK, MAX_BYTES_PER_NODE, MAX_CONTRACTS_PER_BLOCK,
+        MAX_NODES_PER_BLOCK,
+    },
+    state::{
+        BlockHashes, CompactEip1186Proof, CompactEip1186Proofs, CompactStorageProof,
+        CompactStorageProofs, Contract, RecentBlockHash, RequiredBlockState,
+        RequiredBlockStateWithLimits, StateError,
+    },
+};
+use ethers::{
+    types::{EIP1186ProofResponse, StorageProof, H160, H256, U64},
+    utils::keccak256,
 };
-use ethers::types::{EIP1186ProofResponse, StorageProof, H160, H256, U64};
+use log::warn;
 use ssz_rs::prelude::*;
 use thiserror::Error;
 
 use crate::{
     cache::ContractBytes,
-    types::{BlockHashAccesses, BlockProofs},
-    utils::{
-        h160_to_ssz_h160, h256_to_ssz_h256, u256_to_ssz_u256, u64_to_ssz_u64, usize_to_u16,
-        UtilsError,
-    },
+    types::{BlockHashAccess, BlockHashAccesses, BlockProofs},
+    utils::{h160_to_ssz_h160, h256_to_ssz_h256, u256_to_ssz_u256, u64_to_ssz_u64, UtilsError},
 };
 
 #[derive(Debug, Error)]
@@ -43,19 +51,135 @@ pub enum TransferrableError {
     SimpleSerializeError(#[from] SimpleSerializeError),
     #[error("Utils error {0}")]
     UtilsError(#[from] UtilsError),
-    #[error("Unable to find index for node")]
-    NoIndexForNode,
+    #[error("Parcel is missing blockhashes read by the block, for block numbers {0:?}")]
+    MissingBlockHashes(Vec<u64>),
+    #[error("State error {0}")]
+    StateError(#[from] StateError),
+    #[error("Trie node {hash} is {size} bytes, exceeding MAX_BYTES_PER_NODE")]
+    NodeTooLarge { hash: H256, size: usize },
+    #[error("Parcel has {count} {item}, exceeding the limit of {limit}")]
+    LimitExceeded {
+        item: &'static str,
+        count: usize,
+        limit: usize,
+    },
+    #[error("Contracts with keccak {0:?} are not the code of any account in the proofs")]
+    UnreferencedContracts(Vec<H256>),
+}
+
+/// Order of the deduplicated trie nodes in a parcel.
+///
+/// Either order is deterministic, so that the same proofs always produce the same parcel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NodeOrdering {
+    /// Sorted by node hash.
+    #[default]
+    ByHash,
+    /// In the order first encountered. Accounts are visited by address and storage by
+    /// key, each proof from root to leaf.
+    FirstSeen,
 }
 
 /// Creates a compact proof by separating trie nodes and contract code from the proof data.
+///
+/// Trie nodes are sorted by hash.
 pub fn state_from_parts(
     block_proofs: BlockProofs,
     accessed_contracts_sorted: Vec<ContractBytes>,
     accessed_blockhashes: BlockHashAccesses,
 ) -> Result<RequiredBlockState, TransferrableError> {
-    let node_set = get_trie_node_set(&block_proofs.proofs);
+    state_from_parts_with_ordering(
+        block_proofs,
+        accessed_contracts_sorted,
+        accessed_blockhashes,
+        NodeOrdering::default(),
+    )
+}
+
+/// Creates a compact proof and checks that every contract matches the code hash of an
+/// account in the proof (see `RequiredBlockState::verify`).
+///
+/// Contracts that no account refers to are an error, rather than a warning.
+pub fn state_from_parts_verified(
+    block_proofs: BlockProofs,
+    accessed_contracts_sorted: Vec<ContractBytes>,
+    accessed_blockhashes: BlockHashAccesses,
+) -> Result<RequiredBlockState, TransferrableError> {
+    let unreferenced = unreferenced_contracts(&block_proofs, &accessed_contracts_sorted);
+    if !unreferenced.is_empty() {
+        return Err(TransferrableError::UnreferencedContracts(unreferenced));
+    }
+    let state = state_from_parts(
+        block_proofs,
+        accessed_contracts_sorted,
+        accessed_blockhashes,
+    )?;
+    state.verify()?;
+    Ok(state)
+}
 
-    let proof = RequiredBlockState {
+/// Returns the keccak hash of every contract whose code hash no account in the proofs has,
+/// sorted.
+///
+/// Such a contract can never be executed and only adds to the size of the parcel.
+pub fn unreferenced_contracts(
+    block_proofs: &BlockProofs,
+    contracts: &[ContractBytes],
+) -> Vec<H256> {
+    let code_hashes: HashSet<H256> = block_proofs
+        .proofs
+        .values()
+        .map(|proof| proof.code_hash)
+        .collect();
+    let mut unreferenced: Vec<H256> = contracts
+        .iter()
+        .map(|contract| H256::from(keccak256(contract)))
+        .filter(|hash| !code_hashes.contains(hash))
+        .collect();
+    unreferenced.sort();
+    unreferenced
+}
+
+/// Creates a compact proof, with trie nodes in the given order.
+pub fn state_from_parts_with_ordering(
+    block_proofs: BlockProofs,
+    accessed_contracts_sorted: Vec<ContractBytes>,
+    accessed_blockhashes: BlockHashAccesses,
+    ordering: NodeOrdering,
+) -> Result<RequiredBlockState, TransferrableError> {
+    state_from_parts_with_limits(
+        block_proofs,
+        accessed_contracts_sorted,
+        accessed_blockhashes,
+        ordering,
+    )
+}
+
+/// Creates a compact proof with the given list limits, with trie nodes in the given order.
+/// For chains with larger blocks than mainnet.
+///
+/// Returns an error if the accounts, contracts or trie nodes exceed the limits.
+pub fn state_from_parts_with_limits<
+    const MAX_ACCOUNTS: usize,
+    const MAX_CONTRACTS: usize,
+    const MAX_NODES: usize,
+>(
+    block_proofs: BlockProofs,
+    accessed_contracts_sorted: Vec<ContractBytes>,
+    accessed_blockhashes: BlockHashAccesses,
+    ordering: NodeOrdering,
+) -> Result<RequiredBlockStateWithLimits<MAX_ACCOUNTS, MAX_CONTRACTS, MAX_NODES>, TransferrableError>
+{
+    let node_set = get_trie_node_set(&block_proofs.proofs, ordering);
+    check_node_sizes(&node_set)?;
+    check_limit("accounts", block_proofs.proofs.len(), MAX_ACCOUNTS)?;
+    check_limit("contracts", accessed_contracts_sorted.len(), MAX_CONTRACTS)?;
+    check_limit("trie nodes", node_set.0.len(), MAX_NODES)?;
+    for hash in unreferenced_contracts(&block_proofs, &accessed_contracts_sorted) {
+        warn!("Contract with keccak {hash:?} is not the code of any account in the parcel");
+    }
+
+    let proof = RequiredBlockStateWithLimits {
         compact_eip1186_proofs: get_compact_eip1186_proofs(block_proofs)?,
         contracts: contracts_to_ssz(accessed_contracts_sorted),
         trie_nodes: bytes_collection_to_ssz(node_set.0),
@@ -66,38 +190,148 @@ pub fn state_from_parts(
     Ok(proof)
 }
 
+/// Checks that the blockhashes in a parcel include every BLOCKHASH opcode read made by
+/// the block.
+///
+/// The reads are obtained by filtering a (struct log) trace of the block. The parcel may
+/// contain additional blockhashes.
+pub fn check_blockhash_coverage(
+    state: &RequiredBlockState,
+    reads: &BlockHashAccesses,
+) -> Result<(), TransferrableError> {
+    let mut missing = vec![];
+    for read in &reads.blockhash_accesses {
+        let pair = RecentBlockHash {
+            block_number: u64_to_ssz_u64(read.block_number),
+            block_hash: h256_to_ssz_h256(read.block_hash)?,
+        };
+        if !state.blockhashes.contains(&pair) {
+            missing.push(read.block_number.as_u64());
+        }
+    }
+    if !missing.is_empty() {
+        missing.sort();
+        return Err(TransferrableError::MissingBlockHashes(missing));
+    }
+    Ok(())
+}
+
+/// Checks that the number of items of a kind fits within the list limit for the parcel.
+fn check_limit(item: &'static str, count: usize, limit: usize) -> Result<(), TransferrableError> {
+    match count > limit {
+        true => Err(TransferrableError::LimitExceeded { item, count, limit }),
+        false => Ok(()),
+    }
+}
+
+/// Builds a parcel from proofs and contracts that arrive one at a time.
+///
+/// Each proof is made compact as it is added and its trie nodes are deduplicated against
+/// those already added, so that the full proofs for a block need not be held in memory.
+/// The parcel is the same as that made by `state_from_parts` from the same data
+/// (with trie nodes sorted by hash).
+#[derive(Default)]
+pub struct RequiredBlockStateBuilder {
+    /// Compact proofs, keyed by address so that they are sorted.
+    proofs: BTreeMap<H160, CompactEip1186Proof>,
+    node_set: HashSet<NodeBytes>,
+    nodes: Vec<NodeBytes>,
+    contracts: BTreeSet<ContractBytes>,
+    blockhashes: Vec<BlockHashAccess>,
+}
+
+impl RequiredBlockStateBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds an account proof with its storage proofs. A later proof for the same
+    /// address replaces the earlier one, though its nodes remain in the parcel.
+    pub fn add_proof(&mut self, proof: EIP1186ProofResponse) -> Result<(), TransferrableError> {
+        let storage_nodes = proof
+            .storage_proof
+            .iter()
+            .flat_map(|storage| &storage.proof);
+        for node in proof.account_proof.iter().chain(storage_nodes) {
+            check_node_size(node)?;
+            if self.node_set.insert(node.to_vec()) {
+                self.nodes.push(node.to_vec());
+            }
+        }
+        let address = proof.address;
+        self.proofs
+            .insert(address, get_compact_eip1186_proof(proof)?);
+        Ok(())
+    }
+    /// Adds contract bytecode. Duplicates are ignored.
+    pub fn add_contract(&mut self, contract: ContractBytes) {
+        self.contracts.insert(contract);
+    }
+    /// Adds the blockhashes read by the block.
+    pub fn add_blockhashes(&mut self, accesses: BlockHashAccesses) {
+        self.blockhashes.extend(accesses.blockhash_accesses);
+    }
+    /// Finalizes the parcel. Trie nodes are sorted by hash and contracts by bytes.
+    pub fn build(self) -> Result<RequiredBlockState, TransferrableError> {
+        check_limit("accounts", self.proofs.len(), MAX_ACCOUNT_PROOFS_PER_BLOCK)?;
+        check_limit("contracts", self.contracts.len(), MAX_CONTRACTS_PER_BLOCK)?;
+        check_limit("trie nodes", self.nodes.len(), MAX_NODES_PER_BLOCK)?;
+        let mut nodes = self.nodes;
+        nodes.sort_by_cached_key(keccak256);
+        let mut compact_eip1186_proofs = CompactEip1186Proofs::default();
+        self.proofs
+            .into_values()
+            .for_each(|proof| compact_eip1186_proofs.push(proof));
+        let blockhashes = BlockHashAccesses {
+            blockhash_accesses: self.blockhashes,
+        };
+        Ok(RequiredBlockState {
+            compact_eip1186_proofs,
+            contracts: contracts_to_ssz(self.contracts.into_iter().collect()),
+            trie_nodes: bytes_collection_to_ssz(nodes),
+            blockhashes: blockhashes_to_ssz(blockhashes.to_unique_pairs_sorted())?,
+        })
+    }
+}
+
 /// Replace every account proof node with a reference to the index in a list.
 ///
 /// Results are sorted by address. Contains storage proofs, that
 /// are sorted by key.
-fn get_compact_eip1186_proofs(
+fn get_compact_eip1186_proofs<const MAX_ACCOUNTS: usize>(
     block_proofs: BlockProofs,
-) -> Result<CompactEip1186Proofs, TransferrableError> {
+) -> Result<List<CompactEip1186Proof, MAX_ACCOUNTS>, TransferrableError> {
     let mut block_proofs: Vec<(H160, EIP1186ProofResponse)> =
         block_proofs.proofs.into_iter().collect();
     // Sort account proofs by address
     block_proofs.sort_by_key(|x| x.0);
 
-    let mut ssz_eip1186_proofs = CompactEip1186Proofs::default();
+    let mut ssz_eip1186_proofs = List::<CompactEip1186Proof, MAX_ACCOUNTS>::default();
 
     for proof in block_proofs {
-        // Storage
-        let storage_proofs = get_compact_storage_proofs(proof.1.storage_proof)?;
-
-        let ssz_eip1186_proof = CompactEip1186Proof {
-            address: h160_to_ssz_h160(proof.1.address)?,
-            balance: u256_to_ssz_u256(proof.1.balance),
-            code_hash: h256_to_ssz_h256(proof.1.code_hash)?,
-            nonce: u64_to_ssz_u64(proof.1.nonce),
-            storage_hash: h256_to_ssz_h256(proof.1.storage_hash)?,
-            storage_proofs,
-        };
-        ssz_eip1186_proofs.push(ssz_eip1186_proof);
+        ssz_eip1186_proofs.push(get_compact_eip1186_proof(proof.1)?);
     }
 
     Ok(ssz_eip1186_proofs)
 }
 
+/// Replace the nodes of a single account proof and its storage proofs. Storage proofs
+/// are sorted by key.
+fn get_compact_eip1186_proof(
+    proof: EIP1186ProofResponse,
+) -> Result<CompactEip1186Proof, TransferrableError> {
+    // Storage
+    let storage_proofs = get_compact_storage_proofs(proof.storage_proof)?;
+
+    Ok(CompactEip1186Proof {
+        address: h160_to_ssz_h160(proof.address)?,
+        balance: u256_to_ssz_u256(proof.balance),
+        code_hash: h256_to_ssz_h256(proof.code_hash)?,
+        nonce: u64_to_ssz_u64(proof.nonce),
+        storage_hash: h256_to_ssz_h256(proof.storage_hash)?,
+        storage_proofs,
+    })
+}
+
 /// Replace every storage proof node with a reference to the index in a list.
 ///
 /// Results are sorted by key.
@@ -120,31 +354,10 @@ fn get_compact_storage_proofs(
     Ok(compact_storage_proofs)
 }
 
-/// Turns a list of nodes in to a list of indices. The indices
-/// come from a mapping (node -> index)
-///
-/// The node order is unchanged, and will already be sorted (closest to root = first)
-fn nodes_to_node_indices(
-    proof: Vec<ethers::types::Bytes>,
-    map: &HashMap<NodeBytes, usize>,
-) -> Result<NodeIndices, TransferrableError> {
-    let mut indices = NodeIndices::default();
-    // Substitute proof nodes with indices.
-    for node in proof {
-        // Find the index
-        let index: &usize = map
-            .get(node.0.as_ref())
-            .ok_or(TransferrableError::NoIndexForNode)?;
-        // Insert the index
-        indices.push(usize_to_u16(*index)?);
-    }
-    Ok(indices)
-}
-
 /// Holds all nodes present in a block state proof. Used to construct
 /// deduplicated compact proof.
 ///
-/// Account and storage nodes are separate. Members are lexicographically sorted ([0xa.., 0xb..])
+/// Members are ordered according to a `NodeOrdering`.
 #[derive(Clone)]
 struct TrieNodesSet(Vec<NodeBytes>);
 
@@ -159,28 +372,63 @@ type NodeBytes = Vec<u8>;
 
 /// Finds all trie nodes and uses a HashSet to remove duplicates.
 ///
-/// The aggregated account and storage nodes are sorted.
-fn get_trie_node_set(proofs: &HashMap<H160, EIP1186ProofResponse>) -> TrieNodesSet {
-    let mut node_set: HashSet<Vec<u8>> = HashSet::default();
+/// The aggregated account and storage nodes are ordered as specified.
+fn get_trie_node_set(
+    proofs: &HashMap<H160, EIP1186ProofResponse>,
+    ordering: NodeOrdering,
+) -> TrieNodesSet {
+    let mut addresses: Vec<&H160> = proofs.keys().collect();
+    addresses.sort();
 
-    for proof in proofs.values() {
+    let mut node_set: HashSet<Vec<u8>> = HashSet::default();
+    let mut nodes: Vec<Vec<u8>> = vec![];
+    let mut add_node = |node: &ethers::types::Bytes| {
+        if node_set.insert(node.to_vec()) {
+            nodes.push(node.to_vec());
+        }
+    };
+    for address in addresses {
+        let proof = &proofs[address];
         for node in &proof.account_proof {
-            node_set.insert(node.0.clone().into());
+            add_node(node);
         }
-        for storage_proof in &proof.storage_proof {
+        let mut storage_proofs: Vec<&StorageProof> = proof.storage_proof.iter().collect();
+        storage_proofs.sort_by_key(|x| x.key);
+        for storage_proof in storage_proofs {
             for node in &storage_proof.proof {
-                node_set.insert(node.0.clone().into());
+                add_node(node);
             }
         }
     }
-    let mut nodes: Vec<Vec<u8>> = node_set.into_iter().collect();
-    nodes.sort();
+    match ordering {
+        NodeOrdering::ByHash => nodes.sort_by_cached_key(keccak256),
+        NodeOrdering::FirstSeen => {}
+    }
     TrieNodesSet(nodes)
 }
 
+/// Checks that every trie node fits in the parcel. An oversized node could not be
+/// added to the SSZ list.
+fn check_node_sizes(node_set: &TrieNodesSet) -> Result<(), TransferrableError> {
+    node_set.0.iter().try_for_each(|node| check_node_size(node))
+}
+
+/// Checks that a trie node fits in the parcel.
+fn check_node_size(node: &[u8]) -> Result<(), TransferrableError> {
+    match node.len() > MAX_BYTES_PER_NODE {
+        true => Err(TransferrableError::NodeTooLarge {
+            hash: H256::from(keccak256(node)),
+            size: node.len(),
+        }),
+        false => Ok(()),
+    }
+}
+
 /// Turns a collection of contracts into an SSZ format.
-fn contracts_to_ssz(input: Vec<ContractBytes>) -> Contracts {
-    let mut contracts = Contracts::default();
+fn contracts_to_ssz<const MAX_CONTRACTS: usize>(
+    input: Vec<ContractBytes>,
+) -> List<Contract, MAX_CONTRACTS> {
+    let mut contracts = List::<Contract, MAX_CONTRACTS>::default();
     input
         .into_iter()
         .map(|c| {
@@ -223,3 +471,364 @@ fn bytes_collection_to_ssz<const OUTER: usize, const INNER: usize>(
         .for_each(|contract| ssz_collection.push(contract));
     ssz_collection
 }
+
+#[cfg(test)]
+mod test {
+    use std::{fs::File, io::BufReader};
+
+    use archors_types::{execution::StateForEvm, fixture::prior_block_proofs};
+    use archors_verify::{
+        eip1186::verify_proof,
+        proof::{SingleProofPath, Verified},
+    };
+    use ethers::types::Bytes;
+
+    use crate::utils::hex_decode;
+
+    use super::*;
+
+    fn accesses(pairs: &[(u64, u8)]) -> BlockHashAccesses {
+        BlockHashAccesses {
+            blockhash_accesses: pairs
+                .iter()
+                .map(|(number, byte)| BlockHashAccess {
+                    block_number: U64::from(*number),
+                    block_hash: H256::repeat_byte(*byte),
+                })
+                .collect(),
+        }
+    }
+
+    /// Tests that a BLOCKHASH read that is absent from the parcel is reported.
+    #[test]
+    fn test_blockhash_coverage_reports_missing() {
+        let block_proofs = BlockProofs {
+            proofs: HashMap::new(),
+        };
+        let parcel_hashes = accesses(&[(17190870, 1), (17190871, 2)]);
+        let state = state_from_parts(block_proofs, vec![], parcel_hashes).unwrap();
+
+        check_blockhash_coverage(&state, &accesses(&[(17190871, 2)])).unwrap();
+        let reads = accesses(&[(17190870, 1), (17190871, 2), (17190872, 3)]);
+        assert!(matches!(
+            check_blockhash_coverage(&state, &reads),
+            Err(TransferrableError::MissingBlockHashes(missing)) if missing == vec![17190872]
+        ));
+        // Same number, different hash.
+        let reads = accesses(&[(17190871, 9)]);
+        assert!(matches!(
+            check_blockhash_coverage(&state, &reads),
+            Err(TransferrableError::MissingBlockHashes(missing)) if missing == vec![17190871]
+        ));
+    }
+
+    /// Tests that a parcel can be made for an account that has no storage proofs.
+    #[test]
+    fn test_parcel_with_account_only_proof() {
+        let file = File::open("../verify/data/test_proof_3.json").unwrap();
+        let mut proof: EIP1186ProofResponse =
+            serde_json::from_reader(BufReader::new(file)).unwrap();
+        proof.storage_proof.clear();
+        let state_root =
+            hex_decode("0x38e5e1dd67f7873cd8cfff08685a30734c18d0075318e9fca9ed64cc28a597da")
+                .unwrap();
+        verify_proof(&state_root, &proof).unwrap();
+
+        let address = proof.address;
+        let block_proofs = BlockProofs {
+            proofs: HashMap::from([(address, proof)]),
+        };
+        let state = state_from_parts(block_proofs, vec![], accesses(&[])).unwrap();
+        let state = RequiredBlockState::from_ssz_bytes(state.to_ssz_bytes().unwrap()).unwrap();
+        assert_eq!(state.compact_eip1186_proofs.len(), 1);
+        assert!(state.compact_eip1186_proofs[0].storage_proofs.is_empty());
+        let storage = state.get_account_storage(&address.0.into()).unwrap();
+        assert!(storage.is_empty());
+    }
+
+    /// Tests that the account proof nodes extracted from a parcel are the account proof,
+    /// and verify against the state root of the parcel.
+    #[test]
+    fn test_account_proof_nodes_from_parcel() {
+        let block_proofs = BlockProofs {
+            proofs: prior_block_proofs(),
+        };
+        // An account that is in the trie (inclusion proof).
+        let proof = block_proofs
+            .proofs
+            .values()
+            .find(|proof| !proof.balance.is_zero())
+            .unwrap()
+            .clone();
+        let state = state_from_parts(block_proofs, vec![], accesses(&[])).unwrap();
+        let state_root = state.state_root().unwrap();
+
+        let nodes = state.account_proof_nodes(proof.address).unwrap();
+        let expected: Vec<Vec<u8>> = proof
+            .account_proof
+            .iter()
+            .map(|node| node.to_vec())
+            .collect();
+        assert_eq!(nodes, expected);
+        let single = SingleProofPath {
+            proof: nodes.into_iter().map(Bytes::from).collect(),
+            root: state_root.0,
+            path: keccak256(proof.address),
+            claimed_value: state.account_leaf_rlp(proof.address).unwrap(),
+        };
+        assert_eq!(single.verify_strict().unwrap(), Verified::Inclusion);
+    }
+
+    /// Tests that the addresses in a parcel are those of the source proofs.
+    #[test]
+    fn test_parcel_addresses_match_block_proofs() {
+        let mut proofs = HashMap::new();
+        for index in 1..=3 {
+            let file = File::open(format!("../verify/data/test_proof_{index}.json")).unwrap();
+            let proof: EIP1186ProofResponse =
+                serde_json::from_reader(BufReader::new(file)).unwrap();
+            proofs.insert(proof.address, proof);
+        }
+        let mut expected: Vec<H160> = proofs.keys().copied().collect();
+        let block_proofs = BlockProofs { proofs };
+        let state = state_from_parts(block_proofs, vec![], accesses(&[])).unwrap();
+        let state = RequiredBlockState::from_ssz_bytes(state.to_ssz_bytes().unwrap()).unwrap();
+
+        let mut addresses = state.parcel_addresses();
+        addresses.sort();
+        expected.sort();
+        assert_eq!(addresses.len(), 3);
+        assert_eq!(addresses, expected);
+    }
+
+    /// Tests that trie node order does not depend on hash map iteration order.
+    #[test]
+    fn test_trie_node_order_is_reproducible() {
+        let build = |ordering| {
+            let mut proofs = HashMap::new();
+            for index in 1..=3 {
+                let file = File::open(format!("../verify/data/test_proof_{index}.json")).unwrap();
+                let proof: EIP1186ProofResponse =
+                    serde_json::from_reader(BufReader::new(file)).unwrap();
+                proofs.insert(proof.address, proof);
+            }
+            let block_proofs = BlockProofs { proofs };
+            state_from_parts_with_ordering(block_proofs, vec![], accesses(&[]), ordering)
+                .unwrap()
+                .trie_nodes
+        };
+        for ordering in [NodeOrdering::ByHash, NodeOrdering::FirstSeen] {
+            assert_eq!(build(ordering), build(ordering));
+        }
+        let by_hash = build(NodeOrdering::ByHash);
+        let hashes: Vec<[u8; 32]> = by_hash.iter().map(|node| keccak256(&node[..])).collect();
+        let mut sorted = hashes.clone();
+        sorted.sort();
+        assert_eq!(hashes, sorted);
+
+        // First seen starts with the account trie root of the lowest address.
+        let first_seen = build(NodeOrdering::FirstSeen);
+        assert_eq!(first_seen.len(), by_hash.len());
+        let file = File::open("../verify/data/test_proof_3.json").unwrap();
+        let proof: EIP1186ProofResponse = serde_json::from_reader(BufReader::new(file)).unwrap();
+        assert_eq!(first_seen[0][..], proof.account_proof[0][..]);
+    }
+
+    /// Tests that a contract byte changed after the parcel is made is detected.
+    #[test]
+    fn test_tampered_contract_detected() {
+        let file = File::open("../verify/data/test_proof_3.json").unwrap();
+        let mut proof: EIP1186ProofResponse =
+            serde_json::from_reader(BufReader::new(file)).unwrap();
+        let contract: ContractBytes = vec![0x60, 0x01, 0x60, 0x00, 0x55, 0x00];
+        proof.code_hash = H256::from(keccak256(&contract));
+        let block_proofs = BlockProofs {
+            proofs: HashMap::from([(proof.address, proof)]),
+        };
+        let state = state_from_parts_verified(block_proofs, vec![contract], accesses(&[])).unwrap();
+        let mut state = RequiredBlockState::from_ssz_bytes(state.to_ssz_bytes().unwrap()).unwrap();
+        state.verify().unwrap();
+
+        state.contracts[0][1] = 0x02;
+        assert!(matches!(
+            state.verify(),
+            Err(StateError::ContractCodeHashMismatch(_))
+        ));
+    }
+
+    /// Tests that each per-account parcel verifies its account against the state root of
+    /// the whole parcel, without the nodes of other accounts.
+    #[test]
+    fn test_split_by_account() {
+        let block_proofs = BlockProofs {
+            proofs: prior_block_proofs(),
+        };
+        let proofs = block_proofs.proofs.clone();
+        let state = state_from_parts(block_proofs, vec![], accesses(&[])).unwrap();
+        let state_root = state.state_root().unwrap();
+
+        let parcels = state.split_by_account().unwrap();
+        assert_eq!(parcels.len(), proofs.len());
+        let mut split_node_count = 0;
+        for (address, parcel) in parcels {
+            let parcel =
+                RequiredBlockState::from_ssz_bytes(parcel.to_ssz_bytes().unwrap()).unwrap();
+            split_node_count += parcel.trie_nodes.len();
+            assert!(parcel.trie_nodes.len() < state.trie_nodes.len());
+            assert_eq!(parcel.parcel_addresses(), vec![address]);
+            assert_eq!(parcel.state_root().unwrap(), state_root);
+            parcel.verify().unwrap();
+
+            let proof = &proofs[&address];
+            let nodes = parcel.account_proof_nodes(address).unwrap();
+            let expected: Vec<Vec<u8>> = proof
+                .account_proof
+                .iter()
+                .map(|node| node.to_vec())
+                .collect();
+            assert_eq!(nodes, expected);
+            if !proof.balance.is_zero() {
+                let single = SingleProofPath {
+                    proof: nodes.into_iter().map(Bytes::from).collect(),
+                    root: state_root.0,
+                    path: keccak256(address),
+                    claimed_value: parcel.account_leaf_rlp(address).unwrap(),
+                };
+                assert_eq!(single.verify_strict().unwrap(), Verified::Inclusion);
+            }
+            let b160 = address.0.into();
+            assert_eq!(
+                parcel.get_account_storage(&b160).unwrap(),
+                state.get_account_storage(&b160).unwrap()
+            );
+        }
+        // Nodes shared by accounts are repeated.
+        assert!(split_node_count > state.trie_nodes.len());
+    }
+
+    /// Tests that a contract no account refers to is reported, and is an error when
+    /// verifying.
+    #[test]
+    fn test_unreferenced_contract_reported() {
+        let file = File::open("../verify/data/test_proof_3.json").unwrap();
+        let mut proof: EIP1186ProofResponse =
+            serde_json::from_reader(BufReader::new(file)).unwrap();
+        let used: ContractBytes = vec![0x60, 0x01, 0x60, 0x00, 0x55, 0x00];
+        let unused: ContractBytes = vec![0x60, 0x00, 0x60, 0x00, 0xf3];
+        proof.code_hash = H256::from(keccak256(&used));
+        let block_proofs = BlockProofs {
+            proofs: HashMap::from([(proof.address, proof)]),
+        };
+        let contracts = vec![used.clone(), unused.clone()];
+        let unused_hash = H256::from(keccak256(&unused));
+        assert_eq!(
+            unreferenced_contracts(&block_proofs, &contracts),
+            vec![unused_hash]
+        );
+        assert!(unreferenced_contracts(&block_proofs, &[used]).is_empty());
+
+        assert!(matches!(
+            state_from_parts_verified(block_proofs, contracts, accesses(&[])),
+            Err(TransferrableError::UnreferencedContracts(hashes)) if hashes == vec![unused_hash]
+        ));
+    }
+
+    /// Tests that a proof with a node larger than permitted is rejected.
+    #[test]
+    fn test_oversized_node_rejected() {
+        let file = File::open("../verify/data/test_proof_3.json").unwrap();
+        let mut proof: EIP1186ProofResponse =
+            serde_json::from_reader(BufReader::new(file)).unwrap();
+        let oversized = vec![0xab; MAX_BYTES_PER_NODE + 1];
+        proof.account_proof.push(oversized.clone().into());
+        let block_proofs = BlockProofs {
+            proofs: HashMap::from([(proof.address, proof)]),
+        };
+        let expected_hash = H256::from(keccak256(&oversized));
+        assert!(matches!(
+            state_from_parts(block_proofs, vec![], accesses(&[])),
+            Err(TransferrableError::NodeTooLarge { hash, size })
+                if hash == expected_hash && size == MAX_BYTES_PER_NODE + 1
+        ));
+    }
+
+    /// Tests that a parcel with more accounts than the mainnet limit can be built and
+    /// decoded with enlarged limits.
+    #[test]
+    fn test_parcel_with_enlarged_limits() {
+        type LargeParcel = RequiredBlockStateWithLimits<
+            { 2 * MAX_ACCOUNT_PROOFS_PER_BLOCK },
+            MAX_CONTRACTS_PER_BLOCK,
+            MAX_NODES_PER_BLOCK,
+        >;
+        let count = MAX_ACCOUNT_PROOFS_PER_BLOCK + 1;
+        let block_proofs = || BlockProofs {
+            proofs: (0..count as u64)
+                .map(|index| {
+                    let address = H160::from_low_u64_be(index + 1);
+                    let proof = EIP1186ProofResponse {
+                        address,
+                        ..Default::default()
+                    };
+                    (address, proof)
+                })
+                .collect(),
+        };
+        assert!(matches!(
+            state_from_parts(block_proofs(), vec![], accesses(&[])),
+            Err(TransferrableError::LimitExceeded { count: c, limit, .. })
+                if c == count && limit == MAX_ACCOUNT_PROOFS_PER_BLOCK
+        ));
+
+        let state: LargeParcel = state_from_parts_with_limits(
+            block_proofs(),
+            vec![],
+            accesses(&[]),
+            NodeOrdering::default(),
+        )
+        .unwrap();
+        assert_eq!(state.parcel_addresses().len(), count);
+        let decoded = LargeParcel::from_ssz_bytes(state.to_ssz_bytes().unwrap()).unwrap();
+        assert_eq!(decoded.parcel_addresses().len(), count);
+    }
+
+    /// Tests that a parcel built one proof at a time matches one built from all proofs.
+    #[test]
+    fn test_builder_matches_batch() {
+        let mut proofs = vec![];
+        for index in 1..=3 {
+            let file = File::open(format!("../verify/data/test_proof_{index}.json")).unwrap();
+            let proof: EIP1186ProofResponse =
+                serde_json::from_reader(BufReader::new(file)).unwrap();
+            proofs.push(proof);
+        }
+        let contracts: Vec<ContractBytes> = vec![vec![0x60, 0x00], vec![0x60, 0x01, 0x00]];
+        let block_proofs = BlockProofs {
+            proofs: proofs
+                .iter()
+                .map(|proof| (proof.address, proof.clone()))
+                .collect(),
+        };
+        let batch = state_from_parts(
+            block_proofs,
+            contracts.clone(),
+            accesses(&[(17190870, 1), (17190871, 2)]),
+        )
+        .unwrap();
+
+        let mut builder = RequiredBlockStateBuilder::new();
+        for proof in proofs.into_iter().rev() {
+            builder.add_proof(proof).unwrap();
+        }
+        for contract in contracts.into_iter().rev() {
+            builder.add_contract(contract);
+        }
+        builder.add_blockhashes(accesses(&[(17190871, 2)]));
+        builder.add_blockhashes(accesses(&[(17190870, 1)]));
+        let incremental = builder.build().unwrap();
+        assert_eq!(
+            incremental.to_ssz_bytes().unwrap(),
+            batch.to_ssz_bytes().unwrap()
+        );
+    }
+}
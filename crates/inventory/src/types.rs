@@ -1,11 +1,23 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{collections::HashMap, fmt::Display, str::FromStr};
 
 // use archors_verify::eip1186::{verify_proof, VerifyProofError};
-use ethers::types::{EIP1186ProofResponse, H160, H256, U64};
+use ethers::{
+    types::{EIP1186ProofResponse, H160, H256, U256, U64},
+    utils::keccak256,
+};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
 use crate::rpc::BlockPrestateTransactions;
 
+#[derive(Debug, Error, PartialEq)]
+pub enum BlockProofsError {
+    #[error("Storage proof for key {key} does not start at storage root of account {address}")]
+    StorageRootMismatch { address: H160, key: H256 },
+    #[error("Prestate for account {0} has an invalid address or balance")]
+    InvalidPrestate(String),
+}
+
 /// Helper for caching
 #[derive(Deserialize, Serialize)]
 pub struct BlockProofs {
@@ -13,6 +25,118 @@ pub struct BlockProofs {
     pub proofs: HashMap<H160, EIP1186ProofResponse>,
 }
 
+impl BlockProofs {
+    /// Returns the proofs keyed by their path in the account trie (keccak(address)).
+    pub fn by_path(&self) -> HashMap<H256, &EIP1186ProofResponse> {
+        self.proofs
+            .iter()
+            .map(|(address, proof)| (H256::from(keccak256(address)), proof))
+            .collect()
+    }
+    /// Checks that every storage proof begins at the storage root of its account.
+    ///
+    /// The first node of a storage proof is the root node of the storage trie, so
+    /// its hash must equal the `storage_hash` in the account proof.
+    pub fn validate_internal_consistency(&self) -> Result<(), BlockProofsError> {
+        for (address, proof) in &self.proofs {
+            for storage_proof in &proof.storage_proof {
+                let Some(root_node) = storage_proof.proof.first() else {
+                    continue;
+                };
+                if H256::from(keccak256(root_node)) != proof.storage_hash {
+                    return Err(BlockProofsError::StorageRootMismatch {
+                        address: *address,
+                        key: storage_proof.key,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Identifies the block that a transferrable parcel is for. Stored alongside the parcel,
+/// so that a parcel can be matched to the block to be traced.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct ParcelMetadata {
+    /// The block that the parcel holds the state to trace.
+    pub target_block: u64,
+    /// State root of the block prior to the target block, that the parcel proofs are against.
+    pub parent_state_root: H256,
+}
+
+/// Sizes and counts for a transferrable parcel (`RequiredBlockState`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ParcelStats {
+    /// Size of the .ssz encoding in bytes.
+    pub ssz_size: usize,
+    /// Size of the .ssz_snappy encoding in bytes.
+    pub compressed_size: usize,
+    /// Number of trie nodes in the node bag.
+    pub node_count: usize,
+    /// Number of contracts.
+    pub contract_count: usize,
+}
+
+impl Display for ParcelStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Parcel with {} nodes and {} contracts: {} bytes (ssz), {} bytes (ssz_snappy)",
+            self.node_count, self.contract_count, self.ssz_size, self.compressed_size
+        )
+    }
+}
+
+/// Sizes of a cached artifact before and after snappy compression.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompressionRatio {
+    pub uncompressed: u64,
+    pub compressed: u64,
+}
+
+impl CompressionRatio {
+    /// Change in size as a percentage of the uncompressed size (negative is smaller).
+    pub fn change_percent(&self) -> f64 {
+        if self.uncompressed == 0 {
+            return 0.0;
+        }
+        (self.compressed as f64 / self.uncompressed as f64 - 1.0) * 100.0
+    }
+}
+
+/// Compression of the cached artifacts of a block. Absent if either the uncompressed or
+/// compressed artifact is not cached.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BlockCompressionStat {
+    pub block: u64,
+    /// Prior block state proofs (.json and .snappy).
+    pub proofs: Option<CompressionRatio>,
+    /// Deduplicated block accessed state (.json and .snappy).
+    pub state: Option<CompressionRatio>,
+}
+
+impl Display for BlockCompressionStat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let describe = |ratio: Option<CompressionRatio>| match ratio {
+            Some(r) => format!(
+                "{} to {} bytes ({:.0}%)",
+                r.uncompressed,
+                r.compressed,
+                r.change_percent()
+            ),
+            None => String::from("not cached"),
+        };
+        write!(
+            f,
+            "Block {}: proofs {}, state {}",
+            self.block,
+            describe(self.proofs),
+            describe(self.state)
+        )
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct BasicBlockState {
     pub state_root: H256,
@@ -174,11 +298,74 @@ impl BlockStateAccesses {
         }
         accounts
     }
+    /// Adds accounts whose state was not recorded by the prestate tracer (e.g., those only
+    /// inspected by EXTCODEHASH/EXTCODESIZE), so that they are proved.
+    ///
+    /// The balance of an added account is unknown and is left empty. Accounts that are
+    /// already present are unchanged.
+    pub fn include_accounts(&mut self, accounts: &[H160]) -> &mut Self {
+        for account in accounts {
+            self.access_data
+                .entry(format!("{account:?}"))
+                .or_insert_with(|| AccountState {
+                    balance: String::new(),
+                    code: None,
+                    nonce: None,
+                    storage: None,
+                });
+        }
+        self
+    }
     pub fn new() -> Self {
         BlockStateAccesses {
             access_data: HashMap::new(),
         }
     }
+    /// Compares the balance and nonce of each account with the proof for that account.
+    ///
+    /// The two come from independent calls to a node, so a difference indicates an
+    /// inconsistent node. Accounts without a proof and accounts whose state was not recorded
+    /// (see `include_accounts`) are not compared. A nonce that the prestate tracer omitted is
+    /// zero. Discrepancies are sorted by address.
+    pub fn discrepancies_with_proofs(
+        &self,
+        proofs: &BlockProofs,
+    ) -> Result<Vec<StateDiscrepancy>, BlockProofsError> {
+        let mut discrepancies = vec![];
+        for (address, state) in &self.access_data {
+            let invalid = || BlockProofsError::InvalidPrestate(address.to_string());
+            let address = H160::from_str(address).map_err(|_| invalid())?;
+            let Some(proof) = proofs.proofs.get(&address) else {
+                continue;
+            };
+            // Accounts added by `include_accounts` have no recorded state.
+            if state.balance.is_empty() {
+                continue;
+            }
+            let balance = U256::from_str_radix(state.balance.trim_start_matches("0x"), 16)
+                .map_err(|_| invalid())?;
+            if balance != proof.balance {
+                discrepancies.push(StateDiscrepancy {
+                    address,
+                    field: AccountField::Balance,
+                    prestate: balance,
+                    proof: proof.balance,
+                });
+            }
+            // The prestate tracer omits a zero nonce.
+            let nonce = state.nonce.unwrap_or(0);
+            if U64::from(nonce) != proof.nonce {
+                discrepancies.push(StateDiscrepancy {
+                    address,
+                    field: AccountField::Nonce,
+                    prestate: nonce.into(),
+                    proof: proof.nonce.as_u64().into(),
+                });
+            }
+        }
+        discrepancies.sort_by_key(|discrepancy| discrepancy.address);
+        Ok(discrepancies)
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -187,6 +374,23 @@ pub struct AccountToProve {
     pub slots: Vec<String>,
 }
 
+/// An account value that can be reported by both the prestate tracer and eth_getProof.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccountField {
+    Balance,
+    Nonce,
+}
+
+/// An account value that the prestate tracer and eth_getProof disagree on for the same
+/// (prior) block state.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateDiscrepancy {
+    pub address: H160,
+    pub field: AccountField,
+    pub prestate: U256,
+    pub proof: U256,
+}
+
 impl Default for BlockStateAccesses {
     fn default() -> Self {
         Self::new()
@@ -226,6 +430,10 @@ fn include_unseen_states(existing: &AccountState, accessed_state: &AccountState)
 
 #[cfg(test)]
 mod tests {
+    use std::str::FromStr;
+
+    use ethers::types::{Bytes, StorageProof};
+
     use super::*;
 
     fn dummy_state_1() -> AccountState {
@@ -302,4 +510,117 @@ mod tests {
         assert_eq!(result.nonce, Some(2));
         assert_eq!(result.storage.unwrap().len(), 1);
     }
+
+    /// Tests that a balance that differs between the prestate and the proof is reported,
+    /// and that an omitted nonce is compared as zero.
+    #[test]
+    fn test_prestate_proof_discrepancy() {
+        let address = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let other = H160::from_str("0x0a6dd5d5a00d6cb0678a4af507ba79a517d5eb64").unwrap();
+        let mut accesses = BlockStateAccesses::new();
+        for (account, nonce) in [(address, Some(2)), (other, None)] {
+            let state = AccountState {
+                balance: "0x10".to_string(),
+                code: None,
+                nonce,
+                storage: None,
+            };
+            accesses.access_data.insert(format!("{account:?}"), state);
+        }
+        let mut block_proofs = BlockProofs {
+            proofs: HashMap::new(),
+        };
+        for (account, balance, nonce) in [(address, 0x11u64, 2u64), (other, 0x10u64, 0u64)] {
+            let proof = EIP1186ProofResponse {
+                address: account,
+                balance: balance.into(),
+                nonce: nonce.into(),
+                ..Default::default()
+            };
+            block_proofs.proofs.insert(account, proof);
+        }
+        assert_eq!(
+            accesses.discrepancies_with_proofs(&block_proofs).unwrap(),
+            vec![StateDiscrepancy {
+                address,
+                field: AccountField::Balance,
+                prestate: U256::from(0x10),
+                proof: U256::from(0x11),
+            }]
+        );
+
+        block_proofs.proofs.get_mut(&address).unwrap().balance = U256::from(0x10);
+        assert!(accesses
+            .discrepancies_with_proofs(&block_proofs)
+            .unwrap()
+            .is_empty());
+
+        block_proofs.proofs.get_mut(&other).unwrap().nonce = 1u64.into();
+        assert_eq!(
+            accesses.discrepancies_with_proofs(&block_proofs).unwrap(),
+            vec![StateDiscrepancy {
+                address: other,
+                field: AccountField::Nonce,
+                prestate: U256::zero(),
+                proof: U256::one(),
+            }]
+        );
+    }
+
+    /// Tests that proofs found by path are the same as those found by address.
+    #[test]
+    fn test_block_proofs_by_path() {
+        let mut block_proofs = BlockProofs {
+            proofs: HashMap::new(),
+        };
+        for (address, nonce) in [
+            ("0x0300000000000000000000000000000000000000", 3u64),
+            ("0x0a6dd5d5a00d6cb0678a4af507ba79a517d5eb64", 7u64),
+        ] {
+            let address = H160::from_str(address).unwrap();
+            let proof = EIP1186ProofResponse {
+                address,
+                nonce: nonce.into(),
+                ..Default::default()
+            };
+            block_proofs.proofs.insert(address, proof);
+        }
+        let by_path = block_proofs.by_path();
+        assert_eq!(by_path.len(), block_proofs.proofs.len());
+        for (address, proof) in &block_proofs.proofs {
+            let path = H256::from(keccak256(address));
+            assert_eq!(by_path.get(&path).unwrap(), &proof);
+        }
+    }
+
+    /// Tests that a storage proof that does not start at the account storage root is detected.
+    #[test]
+    fn test_block_proofs_storage_root_mismatch() {
+        let address = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let root_node = Bytes::from(vec![0xc2, 0x80, 0x80]);
+        let key = H256::from_low_u64_be(1);
+        let storage_proof = StorageProof {
+            key,
+            proof: vec![root_node.clone()],
+            ..Default::default()
+        };
+        let mut proof = EIP1186ProofResponse {
+            address,
+            storage_hash: H256::from(keccak256(&root_node)),
+            storage_proof: vec![storage_proof],
+            ..Default::default()
+        };
+        let mut block_proofs = BlockProofs {
+            proofs: HashMap::from([(address, proof.clone())]),
+        };
+        assert!(block_proofs.validate_internal_consistency().is_ok());
+
+        // Proof from some other storage trie.
+        proof.storage_proof[0].proof = vec![Bytes::from(vec![0xc2, 0x80, 0x01])];
+        block_proofs.proofs.insert(address, proof);
+        assert_eq!(
+            block_proofs.validate_internal_consistency(),
+            Err(BlockProofsError::StorageRootMismatch { address, key })
+        );
+    }
 }
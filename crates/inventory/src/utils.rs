@@ -25,6 +25,8 @@ pub enum UtilsError {
     TryFromSlice(#[from] TryFromSliceError),
     #[error("Hash must be 32 bytes")]
     InvalidHashLength,
+    #[error("Snappy error {0}")]
+    SnappyError(#[from] snap::Error),
 }
 
 /// Converts bytes to 0x-prefixed hex string.
@@ -38,32 +40,53 @@ pub fn hex_decode<T: AsRef<str>>(string: T) -> Result<Vec<u8>, UtilsError> {
     Ok(hex::decode(s)?)
 }
 
-/// Performs snappy compression on bytes.
+/// Variant of snappy compression.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SnappyFormat {
+    /// Snappy framing format (stream identifier, checksummed chunks). This is what
+    /// .ssz_snappy means in consensus and portal network tooling.
+    #[default]
+    Frame,
+    /// Raw snappy block format (no frames, no checksums).
+    Block,
+}
+
+/// Performs snappy compression on bytes, using the framing format.
 ///
 /// Takes ssz bytes, returns ssz_snappy bytes.
 pub fn compress(ssz_bytes: Vec<u8>) -> Result<Vec<u8>, UtilsError> {
-    /*
-    Raw encoder (no frames):
-    let mut snap_encoder = snap::raw::Encoder::new();
-    let compressed_vec = snap_encoder.compress_vec(ssz_bytes.as_slice())?;
-    */
-    let mut buffer = vec![];
-    snap::read::FrameEncoder::new(ssz_bytes.as_slice()).read_to_end(&mut buffer)?;
-    Ok(buffer)
+    compress_with(ssz_bytes, SnappyFormat::Frame)
 }
 
-/// Performs snappy decompression on bytes.
+/// Performs snappy decompression on bytes, using the framing format.
 ///
 /// Takes ssz_snappy bytes, returns ssz bytes.
 pub fn decompress(ssz_snappy_bytes: Vec<u8>) -> Result<Vec<u8>, UtilsError> {
-    /*
-    Raw decoder (no frames):
-    let mut snap_decoder = snap::raw::Decoder::new();
-    let decompressed_vec = snap_decoder.decompress_vec(ssz_snappy_bytes.as_slice())?;
-    */
-    let mut buffer = vec![];
-    snap::read::FrameDecoder::new(ssz_snappy_bytes.as_slice()).read_to_end(&mut buffer)?;
-    Ok(buffer)
+    decompress_with(ssz_snappy_bytes, SnappyFormat::Frame)
+}
+
+/// Performs snappy compression on bytes, using the given format.
+pub fn compress_with(bytes: Vec<u8>, format: SnappyFormat) -> Result<Vec<u8>, UtilsError> {
+    match format {
+        SnappyFormat::Frame => {
+            let mut buffer = vec![];
+            snap::read::FrameEncoder::new(bytes.as_slice()).read_to_end(&mut buffer)?;
+            Ok(buffer)
+        }
+        SnappyFormat::Block => Ok(snap::raw::Encoder::new().compress_vec(&bytes)?),
+    }
+}
+
+/// Performs snappy decompression on bytes, using the given format.
+pub fn decompress_with(bytes: Vec<u8>, format: SnappyFormat) -> Result<Vec<u8>, UtilsError> {
+    match format {
+        SnappyFormat::Frame => {
+            let mut buffer = vec![];
+            snap::read::FrameDecoder::new(bytes.as_slice()).read_to_end(&mut buffer)?;
+            Ok(buffer)
+        }
+        SnappyFormat::Block => Ok(snap::raw::Decoder::new().decompress_vec(&bytes)?),
+    }
 }
 
 /// Convert ethers H256 to SSZ equivalent.
@@ -82,23 +105,16 @@ pub fn h160_to_ssz_h160(input: H160) -> Result<SszH160, UtilsError> {
 pub fn u256_to_ssz_u256(input: U256) -> SszU256 {
     let mut bytes = [0u8; 32];
     input.to_big_endian(&mut bytes);
-
-    let mut output = SszU256::default();
-    for byte in bytes.into_iter() {
-        output.push(byte)
-    }
-    output
+    SszU256::try_from(bytes.to_vec()).expect("32 bytes always fit in SszU256")
 }
 
 /// Convert ethers U64 to SSZ equivalent.
 ///
 /// Output is big endian.
 pub fn u64_to_ssz_u64(input: U64) -> SszU64 {
-    let mut output = SszU64::default();
-    for byte in input.0[0].to_be_bytes() {
-        output.push(byte)
-    }
-    output
+    // Single limb, as_u64() cannot overflow.
+    let bytes = input.as_u64().to_be_bytes();
+    SszU64::try_from(bytes.to_vec()).expect("8 bytes always fit in SszU64")
 }
 
 /// Converts usize to u16 and prevents overflow.
@@ -115,3 +131,72 @@ pub fn string_to_h256<T: AsRef<str>>(input: T) -> Result<H256, UtilsError> {
     }
     Ok(H256::from_slice(&bytes))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_u256_to_ssz_u256_max() {
+        let ssz = u256_to_ssz_u256(U256::MAX);
+        assert_eq!(ssz.len(), 32);
+        assert!(ssz.iter().all(|byte| *byte == u8::MAX));
+    }
+
+    #[test]
+    fn test_u64_to_ssz_u64_boundary() {
+        let ssz = u64_to_ssz_u64(U64::from(u64::MAX));
+        assert_eq!(ssz.to_vec(), u64::MAX.to_be_bytes().to_vec());
+        let ssz = u64_to_ssz_u64(U64::zero());
+        assert_eq!(ssz.to_vec(), vec![0u8; 8]);
+    }
+
+    /// Framed snappy stream for "hello hello hello": a stream identifier, then one compressed
+    /// chunk (masked CRC-32C, a literal and a back-reference).
+    ///
+    /// Reference encoders (C++ snappy, python-snappy, Go snappy) do not look for matches in
+    /// the last 15 bytes of input, so they store input this short as an uncompressed chunk.
+    /// This stream is instead assembled from the framing format description, and was checked
+    /// with a decoder and CRC-32C written from that description (not `snap`).
+    const FRAMED_HELLO: [u8; 29] = [
+        0xff, 0x06, 0x00, 0x00, 0x73, 0x4e, 0x61, 0x50, 0x70, 0x59, 0x00, 0x0f, 0x00, 0x00, 0xab,
+        0x04, 0xb5, 0x1d, 0x11, 0x14, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x20, 0x2a, 0x06, 0x00,
+    ];
+
+    #[test]
+    fn test_decompress_external_framed_snappy() {
+        let expected = b"hello hello hello".to_vec();
+        assert_eq!(decompress(FRAMED_HELLO.to_vec()).unwrap(), expected);
+        // The same chunk body without framing is a valid block.
+        let block = FRAMED_HELLO[18..].to_vec();
+        assert_eq!(
+            decompress_with(block.clone(), SnappyFormat::Block).unwrap(),
+            expected
+        );
+        // Formats are not interchangeable.
+        assert!(decompress(block).is_err());
+        assert!(decompress_with(FRAMED_HELLO.to_vec(), SnappyFormat::Block).is_err());
+    }
+
+    #[test]
+    fn test_snappy_formats_roundtrip() {
+        let data = vec![7u8; 1000];
+        for format in [SnappyFormat::Frame, SnappyFormat::Block] {
+            let compressed = compress_with(data.clone(), format).unwrap();
+            assert_eq!(decompress_with(compressed, format).unwrap(), data);
+        }
+        assert_eq!(
+            compress(data.clone()).unwrap(),
+            compress_with(data, SnappyFormat::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_usize_to_u16_overflow() {
+        assert_eq!(usize_to_u16(u16::MAX as usize).unwrap(), u16::MAX);
+        assert!(matches!(
+            usize_to_u16(u16::MAX as usize + 1),
+            Err(UtilsError::TryFromIntError(_))
+        ));
+    }
+}
@@ -44,6 +44,8 @@ pub enum MultiProofError {
     UtilsError(#[from] UtilsError),
     #[error("Unable to find account {0} in data structure.")]
     NoAccount(String),
+    #[error("Removing account {0} restructures the account trie, which requires an oracle")]
+    AccountRemovalRequiresOracle(String),
 }
 
 /// Multiple EIP-1186 proofs in a representation that can be updated.
@@ -175,8 +177,6 @@ impl EIP1186MultiProof {
         account: AccountData,
     ) -> Result<H256, MultiProofError> {
         let path = keccak256(address);
-        // Even if SELFDESCTRUCT is used, Intent::Remove is not used because the account is kept
-        // in the trie (with null storage/code hashes).
         let intent = Intent::Modify(account.rlp_bytes().into());
         self.account_proofs
             .traverse(path.into(), &intent)
@@ -186,6 +186,29 @@ impl EIP1186MultiProof {
             })?;
         Ok(self.current_state_root())
     }
+    /// Remove an account from the account multiproof. Returns the updated state root.
+    ///
+    /// Used for accounts removed by SELFDESTRUCT. Whether SELFDESTRUCT removes an account
+    /// depends on the spec (EIP-6780), which is handled when the changes are collected.
+    fn remove_account_proof(&mut self, address: &B160) -> Result<H256, MultiProofError> {
+        let path = keccak256(address);
+        self.account_proofs
+            .traverse(path.into(), &Intent::Remove)
+            .map_err(|e| MultiProofError::AccountProofError {
+                source: e,
+                address: hex_encode(address),
+            })?;
+        if self
+            .account_proofs
+            .traversal_index_for_oracle_task
+            .is_some()
+        {
+            return Err(MultiProofError::AccountRemovalRequiresOracle(hex_encode(
+                address,
+            )));
+        }
+        Ok(self.current_state_root())
+    }
     /// Accepts all changes for a single account returned from REVM and returns the
     /// updated state root.
     ///
@@ -210,6 +233,9 @@ impl EIP1186MultiProof {
             .get(&address_eh)
             .ok_or_else(|| MultiProofError::NoAccount(hex_encode(address)))?
             .clone();
+        if account_updates.is_destroyed {
+            return self.remove_account_proof(address);
+        }
         let mut storage_hash = existing_account.storage_hash;
         let mut tasks: Vec<OracleTask> = vec![];
         // Sort keys before applying oracle update. This is for consistency with testing and
@@ -327,6 +353,20 @@ impl StateForEvm for EIP1186MultiProof {
         Ok(info)
     }
 
+    fn get_contracts(&self) -> Result<rHashMap<B256, Bytecode>, EvmStateError> {
+        let mut contracts = rHashMap::new();
+        for (code_hash, code) in self.code.iter() {
+            let hash = B256::from(code_hash.0);
+            let bytecode = Bytecode {
+                bytecode: Bytes::copy_from_slice(code),
+                hash,
+                state: BytecodeState::Raw,
+            };
+            contracts.insert(hash, bytecode);
+        }
+        Ok(contracts)
+    }
+
     fn addresses(&self) -> Vec<B160> {
         self.accounts.keys().map(|key| B160::from(*key)).collect()
     }
@@ -467,6 +507,36 @@ mod test {
         ]
       }"#;
 
+    #[test]
+    fn test_multiproof_from_eip1186() {
+        let response: EIP1186ProofResponse = serde_json::from_str(PROOF_1).unwrap();
+        let state_root =
+            H256::from_str("0x61effbbcca94f0d3e02e5bd22e986ad57142acabf0cb3d129a6ad8d0f8752e94")
+                .unwrap();
+        let mut account_proof = MultiProof::from_eip1186_account(&response).unwrap();
+        assert_eq!(account_proof.root, state_root);
+        let account_rlp = hex_decode("0xf8440101a08afc95b7d18a226944b9c2070b6bda1c3a36afcc3730429d47579c94b9fe5850a0ce92c756baff35fa740c3557c1a971fd24d2d35b7c8e067880d50cd86bb0bc99").unwrap();
+        account_proof
+            .traverse(
+                keccak256(response.address).into(),
+                &Intent::VerifyInclusion(account_rlp),
+            )
+            .unwrap();
+
+        let storage_proof = &response.storage_proof[0];
+        let mut storage_multiproof =
+            MultiProof::from_eip1186_storage(response.storage_hash, storage_proof).unwrap();
+        assert_eq!(storage_multiproof.root, response.storage_hash);
+        storage_multiproof
+            .traverse(
+                keccak256(storage_proof.key).into(),
+                &Intent::VerifyExclusion,
+            )
+            .unwrap();
+        // Storage proof nodes do not hash to a different storage root.
+        assert!(MultiProof::from_eip1186_storage(state_root, storage_proof).is_err());
+    }
+
     #[test]
     fn test_root_unchanged_after_no_update_to_account_1() {
         let mut proof = load_proof_str(PROOF_1);
@@ -520,6 +590,29 @@ mod test {
         assert_eq!(post_root, state_root);
     }
 
+    #[test]
+    fn test_destroyed_account_removed_from_trie() {
+        let mut proof = load_proof_str(PROOF_1);
+        let state_root = proof.current_state_root();
+        let address = B160::from_str("aa00000000000000000000000000000000000000").unwrap();
+        let account_updates = Account {
+            info: AccountInfo::default(),
+            storage: rHashMap::default(),
+            storage_cleared: false,
+            is_destroyed: true,
+            is_touched: true,
+            is_not_existing: false,
+        };
+        let post_root = proof
+            .apply_account_delta(&address, account_updates)
+            .unwrap();
+        assert_ne!(post_root, state_root);
+        proof
+            .account_proofs
+            .traverse(keccak256(address).into(), &Intent::VerifyExclusion)
+            .unwrap();
+    }
+
     /**
     Check that the trie update mechanism works for a basic nonce increment.
 
@@ -4,6 +4,7 @@ pub use eip1186::EIP1186MultiProof;
 pub mod proof;
 pub mod oracle;
 pub mod node;
+pub mod snapshot;
 pub mod utils;
 
 // Re-export trait for executing using the multiproof.
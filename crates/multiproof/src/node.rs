@@ -1,5 +1,6 @@
 use std::fmt::Display;
 
+use archors_types::error::ErrorKind;
 use archors_verify::path::{NibblePath, PathError, PrefixEncoding};
 use ethers::types::H256;
 use thiserror::Error;
@@ -18,6 +19,18 @@ pub enum NodeError {
     NodeHasInvalidItemCount(usize),
 }
 
+impl NodeError {
+    /// The broad kind of the error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            NodeError::PathError(e) => e.kind(),
+            NodeError::NodeEmpty
+            | NodeError::NoEncoding
+            | NodeError::NodeHasInvalidItemCount(_) => ErrorKind::MalformedProof,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum NodeKind {
     Branch,
@@ -43,6 +56,10 @@ impl NodeKind {
             num => Err(NodeError::NodeHasInvalidItemCount(num)),
         }
     }
+    /// Whether the node ends a traversal. Only a leaf has no child node.
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, NodeKind::Leaf)
+    }
 }
 
 /// A cache of the nodes visited. If the trie is modified, then
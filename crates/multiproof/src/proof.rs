@@ -1,9 +1,14 @@
 //! For verifying a Merkle Patricia Multi Proof for arbitrary proof values.
 //! E.g., Account, storage ...
 
-use std::collections::HashMap;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+};
 
-use archors_types::{oracle::TrieNodeOracle, proof::DisplayProof};
+use archors_types::{
+    constants::EMPTY_TRIE_ROOT, error::ErrorKind, oracle::TrieNodeOracle, proof::DisplayProof,
+};
 use archors_verify::{
     eip1186::Account,
     path::{
@@ -12,7 +17,7 @@ use archors_verify::{
     },
 };
 use ethers::{
-    types::{Bytes, H256, U256},
+    types::{Bytes, EIP1186ProofResponse, StorageProof, H256, U256},
     utils::keccak256,
 };
 use log::debug;
@@ -65,6 +70,10 @@ pub enum ProofError {
     InclusionRequired,
     #[error("An exclusion proof was required, but found an inclusion proof")]
     ExclusionRequired,
+    #[error("An inclusion proof of the zero value was required, but found an exclusion proof")]
+    ZeroValueExcluded,
+    #[error("Exclusion proof found, but the claimed value {0} is not the empty encoding")]
+    AbsentValueNotEmpty(String),
     #[error("The leaf path was expected to be complete")]
     LeafPathIncomplete,
     #[error("An extension node is was present as the final node in the path")]
@@ -81,6 +90,60 @@ pub enum ProofError {
     NodeError(#[from] NodeError),
     #[error("NoNodeInOracle: The oracle was expected to have a node for task {task} ")]
     NoNodeInOracle { task: String },
+    #[error("Path is inconsistent with the root after a change: {0}")]
+    InconsistentAfterChange(String),
+    #[error("Extension or leaf node has fewer than two items")]
+    NodeHasTooFewItems,
+    #[error("Key {0} is not in the trie and cannot be updated in a batch")]
+    BatchRequiresInclusion(String),
+    #[error("Traversal reached node {0} more than once")]
+    TraversalLoop(String),
+    #[error(
+        "Key {0} is not in the trie and its value cannot be replaced, use a full modification"
+    )]
+    ValueReplaceRequiresInclusion(String),
+    #[error("Change was planned against root {planned}, but the proof now has root {current}")]
+    StaleChangePlan { planned: String, current: String },
+}
+
+impl ProofError {
+    /// The broad kind of the error, with wrapped errors reporting their own kind.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ProofError::PathError(e) => e.kind(),
+            ProofError::ModifyError(e) => e.kind(),
+            ProofError::NodeError(e) => e.kind(),
+            ProofError::EmptyOracleResponse
+            | ProofError::NoOracleNodeForHash(_)
+            | ProofError::NoProofNodeForHash(_)
+            | ProofError::NoViewNodeForHash(_)
+            | ProofError::NoNodeInOracle { .. } => ErrorKind::MissingNode,
+            ProofError::NoTraversalHistory
+            | ProofError::PostOracleTraversalFailed(_)
+            | ProofError::InconsistentAfterChange(_) => ErrorKind::Internal,
+            ProofError::BranchItemMissing
+            | ProofError::BadBranchInOracleTask
+            | ProofError::BadExtensionInOracleTask
+            | ProofError::ExtensionHasNoItems
+            | ProofError::ExtensionHasNoNextNode
+            | ProofError::ProofRootMismatch { .. }
+            | ProofError::LeafHasNoFinalPath
+            | ProofError::InclusionRequired
+            | ProofError::ExclusionRequired
+            | ProofError::ZeroValueExcluded
+            | ProofError::AbsentValueNotEmpty(_)
+            | ProofError::LeafPathIncomplete
+            | ProofError::FinalExtension
+            | ProofError::LeafHasNoData
+            | ProofError::LeafInOracleTask
+            | ProofError::IncorrectLeafData
+            | ProofError::NodeHasTooFewItems
+            | ProofError::TraversalLoop(_) => ErrorKind::MalformedProof,
+            ProofError::StaleChangePlan { .. } => ErrorKind::InvalidInput,
+            ProofError::BatchRequiresInclusion(_)
+            | ProofError::ValueReplaceRequiresInclusion(_) => ErrorKind::Unsupported,
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -115,9 +178,35 @@ pub enum ModifyError {
     TooManyBranchItems,
     #[error("Branch item (0-15) must be 32 bytes")]
     BranchItemInvalidLength,
+    #[error("Removing the only child of a branch with a value (item 16) is not supported")]
+    BranchValueWithoutChildren,
     #[error("NodeError {0}")]
     NodeError(#[from] NodeError),
 }
+
+impl ModifyError {
+    /// The broad kind of the error, with wrapped errors reporting their own kind.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ModifyError::PathError(e) => e.kind(),
+            ModifyError::NodeError(e) => e.kind(),
+            ModifyError::BranchValueWithoutChildren => ErrorKind::Unsupported,
+            ModifyError::AbsentOnlyChild | ModifyError::NoNodeForHash => ErrorKind::MissingNode,
+            ModifyError::NoVisitedNodes | ModifyError::NoVisitedNode => ErrorKind::Internal,
+            ModifyError::BranchTooShort
+            | ModifyError::LeafHasNoFinalPath
+            | ModifyError::ExtensionHasNoPath
+            | ModifyError::ExtensionHasNoItem
+            | ModifyError::NodeHasNoItems
+            | ModifyError::NoItemInBranch
+            | ModifyError::PathEndedAtBranch
+            | ModifyError::NodePathTooShort
+            | ModifyError::TooManyBranchItems
+            | ModifyError::BranchItemInvalidLength => ErrorKind::MalformedProof,
+        }
+    }
+}
+
 /// A representation of a Merkle PATRICIA Trie Multi Proof.
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct MultiProof {
@@ -135,6 +224,30 @@ pub enum ProofOutcome {
     IndexForOracle(usize),
 }
 
+/// The result of removing a key from a multiproof.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RemoveOutcome {
+    /// The key was not in the trie, the proof is unchanged.
+    Absent,
+    /// The leaf was removed and the root updated without restructuring.
+    Removed,
+    /// The leaf was removed, leaving its parent branch with a single child. The branch
+    /// must be collapsed, which requires an oracle. The root is not yet updated.
+    BranchCollapse {
+        /// Traversal index of the node that the oracle task will update.
+        traversal_index: usize,
+    },
+}
+
+/// The result of updating a batch of keys in a multiproof.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BatchOutcome {
+    /// The root after all the updates.
+    pub root: H256,
+    /// Number of nodes hashed (new leaves and their updated ancestors).
+    pub nodes_hashed: usize,
+}
+
 impl MultiProof {
     /// Create new multiproof with a known root.
     pub fn init(root: H256) -> Self {
@@ -144,6 +257,37 @@ impl MultiProof {
             traversal_index_for_oracle_task: None,
         }
     }
+    /// Create a multiproof from trie nodes, keyed by their hash.
+    ///
+    /// The nodes are not checked to be related to the root or to each other.
+    pub fn from_nodes<I: IntoIterator<Item = Vec<u8>>>(root: H256, nodes: I) -> Self {
+        let data = nodes
+            .into_iter()
+            .map(|node| (H256::from(keccak256(&node)), node))
+            .collect();
+        MultiProof {
+            data,
+            root,
+            traversal_index_for_oracle_task: None,
+        }
+    }
+    /// Create a multiproof for the account trie from a single eth_getProof response.
+    ///
+    /// The root (state root) is obtained from the first node of the account proof.
+    pub fn from_eip1186_account(proof: &EIP1186ProofResponse) -> Result<Self, ProofError> {
+        let mut multiproof = MultiProof::default();
+        multiproof.insert_proof(proof.account_proof.to_owned())?;
+        Ok(multiproof)
+    }
+    /// Create a multiproof for the storage trie of an account from a single storage proof.
+    pub fn from_eip1186_storage(
+        storage_hash: H256,
+        proof: &StorageProof,
+    ) -> Result<Self, ProofError> {
+        let mut multiproof = MultiProof::init(storage_hash);
+        multiproof.insert_proof(proof.proof.to_owned())?;
+        Ok(multiproof)
+    }
     /// Add a new single proof to the multiproof.
     ///
     /// If the multiproof has no root, the root is obtained from the proof.
@@ -172,15 +316,103 @@ impl MultiProof {
             .get(hash)
             .ok_or_else(|| ProofError::NoProofNodeForHash(hex_encode(hash)))?)
     }
+    /// Returns all nodes as (node_hash, node_rlp), sorted by node hash.
+    ///
+    /// The order is stable across runs, for comparing the state of a multiproof in tests.
+    pub fn dump_sorted(&self) -> Vec<(H256, Vec<u8>)> {
+        let mut nodes: Vec<(H256, Vec<u8>)> = self
+            .data
+            .iter()
+            .map(|(hash, node)| (*hash, node.clone()))
+            .collect();
+        nodes.sort_by_key(|(hash, _)| *hash);
+        nodes
+    }
     /// Traverse a path in the multiproof.
     ///
     /// May either be to update the value or to verify. A task may be returned if information
     /// form an oracle is required.
+    ///
+    /// In debug builds, a modification or removal is followed by a self-check
+    /// (`assert_root_matches`), unless an oracle task is pending.
     pub fn traverse(
         &mut self,
         path: H256,
         intent: &Intent,
     ) -> Result<Vec<VisitedNode>, ProofError> {
+        let visited = self.traverse_unchecked(path, intent)?;
+        if cfg!(debug_assertions) && self.traversal_index_for_oracle_task.is_none() {
+            match intent {
+                Intent::Modify(new_rlp_value) => {
+                    self.assert_root_matches(path, Some(new_rlp_value))?
+                }
+                Intent::Remove => self.assert_root_matches(path, None)?,
+                _ => {}
+            }
+        }
+        Ok(visited)
+    }
+    /// Re-traverses a path and confirms that it is consistent with the root.
+    ///
+    /// Every node on the path must be stored under its own hash and the path must end in an
+    /// inclusion proof for the expected value (or an exclusion proof if `None`). Catches
+    /// silent corruption by a structural modification.
+    pub fn assert_root_matches(
+        &mut self,
+        path: H256,
+        expected: Option<&[u8]>,
+    ) -> Result<(), ProofError> {
+        let intent = match expected {
+            Some(value) => Intent::VerifyInclusion(value.to_vec()),
+            None => Intent::VerifyExclusion,
+        };
+        let visited = self
+            .traverse_unchecked(path, &intent)
+            .map_err(|e| ProofError::InconsistentAfterChange(e.to_string()))?;
+        for node in visited {
+            let node_rlp = self.get_node(&node.node_hash)?;
+            if H256::from(keccak256(node_rlp)) != node.node_hash {
+                return Err(ProofError::InconsistentAfterChange(format!(
+                    "node stored under hash {} has a different hash",
+                    hex_encode(node.node_hash)
+                )));
+            }
+        }
+        Ok(())
+    }
+    /// Traverse a path in the multiproof, without a self-check after modification.
+    fn traverse_unchecked(
+        &mut self,
+        path: H256,
+        intent: &Intent,
+    ) -> Result<Vec<VisitedNode>, ProofError> {
+        let ChangePlan {
+            path,
+            visited,
+            change,
+            ..
+        } = self.plan(path, intent)?;
+        if let Some(change) = change {
+            self.apply_planned_change(path, change, &visited)?;
+        }
+        Ok(visited)
+    }
+    /// Traverse a path in the multiproof and decide what change the intent requires,
+    /// without modifying the proof.
+    ///
+    /// The plan can be inspected (e.g., to provide oracle nodes with `insert_oracle_nodes`)
+    /// and then applied with `apply_change_plan`. Verification intents are checked
+    /// during planning and never require a change.
+    pub fn plan(&self, path: H256, intent: &Intent) -> Result<ChangePlan, ProofError> {
+        if self.root == H256(EMPTY_TRIE_ROOT) {
+            let change = plan_empty_trie(intent)?;
+            return Ok(ChangePlan {
+                root: self.root,
+                path,
+                visited: vec![],
+                change,
+            });
+        }
         let mut traversal = NibblePath::init(path.as_bytes());
         let mut next_node_hash = self.root;
         let mut visited_nodes: Vec<VisitedNode> = vec![];
@@ -191,143 +423,246 @@ impl MultiProof {
                 .get(&next_node_hash)
                 .ok_or(ProofError::NoProofNodeForHash(hex_encode(next_node_hash)))?;
             let next_node: Vec<Vec<u8>> = rlp::decode_list(next_node_rlp);
-            match NodeKind::deduce(&next_node)? {
-                kind @ NodeKind::Branch => {
-                    let traversal_record = traversal.clone();
-                    let item_index = traversal.visit_path_nibble()? as usize;
-                    let item = next_node
-                        .get(item_index)
-                        .ok_or(ProofError::BranchItemMissing)?;
-                    visited_nodes.push(VisitedNode {
-                        kind,
-                        node_hash: next_node_hash,
-                        item_index,
-                        traversal_record,
-                    });
-                    let is_exclusion_proof = item.is_empty();
-                    match (is_exclusion_proof, intent) {
-                        (true, Intent::Modify(new_rlp_value)) => {
-                            self.apply_changes(
-                                Change::BranchExclusionToInclusion(new_rlp_value.clone()),
-                                &visited_nodes,
-                            )?;
-                            return Ok(visited_nodes);
-                        }
-                        (true, Intent::Remove) => {
-                            // Key already not in trie.
-                            return Ok(visited_nodes);
-                        }
-                        (true, Intent::VerifyExclusion) => return Ok(visited_nodes),
-                        (true, Intent::VerifyInclusion(_)) => {
-                            return Err(ProofError::InclusionRequired)
-                        }
-                        (false, _) => {
-                            // Continue traversing
-                            next_node_hash = H256::from_slice(item);
-                        }
-                    }
+            let kind = NodeKind::deduce(&next_node)?;
+            let traversal_record = traversal.clone();
+            let outcome = step(&next_node, &mut traversal)?;
+            let item_index = match kind {
+                // The nibble just visited is the item followed.
+                NodeKind::Branch => {
+                    traversal_record.nibble_at_index(traversal_record.visiting_index())? as usize
                 }
-                kind @ NodeKind::Extension => {
-                    let traversal_record = traversal.clone();
-                    let extension = next_node.get(0).ok_or(ProofError::ExtensionHasNoItems)?;
-                    visited_nodes.push(VisitedNode {
-                        kind,
-                        node_hash: next_node_hash,
-                        item_index: 1,
-                        traversal_record,
-                    });
-
-                    match (traversal.match_or_mismatch(extension)?, intent) {
-                        (SubPathMatches, _) => {
-                            let item =
-                                next_node.get(1).ok_or(ProofError::ExtensionHasNoNextNode)?;
-                            next_node_hash = H256::from_slice(item);
-                            traversal.skip_extension_node_nibbles(extension)?;
-                        }
-                        (SubPathDiverges(divergent_nibble_index), Intent::Modify(new_value)) => {
-                            self.apply_changes(
-                                Change::ExtensionExclusionToInclusion {
-                                    new_value: new_value.clone(),
-                                    divergent_nibble_index,
-                                },
-                                &visited_nodes,
-                            )?;
-                            return Ok(visited_nodes);
-                        }
-                        (SubPathDiverges(_), Intent::Remove) => {
-                            // Key already not in trie
-                            return Ok(visited_nodes);
-                        }
-                        (SubPathDiverges(_), Intent::VerifyExclusion) => return Ok(visited_nodes),
-                        (SubPathDiverges(_), Intent::VerifyInclusion(_)) => {
-                            return Err(ProofError::InclusionRequired)
-                        }
-                        (FullPathMatches | FullPathDiverges(_), _) => {
-                            return Err(ProofError::FinalExtension)
-                        }
-                    };
+                NodeKind::Extension | NodeKind::Leaf => 1,
+            };
+            visited_nodes.push(VisitedNode {
+                kind,
+                node_hash: next_node_hash,
+                item_index,
+                traversal_record,
+            });
+            let change = match (outcome, intent) {
+                (StepResult::Next(child_hash), _) => {
+                    // Continue traversing
+                    next_node_hash = child_hash;
+                    continue;
                 }
-                kind @ NodeKind::Leaf => {
-                    let traversal_record = traversal.clone();
-                    let final_subpath = next_node.get(0).ok_or(ProofError::LeafHasNoFinalPath)?;
-
-                    visited_nodes.push(VisitedNode {
-                        kind,
-                        node_hash: next_node_hash,
-                        item_index: 1,
-                        traversal_record,
-                    });
-                    let traversal_status = traversal.match_or_mismatch(final_subpath)?;
-                    match (traversal_status, intent) {
-                        (SubPathMatches | SubPathDiverges(_), _) => {
-                            return Err(ProofError::LeafPathIncomplete)
-                        }
-                        (FullPathMatches, Intent::Modify(new_value)) => {
-                            self.apply_changes(
-                                Change::LeafInclusionModify(new_value.clone()),
-                                &visited_nodes,
-                            )?;
-                            return Ok(visited_nodes);
-                        }
-                        (FullPathMatches, Intent::VerifyExclusion) => {
-                            return Err(ProofError::ExclusionRequired)
-                        }
-                        (FullPathMatches, Intent::Remove) => {
-                            self.apply_changes(Change::LeafInclusionToExclusion, &visited_nodes)?;
-                            return Ok(visited_nodes);
-                        }
-                        (FullPathMatches, Intent::VerifyInclusion(expected_rlp_data)) => {
-                            let leaf_rlp_data =
-                                next_node.get(1).ok_or(ProofError::LeafHasNoData)?;
-                            if leaf_rlp_data != expected_rlp_data {
-                                return Err(ProofError::IncorrectLeafData);
-                            }
-                            return Ok(visited_nodes);
-                        }
-                        (
-                            FullPathDiverges(divergent_nibble_index),
-                            Intent::Modify(new_rlp_value),
-                        ) => {
-                            self.apply_changes(
-                                Change::LeafExclusionToInclusion {
-                                    new_value: new_rlp_value.clone(),
-                                    divergent_nibble_index,
-                                },
-                                &visited_nodes,
-                            )?;
-                            return Ok(visited_nodes);
-                        }
-                        (FullPathDiverges(_), Intent::Remove) => {
-                            // Key already not in trie
-                            return Ok(visited_nodes);
-                        }
-                        (FullPathDiverges(_), Intent::VerifyExclusion) => return Ok(visited_nodes),
-                        (FullPathDiverges(_), Intent::VerifyInclusion(_)) => {
-                            return Err(ProofError::InclusionRequired)
-                        }
+                (StepResult::BranchExclusion, Intent::Modify(new_rlp_value)) => {
+                    Some(Change::BranchExclusionToInclusion(new_rlp_value.clone()))
+                }
+                (
+                    StepResult::ExtensionExclusion(divergent_nibble_index),
+                    Intent::Modify(new_value),
+                ) => Some(Change::ExtensionExclusionToInclusion {
+                    new_value: new_value.clone(),
+                    divergent_nibble_index,
+                }),
+                (StepResult::LeafExclusion(divergent_nibble_index), Intent::Modify(new_value)) => {
+                    Some(Change::LeafExclusionToInclusion {
+                        new_value: new_value.clone(),
+                        divergent_nibble_index,
+                    })
+                }
+                (StepResult::LeafInclusion, Intent::Modify(new_value)) => {
+                    Some(Change::LeafInclusionModify(new_value.clone()))
+                }
+                (StepResult::LeafInclusion, Intent::Remove) => {
+                    Some(Change::LeafInclusionToExclusion)
+                }
+                (StepResult::LeafInclusion, Intent::VerifyInclusion(expected_rlp_data)) => {
+                    let leaf_rlp_data = next_node.get(1).ok_or(ProofError::LeafHasNoData)?;
+                    if leaf_rlp_data != expected_rlp_data {
+                        return Err(ProofError::IncorrectLeafData);
                     }
+                    None
+                }
+                (
+                    StepResult::LeafInclusion,
+                    Intent::VerifyExclusion | Intent::VerifyAbsentEquals(_),
+                ) => return Err(ProofError::ExclusionRequired),
+                // The remaining outcomes are exclusion proofs.
+                (_, Intent::Remove | Intent::VerifyExclusion) => {
+                    // Key already not in trie.
+                    None
                 }
+                (_, Intent::VerifyAbsentEquals(claimed)) => {
+                    check_absent_value(claimed)?;
+                    None
+                }
+                (_, Intent::VerifyInclusion(expected)) => return Err(inclusion_required(expected)),
+            };
+            return Ok(ChangePlan {
+                root: self.root,
+                path,
+                visited: visited_nodes,
+                change,
+            });
+        }
+    }
+
+    /// Applies a change decided by `plan`, returning the new root.
+    ///
+    /// Oracle nodes required by the change should be added before it is applied. A plan
+    /// made before the proof was changed is an error (`ProofError::StaleChangePlan`), as the
+    /// nodes it visited may no longer be in the proof.
+    pub fn apply_change_plan(&mut self, plan: ChangePlan) -> Result<H256, ProofError> {
+        if plan.root != self.root {
+            return Err(ProofError::StaleChangePlan {
+                planned: hex_encode(plan.root),
+                current: hex_encode(self.root),
+            });
+        }
+        if let Some(change) = plan.change {
+            self.apply_planned_change(plan.path, change, &plan.visited)?;
+        }
+        Ok(self.root)
+    }
+    /// Adds nodes provided by an oracle, so that a planned change that restructures the
+    /// trie has the nodes it requires.
+    pub fn insert_oracle_nodes(&mut self, nodes: Vec<Vec<u8>>) {
+        for node in nodes {
+            self.data.insert(H256::from(keccak256(&node)), node);
+        }
+    }
+    /// Applies a planned change. A change to a trie with no keys has no visited nodes.
+    fn apply_planned_change(
+        &mut self,
+        path: H256,
+        change: Change,
+        visited: &[VisitedNode],
+    ) -> Result<(), ProofError> {
+        match change {
+            Change::EmptyTrieToInclusion(new_rlp_value) => {
+                let leaf_path = NibblePath::init(path.as_bytes()).get_encoded_path(
+                    TargetNodeEncoding::Leaf,
+                    0,
+                    63,
+                )?;
+                let leaf = Node::try_from(vec![leaf_path, new_rlp_value])?;
+                let leaf_rlp = leaf.to_rlp_list();
+                let leaf_hash: H256 = keccak256(&leaf_rlp).into();
+                self.data.insert(leaf_hash, leaf_rlp);
+                self.root = leaf_hash;
+            }
+            change => self.apply_changes(change, visited)?,
+        }
+        Ok(())
+    }
+
+    /// Updates the values of keys that are already in the trie, hashing each changed node
+    /// once.
+    ///
+    /// Updating keys one at a time with `traverse` re-hashes every ancestor up to the root
+    /// for each key, so ancestors shared by keys are hashed repeatedly. Here the new child
+    /// hashes are cached by the position of the node (nibbles from the root) and each node
+    /// is hashed after all its children are updated, deepest first.
+    ///
+    /// A key that is not in the trie requires a structural change, use `traverse` for those.
+    /// The batch is checked before any change is made.
+    pub fn modify_batch(
+        &mut self,
+        updates: &[(H256, Vec<u8>)],
+    ) -> Result<BatchOutcome, ProofError> {
+        // Position -> (node hash before the batch, parent position and item index).
+        let mut positions: HashMap<Vec<u8>, (H256, Option<(Vec<u8>, usize)>)> = HashMap::new();
+        let mut leaves: Vec<(Vec<u8>, Vec<u8>)> = vec![];
+        for (path, new_value) in updates {
+            let plan = self.plan(*path, &Intent::Modify(new_value.clone()))?;
+            let Some(Change::LeafInclusionModify(_)) = plan.change else {
+                return Err(ProofError::BatchRequiresInclusion(hex_encode(path)));
+            };
+            let mut parent: Option<(Vec<u8>, usize)> = None;
+            for node in &plan.visited {
+                let position = node.traversal_record.history()?;
+                positions.insert(position.clone(), (node.node_hash, parent));
+                parent = Some((position, node.item_index));
+            }
+            if let Some((leaf_position, _)) = parent {
+                leaves.push((leaf_position, new_value.clone()));
+            }
+        }
+
+        let mut nodes_hashed = 0;
+        let mut pending = PendingChildHashes::new();
+        let mut root = None;
+        for (position, new_value) in leaves {
+            let (leaf_hash, parent) = &positions[&position];
+            let leaf_rlp = self.get_node(leaf_hash)?;
+            let leaf: Vec<Vec<u8>> = rlp::decode_list(leaf_rlp);
+            let path = leaf.first().ok_or(ModifyError::LeafHasNoFinalPath)?;
+            let new_leaf_rlp = Node::try_from(vec![path.to_owned(), new_value])?.to_rlp_list();
+            let new_leaf_hash = keccak256(&new_leaf_rlp);
+            nodes_hashed += 1;
+            self.data.insert(new_leaf_hash.into(), new_leaf_rlp);
+            root = queue_child_hash(&mut pending, parent, new_leaf_hash).or(root);
+        }
+        while let Some(((_, position), children)) = pending.pop_first() {
+            let (outdated_hash, parent) = &positions[&position];
+            let outdated_rlp = self
+                .data
+                .remove(outdated_hash)
+                .ok_or(ModifyError::NoNodeForHash)?;
+            let mut node: Vec<Vec<u8>> = rlp::decode_list(&outdated_rlp);
+            for (item_index, child_hash) in children {
+                let item = node
+                    .get_mut(item_index)
+                    .ok_or(ModifyError::NoItemInBranch)?;
+                *item = child_hash.to_vec();
+            }
+            let updated_rlp = Node::try_from(node)?.to_rlp_list();
+            let updated_hash = keccak256(&updated_rlp);
+            nodes_hashed += 1;
+            self.data.insert(updated_hash.into(), updated_rlp);
+            root = queue_child_hash(&mut pending, parent, updated_hash).or(root);
+        }
+        if let Some(root) = root {
+            self.root = root.into();
+        }
+        if cfg!(debug_assertions) {
+            for (path, new_value) in updates {
+                self.assert_root_matches(*path, Some(new_value))?;
+            }
+        }
+        Ok(BatchOutcome {
+            root: self.root,
+            nodes_hashed,
+        })
+    }
+
+    /// Replaces the value of a key that is already in the trie, returning the new root.
+    ///
+    /// A `modify_batch` of one key: the trie structure does not change, so only the leaf
+    /// value is replaced and the ancestors re-hashed.
+    ///
+    /// A key that is not in the trie is an error (`ProofError::ValueReplaceRequiresInclusion`)
+    /// and no change is made. Use `traverse` for those.
+    pub fn replace_value_only(
+        &mut self,
+        path: H256,
+        new_value: Vec<u8>,
+    ) -> Result<H256, ProofError> {
+        match self.modify_batch(&[(path, new_value)]) {
+            Ok(outcome) => Ok(outcome.root),
+            Err(ProofError::BatchRequiresInclusion(key)) => {
+                Err(ProofError::ValueReplaceRequiresInclusion(key))
             }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Removes the key at the given path from the multiproof.
+    ///
+    /// Returns whether the removal required the parent branch to be collapsed, in which case
+    /// an oracle task is recorded in `traversal_index_for_oracle_task`.
+    pub fn remove_key(&mut self, path: H256) -> Result<RemoveOutcome, ProofError> {
+        let prior_task = self.traversal_index_for_oracle_task.take();
+        let prior_root = self.root;
+        self.traverse(path, &Intent::Remove)?;
+        if let Some(traversal_index) = self.traversal_index_for_oracle_task {
+            return Ok(RemoveOutcome::BranchCollapse { traversal_index });
+        }
+        self.traversal_index_for_oracle_task = prior_task;
+        match self.root == prior_root {
+            true => Ok(RemoveOutcome::Absent),
+            false => Ok(RemoveOutcome::Removed),
         }
     }
 
@@ -490,12 +825,7 @@ impl MultiProof {
                 let branch_item_index =
                     traversal.nibble_at_index(traversal.visiting_index())? as usize;
                 // Remaining path is for the leaf.
-                let leaf_path_start = traversal.visiting_index() + 1;
-                let leaf_path = last_visited.traversal_record.get_encoded_path(
-                    TargetNodeEncoding::Leaf,
-                    leaf_path_start,
-                    63,
-                )?;
+                let leaf_path = traversal.remaining_encoded(TargetNodeEncoding::Leaf)?;
                 let leaf_node = Node::try_from(vec![leaf_path, new_leaf_rlp_value])?;
                 let leaf_node_rlp = leaf_node.to_rlp_list();
                 let leaf_node_hash = keccak256(&leaf_node_rlp);
@@ -602,6 +932,7 @@ impl MultiProof {
                 }
                 self.root = updated_hash.into();
             }
+            Change::EmptyTrieToInclusion(_) => return Err(ModifyError::NoVisitedNodes),
             Change::LeafInclusionToExclusion => {
                 // 1. Whenever there is branch with 2 items and one is removed,
                 // the branch must be removed.
@@ -641,24 +972,28 @@ impl MultiProof {
         let outdated_node: Vec<Vec<u8>> = rlp::decode_list(&outdated_rlp);
         let updated_node: Node = match visited.kind {
             NodeKind::Branch => {
-                // [next_node_0, ..., next_node_16, value]
+                // [next_node_0, ..., next_node_15, value]
                 let mut updated = Node::default();
                 let mut child_count = 0;
+                let mut has_value = false;
                 for (index, item) in outdated_node.into_iter().enumerate() {
                     if index == visited.item_index {
                         updated.0.push(Item(child_hash.to_vec()));
                         child_count += 1;
                     } else {
-                        if !item.is_empty() {
-                            child_count += 1;
+                        // The value is kept as-is and is not a child.
+                        match (index == BRANCH_VALUE_INDEX, item.is_empty()) {
+                            (true, false) => has_value = true,
+                            (false, false) => child_count += 1,
+                            (_, true) => {}
                         }
                         updated.0.push(Item(item));
                     }
                 }
                 // Branch cannot be removed if there is an only-child sibling extension awaiting
                 // an update in a later EVM operation. In this case the child hash is passed as
-                // an empty array.
-                let can_remove_branch = child_hash != &[0u8; 32];
+                // an empty array. A branch with a value is never removed.
+                let can_remove_branch = child_hash != &[0u8; 32] && !has_value;
                 if child_count == 1 && can_remove_branch {
                     // This node must be removed because it has one child.
                     // It was not updated earlier because it was waiting on this child hash.
@@ -825,25 +1160,32 @@ impl MultiProof {
             todo!("Error: Remove leaf child called on non-branch node.")
         }
 
-        // [next_node_0, ..., next_node_16, value]
+        // [next_node_0, ..., next_node_15, value]
         let mut updated = Node::default();
         let mut item_count = 0;
+        let mut has_value = false;
         // Find where the orphaned sibling leaf belongs in the branch.
         for (index, item) in outdated_node.into_iter().enumerate() {
             if index == parent.item_index {
                 // Erase child
                 updated.0.push(Item(vec![]));
             } else {
-                if !item.is_empty() {
-                    item_count += 1;
+                // The value is kept as-is and is not a child.
+                match (index == BRANCH_VALUE_INDEX, item.is_empty()) {
+                    (true, false) => has_value = true,
+                    (false, false) => item_count += 1,
+                    (_, true) => {}
                 }
                 updated.0.push(Item(item));
             }
         }
 
         match item_count {
+            // The branch would become a leaf holding the value.
+            0 if has_value => Err(ModifyError::BranchValueWithoutChildren),
             0 => todo!("error, not possible"), // Branch should have at least one item.
-            1 => {
+            // A branch with a value remains, even with one child.
+            1 if !has_value => {
                 // The parent is a branch node for deletion (too few items).
                 // This may require oracle knowledge, so it is returned as a task.
 
@@ -987,51 +1329,216 @@ impl MultiProof {
             (NodeKind::Leaf, _) => todo!("error, grandparent cannot be leaf"),
         }
     }
+    /// Returns the paths that the multiproof can decide (prove inclusion or exclusion for).
+    ///
+    /// Nodes are followed from the root. Where a node is not in the proof, paths below it
+    /// are not decided. This can be compared with the keys a proof was intended for to detect
+    /// proofs that contain too many or too few nodes.
+    pub fn paths_covered(&self) -> Result<CoveredPaths, ProofError> {
+        let mut covered = CoveredPaths::default();
+        // (node hash, nibbles from root to the node)
+        let mut pending: Vec<(H256, Vec<u8>)> = vec![(self.root, vec![])];
+        while let Some((hash, prefix)) = pending.pop() {
+            let Some(node_rlp) = self.data.get(&hash) else {
+                continue;
+            };
+            let node: Vec<Vec<u8>> = rlp::decode_list(node_rlp);
+            match NodeKind::deduce(&node)? {
+                NodeKind::Branch => {
+                    for (nibble, item) in node.iter().take(BRANCH_VALUE_INDEX).enumerate() {
+                        let mut child_prefix = prefix.clone();
+                        child_prefix.push(nibble as u8);
+                        match item.len() {
+                            0 => {
+                                covered.decided_prefixes.insert(child_prefix);
+                            }
+                            32 => pending.push((H256::from_slice(item), child_prefix)),
+                            // Inline child (not referred to by hash), paths below are not decided.
+                            _ => {}
+                        }
+                    }
+                }
+                NodeKind::Extension => {
+                    let extension = node.first().ok_or(ProofError::ExtensionHasNoItems)?;
+                    let extension_nibbles = prefixed_bytes_to_nibbles(extension)?;
+                    // Paths that diverge from the extension are excluded.
+                    let mut shared = prefix.clone();
+                    for expected in &extension_nibbles {
+                        for nibble in (0..16u8).filter(|n| n != expected) {
+                            let mut divergent = shared.clone();
+                            divergent.push(nibble);
+                            covered.decided_prefixes.insert(divergent);
+                        }
+                        shared.push(*expected);
+                    }
+                    let item = node.get(1).ok_or(ProofError::ExtensionHasNoNextNode)?;
+                    // Inline child (not referred to by hash), paths below are not decided.
+                    if item.len() == 32 {
+                        pending.push((H256::from_slice(item), shared));
+                    }
+                }
+                NodeKind::Leaf => {
+                    // The leaf includes its own path and excludes every other path below it.
+                    let leaf_path = node.first().ok_or(ProofError::LeafHasNoFinalPath)?;
+                    let mut nibbles = prefix.clone();
+                    nibbles.extend(prefixed_bytes_to_nibbles(leaf_path)?);
+                    let bytes: Vec<u8> = nibbles
+                        .chunks(2)
+                        .map(|pair| pair[0] << 4 | pair.get(1).copied().unwrap_or_default())
+                        .collect();
+                    if bytes.len() != 32 {
+                        return Err(ProofError::LeafPathIncomplete);
+                    }
+                    covered.leaves.insert(H256::from_slice(&bytes));
+                    covered.decided_prefixes.insert(prefix);
+                }
+            }
+        }
+        Ok(covered)
+    }
+    /// Returns the largest number of nodes on a path from the root to a node without
+    /// children in the proof.
+    ///
+    /// An abnormally deep trie may indicate an adversarial proof. A node that refers to
+    /// itself or an ancestor is not followed, so a malformed proof cannot cause a loop.
+    pub fn depth(&self) -> usize {
+        // Number of nodes on the longest path from each visited node, which is reused when a
+        // node is reachable from more than one parent.
+        let mut depths: HashMap<H256, usize> = HashMap::new();
+        // Nodes from the root to the node being visited.
+        let mut ancestors: HashSet<H256> = HashSet::new();
+        // (node hash, whether the children of the node have been visited)
+        let mut pending: Vec<(H256, bool)> = vec![(self.root, false)];
+        while let Some((hash, children_visited)) = pending.pop() {
+            if children_visited {
+                let depth = 1 + self
+                    .child_hashes(&hash)
+                    .iter()
+                    .filter_map(|child| depths.get(child).copied())
+                    .max()
+                    .unwrap_or(0);
+                ancestors.remove(&hash);
+                depths.insert(hash, depth);
+                continue;
+            }
+            if depths.contains_key(&hash) || !self.data.contains_key(&hash) {
+                continue;
+            }
+            ancestors.insert(hash);
+            pending.push((hash, true));
+            for child in self.child_hashes(&hash) {
+                if !ancestors.contains(&child) {
+                    pending.push((child, false));
+                }
+            }
+        }
+        depths.get(&self.root).copied().unwrap_or(0)
+    }
+    /// Returns the hashes of the children of a node that are referred to by hash.
+    fn child_hashes(&self, hash: &H256) -> Vec<H256> {
+        let Some(node_rlp) = self.data.get(hash) else {
+            return vec![];
+        };
+        let node: Vec<Vec<u8>> = rlp::decode_list(node_rlp);
+        let children: Vec<&Vec<u8>> = match NodeKind::deduce(&node) {
+            Ok(NodeKind::Branch) => node.iter().take(BRANCH_VALUE_INDEX).collect(),
+            Ok(NodeKind::Extension) => node.get(1).into_iter().collect(),
+            Ok(NodeKind::Leaf) | Err(_) => vec![],
+        };
+        children
+            .into_iter()
+            .filter(|child| child.len() == 32)
+            .map(|child| H256::from_slice(child))
+            .collect()
+    }
+    /// Returns the index of the nibble at which the path diverges from the trie.
+    ///
+    /// For an exclusion proof this is the first nibble in the path that has no
+    /// corresponding node. For an inclusion proof, returns None.
+    pub fn divergence_index(&self, path: H256) -> Result<Option<usize>, ProofError> {
+        let mut traversal = NibblePath::init(path.as_bytes());
+        let mut next_node_hash = self.root;
+        // Start near root, follow path toward leaves.
+        loop {
+            let next_node_rlp = self
+                .data
+                .get(&next_node_hash)
+                .ok_or(ProofError::NoProofNodeForHash(hex_encode(next_node_hash)))?;
+            let next_node: Vec<Vec<u8>> = rlp::decode_list(next_node_rlp);
+            // A branch exclusion diverges at the nibble the branch visits.
+            let branch_nibble_index = traversal.visiting_index();
+            match step(&next_node, &mut traversal)? {
+                StepResult::Next(child_hash) => next_node_hash = child_hash,
+                StepResult::BranchExclusion => return Ok(Some(branch_nibble_index)),
+                StepResult::ExtensionExclusion(nibble_index)
+                | StepResult::LeafExclusion(nibble_index) => return Ok(Some(nibble_index)),
+                StepResult::LeafInclusion => return Ok(None),
+            }
+        }
+    }
+    /// For an exclusion proof, returns the existing node that the path diverges from, as
+    /// (path, value). The path is the nibbles (one per byte) from the root to the end of
+    /// the node.
+    ///
+    /// - Leaf: the full path of the existing key and the leaf value.
+    /// - Extension: the path to the node that the extension refers to, and its hash.
+    ///
+    /// Returns None for an inclusion proof, or if the path ends at an empty branch item
+    /// (there is no single nearest key).
+    pub fn nearest_existing(&self, path: H256) -> Result<Option<(Vec<u8>, Vec<u8>)>, ProofError> {
+        let mut traversal = NibblePath::init(path.as_bytes());
+        let mut next_node_hash = self.root;
+        // Start near root, follow path toward leaves.
+        loop {
+            let next_node_rlp = self
+                .data
+                .get(&next_node_hash)
+                .ok_or(ProofError::NoProofNodeForHash(hex_encode(next_node_hash)))?;
+            let next_node: Vec<Vec<u8>> = rlp::decode_list(next_node_rlp);
+            // Nibbles from the root to this node.
+            let node_path = traversal.history()?;
+            match step(&next_node, &mut traversal)? {
+                StepResult::Next(child_hash) => next_node_hash = child_hash,
+                StepResult::BranchExclusion | StepResult::LeafInclusion => return Ok(None),
+                StepResult::ExtensionExclusion(_) | StepResult::LeafExclusion(_) => {
+                    let (Some(node_subpath), Some(value)) = (next_node.get(0), next_node.get(1))
+                    else {
+                        return Err(ProofError::NodeHasTooFewItems);
+                    };
+                    let mut full_path = node_path;
+                    full_path.extend(prefixed_bytes_to_nibbles(node_subpath)?);
+                    return Ok(Some((full_path, value.to_owned())));
+                }
+            }
+        }
+    }
     /// View a single proof (follow one path in the multiproof).
+    ///
+    /// A node that is reached twice is an error (`ProofError::TraversalLoop`). Nodes are keyed
+    /// by hash, so this only occurs if the data is malformed.
     pub fn view(&self, path: H256) -> Result<DisplayProof, ProofError> {
         let mut traversal = NibblePath::init(path.as_bytes());
         let mut next_node_hash = self.root;
         let mut visited_nodes: Vec<Vec<u8>> = vec![];
+        let mut visited_hashes: HashSet<H256> = HashSet::new();
         // Start near root, follow path toward leaves.
         loop {
+            if !visited_hashes.insert(next_node_hash) {
+                return Err(ProofError::TraversalLoop(hex_encode(next_node_hash)));
+            }
             let next_node_rlp = self
                 .data
                 .get(&next_node_hash)
                 .ok_or(ProofError::NoViewNodeForHash(hex_encode(next_node_hash)))?;
             visited_nodes.push(next_node_rlp.to_vec());
             let next_node: Vec<Vec<u8>> = rlp::decode_list(next_node_rlp);
-
-            match NodeKind::deduce(&next_node)? {
-                NodeKind::Branch => {
-                    let item_index = traversal.visit_path_nibble()? as usize;
-                    let item = next_node
-                        .get(item_index)
-                        .ok_or(ProofError::BranchItemMissing)?;
-
-                    let is_exclusion_proof = item.is_empty();
-                    match is_exclusion_proof {
-                        true => break,
-                        false => {
-                            // Continue traversing
-                            next_node_hash = H256::from_slice(item);
-                        }
-                    }
-                }
-                NodeKind::Extension => {
-                    let extension = next_node.get(0).ok_or(ProofError::ExtensionHasNoItems)?;
-                    match traversal.match_or_mismatch(extension)? {
-                        SubPathMatches => {
-                            let item =
-                                next_node.get(1).ok_or(ProofError::ExtensionHasNoNextNode)?;
-                            next_node_hash = H256::from_slice(item);
-                            traversal.skip_extension_node_nibbles(extension)?;
-                        }
-                        SubPathDiverges(_) => break,
-                        FullPathMatches => break,
-                        FullPathDiverges(_) => break,
-                    }
-                }
-                NodeKind::Leaf => break,
+            if NodeKind::deduce(&next_node)?.is_terminal() {
+                break;
+            }
+            match step(&next_node, &mut traversal) {
+                Ok(StepResult::Next(child_hash)) => next_node_hash = child_hash,
+                Ok(_) | Err(ProofError::FinalExtension) => break,
+                Err(e) => return Err(e),
             }
         }
         Ok(DisplayProof::init(visited_nodes))
@@ -1106,6 +1613,19 @@ pub enum Change {
     },
     LeafInclusionModify(Vec<u8>),
     LeafInclusionToExclusion,
+    /// The first key is added to a trie with no keys.
+    EmptyTrieToInclusion(Vec<u8>),
+}
+
+/// The outcome of traversing a path: the nodes visited and the change to make, if any.
+#[derive(Debug)]
+pub struct ChangePlan {
+    /// The root of the proof when the plan was made.
+    pub root: H256,
+    pub path: H256,
+    pub visited: Vec<VisitedNode>,
+    /// None if the intent does not change the proof (e.g., verification).
+    pub change: Option<Change>,
 }
 
 /// The action to take when traversing a proof path.
@@ -1122,6 +1642,50 @@ pub enum Intent {
     /// Check that key is not in the tree. The caller can check if the value
     /// represents the absent kind (null account / null storage)
     VerifyExclusion,
+    /// Check that key is not in the tree and that the claimed RLP-encoded value
+    /// is the empty encoding (null account / zero storage value).
+    VerifyAbsentEquals(Vec<u8>),
+}
+
+/// The paths that a multiproof can decide.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CoveredPaths {
+    /// Paths of leaves in the proof (inclusion proofs).
+    pub leaves: BTreeSet<H256>,
+    /// Nibble sequences from the root. Every path that starts with one of these ends at a
+    /// terminal node in the proof, either at a leaf or where the path is excluded.
+    pub decided_prefixes: BTreeSet<Vec<u8>>,
+}
+
+impl CoveredPaths {
+    /// Whether the multiproof can prove inclusion or exclusion for the path.
+    pub fn decides(&self, path: H256) -> bool {
+        let nibbles = NibblePath::init(path.as_bytes());
+        // An empty prefix is for a trie that is a single leaf.
+        let root: &[u8] = &[];
+        self.decided_prefixes.contains(root)
+            || (0..64).any(|end| match nibbles.traversal_to_index(end) {
+                Ok(prefix) => self.decided_prefixes.contains(prefix),
+                Err(_) => false,
+            })
+    }
+}
+
+/// Index of the value item in a branch node. Items 0-15 are children.
+const BRANCH_VALUE_INDEX: usize = 16;
+
+/// Plans a traversal for a trie with no keys (root is the empty trie root).
+///
+/// Every key is excluded. A modification creates a single leaf that becomes the root.
+fn plan_empty_trie(intent: &Intent) -> Result<Option<Change>, ProofError> {
+    match intent {
+        Intent::Remove | Intent::VerifyExclusion => Ok(None),
+        Intent::VerifyAbsentEquals(claimed) => check_absent_value(claimed).map(|_| None),
+        Intent::VerifyInclusion(expected) => Err(inclusion_required(expected)),
+        Intent::Modify(new_rlp_value) => {
+            Ok(Some(Change::EmptyTrieToInclusion(new_rlp_value.clone())))
+        }
+    }
 }
 
 /// Detects if an RLP encoded value is for an empty storage value or account.
@@ -1138,6 +1702,106 @@ fn is_empty_value(rlp_value: &[u8]) -> bool {
     false
 }
 
+/// The outcome of visiting one node while following a path.
+#[derive(Debug, PartialEq)]
+pub enum StepResult {
+    /// The path continues to the node with this hash.
+    Next(H256),
+    /// The branch has no item for the path (exclusion proof).
+    BranchExclusion,
+    /// The extension diverges from the path at the nibble index (exclusion proof).
+    ExtensionExclusion(usize),
+    /// The leaf is for the path (inclusion proof).
+    LeafInclusion,
+    /// The leaf diverges from the path at the nibble index (exclusion proof).
+    LeafExclusion(usize),
+}
+
+/// Visits a node along a path, advancing the traversal past the nibbles that the node
+/// consumes.
+///
+/// This is shared by all functions that follow a path through the proof so that they agree
+/// on the next node.
+pub(crate) fn step(node: &[Vec<u8>], traversal: &mut NibblePath) -> Result<StepResult, ProofError> {
+    match NodeKind::deduce(node)? {
+        NodeKind::Branch => {
+            let item_index = traversal.visit_path_nibble()? as usize;
+            let item = node.get(item_index).ok_or(ProofError::BranchItemMissing)?;
+            match item.is_empty() {
+                true => Ok(StepResult::BranchExclusion),
+                false => Ok(StepResult::Next(H256::from_slice(item))),
+            }
+        }
+        NodeKind::Extension => {
+            let extension = node.get(0).ok_or(ProofError::ExtensionHasNoItems)?;
+            match traversal.match_or_mismatch(extension)? {
+                SubPathMatches => {
+                    let item = node.get(1).ok_or(ProofError::ExtensionHasNoNextNode)?;
+                    traversal.skip_extension_node_nibbles(extension)?;
+                    Ok(StepResult::Next(H256::from_slice(item)))
+                }
+                SubPathDiverges(divergent_nibble_index) => {
+                    Ok(StepResult::ExtensionExclusion(divergent_nibble_index))
+                }
+                FullPathMatches | FullPathDiverges(_) => Err(ProofError::FinalExtension),
+            }
+        }
+        NodeKind::Leaf => {
+            let final_subpath = node.get(0).ok_or(ProofError::LeafHasNoFinalPath)?;
+            match traversal.match_or_mismatch(final_subpath)? {
+                SubPathMatches | SubPathDiverges(_) => Err(ProofError::LeafPathIncomplete),
+                FullPathMatches => Ok(StepResult::LeafInclusion),
+                FullPathDiverges(divergent_nibble_index) => {
+                    Ok(StepResult::LeafExclusion(divergent_nibble_index))
+                }
+            }
+        }
+    }
+}
+
+/// Error for an inclusion proof intent that found an exclusion proof.
+///
+/// An EIP-1186 proof reports a zero value for both an excluded key and a leaf that stores
+/// the zero encoding. Only the latter is an inclusion, so the two are distinguished.
+fn inclusion_required(expected_rlp_value: &[u8]) -> ProofError {
+    match is_empty_value(expected_rlp_value) {
+        true => ProofError::ZeroValueExcluded,
+        false => ProofError::InclusionRequired,
+    }
+}
+
+/// For an exclusion proof, checks that the value claimed by the caller is the empty kind.
+fn check_absent_value(claimed_rlp_value: &[u8]) -> Result<(), ProofError> {
+    match is_empty_value(claimed_rlp_value) {
+        true => Ok(()),
+        false => Err(ProofError::AbsentValueNotEmpty(hex_encode(
+            claimed_rlp_value,
+        ))),
+    }
+}
+
+/// Updated child hashes for the nodes in a batch, keyed by node position (nibbles from the
+/// root) with the deepest nodes first. Each maps item index -> child hash.
+type PendingChildHashes = BTreeMap<(Reverse<usize>, Vec<u8>), BTreeMap<usize, [u8; 32]>>;
+
+/// Records the new hash of a node for the update of its parent (position and item index).
+///
+/// Returns the hash if the node has no parent (is the root).
+fn queue_child_hash(
+    pending: &mut PendingChildHashes,
+    parent: &Option<(Vec<u8>, usize)>,
+    hash: [u8; 32],
+) -> Option<[u8; 32]> {
+    let Some((position, item_index)) = parent else {
+        return Some(hash);
+    };
+    pending
+        .entry((Reverse(position.len()), position.clone()))
+        .or_default()
+        .insert(*item_index, hash);
+    None
+}
+
 #[cfg(test)]
 mod test {
 
@@ -1301,4 +1965,710 @@ mod test {
             panic!("Expected post proof != Post proof")
         };
     }
+
+    /// Plans the exclusion to inclusion change, provides oracle nodes, then applies
+    /// the plan. The proof is unchanged until the plan is applied.
+    #[test]
+    fn test_apply_change_plan_with_oracle_nodes() {
+        let pre_root =
+            H256::from_str("0x6b93962316b2fbd616359d59b41a6ca880f97f99bf631aeaab740b8927691654")
+                .unwrap();
+        let mut multi = MultiProof::init(pre_root);
+        multi
+            .insert_proof(proof_str_to_vec(PROOF_KEY_8C87_PRE.to_vec()))
+            .unwrap();
+        let path: H256 = keccak256(
+            H256::from_str("0x8c874ac9f7bd5ae2f2c60e6a4f1760c4c54770f4781c666f7ae305e1e70add32")
+                .unwrap(),
+        )
+        .into();
+        let new_val = slot_rlp_from_value(ru256::from_str("0x64544dd7").unwrap());
+
+        let plan = multi
+            .plan(path, &Intent::Modify(new_val.to_owned()))
+            .unwrap();
+        assert!(matches!(
+            plan.change,
+            Some(Change::ExtensionExclusionToInclusion { .. })
+        ));
+        assert_eq!(plan.visited.len(), 4);
+        assert_eq!(multi.root, pre_root);
+        multi.traverse(path, &Intent::VerifyExclusion).unwrap();
+
+        // Nodes an oracle would provide for the post-change trie.
+        let oracle_nodes: Vec<Vec<u8>> = proof_str_to_vec(PROOF_KEY_8C87_POST.to_vec())
+            .into_iter()
+            .map(|node| node.to_vec())
+            .collect();
+        multi.insert_oracle_nodes(oracle_nodes);
+
+        let root = multi.apply_change_plan(plan).unwrap();
+        let expected_root =
+            H256::from_str("0xee76a42a55cb9e1b3f54c5dbf382c683b9edaf7ee98886d81e5ecca3cfccea90")
+                .unwrap();
+        assert_eq!(root, expected_root);
+        multi
+            .traverse(path, &Intent::VerifyInclusion(new_val))
+            .unwrap();
+    }
+
+    /// A plan made before the proof was changed is not applied.
+    #[test]
+    fn test_apply_stale_change_plan() {
+        let (mut multi, paths) = three_leaf_trie();
+        let plan = multi.plan(paths[0], &Intent::Modify(vec![0x01])).unwrap();
+        multi
+            .traverse(paths[1], &Intent::Modify(vec![0x02]))
+            .unwrap();
+        let root = multi.root;
+        assert!(matches!(
+            multi.apply_change_plan(plan),
+            Err(ProofError::StaleChangePlan { .. })
+        ));
+        assert_eq!(multi.root, root);
+    }
+
+    /// Checks that an exclusion proof is only accepted when the claimed value is the
+    /// empty encoding.
+    #[test]
+    fn test_verify_absent_equals_rejects_non_empty_value() {
+        let mut multi = MultiProof::init(
+            H256::from_str("0x6b93962316b2fbd616359d59b41a6ca880f97f99bf631aeaab740b8927691654")
+                .unwrap(),
+        );
+        multi
+            .insert_proof(proof_str_to_vec(PROOF_KEY_8C87_PRE.to_vec()))
+            .unwrap();
+        let path: H256 = keccak256(
+            H256::from_str("0x8c874ac9f7bd5ae2f2c60e6a4f1760c4c54770f4781c666f7ae305e1e70add32")
+                .unwrap(),
+        )
+        .into();
+        let zero = slot_rlp_from_value(ru256::ZERO);
+        multi
+            .traverse(path, &Intent::VerifyAbsentEquals(zero))
+            .unwrap();
+
+        let non_zero = slot_rlp_from_value(ru256::from_str("0x64544dd7").unwrap());
+        assert!(matches!(
+            multi.traverse(path, &Intent::VerifyAbsentEquals(non_zero)),
+            Err(ProofError::AbsentValueNotEmpty(_))
+        ));
+    }
+
+    /// Creates a trie where a leaf has two siblings:
+    /// - Root branch
+    ///   - 0x1: Branch
+    ///     - 0x0, 0x1, 0x2: Leaves
+    ///   - 0x2: (other node)
+    ///
+    /// Returns the multiproof and the leaf paths.
+    fn three_leaf_trie() -> (MultiProof, Vec<H256>) {
+        let mut paths = vec![];
+        let mut leaf_nodes = vec![];
+        let mut child_branch: Vec<Vec<u8>> = (0..17).map(|_| vec![]).collect();
+        for nibble in 0..3u8 {
+            let mut path = [0u8; 32];
+            path[0] = 0x10 + nibble;
+            paths.push(H256::from(path));
+            // Two nibbles are used by the branches, 62 remain for the leaf (even, prefix 0x20).
+            let mut leaf_path = vec![0x20];
+            leaf_path.extend_from_slice(&path[1..]);
+            let leaf_rlp = Node::try_from(vec![leaf_path, slot_rlp_from_value(ru256::from(1))])
+                .unwrap()
+                .to_rlp_list();
+            child_branch[nibble as usize] = keccak256(&leaf_rlp).to_vec();
+            leaf_nodes.push(leaf_rlp);
+        }
+        let child_branch_rlp = Node::try_from(child_branch).unwrap().to_rlp_list();
+        let mut root_branch: Vec<Vec<u8>> = (0..17).map(|_| vec![]).collect();
+        root_branch[1] = keccak256(&child_branch_rlp).to_vec();
+        root_branch[2] = keccak256([0u8]).to_vec();
+        let root_branch_rlp = Node::try_from(root_branch).unwrap().to_rlp_list();
+
+        let mut multi = MultiProof::default();
+        for leaf_rlp in leaf_nodes {
+            let proof = vec![&root_branch_rlp, &child_branch_rlp, &leaf_rlp]
+                .into_iter()
+                .map(|node| Bytes::from(node.clone()))
+                .collect();
+            multi.insert_proof(proof).unwrap();
+        }
+        (multi, paths)
+    }
+
+    /// Tests that a batch of sibling updates hashes the shared ancestors once, with the
+    /// same result as updating one key at a time.
+    #[test]
+    fn test_modify_batch_hashes_ancestors_once() {
+        let (mut sequential, paths) = three_leaf_trie();
+        let (mut batched, _) = three_leaf_trie();
+        let updates: Vec<(H256, Vec<u8>)> = paths
+            .iter()
+            .enumerate()
+            .map(|(index, path)| (*path, slot_rlp_from_value(ru256::from(index + 2))))
+            .collect();
+
+        let mut sequential_hashed = 0;
+        for (path, value) in &updates {
+            // The leaf and each ancestor are hashed.
+            sequential_hashed += sequential
+                .traverse(*path, &Intent::Modify(value.clone()))
+                .unwrap()
+                .len();
+        }
+        let outcome = batched.modify_batch(&updates).unwrap();
+        assert_eq!(outcome.root, sequential.root);
+        assert_eq!(batched.dump_sorted(), sequential.dump_sorted());
+        assert_eq!(sequential_hashed, 9);
+        // Three leaves, the shared branch and the root.
+        assert_eq!(outcome.nodes_hashed, 5);
+
+        // A key not in the trie is rejected before any change.
+        let mut absent = paths[0];
+        absent.0[31] = 0xff;
+        let root = batched.root;
+        assert!(matches!(
+            batched.modify_batch(&[(paths[1], vec![1]), (absent, vec![1])]),
+            Err(ProofError::BatchRequiresInclusion(_))
+        ));
+        assert_eq!(batched.root, root);
+    }
+
+    /// Tests that replacing a value in place gives the same trie as the general modification,
+    /// and that a key not in the trie is rejected without change.
+    #[test]
+    fn test_replace_value_only_matches_modify() {
+        let (mut general, paths) = three_leaf_trie();
+        let (mut fast, _) = three_leaf_trie();
+        let new_value = slot_rlp_from_value(ru256::from(7));
+
+        general
+            .traverse(paths[1], &Intent::Modify(new_value.clone()))
+            .unwrap();
+        let root = fast
+            .replace_value_only(paths[1], new_value.clone())
+            .unwrap();
+        assert_eq!(root, general.root);
+        assert_eq!(fast.root, general.root);
+        assert_eq!(fast.dump_sorted(), general.dump_sorted());
+        fast.assert_root_matches(paths[1], Some(&new_value))
+            .unwrap();
+
+        let mut absent = paths[0];
+        absent.0[31] = 0xff;
+        let error = fast.replace_value_only(absent, new_value).unwrap_err();
+        assert!(matches!(
+            error,
+            ProofError::ValueReplaceRequiresInclusion(_)
+        ));
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+        assert_eq!(fast.root, root);
+    }
+
+    /// A leaf with two siblings is removed. The parent branch remains.
+    #[test]
+    fn test_remove_key_simple_leaf() {
+        let (mut multi, paths) = three_leaf_trie();
+        let prior_root = multi.root;
+        assert_eq!(multi.remove_key(paths[0]).unwrap(), RemoveOutcome::Removed);
+        assert_ne!(multi.root, prior_root);
+        assert!(multi.traversal_index_for_oracle_task.is_none());
+        multi.traverse(paths[0], &Intent::VerifyExclusion).unwrap();
+        multi
+            .traverse(
+                paths[1],
+                &Intent::VerifyInclusion(slot_rlp_from_value(ru256::from(1))),
+            )
+            .unwrap();
+        // Removing again has no effect.
+        let root = multi.root;
+        assert_eq!(multi.remove_key(paths[0]).unwrap(), RemoveOutcome::Absent);
+        assert_eq!(multi.root, root);
+    }
+
+    /// A leaf with one sibling is removed. The parent branch must be collapsed, which
+    /// requires an oracle task at the grandparent (traversal a...9...4...).
+    #[test]
+    fn test_remove_key_collapsing_branch() {
+        let root =
+            H256::from_str("0x8791994f88cd3fbd74ac304f488e6c836df640825921f7e5a969c1dafbda8955")
+                .unwrap();
+        let mut multi = MultiProof::init(root);
+        multi
+            .insert_proof(proof_str_to_vec(PROOF_KEY_0A6D.to_vec()))
+            .unwrap();
+        let path: H256 = keccak256(
+            H256::from_str("0x0381163500ec1bb2a711ed278aa3caac8cd61ce95bc6c4ce50958a5e1a83494b")
+                .unwrap(),
+        )
+        .into();
+        assert_eq!(
+            multi.remove_key(path).unwrap(),
+            RemoveOutcome::BranchCollapse { traversal_index: 1 }
+        );
+        assert_eq!(multi.traversal_index_for_oracle_task, Some(1));
+        assert_eq!(multi.root, root);
+    }
+
+    /// A modified leaf is consistent with the new root. A node that no longer matches its
+    /// hash (e.g., a modification that was not cascaded) is detected.
+    #[test]
+    fn test_assert_root_matches_after_modify() {
+        let (mut multi, paths) = three_leaf_trie();
+        let new_value = slot_rlp_from_value(ru256::from(2));
+        multi
+            .traverse(paths[0], &Intent::Modify(new_value.clone()))
+            .unwrap();
+        multi
+            .assert_root_matches(paths[0], Some(&new_value))
+            .unwrap();
+        // An included key is not consistent with an exclusion.
+        assert!(multi.assert_root_matches(paths[1], None).is_err());
+
+        // Corrupt the leaf, leaving it under its old hash.
+        let leaf_hash = multi
+            .traverse(paths[0], &Intent::VerifyInclusion(new_value.clone()))
+            .unwrap()
+            .last()
+            .unwrap()
+            .node_hash;
+        let mut leaf: Vec<Vec<u8>> = rlp::decode_list(multi.get_node(&leaf_hash).unwrap());
+        leaf[1] = slot_rlp_from_value(ru256::from(3));
+        multi
+            .data
+            .insert(leaf_hash, Node::try_from(leaf).unwrap().to_rlp_list());
+        assert!(matches!(
+            multi.assert_root_matches(paths[0], Some(&slot_rlp_from_value(ru256::from(3)))),
+            Err(ProofError::InconsistentAfterChange(_))
+        ));
+    }
+
+    #[test]
+    fn test_divergence_index_inclusion_proof() {
+        let mut multi = MultiProof::init(
+            H256::from_str("0x8791994f88cd3fbd74ac304f488e6c836df640825921f7e5a969c1dafbda8955")
+                .unwrap(),
+        );
+        multi
+            .insert_proof(proof_str_to_vec(PROOF_KEY_0A6D.to_vec()))
+            .unwrap();
+        let path: H256 = keccak256(
+            H256::from_str("0x0381163500ec1bb2a711ed278aa3caac8cd61ce95bc6c4ce50958a5e1a83494b")
+                .unwrap(),
+        )
+        .into();
+        assert_eq!(multi.divergence_index(path).unwrap(), None);
+    }
+
+    /// The exclusion proof ends in an extension node (nibble 0x3) at the fourth nibble
+    /// of the path (0x7baa...).
+    #[test]
+    fn test_divergence_index_exclusion_proof() {
+        let mut multi = MultiProof::init(
+            H256::from_str("0x6b93962316b2fbd616359d59b41a6ca880f97f99bf631aeaab740b8927691654")
+                .unwrap(),
+        );
+        multi
+            .insert_proof(proof_str_to_vec(PROOF_KEY_8C87_PRE.to_vec()))
+            .unwrap();
+        let path: H256 = keccak256(
+            H256::from_str("0x8c874ac9f7bd5ae2f2c60e6a4f1760c4c54770f4781c666f7ae305e1e70add32")
+                .unwrap(),
+        )
+        .into();
+        assert_eq!(multi.divergence_index(path).unwrap(), Some(3));
+    }
+
+    /// Tests that a key that diverges from a leaf returns that leaf.
+    #[test]
+    fn test_nearest_existing_leaf() {
+        let (multi, paths) = three_leaf_trie();
+        let nibbles = |path: H256| -> Vec<u8> {
+            path.as_bytes()
+                .iter()
+                .flat_map(|byte| [byte >> 4, byte & 0x0f])
+                .collect()
+        };
+        // Shares the first two nibbles with the first leaf.
+        let mut excluded = paths[0];
+        excluded.0[31] = 0xff;
+        let (path, value) = multi.nearest_existing(excluded).unwrap().unwrap();
+        assert_eq!(path, nibbles(paths[0]));
+        assert_eq!(value, slot_rlp_from_value(ru256::from(1)));
+
+        // Inclusion proof.
+        assert!(multi.nearest_existing(paths[1]).unwrap().is_none());
+        // Empty branch item (0x13..) has no single nearest key.
+        let mut excluded = paths[0];
+        excluded.0[0] = 0x13;
+        assert!(multi.nearest_existing(excluded).unwrap().is_none());
+    }
+
+    /// Tests that errors report a kind, including errors wrapped from other crates.
+    #[test]
+    fn test_proof_error_kind() {
+        let (mut multi, paths) = three_leaf_trie();
+        let missing = MultiProof::init(H256::repeat_byte(1))
+            .nearest_existing(paths[0])
+            .unwrap_err();
+        assert!(matches!(missing, ProofError::NoProofNodeForHash(_)));
+        assert_eq!(missing.kind(), ErrorKind::MissingNode);
+
+        let excluded = multi
+            .traverse(paths[0], &Intent::VerifyExclusion)
+            .unwrap_err();
+        assert!(matches!(excluded, ProofError::ExclusionRequired));
+        assert_eq!(excluded.kind(), ErrorKind::MalformedProof);
+
+        let wrapped = ProofError::from(ModifyError::PathError(PathError::InvalidIndex));
+        assert_eq!(wrapped.kind(), ErrorKind::Internal);
+        let unsupported = ProofError::from(ModifyError::BranchValueWithoutChildren);
+        assert_eq!(unsupported.kind(), ErrorKind::Unsupported);
+    }
+
+    /// Tests that a trie with the empty trie root excludes every key, and that a first key
+    /// can be added.
+    #[test]
+    fn test_empty_trie_root_exclusion() {
+        let mut multi = MultiProof::init(H256(EMPTY_TRIE_ROOT));
+        let path = H256::repeat_byte(0xab);
+        assert!(multi
+            .traverse(path, &Intent::VerifyExclusion)
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            multi.traverse(path, &Intent::VerifyInclusion(vec![1])),
+            Err(ProofError::InclusionRequired)
+        ));
+        assert_eq!(multi.remove_key(path).unwrap(), RemoveOutcome::Absent);
+
+        let value = slot_rlp_from_value(ru256::from(1));
+        multi
+            .traverse(path, &Intent::Modify(value.clone()))
+            .unwrap();
+        assert_ne!(multi.root, H256(EMPTY_TRIE_ROOT));
+        multi
+            .traverse(path, &Intent::VerifyInclusion(value))
+            .unwrap();
+    }
+
+    /// A leaf that stores the zero encoding is an inclusion proof of zero, unlike a key
+    /// that is absent from the trie.
+    #[test]
+    fn test_inclusion_proof_of_zero_value() {
+        let zero = slot_rlp_from_value(ru256::ZERO);
+        let one = slot_rlp_from_value(ru256::from(1));
+        let mut paths = vec![];
+        let mut leaf_nodes = vec![];
+        let mut branch: Vec<Vec<u8>> = (0..17).map(|_| vec![]).collect();
+        for (nibble, value) in [zero.clone(), one].into_iter().enumerate() {
+            let mut path = [0u8; 32];
+            path[0] = (nibble as u8) << 4;
+            paths.push(H256::from(path));
+            // One nibble is used by the branch, 63 remain for the leaf (odd, prefix 0x3).
+            let mut leaf_path = path.to_vec();
+            leaf_path[0] = 0x30;
+            let leaf_rlp = Node::try_from(vec![leaf_path, value])
+                .unwrap()
+                .to_rlp_list();
+            branch[nibble] = keccak256(&leaf_rlp).to_vec();
+            leaf_nodes.push(leaf_rlp);
+        }
+        let branch_rlp = Node::try_from(branch).unwrap().to_rlp_list();
+        let mut multi = MultiProof::default();
+        for leaf_rlp in leaf_nodes {
+            let proof = vec![&branch_rlp, &leaf_rlp]
+                .into_iter()
+                .map(|node| Bytes::from(node.clone()))
+                .collect();
+            multi.insert_proof(proof).unwrap();
+        }
+
+        multi
+            .traverse(paths[0], &Intent::VerifyInclusion(zero.clone()))
+            .unwrap();
+        assert!(matches!(
+            multi.traverse(paths[0], &Intent::VerifyExclusion),
+            Err(ProofError::ExclusionRequired)
+        ));
+        assert!(matches!(
+            multi.traverse(paths[0], &Intent::VerifyAbsentEquals(zero.clone())),
+            Err(ProofError::ExclusionRequired)
+        ));
+        // A key that is absent is not an inclusion proof of zero.
+        let absent = H256::repeat_byte(0x55);
+        assert!(matches!(
+            multi.traverse(absent, &Intent::VerifyInclusion(zero.clone())),
+            Err(ProofError::ZeroValueExcluded)
+        ));
+        multi
+            .traverse(absent, &Intent::VerifyAbsentEquals(zero))
+            .unwrap();
+    }
+
+    /// Keys below a branch that has a value (item 16). Modifying and removing children
+    /// keeps the value and does not treat it as a child.
+    #[test]
+    fn test_modify_through_branch_with_value() {
+        let branch_value = vec![0x83, 0x01, 0x02, 0x03];
+        let mut paths = vec![];
+        let mut leaf_nodes = vec![];
+        let mut child_branch: Vec<Vec<u8>> = (0..17).map(|_| vec![]).collect();
+        child_branch[BRANCH_VALUE_INDEX] = branch_value.clone();
+        for nibble in 0..2u8 {
+            let mut path = [0u8; 32];
+            path[0] = 0x10 + nibble;
+            paths.push(H256::from(path));
+            let mut leaf_path = vec![0x20];
+            leaf_path.extend_from_slice(&path[1..]);
+            let leaf_rlp = Node::try_from(vec![leaf_path, slot_rlp_from_value(ru256::from(1))])
+                .unwrap()
+                .to_rlp_list();
+            child_branch[nibble as usize] = keccak256(&leaf_rlp).to_vec();
+            leaf_nodes.push(leaf_rlp);
+        }
+        let child_branch_rlp = Node::try_from(child_branch).unwrap().to_rlp_list();
+        let mut root_branch: Vec<Vec<u8>> = (0..17).map(|_| vec![]).collect();
+        root_branch[1] = keccak256(&child_branch_rlp).to_vec();
+        root_branch[2] = keccak256([0u8]).to_vec();
+        let root_branch_rlp = Node::try_from(root_branch).unwrap().to_rlp_list();
+        let mut multi = MultiProof::default();
+        for leaf_rlp in leaf_nodes {
+            let proof = vec![&root_branch_rlp, &child_branch_rlp, &leaf_rlp]
+                .into_iter()
+                .map(|node| Bytes::from(node.clone()))
+                .collect();
+            multi.insert_proof(proof).unwrap();
+        }
+        // Excluded at the child branch (item 5 is empty).
+        let mut excluded_path = [0u8; 32];
+        excluded_path[0] = 0x15;
+        let value_of_child_branch = |multi: &MultiProof| {
+            let visited = multi
+                .clone()
+                .traverse(H256::from(excluded_path), &Intent::VerifyExclusion)
+                .unwrap();
+            let branch: Vec<Vec<u8>> =
+                rlp::decode_list(multi.get_node(&visited[1].node_hash).unwrap());
+            branch[BRANCH_VALUE_INDEX].clone()
+        };
+
+        // Modify
+        let new_value = slot_rlp_from_value(ru256::from(2));
+        multi
+            .traverse(paths[0], &Intent::Modify(new_value.clone()))
+            .unwrap();
+        multi
+            .traverse(paths[0], &Intent::VerifyInclusion(new_value))
+            .unwrap();
+        assert_eq!(value_of_child_branch(&multi), branch_value);
+
+        // Remove one of two children. The branch remains and no oracle is needed.
+        assert_eq!(multi.remove_key(paths[1]).unwrap(), RemoveOutcome::Removed);
+        assert_eq!(value_of_child_branch(&multi), branch_value);
+
+        // Remove the last child.
+        assert!(matches!(
+            multi.remove_key(paths[0]),
+            Err(ProofError::ModifyError(
+                ModifyError::BranchValueWithoutChildren
+            ))
+        ));
+    }
+
+    /// Tests that the covered paths are those of the leaves a proof was built for.
+    #[test]
+    fn test_paths_covered() {
+        let (multi, paths) = three_leaf_trie();
+        let covered = multi.paths_covered().unwrap();
+        assert_eq!(covered.leaves, paths.iter().copied().collect());
+        for path in &paths {
+            assert!(covered.decides(*path));
+        }
+        // Excluded at the root branch (item 3 is empty).
+        assert!(covered.decides(H256::repeat_byte(0x30)));
+        // Excluded at the child branch (item 5 is empty).
+        assert!(covered.decides(H256::repeat_byte(0x15)));
+        // Root branch item 2 refers to a node that is not in the proof.
+        assert!(!covered.decides(H256::repeat_byte(0x20)));
+    }
+
+    /// Children that are not referred to by hash (inline nodes) are not followed, and the
+    /// paths below them are not decided.
+    #[test]
+    fn test_paths_covered_with_inline_child() {
+        let inline_child = vec![0xc2, 0x20, 0x01];
+        let root_hash = H256::repeat_byte(1);
+        let extension_hash = H256::repeat_byte(2);
+        let mut branch: Vec<Vec<u8>> = (0..17).map(|_| vec![]).collect();
+        branch[0] = inline_child.clone();
+        branch[1] = extension_hash.as_bytes().to_vec();
+        // Extension (odd, one nibble: 2) to an inline child.
+        let extension: Vec<Vec<u8>> = vec![vec![0x12], inline_child];
+        let mut multi = MultiProof::init(root_hash);
+        multi
+            .data
+            .insert(root_hash, Node::try_from(branch).unwrap().to_rlp_list());
+        multi.data.insert(
+            extension_hash,
+            Node::try_from(extension).unwrap().to_rlp_list(),
+        );
+        let covered = multi.paths_covered().unwrap();
+        assert!(covered.leaves.is_empty());
+        // Below the inline child of the branch.
+        assert!(!covered.decides(H256::repeat_byte(0x01)));
+        // Below the inline child of the extension.
+        assert!(!covered.decides(H256::repeat_byte(0x12)));
+        // Diverges from the extension.
+        assert!(covered.decides(H256::repeat_byte(0x13)));
+        // Empty item in the branch.
+        assert!(covered.decides(H256::repeat_byte(0x30)));
+    }
+
+    #[test]
+    fn test_depth() {
+        let (multi, _) = three_leaf_trie();
+        assert_eq!(multi.depth(), 3);
+        assert_eq!(MultiProof::default().depth(), 0);
+
+        let root =
+            H256::from_str("0x8791994f88cd3fbd74ac304f488e6c836df640825921f7e5a969c1dafbda8955")
+                .unwrap();
+        let mut multi = MultiProof::init(root);
+        multi
+            .insert_proof(proof_str_to_vec(PROOF_KEY_0A6D.to_vec()))
+            .unwrap();
+        assert_eq!(multi.depth(), PROOF_KEY_0A6D.len());
+    }
+
+    #[test]
+    fn test_dump_sorted() {
+        let mut multi = MultiProof::default();
+        multi
+            .insert_proof(proof_str_to_vec(PROOF_KEY_0A6D.to_vec()))
+            .unwrap();
+        let (multi_b, _) = three_leaf_trie();
+        for (hash, node) in multi_b.data {
+            multi.data.insert(hash, node);
+        }
+        let dump = multi.dump_sorted();
+        assert_eq!(dump.len(), multi.data.len());
+        assert!(dump.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        for (hash, node) in &dump {
+            assert_eq!(multi.get_node(hash).unwrap(), node.as_slice());
+        }
+        assert_eq!(dump, multi.clone().dump_sorted());
+    }
+
+    /// A node that refers to itself is not followed.
+    #[test]
+    fn test_depth_with_cycle() {
+        let hash = H256::repeat_byte(1);
+        let mut branch: Vec<Vec<u8>> = (0..17).map(|_| vec![]).collect();
+        branch[0] = hash.as_bytes().to_vec();
+        branch[1] = H256::repeat_byte(2).as_bytes().to_vec();
+        let mut multi = MultiProof::init(hash);
+        multi
+            .data
+            .insert(hash, Node::try_from(branch).unwrap().to_rlp_list());
+        assert_eq!(multi.depth(), 1);
+    }
+
+    /// Nodes reachable from more than one parent are only visited once, so a proof where
+    /// every branch refers to the next branch sixteen times does not take 16^levels steps.
+    #[test]
+    fn test_depth_with_shared_children() {
+        let levels = 64;
+        let hashes: Vec<H256> = (0..levels).map(H256::from_low_u64_be).collect();
+        let mut multi = MultiProof::init(hashes[0]);
+        for level in 0..levels as usize {
+            let mut branch: Vec<Vec<u8>> = (0..17).map(|_| vec![]).collect();
+            if let Some(next) = hashes.get(level + 1) {
+                for child in branch.iter_mut().take(16) {
+                    *child = next.as_bytes().to_vec();
+                }
+            }
+            multi
+                .data
+                .insert(hashes[level], Node::try_from(branch).unwrap().to_rlp_list());
+        }
+        assert_eq!(multi.depth(), levels as usize);
+    }
+
+    /// Tests that a step from the root follows the branch item for the first nibble.
+    #[test]
+    fn test_step_branch() {
+        let (multi, paths) = three_leaf_trie();
+        let root: Vec<Vec<u8>> = rlp::decode_list(multi.data.get(&multi.root).unwrap());
+        let mut traversal = NibblePath::init(paths[0].as_bytes());
+        let expected = H256::from_slice(&root[1]);
+        assert_eq!(
+            step(&root, &mut traversal).unwrap(),
+            StepResult::Next(expected)
+        );
+        assert_eq!(traversal.visiting_index(), 1);
+
+        let mut traversal = NibblePath::init(H256::repeat_byte(0x30).as_bytes());
+        assert_eq!(
+            step(&root, &mut traversal).unwrap(),
+            StepResult::BranchExclusion
+        );
+    }
+
+    /// Tests that plan and view visit the same nodes for inclusion and exclusion paths.
+    #[test]
+    fn test_plan_and_view_have_same_next_hops() {
+        let (multi, paths) = three_leaf_trie();
+        let mut excluded = [0u8; 32];
+        excluded[0] = 0x13;
+        let cases = paths
+            .into_iter()
+            .map(|path| {
+                (
+                    path,
+                    Intent::VerifyInclusion(slot_rlp_from_value(ru256::from(1))),
+                )
+            })
+            .chain([
+                (H256::repeat_byte(0x30), Intent::VerifyExclusion),
+                (H256::from(excluded), Intent::VerifyExclusion),
+            ]);
+        for (path, intent) in cases {
+            let planned: Vec<H256> = multi
+                .plan(path, &intent)
+                .unwrap()
+                .visited
+                .iter()
+                .map(|node| node.node_hash)
+                .collect();
+            let viewed: Vec<H256> = multi
+                .view(path)
+                .unwrap()
+                .inner()
+                .iter()
+                .map(|node| H256::from(keccak256(node)))
+                .collect();
+            assert_eq!(planned, viewed);
+        }
+    }
+
+    /// Tests that a node that refers to itself is detected when viewing a path, rather
+    /// than followed.
+    #[test]
+    fn test_view_detects_traversal_loop() {
+        let looping = H256::repeat_byte(0xab);
+        // Branch with every child referring to the branch itself.
+        let mut stream = RlpStream::new_list(17);
+        for _ in 0..16 {
+            stream.append(&looping.as_bytes().to_vec());
+        }
+        stream.append_empty_data();
+        let mut multi = MultiProof::init(looping);
+        multi.data.insert(looping, stream.out().to_vec());
+
+        let err = multi.view(H256::repeat_byte(0x11)).err().unwrap();
+        assert!(matches!(&err, ProofError::TraversalLoop(hash) if hash == &hex_encode(looping)));
+        assert_eq!(err.kind(), ErrorKind::MalformedProof);
+    }
 }
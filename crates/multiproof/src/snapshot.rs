@@ -0,0 +1,172 @@
+//! For holding the state of a parcel as a single trie that can be queried offline.
+//!
+//! The account trie and every storage trie share one node store, keyed by node hash.
+//! Storage values are found by following the account leaf to its storage root.
+use std::collections::HashSet;
+
+use archors_types::{
+    constants::EMPTY_TRIE_ROOT,
+    state::{RequiredBlockState, StateError},
+};
+use archors_verify::{eip1186::Account, path::NibblePath};
+use ethers::{
+    types::{H160, H256, U256},
+    utils::keccak256,
+};
+use thiserror::Error;
+
+use crate::{
+    proof::{step, MultiProof, ProofError, StepResult},
+    utils::hex_encode,
+};
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("Account {0:?} in the parcel differs from the account trie")]
+    AccountMismatch(H160),
+    #[error("RLP decode error {0}")]
+    DecodeError(#[from] rlp::DecoderError),
+    #[error("ProofError {0}")]
+    ProofError(#[from] ProofError),
+    #[error("StateError {0}")]
+    StateError(#[from] StateError),
+    #[error(
+        "Storage key {key:?} of account {address:?} in the parcel differs from the storage trie"
+    )]
+    StorageMismatch { address: H160, key: H256 },
+}
+
+/// The state of a parcel as one trie structure holding the account trie and all storage
+/// tries.
+///
+/// Only the paths in the parcel are present. Queries for other paths return
+/// `ProofError::NoProofNodeForHash` where the trie is incomplete.
+#[derive(Debug)]
+pub struct StateSnapshot {
+    /// Nodes of all tries. The root is the state root.
+    trie: MultiProof,
+}
+
+impl StateSnapshot {
+    /// Builds the snapshot from a parcel.
+    ///
+    /// Every account and storage value in the parcel is checked against the tries. The state
+    /// root of the parcel (`RequiredBlockState::state_root`) should be checked against a
+    /// trusted block header before the snapshot is relied upon.
+    pub fn from_parcel(state: &RequiredBlockState) -> Result<Self, SnapshotError> {
+        state.verify()?;
+        let root = state.state_root()?;
+        let nodes = state.trie_nodes.iter().map(|node| node.to_vec());
+        let snapshot = Self {
+            trie: MultiProof::from_nodes(root, nodes),
+        };
+        for proof in state.compact_eip1186_proofs.iter() {
+            let address = H160::from_slice(&proof.address);
+            let claimed: Account = rlp::decode(
+                &state
+                    .account_leaf_rlp(address)
+                    .ok_or(SnapshotError::AccountMismatch(address))?,
+            )?;
+            let consistent = match snapshot.account(address)? {
+                Some(account) => account == claimed,
+                None => claimed.nonce.is_zero() && claimed.balance.is_zero(),
+            };
+            if !consistent {
+                return Err(SnapshotError::AccountMismatch(address));
+            }
+            for storage in proof.storage_proofs.iter() {
+                let key = H256::from_slice(&storage.key);
+                let value = U256::from_big_endian(&storage.value);
+                if snapshot.storage(address, key)? != value {
+                    return Err(SnapshotError::StorageMismatch { address, key });
+                }
+            }
+        }
+        Ok(snapshot)
+    }
+    /// Returns the state root of the snapshot.
+    pub fn state_root(&self) -> H256 {
+        self.trie.root
+    }
+    /// Returns the account at the address, or None if the account is not in the trie.
+    pub fn account(&self, address: H160) -> Result<Option<Account>, SnapshotError> {
+        let path = H256::from(keccak256(address.as_bytes()));
+        match self.lookup(self.trie.root, path)? {
+            Some(leaf_value) => Ok(Some(rlp::decode(&leaf_value)?)),
+            None => Ok(None),
+        }
+    }
+    /// Returns the value of a storage slot. Slots that are not in the trie, including those
+    /// of absent accounts, are zero.
+    pub fn storage(&self, address: H160, key: H256) -> Result<U256, SnapshotError> {
+        let Some(account) = self.account(address)? else {
+            return Ok(U256::zero());
+        };
+        let path = H256::from(keccak256(key.as_bytes()));
+        match self.lookup(account.storage_hash, path)? {
+            Some(leaf_value) => Ok(rlp::decode(&leaf_value)?),
+            None => Ok(U256::zero()),
+        }
+    }
+    /// Follows a path from the root of a trie, returning the leaf value if the path is in
+    /// the trie.
+    fn lookup(&self, root: H256, path: H256) -> Result<Option<Vec<u8>>, ProofError> {
+        if root == H256::from(EMPTY_TRIE_ROOT) {
+            return Ok(None);
+        }
+        let mut traversal = NibblePath::init(path.as_bytes());
+        let mut next_node_hash = root;
+        let mut visited_hashes: HashSet<H256> = HashSet::new();
+        loop {
+            if !visited_hashes.insert(next_node_hash) {
+                return Err(ProofError::TraversalLoop(hex_encode(next_node_hash)));
+            }
+            let node: Vec<Vec<u8>> = rlp::decode_list(self.trie.get_node(&next_node_hash)?);
+            match step(&node, &mut traversal)? {
+                StepResult::Next(child_hash) => next_node_hash = child_hash,
+                StepResult::LeafInclusion => {
+                    return node
+                        .get(1)
+                        .cloned()
+                        .map(Some)
+                        .ok_or(ProofError::LeafHasNoData)
+                }
+                StepResult::BranchExclusion
+                | StepResult::ExtensionExclusion(_)
+                | StepResult::LeafExclusion(_) => return Ok(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use archors_types::fixture::{parcel, prior_block_proofs};
+
+    use super::*;
+
+    /// Tests that a snapshot of a fixture parcel has the slot values of the proofs.
+    #[test]
+    fn test_snapshot_storage_value() {
+        let state = parcel();
+        let proofs = prior_block_proofs();
+        let snapshot = StateSnapshot::from_parcel(&state).unwrap();
+
+        let proof = proofs
+            .values()
+            .find(|proof| proof.storage_proof.iter().any(|slot| !slot.value.is_zero()))
+            .unwrap();
+        let account = snapshot.account(proof.address).unwrap().unwrap();
+        assert_eq!(account.balance, proof.balance);
+        assert_eq!(account.storage_hash, proof.storage_hash);
+        let slot = proof
+            .storage_proof
+            .iter()
+            .find(|slot| !slot.value.is_zero())
+            .unwrap();
+        assert_eq!(
+            snapshot.storage(proof.address, slot.key).unwrap(),
+            slot.value
+        );
+    }
+}
@@ -0,0 +1,298 @@
+//! For recording the calls made by a transaction as a tree, in the style of the Geth
+//! `callTracer`.
+//!
+//! Each call (or contract creation) records the calls it made, which is often more useful
+//! than the flat, per-opcode EIP-3155 trace.
+
+use std::{cell::RefCell, rc::Rc};
+
+use archors_types::utils::ru256_to_eu256;
+use ethers::types::{Bytes as EBytes, H160, U256 as EU256, U64};
+use revm::{
+    interpreter::{CallInputs, CallScheme, CreateInputs, CreateScheme, Gas, InstructionResult},
+    primitives::{Bytes, B160},
+    Database, EVMData, Inspector,
+};
+use serde::Serialize;
+
+/// A call and the calls it made, serialized as by the Geth `callTracer`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CallFrame {
+    /// CALL, CALLCODE, DELEGATECALL, STATICCALL, CREATE or CREATE2.
+    #[serde(rename = "type")]
+    pub call_type: String,
+    pub from: H160,
+    /// The called address, or the created address. Absent if a creation failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<H160>,
+    /// Absent for DELEGATECALL and STATICCALL, which cannot transfer value.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<EU256>,
+    /// Gas available to the call.
+    pub gas: U64,
+    pub gas_used: U64,
+    pub input: EBytes,
+    pub output: EBytes,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub calls: Vec<CallFrame>,
+}
+
+/// An inspector that records the tree of calls made by a transaction.
+///
+/// The inspector is consumed by the EVM, so clones share the same record.
+#[derive(Clone, Debug, Default)]
+pub struct CallInspector {
+    record: Rc<RefCell<CallRecord>>,
+}
+
+#[derive(Debug, Default)]
+struct CallRecord {
+    /// Calls that have started and not yet ended, outermost first.
+    open: Vec<CallFrame>,
+    /// The outermost call, once it has ended.
+    root: Option<CallFrame>,
+}
+
+impl CallInspector {
+    /// Returns the outermost call of the transaction, with all nested calls. None if the
+    /// transaction has not finished executing.
+    pub fn call_tree(&self) -> Option<CallFrame> {
+        self.record.borrow().root.clone()
+    }
+    fn start(&self, frame: CallFrame) {
+        self.record.borrow_mut().open.push(frame);
+    }
+    /// Completes the innermost open call and adds it to its parent.
+    fn end(&self, gas: &Gas, result: InstructionResult, output: &Bytes, created: Option<B160>) {
+        let mut record = self.record.borrow_mut();
+        let Some(mut frame) = record.open.pop() else {
+            return;
+        };
+        frame.gas_used = gas.spend().into();
+        frame.output = EBytes::from(output.to_vec());
+        frame.error = call_error(result);
+        if frame.call_type.starts_with("CREATE") {
+            frame.to = created.map(|address| H160::from(address.0));
+        }
+        match record.open.last_mut() {
+            Some(parent) => parent.calls.push(frame),
+            None => record.root = Some(frame),
+        }
+    }
+}
+
+impl<DB: Database> Inspector<DB> for CallInspector {
+    fn call(
+        &mut self,
+        _data: &mut EVMData<'_, DB>,
+        inputs: &mut CallInputs,
+        _is_static: bool,
+    ) -> (InstructionResult, Gas, Bytes) {
+        let (call_type, from, value) = match inputs.context.scheme {
+            CallScheme::Call => ("CALL", inputs.context.caller, Some(inputs.transfer.value)),
+            CallScheme::CallCode => (
+                "CALLCODE",
+                inputs.context.caller,
+                Some(inputs.transfer.value),
+            ),
+            // Executes in the context of the calling contract.
+            CallScheme::DelegateCall => ("DELEGATECALL", inputs.context.address, None),
+            CallScheme::StaticCall => ("STATICCALL", inputs.context.caller, None),
+        };
+        self.start(CallFrame {
+            call_type: call_type.to_string(),
+            from: H160::from(from.0),
+            to: Some(H160::from(inputs.contract.0)),
+            value: value.map(ru256_to_eu256),
+            gas: inputs.gas_limit.into(),
+            input: EBytes::from(inputs.input.to_vec()),
+            ..Default::default()
+        });
+        (InstructionResult::Continue, Gas::new(0), Bytes::new())
+    }
+
+    fn call_end(
+        &mut self,
+        _data: &mut EVMData<'_, DB>,
+        _inputs: &CallInputs,
+        remaining_gas: Gas,
+        ret: InstructionResult,
+        out: Bytes,
+        _is_static: bool,
+    ) -> (InstructionResult, Gas, Bytes) {
+        self.end(&remaining_gas, ret, &out, None);
+        (ret, remaining_gas, out)
+    }
+
+    fn create(
+        &mut self,
+        _data: &mut EVMData<'_, DB>,
+        inputs: &mut CreateInputs,
+    ) -> (InstructionResult, Option<B160>, Gas, Bytes) {
+        let call_type = match inputs.scheme {
+            CreateScheme::Create => "CREATE",
+            CreateScheme::Create2 { .. } => "CREATE2",
+        };
+        self.start(CallFrame {
+            call_type: call_type.to_string(),
+            from: H160::from(inputs.caller.0),
+            value: Some(ru256_to_eu256(inputs.value)),
+            gas: inputs.gas_limit.into(),
+            input: EBytes::from(inputs.init_code.to_vec()),
+            ..Default::default()
+        });
+        (InstructionResult::Continue, None, Gas::new(0), Bytes::new())
+    }
+
+    fn create_end(
+        &mut self,
+        _data: &mut EVMData<'_, DB>,
+        _inputs: &CreateInputs,
+        ret: InstructionResult,
+        address: Option<B160>,
+        remaining_gas: Gas,
+        out: Bytes,
+    ) -> (InstructionResult, Option<B160>, Gas, Bytes) {
+        self.end(&remaining_gas, ret, &out, address);
+        (ret, address, remaining_gas, out)
+    }
+}
+
+/// The error of a call that did not succeed, as reported by the Geth `callTracer`.
+///
+/// Results without a Geth equivalent (e.g., a precompile error) use the revm name.
+fn call_error(result: InstructionResult) -> Option<String> {
+    let error = match result {
+        InstructionResult::Continue
+        | InstructionResult::Stop
+        | InstructionResult::Return
+        | InstructionResult::SelfDestruct => return None,
+        InstructionResult::Revert => "execution reverted",
+        InstructionResult::OutOfGas
+        | InstructionResult::MemoryOOG
+        | InstructionResult::MemoryLimitOOG
+        | InstructionResult::PrecompileOOG
+        | InstructionResult::InvalidOperandOOG => "out of gas",
+        InstructionResult::CallTooDeep => "max call depth exceeded",
+        InstructionResult::OutOfFund => "insufficient balance for transfer",
+        InstructionResult::OpcodeNotFound | InstructionResult::NotActivated => "invalid opcode",
+        InstructionResult::InvalidFEOpcode => "invalid opcode: INVALID",
+        InstructionResult::InvalidJump => "invalid jump destination",
+        InstructionResult::CallNotAllowedInsideStatic
+        | InstructionResult::StateChangeDuringStaticCall => "write protection",
+        InstructionResult::StackUnderflow => "stack underflow",
+        InstructionResult::StackOverflow => "stack limit reached",
+        InstructionResult::OutOfOffset => "return data out of bounds",
+        InstructionResult::CreateCollision => "contract address collision",
+        InstructionResult::NonceOverflow => "nonce uint64 overflow",
+        InstructionResult::CreateContractSizeLimit => "max code size exceeded",
+        InstructionResult::CreateContractStartingWithEF => "invalid code: must not begin with 0xef",
+        InstructionResult::CreateInitcodeSizeLimit => "max initcode size exceeded",
+        other => return Some(format!("{other:?}")),
+    };
+    Some(error.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use ethers::types::Transaction;
+
+    use crate::{
+        evm::BlockEvm,
+        state::build_state_from_proofs,
+        test_utils::{add_contract, funded_state, test_block},
+    };
+
+    use super::*;
+
+    /// Tests that a call made by the called contract is nested in the call tree.
+    #[test]
+    fn test_call_tree_with_nested_call() {
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let caller = H160::from_str("0x0200000000000000000000000000000000000000").unwrap();
+        let callee = H160::from_str("0x0400000000000000000000000000000000000000").unwrap();
+        let mut state = funded_state(sender);
+        // CALL(gas, callee, 0, 0, 0, 0, 0), POP, STOP
+        let mut caller_code = vec![0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00];
+        caller_code.push(0x73);
+        caller_code.extend_from_slice(callee.as_bytes());
+        caller_code.extend_from_slice(&[0x5a, 0xf1, 0x50, 0x00]);
+        add_contract(&mut state, caller, caller_code);
+        // MSTORE(0, 42), RETURN(0, 32)
+        let callee_code = vec![0x60, 0x2a, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3];
+        add_contract(&mut state, callee, callee_code);
+
+        let tx = Transaction {
+            from: sender,
+            to: Some(caller),
+            gas: 100_000u64.into(),
+            gas_price: Some(ethers::types::U256::default()),
+            ..Default::default()
+        };
+        let block = test_block(vec![]);
+        let mut evm = BlockEvm::init_from_db(build_state_from_proofs(&state).unwrap());
+        evm.add_block_environment(&block).unwrap();
+        let (outcome, tree) = evm
+            .add_transaction_environment(tx)
+            .unwrap()
+            .execute_with_call_inspector()
+            .unwrap();
+        assert!(outcome.result.is_success());
+
+        let tree = tree.unwrap();
+        assert_eq!(tree.call_type, "CALL");
+        assert_eq!(tree.from, sender);
+        assert_eq!(tree.to, Some(caller));
+        assert_eq!(tree.value, Some(EU256::zero()));
+        assert_eq!(tree.calls.len(), 1);
+        let nested = &tree.calls[0];
+        assert_eq!(nested.call_type, "CALL");
+        assert_eq!(nested.from, caller);
+        assert_eq!(nested.to, Some(callee));
+        assert!(nested.calls.is_empty());
+        assert_eq!(nested.error, None);
+        let mut expected_output = [0u8; 32];
+        expected_output[31] = 42;
+        assert_eq!(nested.output.to_vec(), expected_output);
+        assert!(nested.gas_used > U64::zero());
+        assert!(tree.gas_used > nested.gas_used);
+
+        let json = serde_json::to_value(&tree).unwrap();
+        assert_eq!(json["type"], "CALL");
+        assert_eq!(json["calls"][0]["to"], format!("{callee:?}"));
+        assert!(json["calls"][0].get("gasUsed").is_some());
+        assert!(json["calls"][0].get("error").is_none());
+        assert!(json["calls"][0].get("calls").is_none());
+    }
+
+    /// Tests that errors use the Geth `callTracer` strings.
+    #[test]
+    fn test_call_error_geth_strings() {
+        assert_eq!(call_error(InstructionResult::Return), None);
+        assert_eq!(
+            call_error(InstructionResult::Revert).as_deref(),
+            Some("execution reverted")
+        );
+        assert_eq!(
+            call_error(InstructionResult::OutOfGas).as_deref(),
+            Some("out of gas")
+        );
+        assert_eq!(
+            call_error(InstructionResult::MemoryOOG).as_deref(),
+            Some("out of gas")
+        );
+        assert_eq!(
+            call_error(InstructionResult::InvalidJump).as_deref(),
+            Some("invalid jump destination")
+        );
+        assert_eq!(
+            call_error(InstructionResult::StateChangeDuringStaticCall).as_deref(),
+            Some("write protection")
+        );
+    }
+}
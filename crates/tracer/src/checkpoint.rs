@@ -0,0 +1,77 @@
+//! For pausing block execution after some transactions and resuming it later.
+//!
+//! A checkpoint holds the state in the EVM database after the committed transactions,
+//! along with the state changes accumulated for the post-block state root. Transactions
+//! before the checkpoint are not executed again when resuming.
+
+use std::collections::BTreeMap;
+
+use revm::{
+    db::DbAccount,
+    primitives::{Bytecode, B160, B256, U256},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    state::BlockDb,
+    trace::{PostBlockStateDelta, TraceError},
+};
+
+/// State of a partially executed block.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecutionCheckpoint {
+    /// Index of the next transaction to execute.
+    pub next_tx_index: usize,
+    /// Accounts in the EVM database (touched or loaded from the block state).
+    accounts: BTreeMap<B160, DbAccount>,
+    /// Contract code loaded into the EVM database.
+    contracts: BTreeMap<B256, Bytecode>,
+    /// Block hashes available to the BLOCKHASH opcode.
+    block_hashes: BTreeMap<U256, B256>,
+    /// Changes made by the executed transactions.
+    delta: PostBlockStateDelta,
+}
+
+impl ExecutionCheckpoint {
+    /// Records the state of the EVM database after the transactions before `next_tx_index`.
+    pub(crate) fn new(db: &BlockDb, next_tx_index: usize, delta: PostBlockStateDelta) -> Self {
+        Self {
+            next_tx_index,
+            accounts: db
+                .accounts
+                .iter()
+                .map(|(address, account)| (*address, account.clone()))
+                .collect(),
+            contracts: db
+                .contracts
+                .iter()
+                .map(|(hash, code)| (*hash, code.clone()))
+                .collect(),
+            block_hashes: db
+                .block_hashes
+                .iter()
+                .map(|(number, hash)| (*number, *hash))
+                .collect(),
+            delta,
+        }
+    }
+    /// Replaces the state in the EVM database with the state of the checkpoint.
+    ///
+    /// Returns the changes made by the executed transactions and the index of the next
+    /// transaction to execute.
+    pub(crate) fn restore(self, db: &mut BlockDb) -> (PostBlockStateDelta, usize) {
+        db.accounts = self.accounts.into_iter().collect();
+        db.contracts = self.contracts.into_iter().collect();
+        db.block_hashes = self.block_hashes.into_iter().collect();
+        (self.delta, self.next_tx_index)
+    }
+    /// Returns the checkpoint as JSON.
+    pub fn to_json(&self) -> Result<String, TraceError> {
+        serde_json::to_string(self).map_err(|e| TraceError::CheckpointSerialization(e.to_string()))
+    }
+    /// Reads a checkpoint from JSON.
+    pub fn from_json(json: &str) -> Result<Self, TraceError> {
+        serde_json::from_str(json).map_err(|e| TraceError::CheckpointSerialization(e.to_string()))
+    }
+}
@@ -0,0 +1,59 @@
+//! For the irregular state transition at the mainnet DAO fork block.
+//!
+//! At the fork block, the balances of the DAO and its child DAOs were moved to a refund
+//! contract without a transaction. Executing the transactions alone does not reproduce this,
+//! so the post-block state root would not match the header.
+//!
+//! The accounts to drain are supplied by the caller (see `DAODrainList` in go-ethereum
+//! `params/dao.go`). Their proofs must be present in the block state.
+
+use archors_types::{execution::EvmStateError, utils::hex_encode};
+use revm::primitives::{Account, AccountInfo, HashMap as rHashMap, B160, U256};
+
+use crate::state::BlockDb;
+
+/// Mainnet block at which the DAO fork irregular state transition was applied.
+pub const DAO_FORK_BLOCK: u64 = 1_920_000;
+
+/// Mainnet contract that received the drained balances.
+pub const DAO_REFUND_CONTRACT: &str = "0xbf4ed7b27f1d666546e30d74d50d173d20bca754";
+
+/// Moves the balance of every drained account to the refund contract.
+///
+/// Returns the changed accounts so they can be included in the post-block state delta.
+pub(crate) fn apply_dao_fork_transition(
+    db: &mut BlockDb,
+    drain_list: &[B160],
+    refund_contract: B160,
+) -> Result<rHashMap<B160, Account>, EvmStateError> {
+    let mut changes = rHashMap::default();
+    let mut moved = U256::ZERO;
+    for address in drain_list {
+        let account = db
+            .accounts
+            .get_mut(address)
+            .ok_or_else(|| EvmStateError::NoProofForAddress(hex_encode(address)))?;
+        moved += account.info.balance;
+        account.info.balance = U256::ZERO;
+        changes.insert(*address, balance_change(account.info.clone()));
+    }
+    let refund = db
+        .accounts
+        .get_mut(&refund_contract)
+        .ok_or_else(|| EvmStateError::NoProofForAddress(hex_encode(refund_contract)))?;
+    refund.info.balance += moved;
+    changes.insert(refund_contract, balance_change(refund.info.clone()));
+    Ok(changes)
+}
+
+/// Represents an account whose balance was changed outside of a transaction.
+fn balance_change(info: AccountInfo) -> Account {
+    Account {
+        info,
+        storage: rHashMap::default(),
+        storage_cleared: false,
+        is_destroyed: false,
+        is_touched: true,
+        is_not_existing: false,
+    }
+}
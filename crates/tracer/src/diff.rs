@@ -0,0 +1,158 @@
+//! For per-transaction state diffs in the shape of the Geth prestateTracer diff mode.
+//!
+//! The output can be compared directly against `debug_traceBlockByNumber` with
+//! `{"tracer": "prestateTracer", "tracerConfig": {"diffMode": true}}`.
+//!
+//! Contract code is represented by its hash (`codeHash`) rather than the full bytecode.
+
+use std::collections::BTreeMap;
+
+use revm::primitives::{Account, AccountInfo, HashMap as rHashMap, B160, B256, U256};
+use serde::Serialize;
+
+use crate::trace::TraceError;
+
+/// State changed by a single transaction. Pre-transaction values are in `pre` and the
+/// modified values are in `post`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TxStateDiff {
+    pub pre: BTreeMap<B160, DiffAccount>,
+    pub post: BTreeMap<B160, DiffAccount>,
+}
+
+/// Account values in a state diff. Absent members are omitted.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiffAccount {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance: Option<U256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code_hash: Option<B256>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub storage: BTreeMap<B256, B256>,
+}
+
+impl TxStateDiff {
+    /// Creates the diff for the account changes of one transaction.
+    ///
+    /// The pre-transaction account values are provided by `pre_info`. Storage values
+    /// before the transaction are the original values recorded by the EVM.
+    ///
+    /// Accounts that were only read (no value changed) are omitted. The pre-state of a
+    /// changed account includes all account values, the post-state only those that changed.
+    pub fn from_changes<F>(changes: &rHashMap<B160, Account>, pre_info: F) -> Self
+    where
+        F: Fn(&B160) -> AccountInfo,
+    {
+        let mut diff = TxStateDiff::default();
+        for (address, account) in changes {
+            let before = pre_info(address);
+            let after = &account.info;
+            let mut pre = DiffAccount {
+                balance: Some(before.balance),
+                nonce: Some(before.nonce),
+                code_hash: Some(before.code_hash),
+                storage: BTreeMap::new(),
+            };
+            let mut post = DiffAccount {
+                balance: (after.balance != before.balance).then_some(after.balance),
+                nonce: (after.nonce != before.nonce).then_some(after.nonce),
+                code_hash: (after.code_hash != before.code_hash).then_some(after.code_hash),
+                storage: BTreeMap::new(),
+            };
+            for (key, slot) in &account.storage {
+                if slot.original_value == slot.present_value {
+                    continue;
+                }
+                let key = u256_to_b256(*key);
+                pre.storage.insert(key, u256_to_b256(slot.original_value));
+                post.storage.insert(key, u256_to_b256(slot.present_value));
+            }
+            if post == DiffAccount::default() {
+                continue;
+            }
+            diff.pre.insert(*address, pre);
+            diff.post.insert(*address, post);
+        }
+        diff
+    }
+    /// Returns the diff as JSON ({"pre": {..}, "post": {..}}).
+    pub fn to_json(&self) -> Result<String, TraceError> {
+        serde_json::to_string(self).map_err(|e| TraceError::DiffSerialization(e.to_string()))
+    }
+}
+
+/// Storage keys and values are represented as 32 byte words.
+fn u256_to_b256(value: U256) -> B256 {
+    B256::from(value.to_be_bytes::<32>())
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use revm::primitives::StorageSlot;
+
+    use super::*;
+
+    #[test]
+    fn test_diff_has_pre_and_post_for_modified_slot() {
+        let address = B160::from_str("0x00000000000000adc04c56bf30ac9d3c0aaf14dc").unwrap();
+        let info = AccountInfo {
+            balance: U256::from(5),
+            nonce: 1,
+            ..Default::default()
+        };
+        let mut account = Account {
+            info: info.clone(),
+            storage: rHashMap::default(),
+            storage_cleared: false,
+            is_destroyed: false,
+            is_touched: true,
+            is_not_existing: false,
+        };
+        account.storage.insert(
+            U256::from(1),
+            StorageSlot {
+                original_value: U256::from(2),
+                present_value: U256::from(3),
+            },
+        );
+        // Read but unchanged.
+        account.storage.insert(
+            U256::from(4),
+            StorageSlot {
+                original_value: U256::from(5),
+                present_value: U256::from(5),
+            },
+        );
+        let changes = rHashMap::from_iter([(address, account)]);
+        let diff = TxStateDiff::from_changes(&changes, |_| info.clone());
+
+        let json: serde_json::Value = serde_json::from_str(&diff.to_json().unwrap()).unwrap();
+        let address_key = format!("{address:?}");
+        let slot_key = format!("0x{:064x}", 1);
+        let pre = &json["pre"][&address_key];
+        let post = &json["post"][&address_key];
+        assert_eq!(pre["storage"][&slot_key], format!("0x{:064x}", 2));
+        assert_eq!(post["storage"][&slot_key], format!("0x{:064x}", 3));
+        assert_eq!(pre["storage"].as_object().unwrap().len(), 1);
+        assert_eq!(pre["nonce"], 1);
+        // Unchanged account values are only in the pre-state.
+        assert!(post.get("balance").is_none());
+        assert!(post.get("nonce").is_none());
+
+        // An account that is only read is omitted.
+        let mut read_only = changes;
+        read_only
+            .get_mut(&address)
+            .unwrap()
+            .storage
+            .remove(&U256::from(1));
+        let diff = TxStateDiff::from_changes(&read_only, |_| info.clone());
+        assert!(diff.pre.is_empty());
+        assert!(diff.post.is_empty());
+    }
+}
@@ -1,24 +1,84 @@
 //! For creation and use of an EVM for a single block.
 
-use std::io::stdout;
+use std::{
+    fmt::Display,
+    io::{stdout, Write},
+};
 
-use archors_types::utils::{
-    access_list_e_to_r, eu256_to_ru256, eu256_to_u64, eu64_to_ru256, ru256_to_u64, UtilsError,
+use archors_types::{
+    error::ErrorKind,
+    utils::{
+        access_list_e_to_r, eu256_to_ru256, eu256_to_u64, eu64_to_ru256, ru256_to_u64, UtilsError,
+    },
 };
 use ethers::types::{Block, Transaction};
 use revm::{
-    db::{CacheDB, EmptyDB},
     inspectors::{NoOpInspector, TracerEip3155},
-    primitives::{EVMError, ResultAndState, TransactTo, TxEnv, U256},
-    EVM,
+    primitives::{
+        EVMError, ExecutionResult, InvalidTransaction, ResultAndState, SpecId, TransactTo, TxEnv,
+        U256,
+    },
+    DatabaseCommit, EVM,
 };
 use thiserror::Error;
 
+use crate::{
+    calls::{CallFrame, CallInspector},
+    dao::DAO_FORK_BLOCK,
+    state::BlockDb,
+    storage::{StorageAccess, StorageInspector},
+};
+
+/// Mainnet block of the merge (Paris), after which hard forks activate by timestamp.
+const MAINNET_MERGE_BLOCK: u64 = 15_537_394;
+
+/// Mainnet hard forks that activate at a block number, latest first.
+const MAINNET_FORK_BLOCKS: [(u64, SpecId); 13] = [
+    (MAINNET_MERGE_BLOCK, SpecId::MERGE),
+    (15_050_000, SpecId::GRAY_GLACIER),
+    (13_773_000, SpecId::ARROW_GLACIER),
+    (12_965_000, SpecId::LONDON),
+    (12_244_000, SpecId::BERLIN),
+    (9_200_000, SpecId::MUIR_GLACIER),
+    (9_069_000, SpecId::ISTANBUL),
+    (7_280_000, SpecId::PETERSBURG),
+    (4_370_000, SpecId::BYZANTIUM),
+    (2_675_000, SpecId::SPURIOUS_DRAGON),
+    (2_463_000, SpecId::TANGERINE),
+    (DAO_FORK_BLOCK, SpecId::DAO_FORK),
+    (1_150_000, SpecId::HOMESTEAD),
+];
+
+/// Mainnet hard forks that activate at a timestamp (after the merge), latest first.
+const MAINNET_FORK_TIMESTAMPS: [(u64, SpecId); 2] = [
+    (1_710_338_135, SpecId::CANCUN),
+    (1_681_338_455, SpecId::SHANGHAI),
+];
+
+/// Returns the mainnet hard fork that a block with the given number and timestamp is in.
+pub fn mainnet_spec_id(number: u64, timestamp: u64) -> SpecId {
+    if number >= MAINNET_MERGE_BLOCK {
+        if let Some((_, spec_id)) = MAINNET_FORK_TIMESTAMPS
+            .iter()
+            .find(|(activation, _)| timestamp >= *activation)
+        {
+            return *spec_id;
+        }
+    }
+    MAINNET_FORK_BLOCKS
+        .iter()
+        .find(|(activation, _)| number >= *activation)
+        .map(|(_, spec_id)| *spec_id)
+        .unwrap_or(SpecId::FRONTIER)
+}
+
 /// An error with tracing a block
 #[derive(Debug, Error, PartialEq)]
 pub enum EvmError {
     #[error("Attempted to set block environment twice")]
     BlockEnvAlreadySet,
+    #[error("Attempted to override block values after the block environment was set")]
+    BlockEnvLocked,
     #[error("Expected a block author (coinbase) to set up the EVM, found none")]
     NoBlockAuthor,
     #[error("Expected a block number to set up the EVM, found none")]
@@ -29,29 +89,85 @@ pub enum EvmError {
     TxAlreadySet,
     #[error("UtilsError {0}")]
     UtilsError(#[from] UtilsError),
+    #[error("revm transaction error {0}")]
+    RevmTransaction(String),
+    #[error("revm database error {0}")]
+    RevmDatabase(String),
     #[error("revm Error {0}")]
     RevmError(String),
+    #[error("Transaction with gas {tx_gas} spent the gas cap of {cap}")]
+    GasCapExceeded { cap: u64, tx_gas: u64 },
+    #[error("Transaction nonce {got} does not match sender nonce {expected} (incomplete state?)")]
+    NonceMismatch { expected: u64, got: u64 },
+    #[error("Transaction gas price {gas_price} is below the block base fee {base_fee}")]
+    GasPriceBelowBaseFee { gas_price: U256, base_fee: U256 },
+    #[error("Transaction has neither a gas price nor a max fee per gas")]
+    MissingGasPrice,
+    #[error("Transaction runs differ: reference run used {reference_gas} gas (success {reference_success}), commit run used {commit_gas} gas (success {commit_success})")]
+    DivergentRuns {
+        reference_gas: u64,
+        reference_success: bool,
+        commit_gas: u64,
+        commit_success: bool,
+    },
+}
+
+impl EvmError {
+    /// The broad kind of the error.
+    ///
+    /// A nonce mismatch or a database error indicates state values absent from the block
+    /// state.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            EvmError::NonceMismatch { .. } | EvmError::RevmDatabase(_) => ErrorKind::MissingNode,
+            EvmError::GasCapExceeded { .. } => ErrorKind::Unsupported,
+            EvmError::NoBlockAuthor
+            | EvmError::NoBlockNumber
+            | EvmError::UtilsError(_)
+            | EvmError::GasPriceBelowBaseFee { .. }
+            | EvmError::MissingGasPrice
+            | EvmError::RevmTransaction(_) => ErrorKind::InvalidInput,
+            EvmError::BlockEnvAlreadySet
+            | EvmError::BlockEnvLocked
+            | EvmError::TxNotSet
+            | EvmError::TxAlreadySet
+            | EvmError::RevmError(_)
+            | EvmError::DivergentRuns { .. } => ErrorKind::Internal,
+        }
+    }
 }
 
 // A wrapper to implement handy methods for working with the revm EVM.
 #[derive(Clone)]
 pub struct BlockEvm {
-    pub evm: EVM<CacheDB<EmptyDB>>,
+    pub evm: EVM<BlockDb>,
     tx_env_status: TxStatus,
     block_env_status: BlockStatus,
+    /// Optional upper bound on the gas available to each transaction.
+    gas_cap: Option<u64>,
+    /// Gas of the loaded transaction, if it was reduced to the gas cap.
+    capped_tx_gas: Option<u64>,
+    /// Simulated block timestamp, used instead of the header value.
+    timestamp_override: Option<u64>,
+    /// Simulated block number, used instead of the header value.
+    number_override: Option<u64>,
 }
 
 impl BlockEvm {
     /// Create the EVM and insert a populated database of state values.
     ///
     /// The DB should contain the states required to execute the intended transactions.
-    pub fn init_from_db(db: CacheDB<EmptyDB>) -> Self {
+    pub fn init_from_db(db: BlockDb) -> Self {
         let mut evm = EVM::new();
         evm.database(db);
         Self {
             evm,
             tx_env_status: TxStatus::NotLoaded,
             block_env_status: BlockStatus::NotSet,
+            gas_cap: None,
+            capped_tx_gas: None,
+            timestamp_override: None,
+            number_override: None,
         }
     }
     /// Set the chain ID (mainnet = 1).
@@ -59,7 +175,56 @@ impl BlockEvm {
         self.evm.env.cfg.chain_id = U256::from(id);
         self
     }
+    /// Cap the gas available to each transaction, below the value in the transaction.
+    ///
+    /// Bounds resource use when replaying untrusted blocks. A transaction that spends (nearly)
+    /// all of the capped gas, and so may have had a different outcome without the cap, is
+    /// reported as `EvmError::GasCapExceeded`. This includes a transaction that ran out of gas
+    /// in a nested call.
+    pub fn add_gas_cap(&mut self, cap: u64) -> &mut Self {
+        self.gas_cap = Some(cap);
+        self
+    }
+    /// Simulate the block at a different timestamp (TIMESTAMP opcode) than in the header.
+    ///
+    /// Must be set before the block environment.
+    pub fn override_timestamp(&mut self, timestamp: u64) -> Result<&mut Self, EvmError> {
+        if self.block_env_status == BlockStatus::Set {
+            return Err(EvmError::BlockEnvLocked);
+        }
+        self.timestamp_override = Some(timestamp);
+        Ok(self)
+    }
+    /// Simulate the block at a different number (NUMBER opcode) than in the header.
+    ///
+    /// Must be set before the block environment. The hard fork is not changed.
+    pub fn override_block_number(&mut self, number: u64) -> Result<&mut Self, EvmError> {
+        if self.block_env_status == BlockStatus::Set {
+            return Err(EvmError::BlockEnvLocked);
+        }
+        self.number_override = Some(number);
+        Ok(self)
+    }
+    /// Execute the block under the given hard fork, rather than the latest (or that of
+    /// `add_spec_id`).
+    ///
+    /// Must be set before the block environment, as the base fee depends on the hard fork.
+    pub fn override_spec_id(&mut self, spec_id: SpecId) -> Result<&mut Self, EvmError> {
+        if self.block_env_status == BlockStatus::Set {
+            return Err(EvmError::BlockEnvLocked);
+        }
+        self.evm.env.cfg.spec_id = spec_id;
+        Ok(self)
+    }
     /// Set initial block values (BaseFee, GasLimit, ..., Etc.).
+    ///
+    /// Overridden values are used in place of those in the block.
+    ///
+    /// The base fee (EIP-1559) is only set for London and later hard forks. Blocks before
+    /// London have no base fee, so it is zero (BASEFEE is not an opcode before London and
+    /// transactions pay their gas price in full). A base fee in the header of such a block
+    /// is ignored. For a London or later block, a header without a base fee is treated as
+    /// zero.
     pub fn add_block_environment(
         &mut self,
         block: &Block<Transaction>,
@@ -69,45 +234,83 @@ impl BlockEvm {
         }
         let env = &mut self.evm.env.block;
 
-        env.number = eu64_to_ru256(block.number.ok_or(EvmError::NoBlockNumber)?);
+        env.number = match self.number_override {
+            Some(number) => U256::from(number),
+            None => eu64_to_ru256(block.number.ok_or(EvmError::NoBlockNumber)?),
+        };
         env.coinbase = block.author.ok_or(EvmError::NoBlockAuthor)?.into();
-        env.timestamp = block.timestamp.into();
+        env.timestamp = match self.timestamp_override {
+            Some(timestamp) => U256::from(timestamp),
+            None => block.timestamp.into(),
+        };
         env.gas_limit = block.gas_limit.into();
-        env.basefee = block.base_fee_per_gas.unwrap_or_default().into();
+        env.basefee = match SpecId::enabled(self.evm.env.cfg.spec_id, SpecId::LONDON) {
+            true => block.base_fee_per_gas.unwrap_or_default().into(),
+            false => U256::ZERO,
+        };
         env.difficulty = block.difficulty.into();
         env.prevrandao = Some(block.difficulty.into());
         self.block_env_status = BlockStatus::Set;
         Ok(self)
     }
-    /// Set the spec id (hard fork definition).
-    pub fn add_spec_id(&mut self, _block: &Block<Transaction>) -> Result<&mut Self, EvmError> {
-        // TODO. E.g.,
-        // if block x < block.number < y,
-        // self.env.cfg.spec_id = SpecId::Constantinople
-        Ok(self)
+    /// Set the spec id (hard fork definition) to the mainnet hard fork of the block.
+    ///
+    /// Must be set before the block environment, as the base fee depends on the hard fork.
+    /// The header values are used, not the overridden block number or timestamp.
+    pub fn add_spec_id(&mut self, block: &Block<Transaction>) -> Result<&mut Self, EvmError> {
+        let number = block.number.ok_or(EvmError::NoBlockNumber)?.as_u64();
+        let timestamp = eu256_to_u64(block.timestamp)?;
+        self.override_spec_id(mainnet_spec_id(number, timestamp))
     }
     /// Add a single transaction environment (index, sender, recipient, etc.).
     pub fn add_transaction_environment(&mut self, tx: Transaction) -> Result<&mut Self, EvmError> {
         self.tx_env_status.ready_to_set()?;
 
         let caller = tx.from.into();
-        let gas_limit = eu256_to_u64(tx.gas);
-        let gas_price = match tx.gas_price {
-            Some(price) => eu256_to_ru256(price)?,
-            None => todo!("handle Type II transaction gas price"),
+        let tx_gas = eu256_to_u64(tx.gas)?;
+        let gas_limit = match self.gas_cap {
+            Some(cap) if tx_gas > cap => {
+                self.capped_tx_gas = Some(tx_gas);
+                cap
+            }
+            _ => {
+                self.capped_tx_gas = None;
+                tx_gas
+            }
         };
-        let gas_priority_fee = match tx.max_priority_fee_per_gas {
-            Some(fee) => Some(eu256_to_ru256(fee)?),
-            None => None,
+        // For EIP-1559 (type 2) transactions, revm takes the max fee as the gas price and
+        // computes the effective price from the base fee and priority fee. Nodes also report
+        // the effective price as the gas price of these transactions, so the max fee is
+        // checked first. Legacy and EIP-2930 (type 1) transactions have no max fee and pay
+        // the gas price as is, without a priority fee.
+        let (gas_price, gas_priority_fee) = match (tx.max_fee_per_gas, tx.gas_price) {
+            (Some(max_fee), _) => {
+                let priority_fee = match tx.max_priority_fee_per_gas {
+                    Some(fee) => Some(eu256_to_ru256(fee)?),
+                    None => None,
+                };
+                (eu256_to_ru256(max_fee)?, priority_fee)
+            }
+            (None, Some(price)) => (eu256_to_ru256(price)?, None),
+            (None, None) => return Err(EvmError::MissingGasPrice),
         };
+        // An included transaction pays at least the base fee, so this indicates inconsistent
+        // block data. Reported here, rather than as a revm error during execution.
+        let base_fee = self.evm.env.block.basefee;
+        if gas_price < base_fee {
+            return Err(EvmError::GasPriceBelowBaseFee {
+                gas_price,
+                base_fee,
+            });
+        }
         let transact_to = match tx.to {
             Some(to) => TransactTo::Call(to.into()),
             None => todo!("handle tx create scheme"), // TransactTo::Create(),
         };
         let value = tx.value.into();
         let data = tx.input.0;
-        let chain_id = Some(ru256_to_u64(self.evm.env.cfg.chain_id));
-        let nonce = Some(eu256_to_u64(tx.nonce));
+        let chain_id = Some(ru256_to_u64(self.evm.env.cfg.chain_id)?);
+        let nonce = Some(eu256_to_u64(tx.nonce)?);
         let access_list = match tx.access_list {
             Some(list_in) => access_list_e_to_r(list_in),
             None => vec![],
@@ -135,21 +338,63 @@ impl BlockEvm {
     /// This applies the transaction, monitors the output and leaves the EVM ready for the
     /// next transaction to be added.
     pub fn execute_with_inspector_eip3155(&mut self) -> Result<ResultAndState, EvmError> {
+        self.execute_with_inspector_eip3155_to(Box::new(stdout()))
+    }
+    /// Runs the transaction twice (once for state change, once to commit), with the
+    /// EIP-3155 trace written to the given output rather than stdout.
+    pub fn execute_with_inspector_eip3155_to(
+        &mut self,
+        output: Box<dyn Write>,
+    ) -> Result<ResultAndState, EvmError> {
         self.tx_env_status.ready_to_execute()?;
         // Run the tx to get the state changes, but don't commit to the EVM env yet.
         // The changes will be used to compute the post-tx state root.
         // Use a dummy inspector.
         let noop_inspector = NoOpInspector {};
         let state_changes = self.evm.inspect_ref(noop_inspector)?;
+        self.check_gas_cap(&state_changes.result)?;
 
         // Now run the tx again and this time commit the changes.
         // see: https://github.com/bluealloy/revm/blob/main/bins/revme/src/statetest/runner.rs#L259
         // Initialize the inspector
-        let inspector = TracerEip3155::new(Box::new(stdout()), true, true);
-        let _outcome = self.evm.inspect_commit(inspector).map_err(EvmError::from)?;
+        let inspector = TracerEip3155::new(output, true, true);
+        let outcome = self.evm.inspect_commit(inspector).map_err(EvmError::from)?;
         self.tx_env_status.executed()?;
+        runs_match(&state_changes.result, &outcome)?;
         Ok(state_changes)
     }
+    /// Runs the transaction twice (once for state change, once to commit), recording the
+    /// storage reads and writes made during the first run.
+    pub fn execute_with_storage_inspector(
+        &mut self,
+    ) -> Result<(ResultAndState, Vec<StorageAccess>), EvmError> {
+        self.tx_env_status.ready_to_execute()?;
+        let inspector = StorageInspector::default();
+        let state_changes = self.evm.inspect_ref(inspector.clone())?;
+        self.check_gas_cap(&state_changes.result)?;
+
+        // Now run the tx again, this time to commit the changes.
+        let outcome = self.evm.transact_commit().map_err(EvmError::from)?;
+        self.tx_env_status.executed()?;
+        runs_match(&state_changes.result, &outcome)?;
+        Ok((state_changes, inspector.accesses()))
+    }
+    /// Runs the transaction twice (once for state change, once to commit), recording the
+    /// tree of calls made during the first run (Geth `callTracer` style).
+    pub fn execute_with_call_inspector(
+        &mut self,
+    ) -> Result<(ResultAndState, Option<CallFrame>), EvmError> {
+        self.tx_env_status.ready_to_execute()?;
+        let inspector = CallInspector::default();
+        let state_changes = self.evm.inspect_ref(inspector.clone())?;
+        self.check_gas_cap(&state_changes.result)?;
+
+        // Now run the tx again, this time to commit the changes.
+        let outcome = self.evm.transact_commit().map_err(EvmError::from)?;
+        self.tx_env_status.executed()?;
+        runs_match(&state_changes.result, &outcome)?;
+        Ok((state_changes, inspector.call_tree()))
+    }
     /// Execute a loaded transaction without an inspector.
     ///
     /// This applies the transaction and leaves the EVM ready for the
@@ -161,12 +406,73 @@ impl BlockEvm {
         // Use a dummy inspector.
         let noop_inspector = NoOpInspector {};
         let state_changes = self.evm.inspect_ref(noop_inspector)?;
+        self.check_gas_cap(&state_changes.result)?;
 
         // Now run the tx again, this time to commit the changes.
-        let _outcome = self.evm.transact_commit().map_err(EvmError::from)?;
+        let outcome = self.evm.transact_commit().map_err(EvmError::from)?;
         self.tx_env_status.executed()?;
+        runs_match(&state_changes.result, &outcome)?;
         Ok(state_changes)
     }
+    /// Execute a loaded transaction once, committing the changes, without an inspector.
+    ///
+    /// Unlike `execute_without_inspector`, the transaction is not run a second time to
+    /// obtain the state changes separately. Use when only the committed result is needed.
+    /// The post-transaction state can be read from the EVM database.
+    ///
+    /// A transaction that exceeds the gas cap is not committed.
+    pub fn execute_and_commit(&mut self) -> Result<ExecutionResult, EvmError> {
+        self.tx_env_status.ready_to_execute()?;
+        let ResultAndState { result, state } = self.evm.transact().map_err(EvmError::from)?;
+        self.check_gas_cap(&result)?;
+        if let Some(db) = self.evm.db() {
+            db.commit(state);
+        }
+        self.tx_env_status.executed()?;
+        Ok(result)
+    }
+    /// Reports a transaction that spent the gas cap, if it would have had more gas without
+    /// the cap.
+    ///
+    /// A call is given at most 63/64 of the remaining gas (EIP-150), so a transaction where a
+    /// nested call ran out of gas spends at least 63/64 of the cap, and may still succeed.
+    /// Refunded gas was spent during execution, so it is counted.
+    fn check_gas_cap(&self, result: &ExecutionResult) -> Result<(), EvmError> {
+        let (Some(cap), Some(tx_gas)) = (self.gas_cap, self.capped_tx_gas) else {
+            return Ok(());
+        };
+        let spent = match result {
+            ExecutionResult::Success {
+                gas_used,
+                gas_refunded,
+                ..
+            } => gas_used + gas_refunded,
+            ExecutionResult::Revert { gas_used, .. } | ExecutionResult::Halt { gas_used, .. } => {
+                *gas_used
+            }
+        };
+        match spent >= cap - cap / 64 {
+            true => Err(EvmError::GasCapExceeded { cap, tx_gas }),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Checks that the run of a transaction that produced the state changes and the run that
+/// committed them had the same outcome. The runs are expected to be identical, so a
+/// difference indicates nondeterminism (e.g., an inspector with side effects).
+fn runs_match(reference: &ExecutionResult, committed: &ExecutionResult) -> Result<(), EvmError> {
+    if reference.gas_used() != committed.gas_used()
+        || reference.is_success() != committed.is_success()
+    {
+        return Err(EvmError::DivergentRuns {
+            reference_gas: reference.gas_used(),
+            reference_success: reference.is_success(),
+            commit_gas: committed.gas_used(),
+            commit_success: committed.is_success(),
+        });
+    }
+    Ok(())
 }
 
 /// Transactions are executed individually, this status prevents accidental
@@ -211,19 +517,492 @@ impl TxStatus {
 }
 
 /// Convert revm Error type (no Display impl) to local error type.
-impl<DBError> From<EVMError<DBError>> for EvmError {
+///
+/// Transaction errors (invalid input) are kept apart from database errors (missing state).
+impl<DBError: Display> From<EVMError<DBError>> for EvmError {
     fn from(value: EVMError<DBError>) -> Self {
-        let e = match value {
+        match value {
+            // A nonce mismatch usually means the sender state is missing or stale.
+            EVMError::Transaction(
+                InvalidTransaction::NonceTooHigh { tx, state }
+                | InvalidTransaction::NonceTooLow { tx, state },
+            ) => EvmError::NonceMismatch {
+                expected: state,
+                got: tx,
+            },
             EVMError::Transaction(t) => {
-                match serde_json::to_string(&t).map_err(|e| e.to_string()) {
+                EvmError::RevmTransaction(match serde_json::to_string(&t) {
                     Ok(tx_err) => tx_err,
-                    Err(serde_err) => serde_err,
-                }
+                    Err(serde_err) => serde_err.to_string(),
+                })
             }
-            // _d is Infallible - ignore.
-            EVMError::Database(_d) => "database error".to_string(),
-            EVMError::PrevrandaoNotSet => String::from("prevrandao error"),
+            EVMError::Database(d) => EvmError::RevmDatabase(d.to_string()),
+            EVMError::PrevrandaoNotSet => EvmError::RevmError(String::from("prevrandao error")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use ethers::types::{
+        transaction::eip2930::{AccessList, AccessListItem},
+        H160, H256,
+    };
+    use revm::{db::DatabaseRef, primitives::B160};
+
+    use crate::{
+        state::{build_state_from_proofs, CodeDbError},
+        test_utils::{add_contract, empty_state, funded_state, test_block},
+    };
+
+    use super::*;
+
+    /// Tests that committing after a single execution produces the same state as the
+    /// double execution.
+    #[test]
+    fn test_single_run_commit_matches_double_run() {
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let contract = H160::from_str("0x0200000000000000000000000000000000000000").unwrap();
+        let mut state = funded_state(sender);
+        // PUSH1 0x2a, PUSH1 0x01, SSTORE, STOP
+        add_contract(
+            &mut state,
+            contract,
+            vec![0x60, 0x2a, 0x60, 0x01, 0x55, 0x00],
+        );
+
+        let tx = Transaction {
+            from: sender,
+            to: Some(contract),
+            value: 5u64.into(),
+            gas: 100_000u64.into(),
+            gas_price: Some(7u64.into()),
+            ..Default::default()
+        };
+        let block = test_block(vec![]);
+        let mut double_run = BlockEvm::init_from_db(build_state_from_proofs(&state).unwrap());
+        double_run.add_block_environment(&block).unwrap();
+        let mut single_run = double_run.clone();
+
+        let double_result = double_run
+            .add_transaction_environment(tx.clone())
+            .unwrap()
+            .execute_without_inspector()
+            .unwrap();
+        let single_result = single_run
+            .add_transaction_environment(tx)
+            .unwrap()
+            .execute_and_commit()
+            .unwrap();
+        assert_eq!(single_result, double_result.result);
+        assert!(single_result.is_success());
+
+        let double_db = double_run.evm.db.as_ref().unwrap();
+        let single_db = single_run.evm.db.as_ref().unwrap();
+        for address in [sender, contract, H160::default()] {
+            let address = B160::from(address.0);
+            assert_eq!(
+                single_db.basic(address).unwrap(),
+                double_db.basic(address).unwrap()
+            );
+        }
+        let slot = single_db.storage(contract.0.into(), U256::from(1)).unwrap();
+        assert_eq!(slot, U256::from(0x2a));
+        assert_eq!(
+            slot,
+            double_db.storage(contract.0.into(), U256::from(1)).unwrap()
+        );
+    }
+
+    /// Tests that a single run that exceeds the gas cap is not committed.
+    #[test]
+    fn test_single_run_gas_cap_not_committed() {
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let looper = H160::from_str("0x0200000000000000000000000000000000000000").unwrap();
+        let mut state = funded_state(sender);
+        // JUMPDEST, PUSH1 0x00, JUMP (loops until out of gas).
+        add_contract(&mut state, looper, vec![0x5b, 0x60, 0x00, 0x56]);
+        let tx = Transaction {
+            from: sender,
+            to: Some(looper),
+            gas: 1_000_000u64.into(),
+            gas_price: Some(7u64.into()),
+            ..Default::default()
+        };
+        let block = test_block(vec![]);
+        let mut evm = BlockEvm::init_from_db(build_state_from_proofs(&state).unwrap());
+        evm.add_gas_cap(50_000)
+            .add_block_environment(&block)
+            .unwrap();
+        let result = evm
+            .add_transaction_environment(tx)
+            .unwrap()
+            .execute_and_commit();
+        assert_eq!(
+            result.err(),
+            Some(EvmError::GasCapExceeded {
+                cap: 50_000,
+                tx_gas: 1_000_000
+            })
+        );
+        let db = evm.evm.db.as_ref().unwrap();
+        let sender = db.basic(B160::from(sender.0)).unwrap().unwrap();
+        assert_eq!(sender.nonce, 0);
+        assert_eq!(
+            sender.balance,
+            eu256_to_ru256(ethers::types::U256::exp10(18)).unwrap()
+        );
+    }
+
+    /// Tests that a transaction paying less than the base fee is rejected before execution.
+    #[test]
+    fn test_gas_price_below_base_fee() {
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let state = empty_state();
+        let block = Block {
+            base_fee_per_gas: Some(10u64.into()),
+            ..test_block(vec![])
+        };
+        let tx = Transaction {
+            from: sender,
+            to: Some(H160::default()),
+            gas: 21_000u64.into(),
+            gas_price: Some(9u64.into()),
+            ..Default::default()
+        };
+        let mut evm = BlockEvm::init_from_db(build_state_from_proofs(&state).unwrap());
+        evm.add_block_environment(&block).unwrap();
+        assert_eq!(
+            evm.add_transaction_environment(tx.clone()).err(),
+            Some(EvmError::GasPriceBelowBaseFee {
+                gas_price: U256::from(9),
+                base_fee: U256::from(10)
+            })
+        );
+        // The transaction was not loaded.
+        assert_eq!(evm.execute_and_commit().err(), Some(EvmError::TxNotSet));
+
+        let tx = Transaction {
+            gas_price: Some(10u64.into()),
+            ..tx
+        };
+        assert!(evm.add_transaction_environment(tx).is_ok());
+    }
+
+    /// Tests that the gas price is taken from the max fee per gas (even if the legacy field
+    /// is also present), falls back to the legacy field, and is an error if neither is
+    /// present.
+    #[test]
+    fn test_gas_price_fields() {
+        let state = empty_state();
+        let block = Block {
+            base_fee_per_gas: Some(10u64.into()),
+            ..test_block(vec![])
+        };
+        let mut evm = BlockEvm::init_from_db(build_state_from_proofs(&state).unwrap());
+        evm.add_block_environment(&block).unwrap();
+        let tx = Transaction {
+            from: H160::from_str("0x0300000000000000000000000000000000000000").unwrap(),
+            to: Some(H160::default()),
+            gas: 21_000u64.into(),
+            ..Default::default()
+        };
+
+        let legacy = Transaction {
+            gas_price: Some(12u64.into()),
+            ..tx.clone()
+        };
+        let mut legacy_evm = evm.clone();
+        legacy_evm.add_transaction_environment(legacy).unwrap();
+        assert_eq!(legacy_evm.evm.env.tx.gas_price, U256::from(12));
+        assert_eq!(legacy_evm.evm.env.tx.gas_priority_fee, None);
+
+        let type_2 = Transaction {
+            max_fee_per_gas: Some(15u64.into()),
+            max_priority_fee_per_gas: Some(2u64.into()),
+            ..tx.clone()
+        };
+        let mut type_2_evm = evm.clone();
+        type_2_evm.add_transaction_environment(type_2).unwrap();
+        assert_eq!(type_2_evm.evm.env.tx.gas_price, U256::from(15));
+        assert_eq!(type_2_evm.evm.env.tx.gas_priority_fee, Some(U256::from(2)));
+
+        // Nodes report the effective gas price of a type 2 transaction in `gas_price`.
+        let type_2_with_price = Transaction {
+            gas_price: Some(12u64.into()),
+            max_fee_per_gas: Some(15u64.into()),
+            max_priority_fee_per_gas: Some(2u64.into()),
+            ..tx.clone()
         };
-        EvmError::RevmError(e)
+        let mut type_2_evm = evm.clone();
+        type_2_evm
+            .add_transaction_environment(type_2_with_price)
+            .unwrap();
+        assert_eq!(type_2_evm.evm.env.tx.gas_price, U256::from(15));
+        assert_eq!(type_2_evm.evm.env.tx.gas_priority_fee, Some(U256::from(2)));
+
+        assert_eq!(
+            evm.add_transaction_environment(tx).err(),
+            Some(EvmError::MissingGasPrice)
+        );
+        assert_eq!(evm.execute_and_commit().err(), Some(EvmError::TxNotSet));
+    }
+
+    /// Tests that an EIP-2930 (type 1) transaction pays its gas price without a priority fee
+    /// and has its access list applied, and that a type 2 transaction reporting an effective
+    /// gas price is priced by its max fee.
+    #[test]
+    fn test_type_1_gas_price_and_access_list() {
+        let state = empty_state();
+        let block = Block {
+            base_fee_per_gas: Some(10u64.into()),
+            ..test_block(vec![])
+        };
+        let mut evm = BlockEvm::init_from_db(build_state_from_proofs(&state).unwrap());
+        evm.add_block_environment(&block).unwrap();
+        let listed = H160::from_low_u64_be(0x9876);
+        let access_list = AccessList(vec![AccessListItem {
+            address: listed,
+            storage_keys: vec![H256::from_low_u64_be(0x1234)],
+        }]);
+        let type_1 = Transaction {
+            from: H160::from_str("0x0300000000000000000000000000000000000000").unwrap(),
+            to: Some(H160::default()),
+            gas: 30_000u64.into(),
+            gas_price: Some(12u64.into()),
+            transaction_type: Some(1u64.into()),
+            access_list: Some(access_list),
+            ..Default::default()
+        };
+
+        let mut type_1_evm = evm.clone();
+        type_1_evm
+            .add_transaction_environment(type_1.clone())
+            .unwrap();
+        let tx_env = &type_1_evm.evm.env.tx;
+        assert_eq!(tx_env.gas_price, U256::from(12));
+        assert_eq!(tx_env.gas_priority_fee, None);
+        assert_eq!(
+            tx_env.access_list,
+            vec![(B160::from(listed.0), vec![U256::from(0x1234)])]
+        );
+
+        let type_2 = Transaction {
+            max_fee_per_gas: Some(15u64.into()),
+            max_priority_fee_per_gas: Some(2u64.into()),
+            transaction_type: Some(2u64.into()),
+            ..type_1
+        };
+        evm.add_transaction_environment(type_2).unwrap();
+        assert_eq!(evm.evm.env.tx.gas_price, U256::from(15));
+        assert_eq!(evm.evm.env.tx.gas_priority_fee, Some(U256::from(2)));
+        assert_eq!(evm.evm.env.tx.access_list.len(), 1);
+    }
+
+    /// Tests that a pre-London block has no base fee, even if the header has one, and that a
+    /// legacy transaction pays its full gas price.
+    #[test]
+    fn test_pre_london_block_has_no_base_fee() {
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let coinbase = H160::from_str("0x0500000000000000000000000000000000000000").unwrap();
+        let state = funded_state(sender);
+        let block = Block::<Transaction> {
+            author: Some(coinbase),
+            number: Some(12_000_000.into()),
+            gas_limit: 30_000_000u64.into(),
+            base_fee_per_gas: Some(10u64.into()),
+            ..Default::default()
+        };
+        let tx = Transaction {
+            from: sender,
+            to: Some(H160::from_low_u64_be(2)),
+            gas: 21_000u64.into(),
+            gas_price: Some(3u64.into()),
+            ..Default::default()
+        };
+
+        // Without a spec id, the block is executed under the latest hard fork.
+        let mut latest = BlockEvm::init_from_db(build_state_from_proofs(&state).unwrap());
+        latest.add_block_environment(&block).unwrap();
+        assert_eq!(latest.evm.env.block.basefee, U256::from(10));
+        assert!(latest.override_spec_id(SpecId::BERLIN).is_err());
+
+        let mut berlin = BlockEvm::init_from_db(build_state_from_proofs(&state).unwrap());
+        berlin
+            .override_spec_id(SpecId::BERLIN)
+            .unwrap()
+            .add_block_environment(&block)
+            .unwrap();
+        assert_eq!(berlin.evm.env.block.basefee, U256::ZERO);
+        // Below the header base fee, which does not apply before London.
+        let outcome = berlin
+            .add_transaction_environment(tx)
+            .unwrap()
+            .execute_and_commit()
+            .unwrap();
+        assert!(outcome.is_success());
+        // The coinbase receives the full gas price, none is burned.
+        let coinbase_balance = berlin
+            .evm
+            .db
+            .as_ref()
+            .unwrap()
+            .basic(B160::from(coinbase.0))
+            .unwrap()
+            .unwrap()
+            .balance;
+        assert_eq!(coinbase_balance, U256::from(21_000 * 3));
+    }
+
+    /// Tests that blocks are executed under the mainnet hard fork at their number, or their
+    /// timestamp after the merge.
+    #[test]
+    fn test_mainnet_spec_id() {
+        assert_eq!(mainnet_spec_id(0, 0), SpecId::FRONTIER);
+        assert_eq!(mainnet_spec_id(DAO_FORK_BLOCK, 0), SpecId::DAO_FORK);
+        assert_eq!(mainnet_spec_id(12_000_000, 0), SpecId::MUIR_GLACIER);
+        assert_eq!(mainnet_spec_id(12_964_999, 0), SpecId::BERLIN);
+        assert_eq!(mainnet_spec_id(12_965_000, 0), SpecId::LONDON);
+        assert_eq!(mainnet_spec_id(15_537_394, 1_663_224_179), SpecId::MERGE);
+        assert_eq!(mainnet_spec_id(17_190_873, 1_683_049_919), SpecId::SHANGHAI);
+        assert_eq!(mainnet_spec_id(19_426_587, 1_710_338_135), SpecId::CANCUN);
+
+        let state = empty_state();
+        let block = Block::<Transaction> {
+            author: Some(H160::default()),
+            number: Some(12_000_000.into()),
+            base_fee_per_gas: Some(10u64.into()),
+            ..Default::default()
+        };
+        let mut evm = BlockEvm::init_from_db(build_state_from_proofs(&state).unwrap());
+        evm.add_spec_id(&block)
+            .unwrap()
+            .add_block_environment(&block)
+            .unwrap();
+        assert_eq!(evm.evm.env.cfg.spec_id, SpecId::MUIR_GLACIER);
+        assert_eq!(evm.evm.env.block.basefee, U256::ZERO);
+        assert_eq!(
+            evm.add_spec_id(&block).err(),
+            Some(EvmError::BlockEnvLocked)
+        );
+    }
+
+    /// Tests that EVM errors map to kinds that separate bad input from library misuse.
+    #[test]
+    fn test_evm_error_kind() {
+        let state = empty_state();
+        let mut evm = BlockEvm::init_from_db(build_state_from_proofs(&state).unwrap());
+        let no_number = evm
+            .add_block_environment(&Block::<Transaction>::default())
+            .err()
+            .unwrap();
+        assert_eq!(no_number, EvmError::NoBlockNumber);
+        assert_eq!(no_number.kind(), ErrorKind::InvalidInput);
+
+        let not_set = evm.execute_and_commit().err().unwrap();
+        assert_eq!(not_set, EvmError::TxNotSet);
+        assert_eq!(not_set.kind(), ErrorKind::Internal);
+        let mismatch = EvmError::NonceMismatch {
+            expected: 1,
+            got: 2,
+        };
+        assert_eq!(mismatch.kind(), ErrorKind::MissingNode);
+
+        let database = EvmError::from(EVMError::Database(CodeDbError::MissingCode(
+            "0x01".to_string(),
+        )));
+        assert!(matches!(database, EvmError::RevmDatabase(_)));
+        assert_eq!(database.kind(), ErrorKind::MissingNode);
+        let transaction = EvmError::from(EVMError::<CodeDbError>::Transaction(
+            InvalidTransaction::CallerGasLimitMoreThanBlock,
+        ));
+        assert!(matches!(transaction, EvmError::RevmTransaction(_)));
+        assert_eq!(transaction.kind(), ErrorKind::InvalidInput);
+    }
+
+    /// Tests that the reference and commit runs of a transfer match, and that differing
+    /// runs are reported.
+    #[test]
+    fn test_reference_and_commit_runs_match() {
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let state = funded_state(sender);
+        let block = test_block(vec![]);
+        let tx = Transaction {
+            from: sender,
+            to: Some(H160::from_low_u64_be(2)),
+            value: 9u64.into(),
+            gas: 21_000u64.into(),
+            gas_price: Some(ethers::types::U256::default()),
+            ..Default::default()
+        };
+        let mut evm = BlockEvm::init_from_db(build_state_from_proofs(&state).unwrap());
+        evm.add_block_environment(&block).unwrap();
+        let outcome = evm
+            .add_transaction_environment(tx)
+            .unwrap()
+            .execute_with_inspector_eip3155_to(Box::new(std::io::sink()))
+            .unwrap();
+        assert!(outcome.result.is_success());
+        assert_eq!(outcome.result.gas_used(), 21_000);
+
+        let revert = |gas_used| ExecutionResult::Revert {
+            gas_used,
+            output: Default::default(),
+        };
+        assert!(runs_match(&revert(21_000), &revert(21_000)).is_ok());
+        assert_eq!(
+            runs_match(&revert(21_000), &revert(22_000)),
+            Err(EvmError::DivergentRuns {
+                reference_gas: 21_000,
+                reference_success: false,
+                commit_gas: 22_000,
+                commit_success: false,
+            })
+        );
+    }
+
+    /// Tests that a transaction reading TIMESTAMP sees an overridden timestamp, and that
+    /// overrides cannot be set after the block environment.
+    #[test]
+    fn test_override_timestamp() {
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let contract = H160::from_str("0x0200000000000000000000000000000000000000").unwrap();
+        let mut state = funded_state(sender);
+        // TIMESTAMP, PUSH1 0x00, SSTORE, STOP
+        add_contract(&mut state, contract, vec![0x42, 0x60, 0x00, 0x55, 0x00]);
+
+        let tx = Transaction {
+            from: sender,
+            to: Some(contract),
+            gas: 100_000u64.into(),
+            gas_price: Some(ethers::types::U256::default()),
+            ..Default::default()
+        };
+        let block = Block {
+            timestamp: 1_600_000_000u64.into(),
+            ..test_block(vec![])
+        };
+        let mut evm = BlockEvm::init_from_db(build_state_from_proofs(&state).unwrap());
+        evm.override_timestamp(1_700_000_000)
+            .unwrap()
+            .add_block_environment(&block)
+            .unwrap();
+        assert_eq!(
+            evm.override_block_number(1).err(),
+            Some(EvmError::BlockEnvLocked)
+        );
+        assert_eq!(evm.evm.env.block.number, U256::from(10_000_000));
+
+        let result = evm
+            .add_transaction_environment(tx)
+            .unwrap()
+            .execute_and_commit()
+            .unwrap();
+        assert!(result.is_success());
+        let db = evm.evm.db.as_ref().unwrap();
+        let slot = db.storage(contract.0.into(), U256::from(0)).unwrap();
+        assert_eq!(slot, U256::from(1_700_000_000u64));
     }
 }
@@ -1,3 +1,10 @@
+pub mod calls;
+pub mod checkpoint;
+pub mod dao;
+pub mod diff;
 pub mod evm;
 pub mod state;
+pub mod storage;
+#[cfg(test)]
+mod test_utils;
 pub mod trace;
@@ -1,16 +1,25 @@
 //! For representing state for an historical block.
 
-use std::collections::HashMap;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+};
 
 use archors_types::{
+    constants::EMPTY_CODE_HASH,
     execution::{EvmStateError, StateForEvm},
-    utils::{eh256_to_ru256, eu256_to_ru256, eu64_to_ru256, hex_encode}, proof::{DisplayProof, DisplayStorageProof},
+    proof::{DisplayProof, DisplayStorageProof},
+    utils::{eh256_to_ru256, eu256_to_ru256, eu64_to_ru256, hex_encode},
 };
-use ethers::types::{EIP1186ProofResponse, H160, H256, U64};
+use ethers::types::{Bytes as EBytes, EIP1186ProofResponse, H160, H256, U256 as EU256, U64};
 use revm::{
-    db::{CacheDB, EmptyDB},
-    primitives::{Account, AccountInfo, Bytecode, Bytes, HashMap as rHashMap, B160, B256, U256},
+    db::{CacheDB, DatabaseRef},
+    primitives::{
+        keccak256, Account, AccountInfo, Bytecode, Bytes, HashMap as rHashMap, B160, B256, U256,
+    },
 };
+use serde::Deserialize;
+use thiserror::Error;
 
 /// A basic map of accounts to proofs. Includes all state required to trace a block.
 ///
@@ -70,6 +79,14 @@ impl StateForEvm for BlockProofsBasic {
 
         Ok(storage)
     }
+    fn get_contracts(&self) -> Result<rHashMap<B256, Bytecode>, EvmStateError> {
+        let mut contracts = rHashMap::new();
+        for (code_hash, data) in self.code.iter() {
+            let revm_bytes = Bytes::copy_from_slice(data);
+            contracts.insert(code_hash.0.into(), Bytecode::new_raw(revm_bytes));
+        }
+        Ok(contracts)
+    }
     fn addresses(&self) -> Vec<B160> {
         self.proofs
             .keys()
@@ -94,7 +111,10 @@ impl StateForEvm for BlockProofsBasic {
         unimplemented!("Post execution root check is not implemented for basic proof data format.")
     }
 
-    fn print_account_proof<T: AsRef<str>>(&self, _account_address: T) -> Result<DisplayProof, EvmStateError> {
+    fn print_account_proof<T: AsRef<str>>(
+        &self,
+        _account_address: T,
+    ) -> Result<DisplayProof, EvmStateError> {
         todo!()
     }
 
@@ -107,16 +127,119 @@ impl StateForEvm for BlockProofsBasic {
     }
 }
 
+/// The in-memory DB used by the EVM. Contract bytecode is held separately and only
+/// enters the cache when the EVM requests it.
+pub type BlockDb = CacheDB<ContractCodeDb>;
+
+/// An error with the contract code available to the EVM.
+#[derive(Debug, Error, PartialEq)]
+pub enum CodeDbError {
+    #[error("No contract code for code hash {0} (incomplete state?)")]
+    MissingCode(String),
+}
+
+/// A database that only provides contract bytecode, by code hash.
+///
+/// Backs the EVM cache so that bytecode for contracts that are never executed is not
+/// loaded into the cache. Code that the EVM requests but that was not provided is an error
+/// (`CodeDbError::MissingCode`), rather than empty code.
+///
+/// The hashes of the code the EVM loads are recorded. This includes code that is executed,
+/// and code that is loaded with an account that is read by another contract (e.g.,
+/// EXTCODESIZE, EXTCODECOPY, EXTCODEHASH).
+#[derive(Clone, Debug, Default)]
+pub struct ContractCodeDb {
+    /// Map of codehash -> code
+    code: rHashMap<B256, Bytecode>,
+    /// Hashes of the code requested by the EVM.
+    loaded: RefCell<HashSet<B256>>,
+}
+
+impl ContractCodeDb {
+    pub fn init(code: rHashMap<B256, Bytecode>) -> Self {
+        Self {
+            code,
+            loaded: RefCell::default(),
+        }
+    }
+    /// Returns the hashes of the code loaded by the EVM so far.
+    ///
+    /// Code in the EVM cache (e.g., from a checkpoint, or a code override) is not loaded
+    /// through this database, so is not included.
+    pub fn loaded_code_hashes(&self) -> HashSet<B256> {
+        self.loaded.borrow().clone()
+    }
+}
+
+impl DatabaseRef for ContractCodeDb {
+    type Error = CodeDbError;
+
+    fn basic(&self, _address: B160) -> Result<Option<AccountInfo>, Self::Error> {
+        Ok(None)
+    }
+
+    fn code_by_hash(&self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        let code = self
+            .code
+            .get(&code_hash)
+            .cloned()
+            .ok_or_else(|| CodeDbError::MissingCode(hex_encode(code_hash)))?;
+        self.loaded.borrow_mut().insert(code_hash);
+        Ok(code)
+    }
+
+    fn storage(&self, _address: B160, _index: U256) -> Result<U256, Self::Error> {
+        Ok(U256::ZERO)
+    }
+
+    fn block_hash(&self, number: U256) -> Result<B256, Self::Error> {
+        Ok(keccak256(&number.to_be_bytes::<{ U256::BYTES }>()))
+    }
+}
+
+/// Builds the in-memory DB to execute a block with: the accounts, storage, contracts and
+/// blockhashes of the proofs (e.g., a parcel).
+///
+/// The proofs are not checked against a state root, and for a parcel the contracts are not
+/// checked against the account code hashes (see `RequiredBlockState::verify`). Both should be
+/// done before the DB is used.
+pub fn build_block_db<T>(block_proofs: &T) -> Result<BlockDb, EvmStateError>
+where
+    T: StateForEvm,
+{
+    let mut db = build_state_from_proofs(block_proofs)?;
+    db.block_hashes = block_proofs.get_blockhash_accesses()?;
+    Ok(db)
+}
+
 /// Inserts state from a collection of EIP-1186 proof into an in-memory DB.
 /// The DB can then be used by the EVM to read/write state during execution.
-pub fn build_state_from_proofs<T>(block_proofs: &T) -> Result<CacheDB<EmptyDB>, EvmStateError>
+///
+/// Contract bytecode is resolved by code hash when the EVM requests it.
+pub fn build_state_from_proofs<T>(block_proofs: &T) -> Result<BlockDb, EvmStateError>
 where
     T: StateForEvm,
 {
-    let mut db = CacheDB::new(EmptyDB::default());
+    build_state_from_selected_proofs(block_proofs, &block_proofs.addresses())
+}
 
-    for address in block_proofs.addresses() {
-        let info = block_proofs.get_account_info(&address)?;
+/// Inserts state for only the selected accounts into an in-memory DB.
+///
+/// Accounts in the proofs that are not selected read as empty (no balance, nonce, code
+/// or storage). Useful for testing whether a transaction depends on the state of a
+/// particular account. Selected accounts that are not in the proofs are an error.
+pub fn build_state_from_selected_proofs<T>(
+    block_proofs: &T,
+    selected: &[B160],
+) -> Result<BlockDb, EvmStateError>
+where
+    T: StateForEvm,
+{
+    let code_db = ContractCodeDb::init(block_proofs.get_contracts()?);
+    let mut db = CacheDB::new(code_db);
+
+    for &address in selected {
+        let info = block_proofs.get_account_info_without_code(&address)?;
         db.insert_account_info(address, info);
 
         let storage = block_proofs.get_account_storage(&address)?;
@@ -129,21 +252,119 @@ where
     Ok(db)
 }
 
+/// Response for debug_traceBlockByNumber with the geth prestateTracer.
+#[derive(Debug, Deserialize)]
+pub struct BlockPrestateResponse {
+    pub result: Vec<TxPrestate>,
+}
+
+/// The state of each account accessed by a transaction, prior to the transaction.
+#[derive(Debug, Deserialize)]
+pub struct TxPrestate {
+    pub result: HashMap<H160, PrestateAccount>,
+}
+
+/// An account state as reported by the prestate tracer. Only accessed fields are present,
+/// and a zero balance, zero nonce or empty code is omitted.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct PrestateAccount {
+    pub balance: Option<EU256>,
+    pub nonce: Option<u64>,
+    pub code: Option<EBytes>,
+    pub storage: Option<HashMap<H256, H256>>,
+}
+
+/// Inserts state from a prestate tracer result into an in-memory DB.
+///
+/// **The state is not verified.** Unlike `build_state_from_proofs`, there are no proofs
+/// against a state root, so the DB holds whatever the node reported. Use for tracing where
+/// the node is trusted.
+///
+/// Where an account appears in multiple transactions, the earliest state is used, as later
+/// transactions see state modified by the block. A balance, nonce or code absent from the
+/// earliest state is zero (or empty), and is not taken from a later transaction. Storage
+/// slots are taken from the earliest transaction that accessed them.
+pub fn build_state_from_prestate(
+    prestate: &BlockPrestateResponse,
+) -> Result<BlockDb, EvmStateError> {
+    let mut accounts: HashMap<H160, PrestateAccount> = HashMap::new();
+    for tx in &prestate.result {
+        for (address, state) in &tx.result {
+            let Some(existing) = accounts.get_mut(address) else {
+                accounts.insert(*address, state.clone());
+                continue;
+            };
+            if let Some(storage) = &state.storage {
+                let existing_storage = existing.storage.get_or_insert_with(HashMap::new);
+                for (key, value) in storage {
+                    existing_storage.entry(*key).or_insert(*value);
+                }
+            }
+        }
+    }
+
+    let contracts: rHashMap<B256, Bytecode> = accounts
+        .values()
+        .filter_map(|state| state.code.as_ref())
+        .filter(|code| !code.is_empty())
+        .map(|code| {
+            (
+                keccak256(code),
+                Bytecode::new_raw(Bytes::copy_from_slice(code)),
+            )
+        })
+        .collect();
+    let mut db = CacheDB::new(ContractCodeDb::init(contracts));
+
+    for (address, state) in accounts {
+        let code_hash = match &state.code {
+            Some(code) if !code.is_empty() => keccak256(code),
+            _ => B256::from(EMPTY_CODE_HASH),
+        };
+        let address = B160::from(address.0);
+        let info = AccountInfo {
+            balance: eu256_to_ru256(state.balance.unwrap_or_default())?,
+            nonce: state.nonce.unwrap_or_default(),
+            code_hash,
+            code: None,
+        };
+        db.insert_account_info(address, info);
+
+        let storage: rHashMap<U256, U256> = state
+            .storage
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(key, value)| (eh256_to_ru256(key), eh256_to_ru256(value)))
+            .collect();
+        db.replace_account_storage(address, storage)
+            .map_err(|source| EvmStateError::AccountStorageInit {
+                error: source.to_string(),
+                address: hex_encode(address),
+            })?;
+    }
+    Ok(db)
+}
+
 #[cfg(test)]
 mod test {
     use std::str::FromStr;
 
-    use revm::primitives::B256;
+    use archors_types::{
+        error::ErrorKind,
+        fixture::{parcel, prior_block_proofs},
+    };
+    use revm::{db::Database, primitives::B256};
+
+    use crate::{
+        evm::{BlockEvm, EvmError},
+        test_utils::{add_contract, empty_state, funded_state, test_block},
+    };
 
     use super::*;
 
     #[test]
     fn test_block_proofs_basic_get_account_info() {
-        let mut state = BlockProofsBasic {
-            proofs: HashMap::default(),
-            code: HashMap::default(),
-            block_hashes: HashMap::default(),
-        };
+        let mut state = empty_state();
         let mut proof = EIP1186ProofResponse::default();
         let address = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
         proof.address = address;
@@ -162,4 +383,241 @@ mod test {
         };
         assert_eq!(retreived_account, expected_account);
     }
+
+    /// Tests that contract code is only loaded into the DB when requested by code hash, and
+    /// that the load is recorded.
+    #[test]
+    fn test_contract_code_loaded_lazily() {
+        let mut state = empty_state();
+        let code = vec![0x60, 0x00, 0x60, 0x00, 0xf3];
+        let address = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let code_hash = add_contract(&mut state, address, code.clone());
+
+        let mut db = build_state_from_proofs(&state).unwrap();
+        let hash = B256::from(code_hash.0);
+        // Account is present, but the code is not yet in the cache.
+        let account = db.accounts.get(&address.0.into()).unwrap();
+        assert_eq!(account.info.code_hash, hash);
+        assert!(account.info.code.is_none());
+        assert!(!db.contracts.contains_key(&hash));
+        assert!(db.db.loaded_code_hashes().is_empty());
+
+        // Requesting the code brings it into the cache, and records the load.
+        let bytecode = Database::code_by_hash(&mut db, hash).unwrap();
+        assert_eq!(bytecode.bytecode.to_vec(), code);
+        assert!(db.contracts.contains_key(&hash));
+        assert_eq!(db.db.loaded_code_hashes(), HashSet::from([hash]));
+    }
+
+    /// Tests that calling an account whose code was not provided is a database error, rather
+    /// than a call to empty code.
+    #[test]
+    fn test_missing_code_is_an_error() {
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let contract = H160::from_str("0x0200000000000000000000000000000000000000").unwrap();
+        let mut state = funded_state(sender);
+        let contract_proof = EIP1186ProofResponse {
+            address: contract,
+            code_hash: H256::repeat_byte(1),
+            ..Default::default()
+        };
+        state.proofs.insert(contract, contract_proof);
+
+        let mut db = build_state_from_proofs(&state).unwrap();
+        let hash = B256::from(H256::repeat_byte(1).0);
+        assert_eq!(
+            Database::code_by_hash(&mut db, hash),
+            Err(CodeDbError::MissingCode(hex_encode(hash)))
+        );
+
+        let block = test_block(vec![]);
+        let tx = ethers::types::Transaction {
+            from: sender,
+            to: Some(contract),
+            gas: 100_000u64.into(),
+            gas_price: Some(EU256::zero()),
+            ..Default::default()
+        };
+        let mut evm = BlockEvm::init_from_db(db);
+        evm.add_block_environment(&block).unwrap();
+        let error = evm
+            .add_transaction_environment(tx)
+            .unwrap()
+            .execute_and_commit()
+            .unwrap_err();
+        assert!(matches!(error, EvmError::RevmDatabase(_)));
+        assert_eq!(error.kind(), ErrorKind::MissingNode);
+    }
+
+    /// Tests that an account left out of the DB reads as empty during execution.
+    #[test]
+    fn test_omitted_account_reads_as_empty() {
+        let mut state = empty_state();
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let recipient = H160::from_str("0x0200000000000000000000000000000000000000").unwrap();
+        for (address, balance) in [(sender, 1_000_000u64), (recipient, 5)] {
+            let proof = EIP1186ProofResponse {
+                address,
+                balance: balance.into(),
+                nonce: 1u64.into(),
+                ..Default::default()
+            };
+            state.proofs.insert(address, proof);
+        }
+        let block = test_block(vec![]);
+        let tx = ethers::types::Transaction {
+            from: sender,
+            to: Some(recipient),
+            value: 9u64.into(),
+            gas: 21_000u64.into(),
+            gas_price: Some(ethers::types::U256::zero()),
+            ..Default::default()
+        };
+        let recipient_after = |db: BlockDb| {
+            let mut evm = BlockEvm::init_from_db(db);
+            evm.add_block_environment(&block).unwrap();
+            evm.add_transaction_environment(tx.clone())
+                .unwrap()
+                .execute_and_commit()
+                .unwrap();
+            let db = evm.evm.db.as_ref().unwrap();
+            DatabaseRef::basic(db, recipient.0.into()).unwrap().unwrap()
+        };
+
+        let full = recipient_after(build_state_from_proofs(&state).unwrap());
+        assert_eq!(full.balance, U256::from(14));
+        assert_eq!(full.nonce, 1);
+
+        let sparse_db = build_state_from_selected_proofs(&state, &[sender.0.into()]).unwrap();
+        assert!(!sparse_db.accounts.contains_key(&recipient.0.into()));
+        let sparse = recipient_after(sparse_db);
+        assert_eq!(sparse.balance, U256::from(9));
+        assert_eq!(sparse.nonce, 0);
+    }
+
+    /// Tests that a fixture parcel loads into a db with the balance and storage from the
+    /// proofs, the contracts and the blockhashes.
+    #[test]
+    fn test_parcel_into_block_db() {
+        let state = parcel();
+        let proofs = prior_block_proofs();
+        let db = build_block_db(&state).unwrap();
+        assert_eq!(db.accounts.len(), proofs.len());
+        assert_eq!(db.block_hashes.len(), state.blockhashes.len());
+        assert_eq!(db.db.code.len(), state.contracts.len());
+
+        let proof = proofs
+            .values()
+            .find(|proof| !proof.storage_proof.is_empty())
+            .unwrap();
+        let account = db.accounts.get(&B160::from(proof.address.0)).unwrap();
+        assert_eq!(account.info.balance, eu256_to_ru256(proof.balance).unwrap());
+        let slot = &proof.storage_proof[0];
+        assert_eq!(
+            account.storage[&eh256_to_ru256(slot.key)],
+            eu256_to_ru256(slot.value).unwrap()
+        );
+    }
+
+    /// Tests that fields omitted from the earliest state of an account are zero, rather than
+    /// taken from a later transaction.
+    #[test]
+    fn test_prestate_omitted_fields_are_zero() {
+        let prestate: BlockPrestateResponse = serde_json::from_str(
+            r#"{
+            "result": [
+                {"result": {
+                    "0x0300000000000000000000000000000000000000": {"balance": "0x5"},
+                    "0x0200000000000000000000000000000000000000": {}
+                }},
+                {"result": {
+                    "0x0300000000000000000000000000000000000000": {"nonce": 1, "code": "0x6000"},
+                    "0x0200000000000000000000000000000000000000": {"balance": "0x9", "nonce": 3}
+                }}
+            ]
+        }"#,
+        )
+        .unwrap();
+        let db = build_state_from_prestate(&prestate).unwrap();
+
+        let account = |address: &str| {
+            let address: B160 = H160::from_str(address).unwrap().0.into();
+            DatabaseRef::basic(&db, address).unwrap().unwrap()
+        };
+        let first = account("0x0300000000000000000000000000000000000000");
+        assert_eq!(first.balance, U256::from(5));
+        assert_eq!(first.nonce, 0);
+        assert_eq!(first.code_hash, B256::from(EMPTY_CODE_HASH));
+        let second = account("0x0200000000000000000000000000000000000000");
+        assert_eq!(second.balance, U256::ZERO);
+        assert_eq!(second.nonce, 0);
+    }
+
+    /// Tests that a transfer can be traced from prestate tracer output alone, with the
+    /// earliest state of each account used.
+    #[test]
+    fn test_transfer_from_prestate() {
+        let prestate: BlockPrestateResponse = serde_json::from_str(
+            r#"{
+            "result": [
+                {"result": {
+                    "0x0300000000000000000000000000000000000000": {"balance": "0xf4240", "nonce": 1},
+                    "0x0200000000000000000000000000000000000000": {"balance": "0x5"}
+                }},
+                {"result": {
+                    "0x0300000000000000000000000000000000000000": {"balance": "0x1", "nonce": 2},
+                    "0x0100000000000000000000000000000000000000": {
+                        "balance": "0x0",
+                        "code": "0x6000",
+                        "storage": {
+                            "0x0000000000000000000000000000000000000000000000000000000000000001": "0x000000000000000000000000000000000000000000000000000000000000002a"
+                        }
+                    }
+                }}
+            ]
+        }"#,
+        )
+        .unwrap();
+        let db = build_state_from_prestate(&prestate).unwrap();
+
+        let contract: B160 = H160::from_str("0x0100000000000000000000000000000000000000")
+            .unwrap()
+            .0
+            .into();
+        let code_hash = keccak256(&[0x60, 0x00]);
+        assert_eq!(
+            db.accounts.get(&contract).unwrap().info.code_hash,
+            code_hash
+        );
+        assert!(db.db.code.contains_key(&code_hash));
+        assert_eq!(
+            DatabaseRef::storage(&db, contract, U256::from(1)).unwrap(),
+            U256::from(42)
+        );
+
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let recipient = H160::from_str("0x0200000000000000000000000000000000000000").unwrap();
+        let block = test_block(vec![]);
+        let tx = ethers::types::Transaction {
+            from: sender,
+            to: Some(recipient),
+            value: 9u64.into(),
+            nonce: 1u64.into(),
+            gas: 21_000u64.into(),
+            gas_price: Some(ethers::types::U256::zero()),
+            ..Default::default()
+        };
+        let mut evm = BlockEvm::init_from_db(db);
+        evm.add_block_environment(&block).unwrap();
+        evm.add_transaction_environment(tx)
+            .unwrap()
+            .execute_and_commit()
+            .unwrap();
+        let db = evm.evm.db.as_ref().unwrap();
+        let recipient = DatabaseRef::basic(db, recipient.0.into()).unwrap().unwrap();
+        assert_eq!(recipient.balance, U256::from(14));
+        let sender = DatabaseRef::basic(db, sender.0.into()).unwrap().unwrap();
+        assert_eq!(sender.balance, U256::from(1_000_000 - 9));
+        assert_eq!(sender.nonce, 2);
+    }
 }
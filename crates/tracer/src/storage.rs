@@ -0,0 +1,199 @@
+//! For recording the storage reads (SLOAD) and writes (SSTORE) made during a transaction.
+//!
+//! This is finer-grained than the post-block state delta, which only has the final value of
+//! each slot.
+
+use std::{cell::RefCell, rc::Rc};
+
+use revm::{
+    interpreter::{opcode, InstructionResult, Interpreter},
+    primitives::{B160, U256},
+    Database, EVMData, Inspector,
+};
+use serde::Serialize;
+
+/// Whether storage was read or written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum StorageAccessKind {
+    Read,
+    Write,
+}
+
+/// A single SLOAD or SSTORE.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageAccess {
+    pub kind: StorageAccessKind,
+    pub address: B160,
+    pub slot: U256,
+    /// Value before the access.
+    pub old: U256,
+    /// Value after the access. Equal to `old` for a read.
+    pub new: U256,
+}
+
+/// An inspector that records storage accesses in execution order.
+///
+/// The inspector is consumed by the EVM, so clones share the same record.
+#[derive(Clone, Debug, Default)]
+pub struct StorageInspector {
+    accesses: Rc<RefCell<Vec<StorageAccess>>>,
+    /// Access started by the current opcode, recorded once the opcode succeeds.
+    pending: Option<StorageAccess>,
+}
+
+impl StorageInspector {
+    /// Returns the accesses recorded so far.
+    pub fn accesses(&self) -> Vec<StorageAccess> {
+        self.accesses.borrow().clone()
+    }
+}
+
+impl<DB: Database> Inspector<DB> for StorageInspector {
+    fn step(
+        &mut self,
+        interp: &mut Interpreter,
+        data: &mut EVMData<'_, DB>,
+        _is_static: bool,
+    ) -> InstructionResult {
+        let address = interp.contract.address;
+        self.pending = match interp.current_opcode() {
+            opcode::SLOAD => interp.stack.peek(0).ok().map(|slot| StorageAccess {
+                kind: StorageAccessKind::Read,
+                address,
+                slot,
+                old: U256::ZERO,
+                new: U256::ZERO,
+            }),
+            opcode::SSTORE => match (interp.stack.peek(0), interp.stack.peek(1)) {
+                (Ok(slot), Ok(new)) => {
+                    present_value(data, address, slot).map(|old| StorageAccess {
+                        kind: StorageAccessKind::Write,
+                        address,
+                        slot,
+                        old,
+                        new,
+                    })
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        InstructionResult::Continue
+    }
+
+    fn step_end(
+        &mut self,
+        interp: &mut Interpreter,
+        _data: &mut EVMData<'_, DB>,
+        _is_static: bool,
+        eval: InstructionResult,
+    ) -> InstructionResult {
+        let Some(mut access) = self.pending.take() else {
+            return InstructionResult::Continue;
+        };
+        if eval != InstructionResult::Continue {
+            return InstructionResult::Continue;
+        }
+        if access.kind == StorageAccessKind::Read {
+            // The loaded value replaces the key on the stack.
+            let Ok(value) = interp.stack.peek(0) else {
+                return InstructionResult::Continue;
+            };
+            access.old = value;
+            access.new = value;
+        }
+        self.accesses.borrow_mut().push(access);
+        InstructionResult::Continue
+    }
+}
+
+/// Value of a slot as seen by the transaction so far. Slots not yet loaded by the
+/// transaction are read from the database.
+fn present_value<DB: Database>(
+    data: &mut EVMData<'_, DB>,
+    address: B160,
+    slot: U256,
+) -> Option<U256> {
+    let loaded = data
+        .journaled_state
+        .state
+        .get(&address)
+        .and_then(|account| account.storage.get(&slot))
+        .map(|storage_slot| storage_slot.present_value);
+    match loaded {
+        Some(value) => Some(value),
+        None => data.db.storage(address, slot).ok(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use ethers::types::{StorageProof, Transaction, H160, H256};
+
+    use crate::{
+        evm::BlockEvm,
+        state::build_state_from_proofs,
+        test_utils::{add_contract, funded_state, test_block},
+    };
+
+    use super::*;
+
+    /// Tests that a read and a write of a slot are captured with before and after values.
+    #[test]
+    fn test_sstore_before_and_after_captured() {
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let contract = H160::from_str("0x0200000000000000000000000000000000000000").unwrap();
+        let mut state = funded_state(sender);
+        // PUSH1 0x01, SLOAD, POP, PUSH1 0x2a, PUSH1 0x01, SSTORE, STOP
+        let code = vec![0x60, 0x01, 0x54, 0x50, 0x60, 0x2a, 0x60, 0x01, 0x55, 0x00];
+        add_contract(&mut state, contract, code);
+        state.proofs.get_mut(&contract).unwrap().storage_proof = vec![StorageProof {
+            key: H256::from_low_u64_be(1),
+            value: 7u64.into(),
+            proof: vec![],
+        }];
+
+        let tx = Transaction {
+            from: sender,
+            to: Some(contract),
+            gas: 100_000u64.into(),
+            gas_price: Some(ethers::types::U256::default()),
+            ..Default::default()
+        };
+        let block = test_block(vec![]);
+        let mut evm = BlockEvm::init_from_db(build_state_from_proofs(&state).unwrap());
+        evm.add_block_environment(&block).unwrap();
+        let (outcome, accesses) = evm
+            .add_transaction_environment(tx)
+            .unwrap()
+            .execute_with_storage_inspector()
+            .unwrap();
+        assert!(outcome.result.is_success());
+
+        let address = B160::from(contract.0);
+        let slot = U256::from(1);
+        assert_eq!(
+            accesses,
+            vec![
+                StorageAccess {
+                    kind: StorageAccessKind::Read,
+                    address,
+                    slot,
+                    old: U256::from(7),
+                    new: U256::from(7),
+                },
+                StorageAccess {
+                    kind: StorageAccessKind::Write,
+                    address,
+                    slot,
+                    old: U256::from(7),
+                    new: U256::from(0x2a),
+                },
+            ]
+        );
+    }
+}
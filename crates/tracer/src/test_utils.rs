@@ -0,0 +1,85 @@
+//! Block state and blocks shared by the tests of this crate.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use ethers::{
+    types::{Block, EIP1186ProofResponse, Transaction, H160, H256, U256},
+    utils::keccak256,
+};
+
+use crate::state::BlockProofsBasic;
+
+/// Block state without any accounts.
+pub(crate) fn empty_state() -> BlockProofsBasic {
+    BlockProofsBasic {
+        proofs: HashMap::default(),
+        code: HashMap::default(),
+        block_hashes: HashMap::default(),
+    }
+}
+
+/// Block state with one account, the sender, which holds 1 ether.
+pub(crate) fn funded_state(sender: H160) -> BlockProofsBasic {
+    let mut state = empty_state();
+    let sender_proof = EIP1186ProofResponse {
+        address: sender,
+        balance: U256::exp10(18),
+        ..Default::default()
+    };
+    state.proofs.insert(sender, sender_proof);
+    state
+}
+
+/// Adds an account with the code (and no balance) to the state. Returns the code hash.
+pub(crate) fn add_contract(state: &mut BlockProofsBasic, address: H160, code: Vec<u8>) -> H256 {
+    let code_hash = H256::from(keccak256(&code));
+    state.code.insert(code_hash, code);
+    let proof = EIP1186ProofResponse {
+        address,
+        code_hash,
+        ..Default::default()
+    };
+    state.proofs.insert(address, proof);
+    code_hash
+}
+
+/// A block (Istanbul) with the transactions, and a coinbase without a proof.
+pub(crate) fn test_block(transactions: Vec<Transaction>) -> Block<Transaction> {
+    Block {
+        author: Some(H160::default()),
+        number: Some(10_000_000.into()),
+        gas_limit: 30_000_000u64.into(),
+        transactions,
+        ..Default::default()
+    }
+}
+
+/// A path in the temp directory, unique to the test, that is removed when dropped.
+pub(crate) struct TempPath {
+    path: PathBuf,
+}
+
+impl TempPath {
+    pub(crate) fn new(name: &str) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let filename = format!("archors_{name}_{}_{count}", std::process::id());
+        Self {
+            path: std::env::temp_dir().join(filename),
+        }
+    }
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -1,19 +1,36 @@
 //! For executing a block using state.
 
-use std::collections::HashMap;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    rc::Rc,
+    str::FromStr,
+};
 
 use archors_types::{
+    constants::EMPTY_CODE_HASH,
     execution::{EvmStateError, StateForEvm},
-    utils::hex_encode,
+    utils::{eh256_to_ru256, hex_encode},
 };
-use ethers::types::{Block, Transaction, H256};
+use ethers::types::{Block, EIP1186ProofResponse, Transaction, H160, H256};
 use log::{info, warn};
-use revm::primitives::{Account, HashMap as rHashMap, B160, B256, U256};
+use revm::primitives::{
+    Account, AccountInfo, Bytecode, Bytes, HashMap as rHashMap, SpecId, B160, B256, U256,
+};
+use serde::{Deserialize, Serialize};
+use snap::write::FrameEncoder;
 use thiserror::Error;
 
 use crate::{
+    calls::CallFrame,
+    checkpoint::ExecutionCheckpoint,
+    dao::{apply_dao_fork_transition, DAO_FORK_BLOCK, DAO_REFUND_CONTRACT},
+    diff::TxStateDiff,
     evm::{BlockEvm, EvmError},
-    state::build_state_from_proofs,
+    state::build_block_db,
 };
 
 /// An error with tracing a block
@@ -36,6 +53,28 @@ pub enum TraceError {
     TxExecutionError { source: EvmError, index: usize },
     #[error("Transaction does not have an index")]
     TxWithoutIndex,
+    #[error("Transaction at position {position} in the block has index {index}")]
+    TxIndexMismatch { position: usize, index: usize },
+    #[error("Unable to serialize state snapshot {0}")]
+    SnapshotSerialization(String),
+    #[error("Unable to serialize state diff {0}")]
+    DiffSerialization(String),
+    #[error("EVM database absent")]
+    NoEvmDatabase,
+    #[error("Coinbase {0} has no proof, required to update the state root with fees")]
+    NoCoinbaseProof(String),
+    #[error("Computed post-state differs from post-block proofs for {count} value(s): {0:?}", count = .0.len())]
+    PostStateMismatch(Vec<AccountDiscrepancy>),
+    #[error("Unable to write trace output {0}")]
+    TraceOutput(String),
+    #[error("Computed state root {computed_root} after transaction (tx_index {index}) does not match expected root {expected_root}")]
+    IntermediateStateRoot {
+        index: usize,
+        computed_root: String,
+        expected_root: String,
+    },
+    #[error("Unable to serialize or deserialize execution checkpoint {0}")]
+    CheckpointSerialization(String),
 }
 
 /// Whether after tracing a block the post-execution state root should be computed
@@ -57,44 +96,199 @@ pub struct BlockExecutor<T: StateForEvm> {
     block_proof_cache: T,
     /// Flag to check post-execution state root or not.
     root_check: PostExecutionProof,
+    /// Accounts drained in the DAO fork irregular state transition (mainnet only).
+    dao_drain_list: Option<Vec<B160>>,
+    /// Post-block proofs to compare touched accounts against after execution.
+    post_state: Option<HashMap<H160, EIP1186ProofResponse>>,
+    /// If present, the state root is computed after every transaction and compared
+    /// against the root at the transaction index (if there is one).
+    intermediate_roots: Option<Vec<H256>>,
+    /// If present, execution resumes from this checkpoint rather than the start of the block.
+    checkpoint: Option<ExecutionCheckpoint>,
 }
 
 impl<T: StateForEvm> BlockExecutor<T> {
     /// Loads the tracer so that it is ready to trace a block.
+    ///
+    /// The state may be a parcel (`RequiredBlockState`), see `build_block_db`.
     pub fn load(
         block: Block<Transaction>,
         block_proofs: T,
         root_check: PostExecutionProof,
     ) -> Result<Self, TraceError> {
         // For all important states, load into db.
-        let mut cache_db = build_state_from_proofs(&block_proofs)?;
-        cache_db.block_hashes = block_proofs.get_blockhash_accesses()?;
-        let mut block_evm = BlockEvm::init_from_db(cache_db);
-        warn!("Did not set spec_id for hard fork");
+        let mut block_evm = BlockEvm::init_from_db(build_block_db(&block_proofs)?);
         block_evm
             .add_chain_id(U256::from(1))
-            .add_spec_id(&block)? // TODO
+            .add_spec_id(&block)?
             .add_block_environment(&block)?;
+        check_coinbase_proof(&block, &block_proofs, &root_check)?;
         Ok(BlockExecutor {
             block_evm,
             block,
             block_proof_cache: block_proofs,
             root_check,
+            dao_drain_list: None,
+            post_state: None,
+            intermediate_roots: None,
+            checkpoint: None,
         })
     }
+    /// Provides the accounts drained at the DAO fork block.
+    ///
+    /// The balances are moved to the refund contract before transactions are applied, if the
+    /// block is the mainnet DAO fork block. Other blocks are unaffected.
+    pub fn with_dao_drain_list(mut self, drain_list: Vec<B160>) -> Self {
+        self.dao_drain_list = Some(drain_list);
+        self
+    }
+    /// Provides post-block proofs (EIP-1186) to check the computed post-state against.
+    ///
+    /// After execution, every touched account is compared individually. Any difference
+    /// results in `TraceError::PostStateMismatch`. This pinpoints the accounts responsible
+    /// for a state root mismatch.
+    pub fn with_post_state(mut self, post_proofs: HashMap<H160, EIP1186ProofResponse>) -> Self {
+        self.post_state = Some(post_proofs);
+        self
+    }
+    /// Computes the state root after every transaction, checking it against the expected
+    /// root for that transaction (`expected[tx_index]`), if provided. Transactions beyond
+    /// the end of the list are not checked.
+    ///
+    /// This localizes a post-block state root mismatch to the first transaction that
+    /// diverges. The state must support root computation (see `PostExecutionProof`).
+    pub fn with_intermediate_roots(mut self, expected: Vec<H256>) -> Self {
+        self.intermediate_roots = Some(expected);
+        self
+    }
+    /// Resumes execution from a checkpoint (see `execute_to_checkpoint`). Transactions
+    /// before the checkpoint are not executed.
+    ///
+    /// The checkpoint replaces the state of the EVM database, so it must be used with the
+    /// same block and block state that it was created with.
+    pub fn with_checkpoint(mut self, checkpoint: ExecutionCheckpoint) -> Self {
+        self.checkpoint = Some(checkpoint);
+        self
+    }
+    /// Caps the gas available to each transaction below the gas in the transaction.
+    ///
+    /// A transaction that runs out of gas because of the cap results in
+    /// `EvmError::GasCapExceeded`.
+    pub fn with_gas_cap(mut self, cap: u64) -> Self {
+        self.block_evm.add_gas_cap(cap);
+        self
+    }
+    /// Replaces the code of an account before the block is executed, keeping its balance,
+    /// nonce and storage. For "what-if" traces, such as a contract upgrade replayed
+    /// against historical state.
+    ///
+    /// The post-block state root will not match the header, so use
+    /// `PostExecutionProof::Ignore`.
+    pub fn with_code_override(mut self, address: H160, code: Vec<u8>) -> Result<Self, TraceError> {
+        let db = self
+            .block_evm
+            .evm
+            .db
+            .as_mut()
+            .ok_or(TraceError::NoEvmDatabase)?;
+        let address = B160::from(address.0);
+        let mut info = db
+            .accounts
+            .get(&address)
+            .map(|account| account.info.clone())
+            .unwrap_or_default();
+        let bytecode = Bytecode::new_raw(Bytes::from(code));
+        info.code_hash = bytecode.hash_slow();
+        info.code = Some(bytecode);
+        // Storage of an existing account is unchanged.
+        db.insert_account_info(address, info);
+        Ok(self)
+    }
+    /// Applies any irregular state transition that occurs at the start of the block.
+    ///
+    /// Returns the accounts changed.
+    fn apply_irregular_state_changes(&mut self) -> Result<rHashMap<B160, Account>, TraceError> {
+        let is_mainnet = self.block_evm.evm.env.cfg.chain_id == U256::from(1);
+        let is_dao_fork_block = self.block.number == Some(DAO_FORK_BLOCK.into());
+        if !(is_mainnet && is_dao_fork_block) {
+            return Ok(rHashMap::default());
+        }
+        let Some(drain_list) = &self.dao_drain_list else {
+            warn!("DAO fork block executed without drain list, post-block root will not match");
+            return Ok(rHashMap::default());
+        };
+        info!("Applying DAO fork irregular state transition");
+        let refund_contract =
+            B160::from_str(DAO_REFUND_CONTRACT).expect("Refund contract address is valid");
+        let db = self
+            .block_evm
+            .evm
+            .db
+            .as_mut()
+            .ok_or(TraceError::NoEvmDatabase)?;
+        Ok(apply_dao_fork_transition(db, drain_list, refund_contract)?)
+    }
+    /// Prepares the state for the first transaction to execute. Either restores the
+    /// checkpoint, or applies any irregular state changes at the start of the block.
+    ///
+    /// Returns the state changes so far and the index of the first transaction to execute.
+    fn start_execution(&mut self) -> Result<(PostBlockStateDelta, usize), TraceError> {
+        if let Some(checkpoint) = self.checkpoint.take() {
+            let db = self
+                .block_evm
+                .evm
+                .db
+                .as_mut()
+                .ok_or(TraceError::NoEvmDatabase)?;
+            let (delta, next_tx_index) = checkpoint.restore(db);
+            info!("Resuming block execution at transaction {next_tx_index}");
+            return Ok((delta, next_tx_index));
+        }
+        let mut post_block_state_delta = PostBlockStateDelta::default();
+        let spec_id = self.block_evm.evm.env.cfg.spec_id;
+        let irregular_changes = self.apply_irregular_state_changes()?;
+        post_block_state_delta.append_tx_changes(
+            irregular_changes,
+            spec_id,
+            &self.block_proof_cache,
+        )?;
+        Ok((post_block_state_delta, 0))
+    }
+    /// Executes the first `tx_count` transactions in the block without producing a trace,
+    /// then returns a checkpoint that execution can be resumed from (see `with_checkpoint`).
+    ///
+    /// Useful for very large blocks, where earlier transactions need not be executed again.
+    pub fn execute_to_checkpoint(self, tx_count: usize) -> Result<ExecutionCheckpoint, TraceError> {
+        let records = self.trace_block_internal(TraceOutput::Silent, false, Some(tx_count))?;
+        Ok(records
+            .checkpoint
+            .expect("Execution with a stopping point produces a checkpoint"))
+    }
     /// Traces a single transaction in the block.
     ///
     /// The entire block is executed but only the specified transaction is inspected
     /// (trace sent to stdout)
     pub fn trace_transaction(mut self, target_tx_index: usize) -> Result<T, TraceError> {
-        let mut post_block_state_delta = PostBlockStateDelta::default();
+        let spec_id = self.block_evm.evm.env.cfg.spec_id;
+        let (mut post_block_state_delta, first_tx) = self.start_execution()?;
 
-        for (check_idx, tx) in self.block.transactions.into_iter().enumerate() {
+        for (check_idx, tx) in self
+            .block
+            .transactions
+            .into_iter()
+            .enumerate()
+            .skip(first_tx)
+        {
             let index = tx
                 .transaction_index
                 .ok_or(TraceError::TxWithoutIndex)?
                 .as_u64() as usize;
-            assert_eq!(check_idx, index);
+            if check_idx != index {
+                return Err(TraceError::TxIndexMismatch {
+                    position: check_idx,
+                    index,
+                });
+            }
             let primed = self
                 .block_evm
                 .add_transaction_environment(tx)
@@ -114,7 +308,11 @@ impl<T: StateForEvm> BlockExecutor<T> {
                         .map_err(|source| TraceError::TxExecutionError { source, index })?
                 }
             };
-            post_block_state_delta.append_tx_changes(post_tx.state)?;
+            post_block_state_delta.append_tx_changes(
+                post_tx.state,
+                spec_id,
+                &self.block_proof_cache,
+            )?;
         }
 
         post_execution_check(
@@ -127,49 +325,267 @@ impl<T: StateForEvm> BlockExecutor<T> {
     }
     /// Traces every transaction in the block.
     pub fn trace_block(self) -> Result<T, TraceError> {
-        self.trace_block_internal(false)
+        Ok(self
+            .trace_block_internal(TraceOutput::Stdout, false, None)?
+            .state)
     }
     /// Trace a block without producing a trace to stdout. Used for debugging.
     pub fn trace_block_silent(self) -> Result<T, TraceError> {
-        self.trace_block_internal(true)
+        Ok(self
+            .trace_block_internal(TraceOutput::Silent, false, None)?
+            .state)
+    }
+    /// Traces every transaction in the block, writing the trace to a snappy-compressed
+    /// (framed) file rather than stdout.
+    ///
+    /// Useful for archiving traces, which can be many GB uncompressed.
+    pub fn trace_block_to_compressed_file(self, path: &Path) -> Result<T, TraceError> {
+        let file = File::create(path).map_err(|e| TraceError::TraceOutput(e.to_string()))?;
+        let writer = SharedWriter::new(FrameEncoder::new(BufWriter::new(file)));
+        let state = self
+            .trace_block_internal(TraceOutput::Writer(writer.clone()), false, None)?
+            .state;
+        writer
+            .finish()
+            .map_err(|e| TraceError::TraceOutput(e.to_string()))?;
+        Ok(state)
+    }
+    /// Executes a block without producing a trace and returns a snapshot of all state
+    /// touched by the block, as it is after the block.
+    ///
+    /// The snapshot can be diffed against an independent execution.
+    pub fn execute_block_with_snapshot(self) -> Result<(T, StateSnapshot), TraceError> {
+        let records = self.trace_block_internal(TraceOutput::Silent, false, None)?;
+        Ok((records.state, records.snapshot))
+    }
+    /// Executes a block without producing a trace and returns the state diff of each
+    /// transaction, in the Geth prestateTracer diff mode format.
+    pub fn execute_block_with_state_diffs(self) -> Result<(T, Vec<TxStateDiff>), TraceError> {
+        let records = self.trace_block_internal(TraceOutput::Silent, true, None)?;
+        Ok((records.state, records.state_diffs))
+    }
+    /// Executes a block without producing a trace and returns the hashes of the contract
+    /// code that the EVM loads. This is code that is executed, or that is loaded with an
+    /// account read by another contract (EXTCODESIZE, EXTCODECOPY, EXTCODEHASH).
+    ///
+    /// Contracts with other code hashes are not required to re-execute the block. Code
+    /// loaded before a checkpoint that execution resumes from is not included.
+    pub fn execute_block_with_code_hashes(self) -> Result<(T, HashSet<B256>), TraceError> {
+        let records = self.trace_block_internal(TraceOutput::Silent, false, None)?;
+        Ok((records.state, records.code_hashes))
+    }
+    /// Executes a block and returns the tree of calls made by each transaction, in the
+    /// style of the Geth `callTracer`.
+    pub fn execute_block_with_call_traces(self) -> Result<(T, Vec<CallFrame>), TraceError> {
+        let records = self.trace_block_internal(TraceOutput::CallTraces, false, None)?;
+        Ok((records.state, records.call_traces))
     }
-    /// Executes a block. The execution trace can be toggled off.
-    fn trace_block_internal(mut self, silent: bool) -> Result<T, TraceError> {
+    /// Executes a block. The execution trace can be toggled off or sent to a writer.
+    /// Per-transaction state diffs are only produced if requested.
+    ///
+    /// If `stop_before` is a transaction index, execution stops before that transaction
+    /// and a checkpoint is returned instead of performing the post-execution checks.
+    fn trace_block_internal(
+        mut self,
+        output: TraceOutput,
+        diff_mode: bool,
+        stop_before: Option<usize>,
+    ) -> Result<BlockRecords<T>, TraceError> {
         info!("Executing block using pre-state and transactions");
-        let mut post_block_state_delta = PostBlockStateDelta::default();
-        for (check_idx, tx) in self.block.transactions.into_iter().enumerate() {
+        let spec_id = self.block_evm.evm.env.cfg.spec_id;
+        let (mut post_block_state_delta, first_tx) = self.start_execution()?;
+        let tx_total = self.block.transactions.len();
+        let stop_before = stop_before.map(|index| index.min(tx_total).max(first_tx));
+        if self.intermediate_roots.is_some() {
+            // Irregular changes (or a checkpoint) precede the first transaction.
+            self.block_proof_cache
+                .state_root_post_block(post_block_state_delta.clone().get_changes())?;
+        }
+        let mut state_diffs = vec![];
+        let mut call_traces = vec![];
+        for (check_idx, tx) in self
+            .block
+            .transactions
+            .into_iter()
+            .enumerate()
+            .take(stop_before.unwrap_or(tx_total))
+            .skip(first_tx)
+        {
             let index = tx
                 .transaction_index
                 .ok_or(TraceError::TxWithoutIndex)?
                 .as_u64() as usize;
-            assert_eq!(check_idx, index);
+            if check_idx != index {
+                return Err(TraceError::TxIndexMismatch {
+                    position: check_idx,
+                    index,
+                });
+            }
             let primed = self
                 .block_evm
                 .add_transaction_environment(tx)
                 .map_err(|source| TraceError::TxEnvError { source, index })?;
 
-            let post_tx = match silent {
-                false => primed
+            let post_tx = match &output {
+                TraceOutput::Stdout => primed
                     .execute_with_inspector_eip3155()
                     .map_err(|source| TraceError::TxExecutionError { source, index })?,
-                true => primed
+                TraceOutput::Writer(writer) => primed
+                    .execute_with_inspector_eip3155_to(Box::new(writer.clone()))
+                    .map_err(|source| TraceError::TxExecutionError { source, index })?,
+                TraceOutput::Silent => primed
                     .execute_without_inspector()
                     .map_err(|source| TraceError::TxExecutionError { source, index })?,
+                TraceOutput::CallTraces => {
+                    let (post_tx, call_tree) = primed
+                        .execute_with_call_inspector()
+                        .map_err(|source| TraceError::TxExecutionError { source, index })?;
+                    call_traces.extend(call_tree);
+                    post_tx
+                }
             };
 
             let _result = post_tx.result;
+            if diff_mode {
+                state_diffs.push(
+                    post_block_state_delta.tx_state_diff(&post_tx.state, &self.block_proof_cache),
+                );
+            }
+            let touched: Vec<B160> = post_tx.state.keys().copied().collect();
             // Update a proof object with state that changed after a transaction was executed.
-            post_block_state_delta.append_tx_changes(post_tx.state)?;
+            post_block_state_delta.append_tx_changes(
+                post_tx.state,
+                spec_id,
+                &self.block_proof_cache,
+            )?;
+            if let Some(expected_roots) = &self.intermediate_roots {
+                let computed_root = self
+                    .block_proof_cache
+                    .state_root_post_block(post_block_state_delta.changes_for(&touched))?;
+                intermediate_root_ok(index, expected_roots.get(index), &computed_root)?;
+            }
         }
 
+        let snapshot = post_block_state_delta.snapshot();
+        let db = self
+            .block_evm
+            .evm
+            .db
+            .as_ref()
+            .ok_or(TraceError::NoEvmDatabase)?;
+        let code_hashes = db.db.loaded_code_hashes();
+        if let Some(next_tx_index) = stop_before {
+            let checkpoint = ExecutionCheckpoint::new(db, next_tx_index, post_block_state_delta);
+            return Ok(BlockRecords {
+                state: self.block_proof_cache,
+                snapshot,
+                state_diffs,
+                code_hashes,
+                call_traces,
+                checkpoint: Some(checkpoint),
+            });
+        }
+        if let Some(post_proofs) = &self.post_state {
+            let discrepancies = snapshot.compare_with_post_state(post_proofs);
+            if !discrepancies.is_empty() {
+                return Err(TraceError::PostStateMismatch(discrepancies));
+            }
+            info!("Post-execution account values match post-block proofs.");
+        }
+        // Intermediate roots have already applied every change to the state.
+        let remaining_delta = match self.intermediate_roots {
+            Some(_) => PostBlockStateDelta::default(),
+            None => post_block_state_delta,
+        };
         post_execution_check(
             self.root_check,
             self.block.state_root,
             &mut self.block_proof_cache,
-            post_block_state_delta,
+            remaining_delta,
         )?;
-        Ok(self.block_proof_cache)
+        Ok(BlockRecords {
+            state: self.block_proof_cache,
+            snapshot,
+            state_diffs,
+            code_hashes,
+            call_traces,
+            checkpoint: None,
+        })
+    }
+}
+
+/// Values recorded while executing a block (see `trace_block_internal`).
+struct BlockRecords<T> {
+    /// The block state, with post-block changes applied if the root was computed.
+    state: T,
+    /// State touched by the block, after the last executed transaction.
+    snapshot: StateSnapshot,
+    /// Per-transaction state diffs, empty unless requested.
+    state_diffs: Vec<TxStateDiff>,
+    /// Hashes of the contract code loaded by the EVM.
+    code_hashes: HashSet<B256>,
+    /// The call tree of each transaction, empty unless requested.
+    call_traces: Vec<CallFrame>,
+    /// Present if execution stopped before the end of the block.
+    checkpoint: Option<ExecutionCheckpoint>,
+}
+
+/// Destination of the EIP-3155 trace produced during block execution, or the record made
+/// instead of the trace.
+enum TraceOutput {
+    Silent,
+    Stdout,
+    Writer(SharedWriter),
+    /// The call tree of each transaction (Geth `callTracer` style).
+    CallTraces,
+}
+
+/// A writer that is shared by the inspectors of all transactions in a block.
+///
+/// The inspector flushes after every line, which for a compressing writer would produce
+/// many small frames. So flushing is deferred until the block is finished.
+#[derive(Clone)]
+struct SharedWriter(Rc<RefCell<Box<dyn Write>>>);
+
+impl SharedWriter {
+    fn new(writer: impl Write + 'static) -> Self {
+        Self(Rc::new(RefCell::new(Box::new(writer))))
     }
+    /// Flushes all buffered output to the underlying writer.
+    fn finish(&self) -> std::io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Checks that the coinbase has a proof if the post-block state root will be computed.
+///
+/// The EVM credits the fees of every transaction to the coinbase, so the coinbase is
+/// always in the post-block state delta. Without a proof, the balance would start from
+/// zero and the state root update would fail.
+fn check_coinbase_proof<T: StateForEvm>(
+    block: &Block<Transaction>,
+    block_proofs: &T,
+    root_check: &PostExecutionProof,
+) -> Result<(), TraceError> {
+    if matches!(root_check, PostExecutionProof::Ignore) || block.transactions.is_empty() {
+        return Ok(());
+    }
+    let Some(coinbase) = block.author else {
+        return Ok(());
+    };
+    if !block_proofs.addresses().contains(&B160::from(coinbase.0)) {
+        return Err(TraceError::NoCoinbaseProof(hex_encode(coinbase)));
+    }
+    Ok(())
 }
 
 /// If required, updates the state multiproof with the changes acquired from block execution, then
@@ -213,27 +629,86 @@ fn post_root_ok(&header_root: &H256, computed_root: &B256) -> Result<(), TraceEr
     Ok(())
 }
 
+/// Checks the state root after a transaction against the expected root, if there is one.
+fn intermediate_root_ok(
+    index: usize,
+    expected_root: Option<&H256>,
+    computed_root: &B256,
+) -> Result<(), TraceError> {
+    info!(
+        "State root after transaction {index}: {}",
+        hex_encode(computed_root)
+    );
+    let Some(expected_root) = expected_root else {
+        return Ok(());
+    };
+    if computed_root != &B256::from(expected_root.0) {
+        return Err(TraceError::IntermediateStateRoot {
+            index,
+            computed_root: hex_encode(computed_root),
+            expected_root: hex_encode(expected_root),
+        });
+    }
+    Ok(())
+}
+
 /// Produces the net account changes caused by running the EVM across multiple transactions.
 ///
 /// REVM produces net changes after one transaction. If two transactions affect the same
 /// account, the storage slot changes should be included from both transactions. Later
 /// changes overwrite earlier changes.
 ///
-/// Other members in Account (.is_destroyed, etc) are not updated and are not used elsewhere.
-#[derive(Default, Debug, Clone)]
+/// Account.is_destroyed is only kept if the SELFDESTRUCT removes the account for the active
+/// spec (see `selfdestruct_removes_account`). Other members in Account (.is_touched, etc)
+/// are not updated and are not used elsewhere.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct PostBlockStateDelta(HashMap<B160, Account>);
 
 impl PostBlockStateDelta {
     /// Add state changes for multiple accounts to the state delta accumulator.
-    fn append_tx_changes(
+    ///
+    /// The prestate is used to determine if a self-destructed account was created in the
+    /// transaction.
+    fn append_tx_changes<T: StateForEvm>(
         &mut self,
         changed_accounts: rHashMap<B160, Account>,
+        spec_id: SpecId,
+        prestate: &T,
     ) -> Result<(), TraceError> {
-        for (address, account) in changed_accounts {
+        for (address, mut account) in changed_accounts {
+            if account.is_destroyed {
+                let created_in_tx = !self.existed_before_tx(&address, prestate);
+                account.is_destroyed = selfdestruct_removes_account(spec_id, created_in_tx);
+            }
             self.append_account_changes(address, account)?;
         }
         Ok(())
     }
+    /// State diff for the changes of a single transaction that has not yet been appended.
+    fn tx_state_diff<T: StateForEvm>(
+        &self,
+        changed_accounts: &rHashMap<B160, Account>,
+        prestate: &T,
+    ) -> TxStateDiff {
+        TxStateDiff::from_changes(changed_accounts, |address| match self.0.get(address) {
+            Some(account) => account.info.clone(),
+            None => prestate
+                .get_account_info_without_code(address)
+                .unwrap_or_default(),
+        })
+    }
+    /// Whether the account had code or a nonce before the current transaction.
+    ///
+    /// Accounts changed earlier in the block are checked first, then the block prestate.
+    fn existed_before_tx<T: StateForEvm>(&self, address: &B160, prestate: &T) -> bool {
+        match self.0.get(address) {
+            Some(account) => !account.is_destroyed && has_code_or_nonce(&account.info),
+            None => prestate
+                .get_account_info_without_code(address)
+                .map(|info| has_code_or_nonce(&info))
+                .unwrap_or(false),
+        }
+    }
     /// Add state changes for one account to the state delta accumulator.
     fn append_account_changes(
         &mut self,
@@ -253,12 +728,173 @@ impl PostBlockStateDelta {
         }
         // Update account components
         summary.info = changes.info;
+        summary.is_destroyed = changes.is_destroyed;
         Ok(())
     }
     /// Returns the inner map of account changes.
     fn get_changes(self) -> HashMap<B160, Account> {
         self.0
     }
+    /// Returns the accumulated changes for the given accounts.
+    fn changes_for(&self, addresses: &[B160]) -> HashMap<B160, Account> {
+        addresses
+            .iter()
+            .filter_map(|address| Some((*address, self.0.get(address)?.clone())))
+            .collect()
+    }
+    /// Returns the post-block values of every account touched in the block.
+    pub fn snapshot(&self) -> StateSnapshot {
+        let accounts = self
+            .0
+            .iter()
+            .map(|(address, account)| {
+                let storage = account
+                    .storage
+                    .iter()
+                    .map(|(key, slot)| (*key, slot.present_value))
+                    .collect();
+                let snapshot = AccountSnapshot {
+                    balance: account.info.balance,
+                    nonce: account.info.nonce,
+                    code_hash: account.info.code_hash,
+                    storage,
+                };
+                (*address, snapshot)
+            })
+            .collect();
+        StateSnapshot(accounts)
+    }
+}
+
+/// Whether a SELFDESTRUCT removes the account from the state trie.
+///
+/// From Cancun (EIP-6780), only an account created in the same transaction is removed.
+/// Otherwise the balance is sent to the beneficiary and the account remains.
+pub fn selfdestruct_removes_account(spec_id: SpecId, created_in_tx: bool) -> bool {
+    !SpecId::enabled(spec_id, SpecId::CANCUN) || created_in_tx
+}
+
+/// Whether an account has a nonce or code, which an account created in a transaction lacks.
+fn has_code_or_nonce(info: &AccountInfo) -> bool {
+    let no_code = info.code_hash == B256(EMPTY_CODE_HASH) || info.code_hash == B256::zero();
+    info.nonce != 0 || !no_code
+}
+
+/// Touched state after a block has been executed. Ordered by address.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct StateSnapshot(pub BTreeMap<B160, AccountSnapshot>);
+
+impl StateSnapshot {
+    /// Returns the snapshot as JSON (address -> {balance, nonce, code_hash, storage}).
+    pub fn to_json(&self) -> Result<String, TraceError> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| TraceError::SnapshotSerialization(e.to_string()))
+    }
+    /// Compares each account in the snapshot with the claimed post-block values in
+    /// EIP-1186 proofs (keyed by address).
+    ///
+    /// Balance, nonce and code hash are compared. Storage is compared for slots that are
+    /// both touched in the block and present in the post-block proof.
+    pub fn compare_with_post_state(
+        &self,
+        post_proofs: &HashMap<H160, EIP1186ProofResponse>,
+    ) -> Vec<AccountDiscrepancy> {
+        let mut discrepancies = vec![];
+        for (address, computed) in &self.0 {
+            let Some(claimed) = post_proofs.get(&H160::from(address.0)) else {
+                discrepancies.push(AccountDiscrepancy {
+                    address: *address,
+                    kind: DiscrepancyKind::AbsentFromPostState,
+                });
+                continue;
+            };
+            let mut push = |kind| {
+                discrepancies.push(AccountDiscrepancy {
+                    address: *address,
+                    kind,
+                })
+            };
+            let claimed_balance: U256 = claimed.balance.into();
+            if computed.balance != claimed_balance {
+                push(DiscrepancyKind::Balance {
+                    computed: computed.balance,
+                    claimed: claimed_balance,
+                });
+            }
+            let claimed_nonce = claimed.nonce.as_u64();
+            if computed.nonce != claimed_nonce {
+                push(DiscrepancyKind::Nonce {
+                    computed: computed.nonce,
+                    claimed: claimed_nonce,
+                });
+            }
+            let claimed_code_hash = B256::from(claimed.code_hash.0);
+            if computed.code_hash != claimed_code_hash {
+                push(DiscrepancyKind::CodeHash {
+                    computed: computed.code_hash,
+                    claimed: claimed_code_hash,
+                });
+            }
+            for storage in &claimed.storage_proof {
+                let key = eh256_to_ru256(storage.key);
+                let Some(computed_value) = computed.storage.get(&key) else {
+                    continue;
+                };
+                let claimed_value: U256 = storage.value.into();
+                if *computed_value != claimed_value {
+                    push(DiscrepancyKind::StorageSlot {
+                        key,
+                        computed: *computed_value,
+                        claimed: claimed_value,
+                    });
+                }
+            }
+        }
+        discrepancies
+    }
+}
+
+/// A difference between a computed post-block account value and the value claimed in
+/// a post-block proof.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountDiscrepancy {
+    pub address: B160,
+    pub kind: DiscrepancyKind,
+}
+
+/// The account value that differs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiscrepancyKind {
+    /// Account touched in the block has no post-block proof.
+    AbsentFromPostState,
+    Balance {
+        computed: U256,
+        claimed: U256,
+    },
+    Nonce {
+        computed: u64,
+        claimed: u64,
+    },
+    CodeHash {
+        computed: B256,
+        claimed: B256,
+    },
+    StorageSlot {
+        key: U256,
+        computed: U256,
+        claimed: U256,
+    },
+}
+
+/// Values for a single account after a block has been executed.
+///
+/// Only storage slots touched in the block are included. Ordered by key.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct AccountSnapshot {
+    pub balance: U256,
+    pub nonce: u64,
+    pub code_hash: B256,
+    pub storage: BTreeMap<U256, U256>,
 }
 
 #[cfg(test)]
@@ -266,24 +902,27 @@ mod test {
     use super::*;
     use std::str::FromStr;
 
-    use ethers::types::{EIP1186ProofResponse, H160};
+    use archors_types::{
+        proof::{DisplayProof, DisplayStorageProof},
+        utils::eu256_to_ru256,
+    };
+    use ethers::types::StorageProof;
     use revm::{
         db::{CacheDB, DatabaseRef, EmptyDB},
         primitives::{AccountInfo, HashMap as rHashMap, StorageSlot, U256},
     };
 
-    use crate::state::BlockProofsBasic;
+    use crate::{
+        state::BlockProofsBasic,
+        test_utils::{add_contract, empty_state, funded_state, test_block, TempPath},
+    };
 
     /// Tests that a EVM environnment can be constructed from proof data for a block
     /// Values are set for an account, transactions are created and then
     /// applied by running the EVM.
     #[test]
     fn test_trace_block_composable() {
-        let mut state = BlockProofsBasic {
-            proofs: HashMap::default(),
-            code: HashMap::default(),
-            block_hashes: HashMap::default(),
-        };
+        let mut state = empty_state();
         let mut proof = EIP1186ProofResponse::default();
         let address = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
         proof.address = address;
@@ -315,6 +954,557 @@ mod test {
         assert!(executor.trace_block().is_err());
     }
 
+    /// Tests that the post-block snapshot reflects a transfer.
+    #[test]
+    fn test_snapshot_after_transfer() {
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let recipient = H160::from_str("0x0200000000000000000000000000000000000000").unwrap();
+        let initial_balance = ethers::types::U256::exp10(18);
+        let state = funded_state(sender);
+
+        let gas_price = 7u64;
+        let value = 9u64;
+        let tx = Transaction {
+            from: sender,
+            to: Some(recipient),
+            value: value.into(),
+            gas: 21_000u64.into(),
+            gas_price: Some(gas_price.into()),
+            transaction_index: Some(0u64.into()),
+            ..Default::default()
+        };
+        let block = test_block(vec![tx]);
+        let executor = BlockExecutor::load(block, state, PostExecutionProof::Ignore).unwrap();
+        let (_, snapshot) = executor.execute_block_with_snapshot().unwrap();
+
+        let sender_snapshot = snapshot.0.get(&B160::from(sender.0)).unwrap();
+        let expected_balance = eu256_to_ru256(initial_balance).unwrap()
+            - U256::from(value)
+            - U256::from(21_000 * gas_price);
+        assert_eq!(sender_snapshot.balance, expected_balance);
+        assert_eq!(sender_snapshot.nonce, 1);
+        let recipient_snapshot = snapshot.0.get(&B160::from(recipient.0)).unwrap();
+        assert_eq!(recipient_snapshot.balance, U256::from(value));
+        assert!(snapshot.to_json().is_ok());
+    }
+
+    /// Tests that the priority fees of a block are credited to the coinbase, and that the
+    /// coinbase proof is required to update the state root.
+    #[test]
+    fn test_coinbase_receives_priority_fee() {
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let recipient = H160::from_str("0x0200000000000000000000000000000000000000").unwrap();
+        let coinbase = H160::from_str("0x0100000000000000000000000000000000000000").unwrap();
+        let coinbase_balance = 1_000u64;
+        let state = |with_coinbase: bool| {
+            let mut state = funded_state(sender);
+            if with_coinbase {
+                let coinbase_proof = EIP1186ProofResponse {
+                    address: coinbase,
+                    balance: coinbase_balance.into(),
+                    ..Default::default()
+                };
+                state.proofs.insert(coinbase, coinbase_proof);
+            }
+            state
+        };
+        let base_fee = 10u64;
+        let gas_price = 15u64;
+        let tx = Transaction {
+            from: sender,
+            to: Some(recipient),
+            gas: 21_000u64.into(),
+            gas_price: Some(gas_price.into()),
+            transaction_index: Some(0u64.into()),
+            ..Default::default()
+        };
+        // London, the first hard fork with a base fee.
+        let block = Block::<Transaction> {
+            author: Some(coinbase),
+            number: Some(12_965_000.into()),
+            gas_limit: 30_000_000u64.into(),
+            base_fee_per_gas: Some(base_fee.into()),
+            transactions: vec![tx],
+            ..Default::default()
+        };
+        let executor =
+            BlockExecutor::load(block.clone(), state(true), PostExecutionProof::Ignore).unwrap();
+        let (_, snapshot) = executor.execute_block_with_snapshot().unwrap();
+        let coinbase_snapshot = snapshot.0.get(&B160::from(coinbase.0)).unwrap();
+        let priority_fee = (gas_price - base_fee) * 21_000;
+        assert_eq!(
+            coinbase_snapshot.balance,
+            U256::from(coinbase_balance + priority_fee)
+        );
+
+        assert_eq!(
+            BlockExecutor::load(block, state(false), PostExecutionProof::Update).err(),
+            Some(TraceError::NoCoinbaseProof(hex_encode(coinbase)))
+        );
+    }
+
+    /// Tests that a transaction exceeding the gas cap is reported distinctly from an
+    /// ordinary out-of-gas result.
+    #[test]
+    fn test_gas_cap_exceeded() {
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let looper = H160::from_str("0x0200000000000000000000000000000000000000").unwrap();
+        let state = || {
+            let mut state = funded_state(sender);
+            // JUMPDEST, PUSH1 0x00, JUMP (loops until out of gas).
+            add_contract(&mut state, looper, vec![0x5b, 0x60, 0x00, 0x56]);
+            state
+        };
+        let block = || {
+            let tx = Transaction {
+                from: sender,
+                to: Some(looper),
+                gas: 1_000_000u64.into(),
+                gas_price: Some(ethers::types::U256::default()),
+                transaction_index: Some(0u64.into()),
+                ..Default::default()
+            };
+            test_block(vec![tx])
+        };
+        // Without a cap the transaction runs out of gas as an ordinary result.
+        let executor = BlockExecutor::load(block(), state(), PostExecutionProof::Ignore).unwrap();
+        assert!(executor.trace_block_silent().is_ok());
+
+        let executor = BlockExecutor::load(block(), state(), PostExecutionProof::Ignore)
+            .unwrap()
+            .with_gas_cap(50_000);
+        assert_eq!(
+            executor.trace_block_silent().err(),
+            Some(TraceError::TxExecutionError {
+                source: EvmError::GasCapExceeded {
+                    cap: 50_000,
+                    tx_gas: 1_000_000
+                },
+                index: 0
+            })
+        );
+    }
+
+    /// Tests that a transaction is reported as exceeding the gas cap when a nested call runs
+    /// out of gas, even though the transaction itself succeeds.
+    #[test]
+    fn test_gas_cap_exceeded_in_nested_call() {
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let caller = H160::from_str("0x0200000000000000000000000000000000000000").unwrap();
+        let looper = H160::from_str("0x0100000000000000000000000000000000000000").unwrap();
+        let state = || {
+            let mut state = funded_state(sender);
+            // JUMPDEST, PUSH1 0x00, JUMP (loops until out of gas).
+            add_contract(&mut state, looper, vec![0x5b, 0x60, 0x00, 0x56]);
+            // PUSH1 0x00 (x5), PUSH20 looper, GAS, CALL, POP, STOP. The call result is
+            // discarded, so the transaction succeeds.
+            let mut caller_code = vec![0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00];
+            caller_code.push(0x73);
+            caller_code.extend_from_slice(looper.as_bytes());
+            caller_code.extend_from_slice(&[0x5a, 0xf1, 0x50, 0x00]);
+            add_contract(&mut state, caller, caller_code);
+            state
+        };
+        let tx = Transaction {
+            from: sender,
+            to: Some(caller),
+            gas: 1_000_000u64.into(),
+            gas_price: Some(ethers::types::U256::default()),
+            transaction_index: Some(0u64.into()),
+            ..Default::default()
+        };
+        let block = test_block(vec![tx]);
+        let executor = BlockExecutor::load(block, state(), PostExecutionProof::Ignore)
+            .unwrap()
+            .with_gas_cap(50_000);
+        assert_eq!(
+            executor.trace_block_silent().err(),
+            Some(TraceError::TxExecutionError {
+                source: EvmError::GasCapExceeded {
+                    cap: 50_000,
+                    tx_gas: 1_000_000
+                },
+                index: 0
+            })
+        );
+    }
+
+    /// Tests that a transaction with a nonce that differs from the sender state is reported
+    /// as a nonce mismatch.
+    #[test]
+    fn test_nonce_mismatch() {
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let recipient = H160::from_str("0x0200000000000000000000000000000000000000").unwrap();
+        let mut state = funded_state(sender);
+        state.proofs.get_mut(&sender).unwrap().nonce = 2u64.into();
+        let tx = Transaction {
+            from: sender,
+            to: Some(recipient),
+            gas: 21_000u64.into(),
+            gas_price: Some(ethers::types::U256::default()),
+            nonce: 5u64.into(),
+            transaction_index: Some(0u64.into()),
+            ..Default::default()
+        };
+        let block = test_block(vec![tx]);
+        let executor = BlockExecutor::load(block, state, PostExecutionProof::Ignore).unwrap();
+        assert_eq!(
+            executor.trace_block_silent().err(),
+            Some(TraceError::TxExecutionError {
+                source: EvmError::NonceMismatch {
+                    expected: 2,
+                    got: 5
+                },
+                index: 0
+            })
+        );
+    }
+
+    /// State that tracks balances, with a "root" that commits to the nonzero balances.
+    struct BalanceRootState {
+        inner: BlockProofsBasic,
+        balances: BTreeMap<B160, U256>,
+    }
+
+    impl BalanceRootState {
+        fn root(&self) -> B256 {
+            let mut data = vec![];
+            for (address, balance) in &self.balances {
+                if *balance != U256::ZERO {
+                    data.extend_from_slice(address.as_bytes());
+                    data.extend_from_slice(&balance.to_be_bytes::<32>());
+                }
+            }
+            revm::primitives::keccak256(&data)
+        }
+    }
+
+    impl StateForEvm for BalanceRootState {
+        fn get_account_info(&self, address: &B160) -> Result<AccountInfo, EvmStateError> {
+            self.inner.get_account_info(address)
+        }
+        fn get_contracts(&self) -> Result<rHashMap<B256, Bytecode>, EvmStateError> {
+            self.inner.get_contracts()
+        }
+        fn addresses(&self) -> Vec<B160> {
+            self.inner.addresses()
+        }
+        fn get_account_storage(
+            &self,
+            address: &B160,
+        ) -> Result<rHashMap<U256, U256>, EvmStateError> {
+            self.inner.get_account_storage(address)
+        }
+        fn get_blockhash_accesses(&self) -> Result<rHashMap<U256, B256>, EvmStateError> {
+            self.inner.get_blockhash_accesses()
+        }
+        fn state_root_post_block(
+            &mut self,
+            changes: HashMap<B160, Account>,
+        ) -> Result<B256, EvmStateError> {
+            for (address, account) in changes {
+                self.balances.insert(address, account.info.balance);
+            }
+            Ok(self.root())
+        }
+        fn print_account_proof<T: AsRef<str>>(
+            &self,
+            account_address: T,
+        ) -> Result<DisplayProof, EvmStateError> {
+            self.inner.print_account_proof(account_address)
+        }
+        fn print_storage_proof<T: AsRef<str>>(
+            &self,
+            account_address: T,
+            storage_key: T,
+        ) -> Result<DisplayStorageProof, EvmStateError> {
+            self.inner.print_storage_proof(account_address, storage_key)
+        }
+    }
+
+    /// Tests that the state root is computed after each transaction and that the first
+    /// transaction with an unexpected root is reported.
+    #[test]
+    fn test_intermediate_state_roots() {
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let recipient = H160::from_str("0x0200000000000000000000000000000000000000").unwrap();
+        let initial_balance = ethers::types::U256::exp10(18);
+        let state = || {
+            let inner = funded_state(sender);
+            let balances = BTreeMap::from([(
+                B160::from(sender.0),
+                eu256_to_ru256(initial_balance).unwrap(),
+            )]);
+            BalanceRootState { inner, balances }
+        };
+        let block = || {
+            let transactions = (0..2u64)
+                .map(|index| Transaction {
+                    from: sender,
+                    to: Some(recipient),
+                    value: 9u64.into(),
+                    gas: 21_000u64.into(),
+                    gas_price: Some(ethers::types::U256::default()),
+                    nonce: index.into(),
+                    transaction_index: Some(index.into()),
+                    ..Default::default()
+                })
+                .collect();
+            test_block(transactions)
+        };
+        // Root after the first transfer, computed independently.
+        let mut after_tx_0 = state();
+        after_tx_0.balances.insert(
+            B160::from(sender.0),
+            eu256_to_ru256(initial_balance).unwrap() - U256::from(9),
+        );
+        after_tx_0
+            .balances
+            .insert(B160::from(recipient.0), U256::from(9));
+        let root_after_tx_0 = H256::from(after_tx_0.root().0);
+
+        let executor = BlockExecutor::load(block(), state(), PostExecutionProof::Ignore)
+            .unwrap()
+            .with_intermediate_roots(vec![root_after_tx_0]);
+        let post_state = executor.trace_block_silent().unwrap();
+        assert_eq!(
+            post_state.balances.get(&B160::from(recipient.0)),
+            Some(&U256::from(18))
+        );
+
+        // The second transaction is the first to diverge.
+        let executor = BlockExecutor::load(block(), state(), PostExecutionProof::Ignore)
+            .unwrap()
+            .with_intermediate_roots(vec![root_after_tx_0, root_after_tx_0]);
+        assert!(matches!(
+            executor.trace_block_silent(),
+            Err(TraceError::IntermediateStateRoot { index: 1, .. })
+        ));
+    }
+
+    /// Tests that a block executed in two parts, with a serialized checkpoint in between,
+    /// has the same post-block state root as the block executed at once.
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let recipient = H160::from_str("0x0200000000000000000000000000000000000000").unwrap();
+        let coinbase = H160::from_str("0x0400000000000000000000000000000000000000").unwrap();
+        let initial_balance = ethers::types::U256::exp10(18);
+        let state = || {
+            let mut inner = funded_state(sender);
+            let coinbase_proof = EIP1186ProofResponse {
+                address: coinbase,
+                ..Default::default()
+            };
+            inner.proofs.insert(coinbase, coinbase_proof);
+            let balances = BTreeMap::from([(
+                B160::from(sender.0),
+                eu256_to_ru256(initial_balance).unwrap(),
+            )]);
+            BalanceRootState { inner, balances }
+        };
+        // Root after all four transfers, computed independently.
+        let mut expected = state();
+        expected.balances.insert(
+            B160::from(sender.0),
+            eu256_to_ru256(initial_balance).unwrap() - U256::from(36),
+        );
+        expected
+            .balances
+            .insert(B160::from(recipient.0), U256::from(36));
+        let block = || {
+            let transactions = (0..4u64)
+                .map(|index| Transaction {
+                    from: sender,
+                    to: Some(recipient),
+                    value: 9u64.into(),
+                    gas: 21_000u64.into(),
+                    gas_price: Some(ethers::types::U256::default()),
+                    nonce: index.into(),
+                    transaction_index: Some(index.into()),
+                    ..Default::default()
+                })
+                .collect();
+            Block::<Transaction> {
+                author: Some(coinbase),
+                number: Some(10_000_000.into()),
+                gas_limit: 30_000_000u64.into(),
+                state_root: H256::from(expected.root().0),
+                transactions,
+                ..Default::default()
+            }
+        };
+
+        let checkpoint = BlockExecutor::load(block(), state(), PostExecutionProof::Update)
+            .unwrap()
+            .execute_to_checkpoint(2)
+            .unwrap();
+        assert_eq!(checkpoint.next_tx_index, 2);
+        let json = checkpoint.to_json().unwrap();
+        let reloaded = ExecutionCheckpoint::from_json(&json).unwrap();
+
+        let post_state = BlockExecutor::load(block(), state(), PostExecutionProof::Update)
+            .unwrap()
+            .with_checkpoint(reloaded)
+            .trace_block_silent()
+            .unwrap();
+        assert_eq!(
+            post_state.balances.get(&B160::from(recipient.0)),
+            Some(&U256::from(36))
+        );
+
+        // A checkpoint that skips a transaction leaves the sender with an earlier nonce.
+        let mut skipped = ExecutionCheckpoint::from_json(&json).unwrap();
+        skipped.next_tx_index = 3;
+        let executor = BlockExecutor::load(block(), state(), PostExecutionProof::Update)
+            .unwrap()
+            .with_checkpoint(skipped);
+        assert_eq!(
+            executor.trace_block_silent().err(),
+            Some(TraceError::TxExecutionError {
+                source: EvmError::NonceMismatch {
+                    expected: 2,
+                    got: 3
+                },
+                index: 3
+            })
+        );
+    }
+
+    /// Tests that an account with overridden code executes the override, with its
+    /// existing storage.
+    #[test]
+    fn test_code_override() {
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let contract = H160::from_str("0x0200000000000000000000000000000000000000").unwrap();
+        let mut state = funded_state(sender);
+        // PUSH1 0x01, PUSH1 0x00, SSTORE, STOP
+        add_contract(
+            &mut state,
+            contract,
+            vec![0x60, 0x01, 0x60, 0x00, 0x55, 0x00],
+        );
+        state.proofs.get_mut(&contract).unwrap().storage_proof = vec![StorageProof {
+            key: H256::from_low_u64_be(5),
+            value: 9u64.into(),
+            proof: vec![],
+        }];
+        let tx = Transaction {
+            from: sender,
+            to: Some(contract),
+            gas: 100_000u64.into(),
+            gas_price: Some(ethers::types::U256::default()),
+            transaction_index: Some(0u64.into()),
+            ..Default::default()
+        };
+        let block = test_block(vec![tx]);
+        // PUSH1 0x05, SLOAD, PUSH1 0x00, SSTORE, STOP (copies slot 5 to slot 0)
+        let new_code = vec![0x60, 0x05, 0x54, 0x60, 0x00, 0x55, 0x00];
+        let executor = BlockExecutor::load(block, state, PostExecutionProof::Ignore)
+            .unwrap()
+            .with_code_override(contract, new_code.clone())
+            .unwrap();
+        let (_, snapshot) = executor.execute_block_with_snapshot().unwrap();
+
+        let account = snapshot.0.get(&B160::from(contract.0)).unwrap();
+        assert_eq!(
+            account.code_hash,
+            B256::from(ethers::utils::keccak256(&new_code))
+        );
+        assert_eq!(account.storage.get(&U256::ZERO), Some(&U256::from(9)));
+    }
+
+    /// Tests that a trace written to a compressed file decompresses to EIP-3155 lines.
+    #[test]
+    fn test_trace_to_compressed_file() {
+        use std::io::{BufRead, BufReader};
+
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let adder = H160::from_str("0x0200000000000000000000000000000000000000").unwrap();
+        let mut state = funded_state(sender);
+        // PUSH1 0x01, PUSH1 0x02, ADD, STOP.
+        add_contract(&mut state, adder, vec![0x60, 0x01, 0x60, 0x02, 0x01, 0x00]);
+        let tx = Transaction {
+            from: sender,
+            to: Some(adder),
+            gas: 100_000u64.into(),
+            gas_price: Some(ethers::types::U256::default()),
+            transaction_index: Some(0u64.into()),
+            ..Default::default()
+        };
+        let block = test_block(vec![tx]);
+        let temp = TempPath::new("test_trace");
+        let executor = BlockExecutor::load(block, state, PostExecutionProof::Ignore).unwrap();
+        executor
+            .trace_block_to_compressed_file(temp.path())
+            .unwrap();
+
+        let file = File::open(temp.path()).unwrap();
+        let reader = BufReader::new(snap::read::FrameDecoder::new(file));
+        let lines: Vec<serde_json::Value> = reader
+            .lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect();
+        let ops: Vec<&str> = lines
+            .iter()
+            .filter_map(|line| line.get("opName")?.as_str())
+            .collect();
+        assert_eq!(ops, ["PUSH1", "PUSH1", "ADD", "STOP"]);
+    }
+
+    /// Tests that at the DAO fork block drained balances are moved to the refund contract.
+    #[test]
+    fn test_dao_fork_transition() {
+        let drained = [
+            H160::from_str("0x0300000000000000000000000000000000000000").unwrap(),
+            H160::from_str("0x0400000000000000000000000000000000000000").unwrap(),
+        ];
+        let refund = H160::from_str(DAO_REFUND_CONTRACT).unwrap();
+        let make_state = || {
+            let mut state = empty_state();
+            for (address, balance) in [(drained[0], 5u64), (drained[1], 7u64), (refund, 1u64)] {
+                let proof = EIP1186ProofResponse {
+                    address,
+                    balance: balance.into(),
+                    ..Default::default()
+                };
+                state.proofs.insert(address, proof);
+            }
+            state
+        };
+        let make_block = |number: u64| Block::<Transaction> {
+            author: Some(H160::default()),
+            number: Some(number.into()),
+            ..Default::default()
+        };
+        let drain_list: Vec<B160> = drained.iter().map(|a| B160::from(a.0)).collect();
+
+        let executor = BlockExecutor::load(
+            make_block(DAO_FORK_BLOCK),
+            make_state(),
+            PostExecutionProof::Ignore,
+        )
+        .unwrap()
+        .with_dao_drain_list(drain_list.clone());
+        let (_, snapshot) = executor.execute_block_with_snapshot().unwrap();
+        let refund_balance = snapshot.0.get(&B160::from(refund.0)).unwrap().balance;
+        assert_eq!(refund_balance, U256::from(13));
+        for address in &drain_list {
+            assert_eq!(snapshot.0.get(address).unwrap().balance, U256::ZERO);
+        }
+
+        // Other blocks are unaffected.
+        let executor = BlockExecutor::load(
+            make_block(DAO_FORK_BLOCK + 1),
+            make_state(),
+            PostExecutionProof::Ignore,
+        )
+        .unwrap()
+        .with_dao_drain_list(drain_list);
+        let (_, snapshot) = executor.execute_block_with_snapshot().unwrap();
+        assert!(snapshot.0.is_empty());
+    }
+
     /// Test case from revm crate.
     #[test]
     pub fn test_replace_account_storage() {
@@ -432,4 +1622,191 @@ mod test {
         );
         assert_eq!(net_account.info.nonce, 2);
     }
+
+    /// A contract that exists before the block self-destructs. It is only removed before
+    /// Cancun. A contract created and destroyed in the same transaction is always removed.
+    #[test]
+    fn test_selfdestruct_pre_and_post_eip6780() {
+        let existing = B160::from_str("0x00000000000000adc04c56bf30ac9d3c0aaf14dc").unwrap();
+        let created = B160::from_str("0x0100000000000000000000000000000000000000").unwrap();
+        let mut prestate = empty_state();
+        let existing_info = account_factory().info;
+        prestate.proofs.insert(
+            existing.0.into(),
+            EIP1186ProofResponse {
+                address: existing.0.into(),
+                nonce: existing_info.nonce.into(),
+                code_hash: existing_info.code_hash.0.into(),
+                ..Default::default()
+            },
+        );
+        let destroyed = || {
+            let mut account = account_factory();
+            account.is_destroyed = true;
+            account
+        };
+        let tx_changes = || rHashMap::from_iter([(existing, destroyed()), (created, destroyed())]);
+
+        let mut pre_cancun = PostBlockStateDelta::default();
+        pre_cancun
+            .append_tx_changes(tx_changes(), SpecId::SHANGHAI, &prestate)
+            .unwrap();
+        let net = pre_cancun.get_changes();
+        assert!(net.get(&existing).unwrap().is_destroyed);
+        assert!(net.get(&created).unwrap().is_destroyed);
+
+        let mut post_cancun = PostBlockStateDelta::default();
+        post_cancun
+            .append_tx_changes(tx_changes(), SpecId::CANCUN, &prestate)
+            .unwrap();
+        let net = post_cancun.get_changes();
+        assert!(!net.get(&existing).unwrap().is_destroyed);
+        assert!(net.get(&created).unwrap().is_destroyed);
+    }
+
+    /// Tests that a single wrong value in the post-block proofs is reported for that account.
+    #[test]
+    fn test_post_state_discrepancy_reported() {
+        let address = B160::from_str("0x00000000000000adc04c56bf30ac9d3c0aaf14dc").unwrap();
+        let info = account_factory().info;
+        let key = U256::from(1);
+        let snapshot = StateSnapshot(BTreeMap::from([(
+            address,
+            AccountSnapshot {
+                balance: info.balance,
+                nonce: info.nonce,
+                code_hash: info.code_hash,
+                storage: BTreeMap::from([(key, U256::from(7))]),
+            },
+        )]));
+        let post_proof = |slot_value: u64| EIP1186ProofResponse {
+            address: address.0.into(),
+            balance: 1.into(),
+            nonce: info.nonce.into(),
+            code_hash: info.code_hash.0.into(),
+            storage_proof: vec![StorageProof {
+                key: H256::from_low_u64_be(1),
+                value: slot_value.into(),
+                proof: vec![],
+            }],
+            ..Default::default()
+        };
+        let post = HashMap::from([(H160::from(address.0), post_proof(7))]);
+        assert!(snapshot.compare_with_post_state(&post).is_empty());
+
+        let post = HashMap::from([(H160::from(address.0), post_proof(8))]);
+        assert_eq!(
+            snapshot.compare_with_post_state(&post),
+            vec![AccountDiscrepancy {
+                address,
+                kind: DiscrepancyKind::StorageSlot {
+                    key,
+                    computed: U256::from(7),
+                    claimed: U256::from(8),
+                },
+            }]
+        );
+        assert_eq!(
+            snapshot.compare_with_post_state(&HashMap::default()),
+            vec![AccountDiscrepancy {
+                address,
+                kind: DiscrepancyKind::AbsentFromPostState,
+            }]
+        );
+    }
+
+    /// Tests that the code loaded by the block is recorded: code that is executed, or read
+    /// by EXTCODESIZE or EXTCODEHASH. Code of an account that only has its balance read is
+    /// not recorded.
+    #[test]
+    fn test_loaded_code_hashes() {
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let caller = H160::from_str("0x0200000000000000000000000000000000000000").unwrap();
+        let sized = H160::from_str("0x0400000000000000000000000000000000000000").unwrap();
+        let hashed = H160::from_str("0x0500000000000000000000000000000000000000").unwrap();
+        let balance_only = H160::from_str("0x0600000000000000000000000000000000000000").unwrap();
+        let mut state = funded_state(sender);
+        // For each account: PUSH20 address, EXTCODESIZE | EXTCODEHASH | BALANCE, POP. Then STOP.
+        let mut caller_code = vec![];
+        for (address, opcode) in [(sized, 0x3b), (hashed, 0x3f), (balance_only, 0x31)] {
+            caller_code.push(0x73);
+            caller_code.extend_from_slice(address.as_bytes());
+            caller_code.extend_from_slice(&[opcode, 0x50]);
+        }
+        caller_code.push(0x00);
+        let code_hashes: Vec<B256> = [
+            (caller, caller_code),
+            (sized, vec![0x60, 0x01, 0x00]),
+            (hashed, vec![0x60, 0x02, 0x00]),
+            (balance_only, vec![0x60, 0x03, 0x00]),
+        ]
+        .into_iter()
+        .map(|(address, code)| B256::from(add_contract(&mut state, address, code).0))
+        .collect();
+        let tx = Transaction {
+            from: sender,
+            to: Some(caller),
+            gas: 100_000u64.into(),
+            gas_price: Some(ethers::types::U256::default()),
+            transaction_index: Some(0u64.into()),
+            ..Default::default()
+        };
+        let block = test_block(vec![tx]);
+        let (_, loaded) = BlockExecutor::load(block, state, PostExecutionProof::Ignore)
+            .unwrap()
+            .execute_block_with_code_hashes()
+            .unwrap();
+        assert_eq!(
+            loaded,
+            HashSet::from([code_hashes[0], code_hashes[1], code_hashes[2]])
+        );
+    }
+
+    /// Tests that block execution records a call tree for each transaction, and that a
+    /// transaction index that differs from its position in the block is an error.
+    #[test]
+    fn test_call_traces_per_transaction() {
+        let sender = H160::from_str("0x0300000000000000000000000000000000000000").unwrap();
+        let recipient = H160::from_str("0x0200000000000000000000000000000000000000").unwrap();
+        let state = || funded_state(sender);
+        let block = |indices: [u64; 2]| {
+            let transactions = indices
+                .into_iter()
+                .enumerate()
+                .map(|(nonce, index)| Transaction {
+                    from: sender,
+                    to: Some(recipient),
+                    value: 9u64.into(),
+                    gas: 21_000u64.into(),
+                    gas_price: Some(ethers::types::U256::default()),
+                    nonce: nonce.into(),
+                    transaction_index: Some(index.into()),
+                    ..Default::default()
+                })
+                .collect();
+            test_block(transactions)
+        };
+
+        let (_, call_traces) =
+            BlockExecutor::load(block([0, 1]), state(), PostExecutionProof::Ignore)
+                .unwrap()
+                .execute_block_with_call_traces()
+                .unwrap();
+        assert_eq!(call_traces.len(), 2);
+        for frame in call_traces {
+            assert_eq!(frame.to, Some(recipient));
+            assert_eq!(frame.value, Some(9u64.into()));
+            assert_eq!(frame.error, None);
+        }
+
+        let executor =
+            BlockExecutor::load(block([0, 5]), state(), PostExecutionProof::Ignore).unwrap();
+        assert_eq!(
+            executor.execute_block_with_call_traces().err(),
+            Some(TraceError::TxIndexMismatch {
+                position: 1,
+                index: 5
+            })
+        );
+    }
 }
@@ -1,5 +1,19 @@
-//! Useful Simple Serialize (SSZ) constants
-
+//! Useful Simple Serialize (SSZ) constants, and hashes of empty trie and code values.
+
+/// Root of a trie with no keys, keccak256(rlp("")) = keccak256(0x80).
+///
+/// This is the state root of an empty state and the storage root of an account
+/// with no storage.
+pub const EMPTY_TRIE_ROOT: [u8; 32] = [
+    0x56, 0xe8, 0x1f, 0x17, 0x1b, 0xcc, 0x55, 0xa6, 0xff, 0x83, 0x45, 0xe6, 0x92, 0xc0, 0xf8, 0x6e,
+    0x5b, 0x48, 0xe0, 0x1b, 0x99, 0x6c, 0xad, 0xc0, 0x01, 0x62, 0x2f, 0xb5, 0xe3, 0x63, 0xb4, 0x21,
+];
+
+/// Hash of empty code, keccak256(""). This is the code hash of an account with no code.
+pub const EMPTY_CODE_HASH: [u8; 32] = [
+    0xc5, 0xd2, 0x46, 0x01, 0x86, 0xf7, 0x23, 0x3c, 0x92, 0x7e, 0x7d, 0xb2, 0xdc, 0xc7, 0x03, 0xc0,
+    0xe5, 0x00, 0xb6, 0x53, 0xca, 0x82, 0x27, 0x3b, 0x7b, 0xfa, 0xd8, 0x04, 0x5d, 0x85, 0xa4, 0x70,
+];
 
 /// Number of prior blockhashes a block could access via the BLOCKHASH opcode.
 pub const MAX_BLOCKHASH_READS_PER_BLOCK: usize = 256;
@@ -20,6 +34,10 @@ pub const MAX_NODES_PER_PROOF: usize = 64;
 /// Proofs are for the execution of a single block. Set to 2**15.
 pub const MAX_NODES_PER_BLOCK: usize = 32768;
 
+/// Maximum number of trie node oracle targets permitted. The oracle is for the
+/// execution of a single block. Set to 2**13.
+pub const MAX_ORACLE_TARGETS_PER_BLOCK: usize = 8192;
+
 /// Maximum number of account proofs permitted. Proofs are for the execution
 /// of a single block. Set to 2**13.
 pub const MAX_ACCOUNT_PROOFS_PER_BLOCK: usize = 8192;
@@ -27,3 +45,16 @@ pub const MAX_ACCOUNT_PROOFS_PER_BLOCK: usize = 8192;
 /// Maximum number of storage proofs permitted per account.
 /// Proofs are for the execution of a single block. Set to 2**13.
 pub const MAX_STORAGE_PROOFS_PER_ACCOUNT: usize = 8192;
+
+#[cfg(test)]
+mod test {
+    use ethers::utils::keccak256;
+
+    use super::*;
+
+    #[test]
+    fn test_empty_hashes_match_keccak() {
+        assert_eq!(EMPTY_TRIE_ROOT, keccak256([0x80]));
+        assert_eq!(EMPTY_CODE_HASH, keccak256([]));
+    }
+}
@@ -0,0 +1,20 @@
+//! A coarse classification of errors shared by the crates in this workspace.
+
+/// The broad kind of an error, for callers deciding how to respond to a failure.
+///
+/// A malformed proof or missing node will fail again with the same data and indicates
+/// the data should be re-fetched from a provider. Unsupported and invalid input errors
+/// will not be resolved by new data. Internal errors indicate a bug.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ErrorKind {
+    /// A proof or trie node is not correctly formed or does not verify.
+    MalformedProof,
+    /// A trie node, proof or state value required for the operation is not available.
+    MissingNode,
+    /// The data is valid but the operation is not supported.
+    Unsupported,
+    /// Block or transaction data that cannot be used as provided.
+    InvalidInput,
+    /// An invariant of the library was not upheld.
+    Internal,
+}
@@ -2,7 +2,7 @@
 
 use std::collections::HashMap;
 
-use revm::primitives::{Account, AccountInfo, HashMap as rHashMap, B160, B256, U256};
+use revm::primitives::{Account, AccountInfo, Bytecode, HashMap as rHashMap, B160, B256, U256};
 use thiserror::Error;
 
 use crate::{utils::UtilsError, proof::{DisplayProof, DisplayStorageProof}};
@@ -34,6 +34,15 @@ pub trait StateForEvm {
     /// Gets account information in a format that can be inserted into a
     /// revm db. This includes contract bytecode.
     fn get_account_info(&self, address: &B160) -> Result<AccountInfo, EvmStateError>;
+    /// Gets account information without contract bytecode. The code hash is included so
+    /// that the bytecode can be resolved when the EVM requests it.
+    fn get_account_info_without_code(&self, address: &B160) -> Result<AccountInfo, EvmStateError> {
+        let mut info = self.get_account_info(address)?;
+        info.code = None;
+        Ok(info)
+    }
+    /// Gets all contract bytecode, keyed by code hash.
+    fn get_contracts(&self) -> Result<rHashMap<B256, Bytecode>, EvmStateError>;
     /// Gets all the addresses.
     fn addresses(&self) -> Vec<B160>;
     /// Gets the storage key-val pairs for the account of the address.
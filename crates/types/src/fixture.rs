@@ -0,0 +1,41 @@
+//! Cached data for a block (in `data/blocks`), shared by the tests of the workspace.
+//!
+//! Enabled with the `fixture` feature. Functions panic if the data cannot be read.
+
+use std::{collections::HashMap, fs, path::PathBuf};
+
+use ethers::types::{EIP1186ProofResponse, H160};
+
+use crate::state::RequiredBlockState;
+
+/// The block that the fixture data is for.
+pub const FIXTURE_BLOCK: u64 = 17190873;
+
+fn fixture_path(filename: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("../../data/blocks")
+        .join(FIXTURE_BLOCK.to_string())
+        .join(filename)
+}
+
+/// Returns the parcel for the block as stored (ssz_snappy).
+pub fn parcel_bytes() -> Vec<u8> {
+    fs::read(fixture_path(
+        "prior_block_transferrable_state_proofs.ssz_snappy",
+    ))
+    .unwrap()
+}
+
+/// Returns the parcel for the block.
+pub fn parcel() -> RequiredBlockState {
+    RequiredBlockState::from_ssz_snappy_bytes(&parcel_bytes()).unwrap()
+}
+
+/// Returns the account proofs (eth_getProof) for the prior block, that the parcel is made
+/// from, keyed by address.
+pub fn prior_block_proofs() -> HashMap<H160, EIP1186ProofResponse> {
+    let file = fs::read(fixture_path("prior_block_state_proofs.json")).unwrap();
+    let mut stored: HashMap<String, HashMap<H160, EIP1186ProofResponse>> =
+        serde_json::from_slice(&file).unwrap();
+    stored.remove("proofs").unwrap()
+}
@@ -1,6 +1,9 @@
 pub mod alias;
 pub mod constants;
+pub mod error;
 pub mod execution;
+#[cfg(any(test, feature = "fixture"))]
+pub mod fixture;
 pub mod proof;
 pub mod oracle;
 pub mod state;
@@ -1,6 +1,14 @@
 use std::collections::HashMap;
 
 use ethers::types::H160;
+use ssz_rs::prelude::*;
+use ssz_rs_derive::SimpleSerialize;
+
+use crate::{
+    alias::SszH160,
+    constants::{MAX_NODES_PER_PROOF, MAX_ORACLE_TARGETS_PER_BLOCK},
+    state::{StateError, TrieNode},
+};
 
 /// Behaviour that defines an oracle for post-state trie data. When a block updates state
 /// in a way that removes nodes and reorganises the trie, more information may be required.
@@ -39,6 +47,52 @@ impl TrieNodeOracle {
             })
             .map(|x| x.to_owned())
     }
+    /// Number of targets in the oracle.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    /// Serializes the oracle (SSZ). Targets are ordered by address then traversal, so
+    /// equal oracles have equal bytes.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, StateError> {
+        let mut targets: Vec<(&OracleTarget, &Vec<Vec<u8>>)> = self.0.iter().collect();
+        targets.sort_by(|(a, _), (b, _)| {
+            (a.address, &a.traversal_to_target).cmp(&(b.address, &b.traversal_to_target))
+        });
+        // Lists are checked against their limits, so an oversized oracle is an error.
+        let mut entries = vec![];
+        for (target, nodes) in targets {
+            let mut ssz_nodes = vec![];
+            for node in nodes {
+                ssz_nodes.push(TrieNode::try_from(node.to_owned()).map_err(|e| e.1)?);
+            }
+            entries.push(SszOracleEntry {
+                address: SszH160::try_from(target.address.0.to_vec()).map_err(|e| e.1)?,
+                traversal_to_target: List::try_from(target.traversal_to_target.to_owned())
+                    .map_err(|e| e.1)?,
+                nodes: List::try_from(ssz_nodes).map_err(|e| e.1)?,
+            });
+        }
+        let entries = SszOracleEntries::try_from(entries).map_err(|e| e.1)?;
+        let mut buf = vec![];
+        let _ssz_bytes_len = SszTrieNodeOracle { entries }.serialize(&mut buf)?;
+        Ok(buf)
+    }
+    /// Deserializes an oracle produced by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, StateError> {
+        let ssz: SszTrieNodeOracle = deserialize(bytes)?;
+        let mut oracle = TrieNodeOracle::default();
+        for entry in ssz.entries.iter() {
+            oracle.insert_nodes(
+                H160::from_slice(&entry.address),
+                entry.traversal_to_target.to_vec(),
+                entry.nodes.iter().map(|node| node.to_vec()).collect(),
+            );
+        }
+        Ok(oracle)
+    }
 }
 
 /// The key used to look up items in the oracle. Two storage key lookups are permitted to
@@ -58,3 +112,64 @@ struct OracleTarget {
     /// is [0xa, 0x4, 0xf].
     traversal_to_target: Vec<u8>,
 }
+
+/// Encoding of the oracle for storage.
+#[derive(PartialEq, Eq, Debug, Default, SimpleSerialize)]
+struct SszTrieNodeOracle {
+    entries: SszOracleEntries,
+}
+
+type SszOracleEntries = List<SszOracleEntry, MAX_ORACLE_TARGETS_PER_BLOCK>;
+
+/// A single oracle target and its nodes.
+#[derive(PartialEq, Eq, Debug, Default, SimpleSerialize)]
+struct SszOracleEntry {
+    address: SszH160,
+    /// One nibble per byte, at most one per level of the trie.
+    traversal_to_target: List<u8, 64>,
+    nodes: List<TrieNode, MAX_NODES_PER_PROOF>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_oracle_bytes_roundtrip() {
+        let mut oracle = TrieNodeOracle::default();
+        let first = H160::from_low_u64_be(1);
+        let second = H160::from_low_u64_be(2);
+        oracle.insert_nodes(first, vec![0xa, 0x4], vec![vec![0xc2, 0x80, 0x80]]);
+        oracle.insert_nodes(
+            second,
+            vec![0x8, 0x6, 0x6, 0x5],
+            vec![vec![0xe1, 0x01], vec![0xc0]],
+        );
+
+        let bytes = oracle.to_bytes().unwrap();
+        let decoded = TrieNodeOracle::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(
+            decoded.lookup(first, vec![0xa, 0x4]),
+            Some(vec![vec![0xc2, 0x80, 0x80]])
+        );
+        assert_eq!(
+            decoded.lookup(second, vec![0x8, 0x6, 0x6, 0x5]),
+            Some(vec![vec![0xe1, 0x01], vec![0xc0]])
+        );
+        assert_eq!(decoded.lookup(first, vec![0xa]), None);
+        assert_eq!(decoded.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_oracle_bytes_exceeds_entry_limit() {
+        let mut oracle = TrieNodeOracle::default();
+        for index in 0..=MAX_ORACLE_TARGETS_PER_BLOCK as u64 {
+            oracle.insert_nodes(H160::from_low_u64_be(index), vec![0xa], vec![vec![0xc0]]);
+        }
+        assert!(matches!(
+            oracle.to_bytes(),
+            Err(StateError::SimpleSerializeError(_))
+        ));
+    }
+}
@@ -1,5 +1,10 @@
 use std::fmt::Display;
 
+use ethers::{
+    types::{H256, U256},
+    utils::rlp::Rlp,
+};
+
 use crate::utils::hex_encode;
 
 /// A display helper type for storage proofs. Contains of account proof that secures
@@ -47,15 +52,119 @@ impl DisplayProof {
     pub fn inner(&self) -> &[Vec<u8>] {
         &self.0
     }
+    /// Decodes the account in the final node, if the node is an account trie leaf.
+    ///
+    /// Returns None for other nodes, such as a storage trie leaf or an exclusion proof.
+    pub fn account_leaf(&self) -> Option<AccountLeaf> {
+        let leaf = Rlp::new(self.0.last()?);
+        if leaf.item_count().ok()? != 2 {
+            return None;
+        }
+        // Hex prefix flag 2 (even) or 3 (odd) marks a leaf.
+        let flag = leaf.at(0).ok()?.data().ok()?.first()? >> 4;
+        if flag != 2 && flag != 3 {
+            return None;
+        }
+        let value = leaf.at(1).ok()?.data().ok()?;
+        let account = Rlp::new(value);
+        if account.item_count().ok()? != 4 {
+            return None;
+        }
+        let hash_at = |index: usize| -> Option<H256> {
+            let data = account.at(index).ok()?.data().ok()?;
+            (data.len() == 32).then(|| H256::from_slice(data))
+        };
+        Some(AccountLeaf {
+            nonce: account.val_at(0).ok()?,
+            balance: account.val_at(1).ok()?,
+            storage_hash: hash_at(2)?,
+            code_hash: hash_at(3)?,
+        })
+    }
+}
+
+/// The fields of an account, decoded from an account trie leaf.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountLeaf {
+    pub nonce: u64,
+    pub balance: U256,
+    pub storage_hash: H256,
+    pub code_hash: H256,
+}
+
+impl Display for AccountLeaf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "nonce: {}\nbalance: {}\nstorageHash: {}\ncodeHash: {}",
+            self.nonce,
+            self.balance,
+            hex_encode(self.storage_hash),
+            hex_encode(self.code_hash)
+        )
+    }
 }
 
+/// Nodes are displayed as RLP hex. With the alternate flag (`{:#}`), a final node that is
+/// an account leaf is displayed as the account fields instead.
 impl Display for DisplayProof {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "\n----begin proof----\n")?;
-        for node in &self.0 {
+        let account = match f.alternate() {
+            true => self.account_leaf(),
+            false => None,
+        };
+        let raw_nodes = match account {
+            Some(_) => &self.0[..self.0.len() - 1],
+            None => &self.0[..],
+        };
+        for node in raw_nodes {
             write!(f, "\n{}\n", hex_encode(&node))?;
         }
+        if let Some(account) = account {
+            write!(f, "\n{account}\n")?;
+        }
         write!(f, "\n----end proof----\n")?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use ethers::utils::rlp::RlpStream;
+
+    use super::*;
+
+    /// Tests that the alternate display of an account proof shows the account fields.
+    #[test]
+    fn test_display_decoded_account_leaf() {
+        let mut account = RlpStream::new_list(4);
+        account
+            .append(&5u64)
+            .append(&U256::from(1000))
+            .append(&H256::repeat_byte(0x11))
+            .append(&H256::repeat_byte(0x22));
+        let mut path = vec![0x20];
+        path.extend_from_slice(&[0xab; 31]);
+        let mut leaf = RlpStream::new_list(2);
+        leaf.append(&path).append(&account.out().to_vec());
+        let branch = vec![0xc2, 0x80, 0x80];
+        let proof = DisplayProof::init(vec![branch.clone(), leaf.out().to_vec()]);
+
+        let decoded = format!("{proof:#}");
+        assert!(decoded.contains("nonce: 5"));
+        assert!(decoded.contains("balance: 1000"));
+        assert!(decoded.contains(&format!("storageHash: 0x{}", "11".repeat(32))));
+        assert!(decoded.contains(&format!("codeHash: 0x{}", "22".repeat(32))));
+        assert!(decoded.contains(&hex_encode(&branch)));
+
+        let raw = format!("{proof}");
+        assert!(!raw.contains("nonce"));
+        assert!(raw.contains(&"ab".repeat(31)));
+        // A storage leaf (value is not a list) is not decoded.
+        let mut storage_leaf = RlpStream::new_list(2);
+        storage_leaf.append(&path).append(&vec![0x05]);
+        let storage = DisplayProof::init(vec![storage_leaf.out().to_vec()]);
+        assert_eq!(storage.account_leaf(), None);
+    }
+}
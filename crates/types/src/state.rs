@@ -1,8 +1,15 @@
 //! Main data types defined by the spec, for transferrable parcels required for historical
 //! state execution.
 
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Read, Write},
+};
 
+use ethers::{
+    types::{H160, H256, U256 as eU256},
+    utils::rlp::{Rlp, RlpStream},
+};
 use ssz_rs::prelude::*;
 use ssz_rs_derive::SimpleSerialize;
 use thiserror::Error;
@@ -10,9 +17,8 @@ use thiserror::Error;
 use crate::{
     alias::{SszH160, SszH256, SszU256, SszU64},
     constants::{
-        MAX_ACCOUNT_PROOFS_PER_BLOCK, MAX_BYTES_PER_CONTRACT, MAX_BYTES_PER_NODE,
-        MAX_CONTRACTS_PER_BLOCK, MAX_NODES_PER_BLOCK, MAX_NODES_PER_PROOF,
-        MAX_STORAGE_PROOFS_PER_ACCOUNT,
+        EMPTY_TRIE_ROOT, MAX_ACCOUNT_PROOFS_PER_BLOCK, MAX_BYTES_PER_CONTRACT, MAX_BYTES_PER_NODE,
+        MAX_CONTRACTS_PER_BLOCK, MAX_NODES_PER_BLOCK, MAX_STORAGE_PROOFS_PER_ACCOUNT,
     },
     execution::{EvmStateError, StateForEvm},
     proof::{DisplayProof, DisplayStorageProof},
@@ -36,6 +42,16 @@ pub enum StateError {
     UtilsError(#[from] UtilsError),
     #[error("Unable to find index for node")]
     NoIndexForNode,
+    #[error("Contract with keccak {0} does not match the code hash of any account")]
+    ContractCodeHashMismatch(B256),
+    #[error("IO error {0}")]
+    IoError(#[from] io::Error),
+    #[error("Parcel has no account trie root node")]
+    NoStateRoot,
+    #[error("Parcel account proofs have different state roots: {0:?}")]
+    InconsistentStateRoots(Vec<H256>),
+    #[error("Parcel has no trie node with hash {0:?}")]
+    MissingTrieNode(H256),
 }
 
 /// State that has items referred to using indices to deduplicate data.
@@ -52,11 +68,28 @@ pub enum StateError {
 /// - contract code.
 /// - account trie node.
 /// - storage trie node.
+///
+/// Uses the mainnet limits. See `RequiredBlockStateWithLimits` for other limits.
+pub type RequiredBlockState = RequiredBlockStateWithLimits<
+    MAX_ACCOUNT_PROOFS_PER_BLOCK,
+    MAX_CONTRACTS_PER_BLOCK,
+    MAX_NODES_PER_BLOCK,
+>;
+
+/// State required to execute a block, with the per-block list limits as parameters.
+///
+/// Chains with larger blocks than mainnet (e.g., L2s) may require more accounts, contracts
+/// or trie nodes per block. The SSZ encoding of a parcel is the same for any limits that
+/// it fits within, though the hash tree root differs.
 #[derive(PartialEq, Eq, Debug, Default, SimpleSerialize)]
-pub struct RequiredBlockState {
-    pub compact_eip1186_proofs: CompactEip1186Proofs,
-    pub contracts: Contracts,
-    pub trie_nodes: NodeBag,
+pub struct RequiredBlockStateWithLimits<
+    const MAX_ACCOUNTS: usize,
+    const MAX_CONTRACTS: usize,
+    const MAX_NODES: usize,
+> {
+    pub compact_eip1186_proofs: List<CompactEip1186Proof, MAX_ACCOUNTS>,
+    pub contracts: List<Contract, MAX_CONTRACTS>,
+    pub trie_nodes: List<TrieNode, MAX_NODES>,
     pub blockhashes: BlockHashes,
 }
 
@@ -90,7 +123,7 @@ pub struct RecentBlockHash {
 }
 
 /// An EIP-1186 style proof with the trie nodes replaced by their keccak hashes.
-#[derive(PartialEq, Eq, Debug, Default, SimpleSerialize)]
+#[derive(PartialEq, Eq, Debug, Default, Clone, SimpleSerialize)]
 pub struct CompactEip1186Proof {
     pub address: SszH160,
     pub balance: SszU256,
@@ -103,20 +136,15 @@ pub struct CompactEip1186Proof {
 pub type CompactStorageProofs = List<CompactStorageProof, MAX_STORAGE_PROOFS_PER_ACCOUNT>;
 
 /// An EIP-1186 style proof with the trie nodes replaced by their keccak hashes.
-#[derive(PartialEq, Eq, Debug, Default, SimpleSerialize)]
+#[derive(PartialEq, Eq, Debug, Default, Clone, SimpleSerialize)]
 pub struct CompactStorageProof {
     pub key: SszH256,
     pub value: SszU256,
 }
 
-/// An ordered list of indices that point to specific
-/// trie nodes in a different ordered list.
-///
-/// The purpose is deduplication as some nodes appear in different proofs within
-/// the same block.
-pub type NodeIndices = List<u16, MAX_NODES_PER_PROOF>;
-
-impl RequiredBlockState {
+impl<const MAX_ACCOUNTS: usize, const MAX_CONTRACTS: usize, const MAX_NODES: usize>
+    RequiredBlockStateWithLimits<MAX_ACCOUNTS, MAX_CONTRACTS, MAX_NODES>
+{
     pub fn to_ssz_bytes(self) -> Result<Vec<u8>, StateError> {
         let mut buf = vec![];
         let _ssz_bytes_len = self.serialize(&mut buf)?;
@@ -126,9 +154,416 @@ impl RequiredBlockState {
         let proofs = self::deserialize(&ssz_data)?;
         Ok(proofs)
     }
+    /// Writes the parcel as .ssz_snappy (snappy framing format) to the writer.
+    ///
+    /// The SSZ encoding is compressed as it is written, so the compressed parcel is
+    /// never held in memory. Returns the size of the SSZ encoding.
+    pub fn write_ssz_snappy<W: Write>(self, mut writer: W) -> Result<usize, StateError> {
+        let ssz = self.to_ssz_bytes()?;
+        let mut encoder = snap::write::FrameEncoder::new(&mut writer);
+        encoder.write_all(&ssz)?;
+        encoder.flush()?;
+        drop(encoder);
+        writer.flush()?;
+        Ok(ssz.len())
+    }
+    pub fn from_ssz_snappy_bytes(ssz_snappy_data: &[u8]) -> Result<Self, StateError> {
+        let mut ssz = vec![];
+        snap::read::FrameDecoder::new(ssz_snappy_data).read_to_end(&mut ssz)?;
+        Self::from_ssz_bytes(ssz)
+    }
+    /// Returns the address of every account in the parcel, in parcel order.
+    pub fn parcel_addresses(&self) -> Vec<H160> {
+        self.compact_eip1186_proofs
+            .iter()
+            .map(|proof| H160::from_slice(&proof.address))
+            .collect()
+    }
+    /// Returns the account trie nodes on the path to an account (keccak(address)), from the
+    /// state root to the final node. These are the nodes of the account proof.
+    ///
+    /// For an account that is not in the trie, the nodes form an exclusion proof.
+    pub fn account_proof_nodes(&self, address: H160) -> Result<Vec<Vec<u8>>, StateError> {
+        let nodes = self.nodes_by_hash();
+        let state_root = B256::from(self.state_root()?.0);
+        let path = keccak256(address.as_bytes());
+        Ok(path_node_hashes(&nodes, state_root, path)?
+            .iter()
+            .map(|hash| nodes[hash].to_vec())
+            .collect())
+    }
+    /// Splits the parcel into one parcel per account, each with only the trie nodes and
+    /// contracts for that account (account proof, storage proofs and code).
+    ///
+    /// Each parcel is self-contained, so nodes shared by accounts (e.g., near the root) are
+    /// repeated across parcels. Blockhashes are for the whole block and are not included.
+    pub fn split_by_account(&self) -> Result<Vec<(H160, Self)>, StateError> {
+        let nodes = self.nodes_by_hash();
+        let state_root = B256::from(self.state_root()?.0);
+        let node_hashes: Vec<B256> = self.trie_nodes.iter().map(|node| keccak256(node)).collect();
+        let mut parcels = vec![];
+        for account in self.compact_eip1186_proofs.iter() {
+            let address = H160::from_slice(&account.address);
+            let mut required: HashSet<B256> =
+                path_node_hashes(&nodes, state_root, keccak256(address.as_bytes()))?
+                    .into_iter()
+                    .collect();
+            let storage_root = ssz_h256_to_rb256(&account.storage_hash);
+            if storage_root != B256::from(EMPTY_TRIE_ROOT) {
+                for storage in account.storage_proofs.iter() {
+                    let path = keccak256(&storage.key);
+                    required.extend(path_node_hashes(&nodes, storage_root, path)?);
+                }
+            }
+
+            let mut parcel = Self::default();
+            parcel.compact_eip1186_proofs.push(account.clone());
+            for (node, hash) in self.trie_nodes.iter().zip(&node_hashes) {
+                if required.contains(hash) {
+                    parcel.trie_nodes.push(node.clone());
+                }
+            }
+            let code_hash = ssz_h256_to_rb256(&account.code_hash);
+            for contract in self.contracts.iter() {
+                if keccak256(contract) == code_hash {
+                    parcel.contracts.push(contract.clone());
+                }
+            }
+            parcels.push((address, parcel));
+        }
+        Ok(parcels)
+    }
+    /// Lists the trie nodes and contracts that are in only one of two parcels, and the
+    /// accounts whose values differ.
+    ///
+    /// Added items are those in `other` but not in `self`. Parcels for consecutive blocks
+    /// share most nodes, so the difference is what is needed to go from one to the other.
+    pub fn diff(&self, other: &Self) -> ParcelDiff {
+        let node_hashes = |parcel: &Self| -> HashSet<H256> {
+            parcel
+                .trie_nodes
+                .iter()
+                .map(|node| H256::from(keccak256(node).0))
+                .collect()
+        };
+        let contract_hashes = |parcel: &Self| -> HashSet<H256> {
+            parcel
+                .contracts
+                .iter()
+                .map(|contract| H256::from(keccak256(contract).0))
+                .collect()
+        };
+        let accounts = |parcel: &Self| -> HashMap<H160, CompactEip1186Proof> {
+            parcel
+                .compact_eip1186_proofs
+                .iter()
+                .map(|account| (H160::from_slice(&account.address), account.clone()))
+                .collect()
+        };
+        let (old_nodes, new_nodes) = (node_hashes(self), node_hashes(other));
+        let (old_contracts, new_contracts) = (contract_hashes(self), contract_hashes(other));
+        let (old_accounts, new_accounts) = (accounts(self), accounts(other));
+
+        let sorted = |items: HashSet<&H256>| -> Vec<H256> {
+            let mut items: Vec<H256> = items.into_iter().copied().collect();
+            items.sort();
+            items
+        };
+        let mut changed_accounts: Vec<H160> = old_accounts
+            .keys()
+            .chain(new_accounts.keys())
+            .filter(|address| old_accounts.get(*address) != new_accounts.get(*address))
+            .copied()
+            .collect::<HashSet<H160>>()
+            .into_iter()
+            .collect();
+        changed_accounts.sort();
+        ParcelDiff {
+            added_nodes: sorted(new_nodes.difference(&old_nodes).collect()),
+            removed_nodes: sorted(old_nodes.difference(&new_nodes).collect()),
+            added_contracts: sorted(new_contracts.difference(&old_contracts).collect()),
+            removed_contracts: sorted(old_contracts.difference(&new_contracts).collect()),
+            changed_accounts,
+        }
+    }
+    /// Returns the trie nodes keyed by their hash.
+    fn nodes_by_hash(&self) -> HashMap<B256, &TrieNode> {
+        self.trie_nodes
+            .iter()
+            .map(|node| (keccak256(node), node))
+            .collect()
+    }
+    /// Returns the RLP-encoded account (nonce, balance, storage hash, code hash) for an
+    /// address in the parcel. This is the value committed to in the account trie leaf.
+    pub fn account_leaf_rlp(&self, address: H160) -> Option<Vec<u8>> {
+        let account = self
+            .compact_eip1186_proofs
+            .iter()
+            .find(|proof| H160::from_slice(&proof.address) == address)?;
+        let nonce = ssz_u64_to_u64(account.nonce.to_owned()).ok()?;
+        let mut stream = RlpStream::new_list(4);
+        stream.append(&nonce);
+        stream.append(&eU256::from_big_endian(&account.balance));
+        stream.append(&H256::from_slice(&account.storage_hash));
+        stream.append(&H256::from_slice(&account.code_hash));
+        Some(stream.out().to_vec())
+    }
+    /// Checks that the keccak hash of every contract is the code hash of an account
+    /// in the parcel.
+    ///
+    /// Contracts are referred to by hash, so a contract with a modified byte would
+    /// otherwise be silently unused.
+    pub fn verify(&self) -> Result<(), StateError> {
+        let code_hashes: HashSet<B256> = self
+            .compact_eip1186_proofs
+            .iter()
+            .map(|account| ssz_h256_to_rb256(&account.code_hash))
+            .collect();
+        for contract in self.contracts.iter() {
+            let hash = keccak256(contract);
+            if !code_hashes.contains(&hash) {
+                return Err(StateError::ContractCodeHashMismatch(hash));
+            }
+        }
+        Ok(())
+    }
+    /// Keeps only the contracts whose code hash satisfies the predicate, in order.
+    ///
+    /// For example, to drop contracts that are not required to execute the block.
+    pub fn retain_contracts<F: Fn(&B256) -> bool>(&mut self, keep: F) {
+        let contracts = std::mem::take(&mut self.contracts);
+        for contract in contracts.iter() {
+            if keep(&keccak256(contract)) {
+                self.contracts.push(contract.clone());
+            }
+        }
+    }
+    /// Returns the state root that the account proofs in the parcel share.
+    ///
+    /// The root is the only account trie node that no other node refers to. More than one
+    /// such node means the parcel was built from proofs for different blocks.
+    pub fn state_root(&self) -> Result<H256, StateError> {
+        let storage_nodes = self.storage_node_indices();
+        let referenced: HashSet<B256> = self
+            .trie_nodes
+            .iter()
+            .flat_map(|node| child_hashes(node))
+            .collect();
+        let mut roots: Vec<H256> = self
+            .trie_nodes
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !storage_nodes.contains(index))
+            .map(|(_, node)| keccak256(node))
+            .filter(|hash| !referenced.contains(hash))
+            .map(|hash| H256::from(hash.0))
+            .collect();
+        roots.sort();
+        roots.dedup();
+        match roots.len() {
+            0 => Err(StateError::NoStateRoot),
+            1 => Ok(roots[0]),
+            _ => Err(StateError::InconsistentStateRoots(roots)),
+        }
+    }
+    /// Returns the number of SSZ bytes attributable to each component of the parcel.
+    ///
+    /// Trie nodes reachable from the storage root of an account are counted as storage
+    /// nodes, all others as account nodes.
+    pub fn size_breakdown(&self) -> SizeBreakdown {
+        // Each trie node is preceded by a 4 byte offset.
+        let node_size = |node: &TrieNode| BYTES_PER_OFFSET + node.len();
+        let storage_nodes = self.storage_node_indices();
+        let mut breakdown = SizeBreakdown {
+            proofs: ssz_len(&self.compact_eip1186_proofs),
+            contracts: ssz_len(&self.contracts),
+            blockhashes: ssz_len(&self.blockhashes),
+            // One offset for each (variable size) member of the container.
+            offsets: 4 * BYTES_PER_OFFSET,
+            ..Default::default()
+        };
+        for (index, node) in self.trie_nodes.iter().enumerate() {
+            match storage_nodes.contains(&index) {
+                true => breakdown.storage_nodes += node_size(node),
+                false => breakdown.account_nodes += node_size(node),
+            }
+        }
+        breakdown
+    }
+    /// Finds the nodes in the node bag that are reachable from any account storage root.
+    fn storage_node_indices(&self) -> HashSet<usize> {
+        let node_index: HashMap<B256, usize> = self
+            .trie_nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| (keccak256(node), index))
+            .collect();
+        let mut pending: Vec<B256> = self
+            .compact_eip1186_proofs
+            .iter()
+            .map(|account| ssz_h256_to_rb256(&account.storage_hash))
+            .collect();
+        let mut found = HashSet::new();
+        while let Some(hash) = pending.pop() {
+            let Some(index) = node_index.get(&hash) else {
+                continue;
+            };
+            if found.insert(*index) {
+                pending.extend(child_hashes(&self.trie_nodes[*index]));
+            }
+        }
+        found
+    }
+}
+
+/// The difference between two parcels (see `RequiredBlockState::diff`). Hashes and
+/// addresses are sorted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ParcelDiff {
+    /// Hashes of trie nodes only in the second parcel.
+    pub added_nodes: Vec<H256>,
+    /// Hashes of trie nodes only in the first parcel.
+    pub removed_nodes: Vec<H256>,
+    /// Code hashes of contracts only in the second parcel.
+    pub added_contracts: Vec<H256>,
+    /// Code hashes of contracts only in the first parcel.
+    pub removed_contracts: Vec<H256>,
+    /// Accounts in only one parcel, or with different values (including storage) in each.
+    pub changed_accounts: Vec<H160>,
+}
+
+impl ParcelDiff {
+    /// Whether the parcels have the same nodes, contracts and accounts.
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+/// Bytes used by each component of a `RequiredBlockState` when SSZ encoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SizeBreakdown {
+    /// Compact EIP-1186 proofs (account and storage values).
+    pub proofs: usize,
+    pub account_nodes: usize,
+    pub storage_nodes: usize,
+    pub contracts: usize,
+    pub blockhashes: usize,
+    /// Offsets for the members of the parcel and the list of trie nodes.
+    pub offsets: usize,
+}
+
+impl SizeBreakdown {
+    /// Total size, equal to the length of the SSZ encoding.
+    pub fn total(&self) -> usize {
+        self.proofs
+            + self.account_nodes
+            + self.storage_nodes
+            + self.contracts
+            + self.blockhashes
+            + self.offsets
+    }
+}
+
+const BYTES_PER_OFFSET: usize = 4;
+
+/// Length of the SSZ encoding of an item.
+fn ssz_len<T: Serialize>(item: &T) -> usize {
+    let mut buf = vec![];
+    item.serialize(&mut buf).unwrap_or_default()
+}
+
+/// Returns the hashes of the nodes referred to by a trie node. Children short
+/// enough to be embedded in the node are not included.
+fn child_hashes(node: &[u8]) -> Vec<B256> {
+    let rlp = Rlp::new(node);
+    let hash_at = |index: usize| -> Option<B256> {
+        let data = rlp.at(index).ok()?.data().ok()?;
+        (data.len() == 32).then(|| B256::from_slice(data))
+    };
+    match rlp.item_count() {
+        Ok(17) => (0..16).filter_map(hash_at).collect(),
+        Ok(2) => {
+            let is_extension = rlp
+                .at(0)
+                .and_then(|path| path.data().map(|p| p.first().map(|b| b >> 4)))
+                .map(|prefix| matches!(prefix, Some(0) | Some(1)))
+                .unwrap_or(false);
+            match is_extension {
+                true => hash_at(1).into_iter().collect(),
+                false => vec![],
+            }
+        }
+        _ => vec![],
+    }
+}
+
+/// Returns the hashes of the trie nodes on the path (keccak of a key) from the root to the
+/// final node, which is either the leaf for the key or the node that excludes it.
+fn path_node_hashes(
+    nodes: &HashMap<B256, &TrieNode>,
+    root: B256,
+    path: B256,
+) -> Result<Vec<B256>, StateError> {
+    let path: Vec<u8> = path
+        .0
+        .iter()
+        .flat_map(|byte| [byte >> 4, byte & 0x0f])
+        .collect();
+    let mut next_hash = root;
+    let mut path_index = 0;
+    let mut hashes = vec![];
+    loop {
+        let node = nodes
+            .get(&next_hash)
+            .ok_or(StateError::MissingTrieNode(H256::from(next_hash.0)))?;
+        hashes.push(next_hash);
+        match next_hash_on_path(node, &path, &mut path_index) {
+            Some(hash) => next_hash = hash,
+            None => return Ok(hashes),
+        }
+    }
+}
+
+/// Returns the hash of the next node on a path, advancing the path index past the nibbles
+/// the node consumes. None if the node is the last on the path (a leaf, or a node that the
+/// path diverges from).
+fn next_hash_on_path(node: &[u8], path: &[u8], path_index: &mut usize) -> Option<B256> {
+    let rlp = Rlp::new(node);
+    let hash_at = |index: usize| -> Option<B256> {
+        let data = rlp.at(index).ok()?.data().ok()?;
+        (data.len() == 32).then(|| B256::from_slice(data))
+    };
+    match rlp.item_count().ok()? {
+        17 => {
+            let nibble = *path.get(*path_index)?;
+            let hash = hash_at(nibble as usize)?;
+            *path_index += 1;
+            Some(hash)
+        }
+        2 => {
+            let encoded_path = rlp.at(0).ok()?.data().ok()?;
+            let (prefix, rest) = encoded_path.split_first()?;
+            let mut nibbles = match prefix >> 4 {
+                // Extension, even or odd length.
+                0 => vec![],
+                1 => vec![prefix & 0x0f],
+                // Leaf.
+                _ => return None,
+            };
+            nibbles.extend(rest.iter().flat_map(|byte| [byte >> 4, byte & 0x0f]));
+            if !path.get(*path_index..)?.starts_with(&nibbles) {
+                return None;
+            }
+            *path_index += nibbles.len();
+            hash_at(1)
+        }
+        _ => None,
+    }
 }
 
-impl StateForEvm for RequiredBlockState {
+impl<const MAX_ACCOUNTS: usize, const MAX_CONTRACTS: usize, const MAX_NODES: usize> StateForEvm
+    for RequiredBlockStateWithLimits<MAX_ACCOUNTS, MAX_CONTRACTS, MAX_NODES>
+{
     fn get_account_info(&self, address: &B160) -> Result<AccountInfo, EvmStateError> {
         let target = SszH160::try_from(address.0.to_vec()).unwrap();
         for account in self.compact_eip1186_proofs.iter() {
@@ -161,6 +596,37 @@ impl StateForEvm for RequiredBlockState {
         Err(EvmStateError::NoProofForAddress(address.to_string()))
     }
 
+    fn get_account_info_without_code(&self, address: &B160) -> Result<AccountInfo, EvmStateError> {
+        let target = SszH160::try_from(address.0.to_vec()).unwrap();
+        let account = self
+            .compact_eip1186_proofs
+            .iter()
+            .find(|account| account.address == target)
+            .ok_or_else(|| EvmStateError::NoProofForAddress(address.to_string()))?;
+        Ok(AccountInfo {
+            balance: ssz_u256_to_ru256(account.balance.to_owned())?,
+            nonce: ssz_u64_to_u64(account.nonce.to_owned())?,
+            code_hash: ssz_h256_to_rb256(&account.code_hash),
+            code: None,
+        })
+    }
+
+    fn get_contracts(&self) -> Result<rHashMap<B256, Bytecode>, EvmStateError> {
+        let mut contracts = rHashMap::default();
+        for contract in self.contracts.iter() {
+            let code_hash = keccak256(contract);
+            let bytes = contract.to_vec();
+            let len = bytes.len();
+            let bytecode = Bytecode {
+                bytecode: Bytes::from(bytes),
+                hash: code_hash,
+                state: BytecodeState::Checked { len },
+            };
+            contracts.insert(code_hash, bytecode);
+        }
+        Ok(contracts)
+    }
+
     fn addresses(&self) -> Vec<B160> {
         self.compact_eip1186_proofs
             .iter()
@@ -217,3 +683,106 @@ impl StateForEvm for RequiredBlockState {
         todo!()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::fixture::{parcel, parcel_bytes, prior_block_proofs};
+
+    use super::*;
+
+    /// Tests that the size breakdown of a fixture parcel accounts for every byte.
+    #[test]
+    fn test_parcel_size_breakdown_sums_to_ssz_length() {
+        let mut ssz = vec![];
+        snap::read::FrameDecoder::new(parcel_bytes().as_slice())
+            .read_to_end(&mut ssz)
+            .unwrap();
+        let state = RequiredBlockState::from_ssz_bytes(ssz.clone()).unwrap();
+        let node_bytes: usize = state.trie_nodes.iter().map(|node| 4 + node.len()).sum();
+
+        let breakdown = state.size_breakdown();
+        assert_eq!(breakdown.total(), ssz.len());
+        assert_eq!(
+            breakdown.account_nodes + breakdown.storage_nodes,
+            node_bytes
+        );
+        assert!(breakdown.account_nodes > 0);
+        assert!(breakdown.storage_nodes > 0);
+    }
+
+    /// Tests that the parcel state root is that of the fixture proofs, and that a parcel
+    /// with nodes from another trie is inconsistent.
+    #[test]
+    fn test_parcel_state_root() {
+        let mut state = parcel();
+        let proofs = prior_block_proofs();
+        let proof = proofs.values().next().unwrap();
+        let expected = H256::from(keccak256(&proof.account_proof[0]).0);
+        assert_eq!(state.state_root().unwrap(), expected);
+
+        // Root node of a different (single leaf) trie.
+        let foreign_root = vec![0xc2, 0x80, 0x80];
+        let foreign_hash = H256::from(keccak256(&foreign_root).0);
+        state
+            .trie_nodes
+            .push(TrieNode::try_from(foreign_root).unwrap());
+        match state.state_root() {
+            Err(StateError::InconsistentStateRoots(roots)) => {
+                assert_eq!(roots.len(), 2);
+                assert!(roots.contains(&expected));
+                assert!(roots.contains(&foreign_hash));
+            }
+            other => panic!("expected inconsistent roots, got {other:?}"),
+        }
+    }
+
+    /// Tests that the account leaf reconstructed from the parcel is the value in the
+    /// account proof leaf.
+    #[test]
+    fn test_account_leaf_rlp_matches_proof() {
+        let state = parcel();
+        let proofs = prior_block_proofs();
+        let address = state.parcel_addresses()[0];
+        let proof = proofs.get(&address).unwrap();
+
+        // Leaf: [path, account]
+        let leaf: Vec<Vec<u8>> = Rlp::new(proof.account_proof.last().unwrap())
+            .as_list()
+            .unwrap();
+        assert_eq!(state.account_leaf_rlp(address), Some(leaf[1].clone()));
+        assert_eq!(state.account_leaf_rlp(H160::repeat_byte(0xee)), None);
+    }
+
+    /// Tests that a diff of a parcel and a modified copy reports only the modifications.
+    #[test]
+    fn test_parcel_diff() {
+        let mut old = parcel();
+        let mut new = parcel();
+        assert!(old.diff(&new).is_empty());
+
+        let removed_node = vec![0xc2, 0x80, 0x80];
+        let added_node = vec![0xc2, 0x80, 0x01];
+        old.trie_nodes
+            .push(TrieNode::try_from(removed_node.clone()).unwrap());
+        new.trie_nodes
+            .push(TrieNode::try_from(added_node.clone()).unwrap());
+        let removed_contract = keccak256(new.contracts.iter().next().unwrap());
+        new.retain_contracts(|hash| *hash != removed_contract);
+        let added_account = H160::repeat_byte(0xee);
+        new.compact_eip1186_proofs.push(CompactEip1186Proof {
+            address: SszH160::try_from(added_account.0.to_vec()).unwrap(),
+            ..Default::default()
+        });
+
+        let diff = old.diff(&new);
+        assert_eq!(diff.added_nodes, vec![H256::from(keccak256(&added_node).0)]);
+        assert_eq!(
+            diff.removed_nodes,
+            vec![H256::from(keccak256(&removed_node).0)]
+        );
+        assert!(diff.added_contracts.is_empty());
+        assert_eq!(diff.removed_contracts, vec![H256::from(removed_contract.0)]);
+        assert_eq!(diff.changed_accounts, vec![added_account]);
+        assert!(new.diff(&new).is_empty());
+    }
+}
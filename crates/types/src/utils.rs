@@ -18,6 +18,8 @@ pub enum UtilsError {
     H256ValueTooLarge(String),
     #[error("Unable to convert Ethers U256 ({0}) to revm U256 ")]
     U256ValueTooLarge(String),
+    #[error("Unable to convert U256 ({0}) to u64, value too large")]
+    U64ValueTooLarge(String),
     #[error("Hex utils error {0}")]
     HexUtils(#[from] FromHexError),
 }
@@ -47,14 +49,20 @@ pub fn eu64_to_ru256(input: ethers::types::U64) -> U256 {
 }
 
 /// Ethers U256 to u64
-pub fn eu256_to_u64(input: ethers::types::U256) -> u64 {
-    input.as_u64()
+///
+/// Errors if the value does not fit in a u64.
+pub fn eu256_to_u64(input: ethers::types::U256) -> Result<u64, UtilsError> {
+    if input > ethers::types::U256::from(u64::MAX) {
+        return Err(UtilsError::U64ValueTooLarge(input.to_string()));
+    }
+    Ok(input.as_u64())
 }
 
 /// revm U256 to u64
-pub fn ru256_to_u64(input: U256) -> u64 {
-    let limbs = input.as_limbs();
-    limbs[0]
+///
+/// Errors if the value does not fit in a u64.
+pub fn ru256_to_u64(input: U256) -> Result<u64, UtilsError> {
+    u64::try_from(input).map_err(|_| UtilsError::U64ValueTooLarge(input.to_string()))
 }
 
 /// Ethers H256 to revm U256
@@ -165,19 +173,41 @@ mod test {
     #[test]
     fn test_eu256_to_u64() {
         let input = ethers::types::U256::from_str("0x1234").unwrap();
-        let derived: u64 = eu256_to_u64(input);
+        let derived: u64 = eu256_to_u64(input).unwrap();
         let expected: u64 = 4660u64; // 0x1234
         assert_eq!(derived, expected);
     }
 
+    #[test]
+    fn test_eu256_to_u64_boundary() {
+        let max = ethers::types::U256::from(u64::MAX);
+        assert_eq!(eu256_to_u64(max).unwrap(), u64::MAX);
+        let oversized = max + 1;
+        assert_eq!(
+            eu256_to_u64(oversized),
+            Err(UtilsError::U64ValueTooLarge(oversized.to_string()))
+        );
+    }
+
     #[test]
     fn test_ru256_to_u64() {
         let input = U256::from_str("0x1234").unwrap();
-        let derived: u64 = ru256_to_u64(input);
+        let derived: u64 = ru256_to_u64(input).unwrap();
         let expected: u64 = 4660u64; // 0x1234
         assert_eq!(derived, expected);
     }
 
+    #[test]
+    fn test_ru256_to_u64_boundary() {
+        let max = U256::from(u64::MAX);
+        assert_eq!(ru256_to_u64(max).unwrap(), u64::MAX);
+        let oversized = max + U256::from(1);
+        assert!(matches!(
+            ru256_to_u64(oversized),
+            Err(UtilsError::U64ValueTooLarge(_))
+        ));
+    }
+
     #[test]
     fn test_eh256_to_ru256() {
         let input = ethers::types::H256::from_str(
@@ -254,6 +284,12 @@ mod test {
         assert_eq!(expected, derived);
     }
 
+    #[test]
+    fn test_ssz_u64_to_u64_wrong_length() {
+        let ssz = SszU64::try_from(vec![1u8; 4]).unwrap();
+        assert_eq!(ssz_u64_to_u64(ssz), Err(UtilsError::InvalidU64List));
+    }
+
     #[test]
     fn test_ssz_u256_to_ru256() {
         let expected =
@@ -1,7 +1,8 @@
 //! Verifies an EIP-1186 style proof
 
+use archors_types::constants::EMPTY_TRIE_ROOT;
 use ethers::{
-    types::{EIP1186ProofResponse, StorageProof, H256, U256, U64},
+    types::{Bytes, EIP1186ProofResponse, StorageProof, H256, U256, U64},
     utils::keccak256,
 };
 
@@ -11,6 +12,7 @@ use thiserror::Error;
 
 use crate::{
     proof::{ProofError, SingleProofPath, Verified},
+    root::{RootSourceError, StateRootSource},
     utils::hex_encode,
 };
 
@@ -46,6 +48,8 @@ pub enum VerifyProofError {
     },
     #[error("Proof is empty")]
     EmptyProof,
+    #[error("Unable to get state root: {0}")]
+    RootSourceError(#[from] RootSourceError),
 }
 
 #[derive(Debug, Error)]
@@ -64,10 +68,14 @@ pub enum AccountError {
 pub enum StorageError {
     #[error("ProofError {0}")]
     ProofError(#[from] ProofError),
+    #[error("Proof is empty")]
+    EmptyProof,
     #[error("A valid exclusion proof exists, but the claimed storage is not empty")]
     ExclusionProofForNonZeroValue,
     #[error("Unexpected inclusion proof for a storage value of zero, expected exclusion proof")]
     InclusionProofForZeroValue,
+    #[error("Proof has {unused} node(s) not on the path of the key, after {used} node(s) on it")]
+    NodesNotOnKeyPath { used: usize, unused: usize },
 }
 
 /// Verifies a single account proof with respect to a state roof. The
@@ -76,6 +84,21 @@ pub fn verify_proof(
     block_state_root: &[u8],
     proof: &EIP1186ProofResponse,
 ) -> Result<(), VerifyProofError> {
+    // An empty account trie has no nodes to prove exclusion with.
+    if proof.account_proof.is_empty() && block_state_root != EMPTY_TRIE_ROOT {
+        return Err(VerifyProofError::EmptyProof);
+    }
+    // Likewise for storage. Checked before verification so that a malformed response is
+    // reported as such, rather than as an invalid proof.
+    if proof.storage_hash.0 != EMPTY_TRIE_ROOT {
+        if let Some(storage_proof) = proof.storage_proof.iter().find(|s| s.proof.is_empty()) {
+            return Err(VerifyProofError::StorageError {
+                source: StorageError::EmptyProof,
+                account: hex_encode(proof.address),
+                storage_key: hex_encode(storage_proof.key),
+            });
+        }
+    }
     // Account
     verify_account_component(block_state_root, proof).map_err(|source| {
         VerifyProofError::AccountError {
@@ -97,10 +120,56 @@ pub fn verify_proof(
     Ok(())
 }
 
+/// Verifies a single account proof with respect to the state root of a block, as
+/// provided by a trusted source.
+pub fn verify_proof_at_block<S: StateRootSource>(
+    source: &S,
+    block: u64,
+    proof: &EIP1186ProofResponse,
+) -> Result<(), VerifyProofError> {
+    let state_root = source.state_root(block)?;
+    verify_proof(state_root.as_bytes(), proof)
+}
+
+/// Produces a proof that only contains the account proof and the storage proofs for
+/// the given keys.
+///
+/// Storage proofs for other keys are dropped, along with the nodes only they required.
+/// Keys that are not in the original proof are ignored.
+pub fn reduce_proof_to_keys(proof: &EIP1186ProofResponse, keys: &[H256]) -> EIP1186ProofResponse {
+    let storage_proof = proof
+        .storage_proof
+        .iter()
+        .filter(|storage| keys.contains(&storage.key))
+        .cloned()
+        .collect();
+    EIP1186ProofResponse {
+        storage_proof,
+        ..proof.clone()
+    }
+}
+
+/// Produces a proof with storage proofs sorted by trie path (keccak256(key)).
+///
+/// This is the order in which a trie walk visits the keys, so that updates applied in this
+/// order match traversal order. Duplicate keys are removed.
+pub fn normalize_proof(proof: &EIP1186ProofResponse) -> EIP1186ProofResponse {
+    let mut storage_proof = proof.storage_proof.clone();
+    storage_proof.sort_by_cached_key(|storage| keccak256(storage.key));
+    storage_proof.dedup_by_key(|storage| storage.key);
+    EIP1186ProofResponse {
+        storage_proof,
+        ..proof.clone()
+    }
+}
+
 pub fn verify_account_component(
     block_state_root: &[u8],
     proof: &EIP1186ProofResponse,
 ) -> Result<(), AccountError> {
+    if proof.account_proof.is_empty() && block_state_root != EMPTY_TRIE_ROOT {
+        return Err(AccountError::EmptyProof);
+    }
     let claimed_account = Account {
         nonce: proof.nonce,
         balance: proof.balance,
@@ -129,11 +198,30 @@ pub fn verify_account_component(
     Ok(())
 }
 
+/// Verifies a single storage slot with respect to a known storage root, without an
+/// account proof.
+///
+/// A value of zero requires an exclusion proof.
+pub fn verify_storage_slot(
+    storage_root: H256,
+    key: H256,
+    value: U256,
+    proof: Vec<Bytes>,
+) -> Result<(), StorageError> {
+    verify_account_storage_component(&storage_root.0, StorageProof { key, value, proof })
+}
+
 /// Verfies a single storage proof with respect to a known storage hash.
+///
+/// Every node must be on the path of keccak256(key). This rejects a proof whose nodes
+/// were produced for a different key, which may otherwise pass as an exclusion proof.
 fn verify_account_storage_component(
     storage_hash: &[u8; 32],
     storage_proof: StorageProof,
 ) -> Result<(), StorageError> {
+    if storage_proof.proof.is_empty() && storage_hash != &EMPTY_TRIE_ROOT {
+        return Err(StorageError::EmptyProof);
+    }
     let rlp_value = rlp::encode(&storage_proof.value).to_vec();
 
     // TODO: See yellow paper (205). Account for cases where entire node is <32 bytes.
@@ -145,7 +233,13 @@ fn verify_account_storage_component(
         claimed_value: rlp_value,
     };
 
-    match storage_prover.verify()? {
+    let verified = match storage_prover.verify_strict() {
+        Err(ProofError::SuperfluousNodes { used, unused }) => {
+            return Err(StorageError::NodesNotOnKeyPath { used, unused })
+        }
+        result => result?,
+    };
+    match verified {
         Verified::Inclusion => {
             if storage_proof.value == U256::from(0) {
                 return Err(StorageError::InclusionProofForZeroValue);
@@ -161,7 +255,7 @@ fn verify_account_storage_component(
 
 #[cfg(test)]
 mod test {
-    use crate::utils::hex_decode;
+    use crate::{root::HeaderFileRoots, utils::hex_decode};
 
     use super::*;
     use std::{fs::File, io::BufReader};
@@ -186,6 +280,22 @@ mod test {
         verify_proof(&state_root, &account_proof).expect("could not verify proof");
     }
 
+    /// The proof from block 3 verifies against the root in the header file, but not
+    /// against that of another block.
+    #[test]
+    fn test_verify_proof_at_block_from_header_file() {
+        let account_proof = load_proof("data/test_proof_1.json");
+        let source = HeaderFileRoots::load("data/test_headers.json").unwrap();
+        verify_proof_at_block(&source, 3, &account_proof).expect("could not verify proof");
+        assert!(verify_proof_at_block(&source, 2, &account_proof).is_err());
+        assert!(matches!(
+            verify_proof_at_block(&source, 4, &account_proof),
+            Err(VerifyProofError::RootSourceError(
+                RootSourceError::UnknownBlock(4)
+            ))
+        ));
+    }
+
     /// data src: https://github.com/gakonst/ethers-rs/blob/master/ethers-core/testdata/proof.json
     #[test]
     fn test_verify_exclusion_proof_for_storage_key_zero() {
@@ -205,4 +315,184 @@ mod test {
                 .unwrap();
         verify_proof(&state_root, &account_proof).expect("could not verify proof");
     }
+
+    /// Reduces a proof with three storage keys to a single key.
+    #[test]
+    fn test_reduced_proof_still_verifies() {
+        let account_proof = load_proof("data/test_proof_3.json");
+        let state_root =
+            hex_decode("0x38e5e1dd67f7873cd8cfff08685a30734c18d0075318e9fca9ed64cc28a597da")
+                .unwrap();
+        assert_eq!(account_proof.storage_proof.len(), 3);
+        let key = account_proof.storage_proof[1].key;
+
+        let reduced = reduce_proof_to_keys(&account_proof, &[key]);
+        assert_eq!(reduced.storage_proof.len(), 1);
+        assert_eq!(reduced.storage_proof[0].key, key);
+        assert_eq!(reduced.account_proof, account_proof.account_proof);
+        verify_proof(&state_root, &reduced).expect("could not verify reduced proof");
+    }
+
+    /// Storage proofs are sorted by path and the proof still verifies.
+    #[test]
+    fn test_normalized_proof_sorted_by_path() {
+        let mut account_proof = load_proof("data/test_proof_3.json");
+        let state_root =
+            hex_decode("0x38e5e1dd67f7873cd8cfff08685a30734c18d0075318e9fca9ed64cc28a597da")
+                .unwrap();
+        account_proof.storage_proof.reverse();
+        let duplicate = account_proof.storage_proof[0].clone();
+        account_proof.storage_proof.push(duplicate);
+
+        let normalized = normalize_proof(&account_proof);
+        assert_eq!(normalized.storage_proof.len(), 3);
+        let paths: Vec<[u8; 32]> = normalized
+            .storage_proof
+            .iter()
+            .map(|storage| keccak256(storage.key))
+            .collect();
+        assert!(paths.windows(2).all(|pair| pair[0] < pair[1]));
+        verify_proof(&state_root, &normalized).expect("could not verify normalized proof");
+    }
+
+    /// An account proof without any storage proofs is valid.
+    #[test]
+    fn test_verify_account_only_proof() {
+        let mut account_proof = load_proof("data/test_proof_3.json");
+        let state_root =
+            hex_decode("0x38e5e1dd67f7873cd8cfff08685a30734c18d0075318e9fca9ed64cc28a597da")
+                .unwrap();
+        account_proof.storage_proof.clear();
+        verify_proof(&state_root, &account_proof).expect("could not verify account-only proof");
+
+        account_proof.account_proof.clear();
+        assert!(matches!(
+            verify_proof(&state_root, &account_proof),
+            Err(VerifyProofError::EmptyProof)
+        ));
+    }
+
+    /// Tests that any account in an empty account trie has a valid exclusion proof.
+    #[test]
+    fn test_verify_exclusion_proof_for_empty_trie() {
+        let mut proof = EIP1186ProofResponse {
+            address: "0xaa00000000000000000000000000000000000000"
+                .parse()
+                .unwrap(),
+            ..Default::default()
+        };
+        verify_proof(&EMPTY_TRIE_ROOT, &proof).expect("could not verify empty trie proof");
+        proof.account_proof = vec![vec![0x80].into()];
+        verify_account_component(&EMPTY_TRIE_ROOT, &proof).unwrap();
+
+        proof.nonce = 1.into();
+        assert!(matches!(
+            verify_account_component(&EMPTY_TRIE_ROOT, &proof),
+            Err(AccountError::ExclusionProofForNonEmptyAccount)
+        ));
+    }
+
+    /// A storage slot verifies against the storage root alone.
+    #[test]
+    fn test_verify_storage_slot() {
+        let account_proof = load_proof("data/test_proof_3.json");
+        let storage = account_proof.storage_proof[0].clone();
+        assert!(!storage.value.is_zero());
+        verify_storage_slot(
+            account_proof.storage_hash,
+            storage.key,
+            storage.value,
+            storage.proof.clone(),
+        )
+        .expect("could not verify storage slot");
+        assert!(verify_storage_slot(
+            account_proof.storage_hash,
+            storage.key,
+            storage.value + 1,
+            storage.proof
+        )
+        .is_err());
+    }
+
+    /// An absent key verifies with a value of zero and no other value.
+    #[test]
+    fn test_verify_storage_slot_absent_key() {
+        let account_proof = load_proof("data/test_proof_2.json");
+        let storage = account_proof.storage_proof[0].clone();
+        verify_storage_slot(
+            account_proof.storage_hash,
+            storage.key,
+            U256::zero(),
+            storage.proof.clone(),
+        )
+        .expect("could not verify absent storage slot");
+        assert!(matches!(
+            verify_storage_slot(
+                account_proof.storage_hash,
+                storage.key,
+                U256::one(),
+                storage.proof
+            ),
+            Err(StorageError::ExclusionProofForNonZeroValue)
+        ));
+    }
+
+    /// Tests that missing proof nodes are reported before verification.
+    #[test]
+    fn test_verify_proof_with_missing_nodes() {
+        let mut account_proof = load_proof("data/test_proof_3.json");
+        let state_root =
+            hex_decode("0x38e5e1dd67f7873cd8cfff08685a30734c18d0075318e9fca9ed64cc28a597da")
+                .unwrap();
+        let key = account_proof.storage_proof[1].key;
+        account_proof.storage_proof[1].proof.clear();
+        match verify_proof(&state_root, &account_proof) {
+            Err(VerifyProofError::StorageError {
+                source: StorageError::EmptyProof,
+                storage_key,
+                ..
+            }) => assert_eq!(storage_key, hex_encode(key)),
+            other => panic!("expected empty storage proof, got {other:?}"),
+        }
+
+        account_proof.account_proof.clear();
+        assert!(matches!(
+            verify_proof(&state_root, &account_proof),
+            Err(VerifyProofError::EmptyProof)
+        ));
+        assert!(matches!(
+            verify_account_component(&state_root, &account_proof),
+            Err(AccountError::EmptyProof)
+        ));
+    }
+
+    /// Tests that a storage proof is rejected if its nodes belong to a different key.
+    ///
+    /// The path of key 0xba diverges from that of key 0x08 at an empty branch item, so
+    /// the nodes for 0x08 would otherwise pass as an exclusion proof for 0xba.
+    #[test]
+    fn test_verify_storage_proof_nodes_for_different_key() {
+        let account_proof = load_proof("data/test_proof_3.json");
+        let storage = account_proof.storage_proof[2].clone();
+        assert_eq!(storage.key, H256::from_low_u64_be(0x08));
+        let other_key = H256::from_low_u64_be(0xba);
+        let prover = SingleProofPath {
+            proof: storage.proof.clone(),
+            root: account_proof.storage_hash.0,
+            path: keccak256(other_key),
+            claimed_value: rlp::encode(&U256::zero()).to_vec(),
+        };
+        assert_eq!(prover.verify().unwrap(), Verified::Exclusion);
+
+        let used = storage.proof.len() - 1;
+        assert!(matches!(
+            verify_storage_slot(
+                account_proof.storage_hash,
+                other_key,
+                U256::zero(),
+                storage.proof
+            ),
+            Err(StorageError::NodesNotOnKeyPath { used: u, unused: 1 }) if u == used
+        ));
+    }
 }
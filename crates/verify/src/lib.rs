@@ -1,5 +1,7 @@
 pub mod eip1186;
 pub mod node;
+pub mod ordered;
 pub mod path;
 pub mod proof;
+pub mod root;
 pub mod utils;
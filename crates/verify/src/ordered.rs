@@ -0,0 +1,168 @@
+//! For computing the root of a trie built from a list of values, such as the transactions
+//! of a block.
+//!
+//! Each value is keyed by the RLP encoding of its index in the list. The trie is built in
+//! full, so no proof is involved.
+
+use archors_types::constants::EMPTY_TRIE_ROOT;
+use ethers::{types::H256, utils::keccak256};
+use rlp::RlpStream;
+
+use crate::path::{nibbles_to_prefixed_bytes, PathError, TargetNodeEncoding};
+
+/// A key (as nibbles) and the value stored under it.
+type Item = (Vec<u8>, Vec<u8>);
+
+/// Computes the root of the trie where each value is keyed by the RLP encoding of its
+/// index.
+///
+/// E.g., the transactions root of a block, where each value is an encoded transaction
+/// (prefixed with the type for typed transactions).
+pub fn ordered_trie_root(values: &[Vec<u8>]) -> Result<H256, PathError> {
+    if values.is_empty() {
+        return Ok(H256(EMPTY_TRIE_ROOT));
+    }
+    let mut items: Vec<Item> = values
+        .iter()
+        .enumerate()
+        .map(|(index, value)| {
+            let key = rlp::encode(&(index as u64));
+            (bytes_to_nibbles(&key), value.clone())
+        })
+        .collect();
+    items.sort();
+    // The root is hashed even if the node is shorter than 32 bytes.
+    Ok(H256::from(keccak256(encode_node(&items, 0)?)))
+}
+
+/// Returns the RLP-encoded node for sorted keys that share the first `depth` nibbles.
+fn encode_node(items: &[Item], depth: usize) -> Result<Vec<u8>, PathError> {
+    if let [(key, value)] = items {
+        let path = nibbles_to_prefixed_bytes(&key[depth..], TargetNodeEncoding::Leaf)?;
+        let mut leaf = RlpStream::new_list(2);
+        leaf.append(&path);
+        leaf.append(value);
+        return Ok(leaf.out().to_vec());
+    }
+    let shared = shared_nibbles(items, depth);
+    if shared > 0 {
+        let path = nibbles_to_prefixed_bytes(
+            &items[0].0[depth..depth + shared],
+            TargetNodeEncoding::Extension,
+        )?;
+        let mut extension = RlpStream::new_list(2);
+        extension.append(&path);
+        append_child(&mut extension, &encode_node(items, depth + shared)?);
+        return Ok(extension.out().to_vec());
+    }
+    let mut branch = RlpStream::new_list(17);
+    for nibble in 0..16u8 {
+        let children: Vec<Item> = items
+            .iter()
+            .filter(|(key, _)| key.get(depth) == Some(&nibble))
+            .cloned()
+            .collect();
+        match children.is_empty() {
+            true => branch.append_empty_data(),
+            false => append_child(&mut branch, &encode_node(&children, depth + 1)?),
+        };
+    }
+    // A key that ends at the branch has its value in the branch.
+    match items.iter().find(|(key, _)| key.len() == depth) {
+        Some((_, value)) => branch.append(value),
+        None => branch.append_empty_data(),
+    };
+    Ok(branch.out().to_vec())
+}
+
+/// Adds a child to a node. Children shorter than 32 bytes are embedded, others are
+/// referred to by hash.
+fn append_child<'a>(stream: &'a mut RlpStream, child: &[u8]) -> &'a mut RlpStream {
+    match child.len() < 32 {
+        true => stream.append_raw(child, 1),
+        false => stream.append(&keccak256(child).to_vec()),
+    }
+}
+
+/// Number of nibbles after `depth` that all keys have in common.
+fn shared_nibbles(items: &[Item], depth: usize) -> usize {
+    let Some(((first, _), rest)) = items.split_first() else {
+        return 0;
+    };
+    let mut count = 0;
+    while let Some(nibble) = first.get(depth + count) {
+        if rest
+            .iter()
+            .any(|(key, _)| key.get(depth + count) != Some(nibble))
+        {
+            break;
+        }
+        count += 1;
+    }
+    count
+}
+
+/// Represents bytes as nibbles: [0xbc, 0x3a] -> [0xb, 0xc, 0x3, 0xa]
+fn bytes_to_nibbles(bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .iter()
+        .flat_map(|byte| [byte >> 4, byte & 0x0f])
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ordered_trie_root_empty() {
+        assert_eq!(ordered_trie_root(&[]).unwrap(), H256(EMPTY_TRIE_ROOT));
+    }
+
+    /// A single value is a leaf at the root, with the path of key rlp(0) = 0x80.
+    #[test]
+    fn test_ordered_trie_root_single_value() {
+        let leaf = [0xc4, 0x82, 0x20, 0x80, 0x01];
+        assert_eq!(
+            ordered_trie_root(&[vec![0x01]]).unwrap(),
+            H256::from(keccak256(leaf))
+        );
+    }
+
+    /// Keys rlp(1) = 0x01 and rlp(2) = 0x02 share the first nibble and diverge at the
+    /// second, so they are in a branch within the root branch. All nodes except the root are
+    /// shorter than 32 bytes and are embedded.
+    #[test]
+    fn test_ordered_trie_root_nested_branch() {
+        let values = vec![vec![0x0a], vec![0x0b], vec![0x0c]];
+        let mut inner = RlpStream::new_list(17);
+        for nibble in 0..17u8 {
+            match nibble {
+                1 => inner.append_raw(&leaf(vec![0x20], 0x0b), 1),
+                2 => inner.append_raw(&leaf(vec![0x20], 0x0c), 1),
+                _ => inner.append_empty_data(),
+            };
+        }
+        let inner = inner.out().to_vec();
+        assert!(inner.len() < 32);
+        // Key rlp(0) = 0x80 diverges from the others at the first nibble.
+        let mut root = RlpStream::new_list(17);
+        for nibble in 0..17u8 {
+            match nibble {
+                0 => root.append_raw(&inner, 1),
+                8 => root.append_raw(&leaf(vec![0x30], 0x0a), 1),
+                _ => root.append_empty_data(),
+            };
+        }
+        assert_eq!(
+            ordered_trie_root(&values).unwrap(),
+            H256::from(keccak256(root.out()))
+        );
+    }
+
+    fn leaf(path: Vec<u8>, value: u8) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(2);
+        stream.append(&path).append(&vec![value]);
+        stream.out().to_vec()
+    }
+}
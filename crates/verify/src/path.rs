@@ -17,6 +17,7 @@
 //! be a node item with some hash of a null/zero value, depending on the RLP
 //! encoding of the data type e.g., hash(rlp(0)).
 
+use archors_types::error::ErrorKind;
 use serde::Deserialize;
 use thiserror::Error;
 
@@ -37,8 +38,8 @@ pub enum PathError {
     ExtensionPathLongerThanExpected,
     #[error("Attempted to determine inclusion/exclusion without looking past nibble {0}")]
     EvaluatedProofOnPartialPath(usize),
-    #[error("Unable to decode invalid hex compact trie path encoding prefix")]
-    InvalidPathPrefix,
+    #[error("Invalid hex prefix encoding byte 0x{0:02x}, the high nibble must be 0, 1, 2 or 3")]
+    InvalidPathPrefix(u8),
     #[error("Nibble must be in the range 0-15, got {0}")]
     InvalidNibble(u8),
     #[error("Attempted traversal to next node in path but path has no remaining nibbles")]
@@ -57,6 +58,32 @@ pub enum PathError {
     OddNumberOfNibbles,
     #[error("Unable to request nibble at index greater than 63")]
     InvalidIndex,
+    #[error("All 64 nibbles of the path have been visited, index {0} is past the end")]
+    PathExhausted(usize),
+}
+
+impl PathError {
+    /// The broad kind of the error. Errors from calling with bad arguments are internal.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            PathError::EvaluatedProofOnPartialPath(_)
+            | PathError::NibblesEmpty
+            | PathError::OddNumberOfNibbles
+            | PathError::InvalidIndex => ErrorKind::Internal,
+            PathError::BranchNodeHasValue
+            | PathError::ExtensionNibbleMismatch { .. }
+            | PathError::ExtensionPathEmpty
+            | PathError::ExtensionPathLongerThanExpected
+            | PathError::InvalidPathPrefix(_)
+            | PathError::InvalidNibble(_)
+            | PathError::NextNodeNotInPath
+            | PathError::NoPath
+            | PathError::PathEmpty
+            | PathError::PathTooLong
+            | PathError::PathTooShort
+            | PathError::PathExhausted(_) => ErrorKind::MalformedProof,
+        }
+    }
 }
 
 /// A sequence of nibbles that represent a traversal from the root of a merkle patricia tree.
@@ -106,7 +133,12 @@ impl NibblePath {
         Ok(self)
     }
     /// Visits the next nibble in the traversal and then increment.
+    ///
+    /// A branch encountered after all 64 nibbles are visited indicates a malformed proof.
     pub fn visit_path_nibble(&mut self) -> Result<u8, PathError> {
+        if self.visiting_index > 63 {
+            return Err(PathError::PathExhausted(self.visiting_index));
+        }
         let node_index = self
             .path
             .get(self.visiting_index)
@@ -206,8 +238,26 @@ impl NibblePath {
     pub fn visiting_index(&self) -> usize {
         self.visiting_index
     }
+    /// Returns the number of nibbles left to traverse, including the nibble being visited.
+    pub fn remaining(&self) -> usize {
+        self.path.len().saturating_sub(self.visiting_index)
+    }
+    /// Returns the prefix-encoded path of the nibbles after the nibble being visited.
+    ///
+    /// The nibble being visited is the item in a branch node that refers to a node with
+    /// this path. E.g., the path of a new leaf added to a branch.
+    pub fn remaining_encoded(&self, target: TargetNodeEncoding) -> Result<Vec<u8>, PathError> {
+        let nibbles = self
+            .path
+            .get(self.visiting_index + 1..)
+            .ok_or(PathError::PathExhausted(self.visiting_index))?;
+        nibbles_to_prefixed_bytes(nibbles, target)
+    }
     /// Returns the nibble at the specified index.
     pub fn nibble_at_index(&self, index: usize) -> Result<u8, PathError> {
+        if index > 63 {
+            return Err(PathError::PathExhausted(index));
+        }
         Ok(*self.path.get(index).ok_or(PathError::InvalidIndex)?)
     }
     /// Returns the nibble path up to and including a specific traversal index.
@@ -333,7 +383,7 @@ impl TryFrom<&u8> for PrefixEncoding {
             [1, nibble] => PrefixEncoding::ExtensionOdd(nibble),
             [2, _] => PrefixEncoding::LeafEven,
             [3, nibble] => PrefixEncoding::LeafOdd(nibble),
-            [_, _] => return Err(PathError::InvalidPathPrefix),
+            [_, _] => return Err(PathError::InvalidPathPrefix(*value)),
         };
         Ok(encoding)
     }
@@ -525,6 +575,63 @@ mod test {
         assert!(traversal.visit_path_nibble().is_err());
     }
 
+    #[test]
+    fn test_visit_path_nibble_exhausted() {
+        let mut traversal = NibblePath::init(&[0xab; 32]);
+        for _ in 0..32 {
+            assert_eq!(traversal.visit_path_nibble().unwrap(), 0xa);
+            assert_eq!(traversal.visit_path_nibble().unwrap(), 0xb);
+        }
+        assert_eq!(
+            traversal.visit_path_nibble(),
+            Err(PathError::PathExhausted(64))
+        );
+        assert_eq!(traversal.nibble_at_index(63).unwrap(), 0xb);
+        assert_eq!(
+            traversal.nibble_at_index(64),
+            Err(PathError::PathExhausted(64))
+        );
+    }
+
+    #[test]
+    fn test_remaining() {
+        let mut traversal = NibblePath::init(&[0xab; 32]);
+        // Start
+        assert_eq!(traversal.remaining(), 64);
+        let mut expected_leaf = vec![0x3b];
+        expected_leaf.extend([0xab; 31]);
+        assert_eq!(
+            traversal.remaining_encoded(TargetNodeEncoding::Leaf).unwrap(),
+            expected_leaf
+        );
+        // Middle
+        for _ in 0..5 {
+            traversal.visit_path_nibble().unwrap();
+        }
+        assert_eq!(traversal.remaining(), 59);
+        assert_eq!(
+            traversal.remaining_encoded(TargetNodeEncoding::Leaf).unwrap(),
+            traversal
+                .get_encoded_path(TargetNodeEncoding::Leaf, 6, 63)
+                .unwrap()
+        );
+        // End
+        for _ in 0..58 {
+            traversal.visit_path_nibble().unwrap();
+        }
+        assert_eq!(traversal.remaining(), 1);
+        assert_eq!(
+            traversal.remaining_encoded(TargetNodeEncoding::Leaf).unwrap(),
+            vec![0x20]
+        );
+        traversal.visit_path_nibble().unwrap();
+        assert_eq!(traversal.remaining(), 0);
+        assert_eq!(
+            traversal.remaining_encoded(TargetNodeEncoding::Leaf),
+            Err(PathError::PathExhausted(64))
+        );
+    }
+
     #[test]
     fn test_skip_extension_node_odd_nibbles() {
         // Skip 'c2345' (an odd number of nibbles, for an extension node, hence prefix '1')
@@ -841,4 +948,15 @@ mod test {
             vec![0x00, 0x01, 0x02]
         );
     }
+
+    #[test]
+    fn test_path_error_kind() {
+        let bad_prefix = prefixed_bytes_to_nibbles(&[0x40, 0x12]).unwrap_err();
+        assert_eq!(bad_prefix, PathError::InvalidPathPrefix(0x40));
+        assert_eq!(bad_prefix.kind(), ErrorKind::MalformedProof);
+
+        let odd = nibbles_to_bytes(&[0xb, 0xc, 0x3]).unwrap_err();
+        assert_eq!(odd.kind(), ErrorKind::Internal);
+        assert_eq!(PathError::PathExhausted(64).kind(), ErrorKind::MalformedProof);
+    }
 }
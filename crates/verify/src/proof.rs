@@ -14,6 +14,11 @@ use crate::{
     utils::{hex_encode, UtilsError},
 };
 
+pub use archors_types::constants::EMPTY_TRIE_ROOT;
+
+/// The RLP encoding of the empty string, which is the root node of a trie with no keys.
+const EMPTY_STRING_RLP: u8 = 0x80;
+
 #[derive(Debug, Error)]
 pub enum ProofError {
     #[error("Branch does not have enough items")]
@@ -38,6 +43,8 @@ pub enum ProofError {
         "The claimed proof value ({claimed}) is different from the value in the proof ({expected})"
     )]
     IncorrectLeafValue { claimed: String, expected: String },
+    #[error("Trie is empty (root is the empty trie root) but the proof has non-empty nodes")]
+    NonEmptyProofForEmptyTrie,
     #[error("Merkle Patricia Node to have max 17 (16 + 1) items, got {0}")]
     InvalidNodeItemCount(usize),
     #[error("Node (index = {node_index} error {source}")]
@@ -58,6 +65,8 @@ pub enum ProofError {
     UtilsError(#[from] UtilsError),
     #[error("VerificationError {0}")]
     VerificationError(String),
+    #[error("Proof has {unused} superfluous node(s) after the {used} node(s) on the path")]
+    SuperfluousNodes { used: usize, unused: usize },
 }
 
 /// A proof for some data in a Merkle Patricia Tree, such as an account, or a storage value.
@@ -74,7 +83,27 @@ pub struct SingleProofPath {
 }
 
 impl SingleProofPath {
+    /// Verifies the proof. Nodes after the final node on the path are ignored.
     pub fn verify(&self) -> Result<Verified, ProofError> {
+        self.verify_with_mode(false)
+    }
+    /// Verifies the proof. Nodes after the final node on the path are an error
+    /// (`ProofError::SuperfluousNodes`).
+    ///
+    /// Useful for detecting proofs that could be smaller, such as when minimizing parcels.
+    pub fn verify_strict(&self) -> Result<Verified, ProofError> {
+        self.verify_with_mode(true)
+    }
+    /// Verifies the proof, optionally reporting nodes that are not on the path.
+    pub fn verify_with_mode(&self, strict: bool) -> Result<Verified, ProofError> {
+        if self.root == EMPTY_TRIE_ROOT {
+            // No keys in the trie. The proof is either absent or the empty string RLP node.
+            return match self.proof.as_slice() {
+                [] => Ok(Verified::Exclusion),
+                [node] if node.as_ref() == [EMPTY_STRING_RLP] => Ok(Verified::Exclusion),
+                _ => Err(ProofError::NonEmptyProofForEmptyTrie),
+            };
+        }
         if self.proof.is_empty() {
             return Err(ProofError::EmptyProof);
         }
@@ -87,13 +116,18 @@ impl SingleProofPath {
             let node: Vec<Vec<u8>> = rlp::decode_list(&rlp_node.0);
 
             let proof_type = NodeKind::deduce(&node)
-                .map_err(|source| ProofError::NodeError { source, node_index })?
+                .map_err(|source| node_error(source, node_index))?
                 .traverse_node(node, &mut traversal, &mut parent_hash)
-                .map_err(|source| ProofError::NodeError { source, node_index })?;
+                .map_err(|source| node_error(source, node_index))?;
 
             let verification = proof_type.get_verification_of_value(&self.claimed_value)?;
 
             if let Some(verified) = verification {
+                let used = node_index + 1;
+                let unused = self.proof.len() - used;
+                if strict && unused != 0 {
+                    return Err(ProofError::SuperfluousNodes { used, unused });
+                }
                 return Ok(verified);
             }
         }
@@ -113,6 +147,15 @@ pub enum Verified {
     Exclusion,
 }
 
+/// Attributes a node error to the node in the proof. Path errors (e.g., an invalid
+/// path encoding) are surfaced directly.
+fn node_error(source: NodeError, node_index: usize) -> ProofError {
+    match source {
+        NodeError::PathError(source) => ProofError::PathError { source, node_index },
+        source => ProofError::NodeError { source, node_index },
+    }
+}
+
 /// Checks that the hash of one node is correct.
 fn node_hash_correct(rlp_node: &[u8], parent_hash: [u8; 32]) -> Result<(), ProofError> {
     let computed_hash = keccak256(rlp_node);
@@ -316,4 +359,55 @@ mod tests {
             "5fe7f977e71dba2ea1a68e21057beebb9be2ac30c6410aa38d4f3fbe41dcffd2"
         );
     }
+
+    /// Tests that a node with an invalid path encoding byte is reported with the byte.
+    #[test]
+    fn test_invalid_path_prefix_reported() {
+        // Leaf-like node with encoding byte 0x45 (high nibble 4 is not an encoding).
+        let node = rlp::encode_list::<Vec<u8>, Vec<u8>>(&[vec![0x45, 0x12], vec![0x01]]).to_vec();
+        let proof = SingleProofPath {
+            root: keccak256(&node),
+            proof: vec![Bytes::from(node)],
+            path: [0x51; 32],
+            claimed_value: vec![0x01],
+        };
+        let err = proof.verify().unwrap_err();
+        assert!(matches!(
+            err,
+            ProofError::PathError {
+                source: PathError::InvalidPathPrefix(0x45),
+                node_index: 0
+            }
+        ));
+        assert!(err.to_string().contains("0x45"));
+    }
+
+    /// Tests that a proof with an extra node after the final node is accepted, unless
+    /// verified in strict mode.
+    #[test]
+    fn test_superfluous_node_reported_in_strict_mode() {
+        let proof = load_proof();
+        let storage_proof = proof.storage_proof.first().unwrap();
+        let mut padded = storage_proof.proof.clone();
+        padded.push(storage_proof.proof[0].clone());
+        let single = SingleProofPath {
+            proof: padded,
+            root: proof.storage_hash.0,
+            path: keccak256(storage_proof.key),
+            claimed_value: rlp::encode(&storage_proof.value).to_vec(),
+        };
+        let lenient = single.verify().unwrap();
+        assert_eq!(single.verify_with_mode(false).unwrap(), lenient);
+        let err = single.verify_strict().unwrap_err();
+        assert!(matches!(
+            err,
+            ProofError::SuperfluousNodes { used, unused: 1 } if used == storage_proof.proof.len()
+        ));
+
+        let unpadded = SingleProofPath {
+            proof: storage_proof.proof.clone(),
+            ..single
+        };
+        assert_eq!(unpadded.verify_strict().unwrap(), lenient);
+    }
 }
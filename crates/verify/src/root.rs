@@ -0,0 +1,103 @@
+//! For obtaining a trusted state root to verify proofs against.
+//!
+//! The root may come from a local header chain file, a node or a list of checkpoints
+//! that were obtained out of band (e.g., via a light client).
+
+use std::{collections::BTreeMap, fs::File, io::BufReader, path::Path};
+
+use ethers::types::{H256, U64};
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum RootSourceError {
+    #[error("No state root known for block {0}")]
+    UnknownBlock(u64),
+    #[error("IO error {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("serde_json error {0}")]
+    SerdeJsonError(#[from] serde_json::Error),
+    #[error("Header file has conflicting state roots for block {0}")]
+    ConflictingRoots(u64),
+}
+
+/// A source of state roots that the caller trusts.
+pub trait StateRootSource {
+    /// Returns the state root after the given block.
+    fn state_root(&self, block: u64) -> Result<H256, RootSourceError>;
+}
+
+/// State roots from a fixed list of (block number, state root) checkpoints.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckpointRoots(BTreeMap<u64, H256>);
+
+impl CheckpointRoots {
+    pub fn new(checkpoints: &[(u64, H256)]) -> Self {
+        Self(checkpoints.iter().copied().collect())
+    }
+}
+
+impl StateRootSource for CheckpointRoots {
+    fn state_root(&self, block: u64) -> Result<H256, RootSourceError> {
+        self.0
+            .get(&block)
+            .copied()
+            .ok_or(RootSourceError::UnknownBlock(block))
+    }
+}
+
+/// The members of a header that are needed to know the state root.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HeaderRoot {
+    number: U64,
+    state_root: H256,
+}
+
+/// State roots from a local header chain file.
+///
+/// The file is a JSON list of headers as returned by eth_getBlockByNumber. Only
+/// the `number` and `stateRoot` members are read.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeaderFileRoots(CheckpointRoots);
+
+impl HeaderFileRoots {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, RootSourceError> {
+        let file = File::open(path)?;
+        let headers: Vec<HeaderRoot> = serde_json::from_reader(BufReader::new(file))?;
+        let mut roots = BTreeMap::new();
+        for header in headers {
+            let block = header.number.as_u64();
+            if let Some(existing) = roots.insert(block, header.state_root) {
+                if existing != header.state_root {
+                    return Err(RootSourceError::ConflictingRoots(block));
+                }
+            }
+        }
+        Ok(Self(CheckpointRoots(roots)))
+    }
+}
+
+impl StateRootSource for HeaderFileRoots {
+    fn state_root(&self, block: u64) -> Result<H256, RootSourceError> {
+        self.0.state_root(block)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_header_file_roots() {
+        let source = HeaderFileRoots::load("data/test_headers.json").unwrap();
+        let expected: H256 = "0x61effbbcca94f0d3e02e5bd22e986ad57142acabf0cb3d129a6ad8d0f8752e94"
+            .parse()
+            .unwrap();
+        assert_eq!(source.state_root(3).unwrap(), expected);
+        assert!(matches!(
+            source.state_root(4),
+            Err(RootSourceError::UnknownBlock(4))
+        ));
+    }
+}
@@ -14,7 +14,8 @@ fn main() -> Result<()> {
 
     // Package block state proof into a ssz format with minimal duplication of
     // data, optimised for transfer to a peer.
-    create_transferrable_proof(17190873)?;
+    let stats = create_transferrable_proof(17190873)?;
+    println!("{stats}");
 
     Ok(())
 }
@@ -1 +1,4 @@
 //! A library for actions that combine different archors crates.
+
+pub mod minimal;
+pub mod online;
@@ -0,0 +1,54 @@
+//! For parcels that only contain the contracts a block requires.
+//!
+//! The accessed state of a block includes the code of every contract that was touched,
+//! including those that were not executed (e.g., only the balance was read). The block is
+//! traced to find the code it requires, and other contracts are left out of the parcel.
+
+use archors_inventory::{
+    cache::{get_block_from_store, save_transferrable_data, transferrable_from_store, CacheError},
+    store::BlockStore,
+    types::ParcelStats,
+};
+use archors_tracer::trace::{BlockExecutor, PostExecutionProof, TraceError};
+use archors_types::state::RequiredBlockState;
+use log::info;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum MinimalError {
+    #[error("Cache error {0}")]
+    CacheError(#[from] CacheError),
+    #[error("Trace error {0}")]
+    TraceError(#[from] TraceError),
+}
+
+/// Constructs the parcel for a block from the given store, with only the contracts that
+/// the block executes or reads.
+pub fn minimal_transferrable_state_in<S: BlockStore>(
+    store: &S,
+    target_block: u64,
+) -> Result<RequiredBlockState, MinimalError> {
+    let state = transferrable_from_store(store, target_block)?;
+    let block = get_block_from_store(store, target_block)?;
+    let executor = BlockExecutor::load(block, state, PostExecutionProof::Ignore)?;
+    let (mut state, required) = executor.execute_block_with_code_hashes()?;
+    let total = state.contracts.len();
+    state.retain_contracts(|code_hash| required.contains(code_hash));
+    info!(
+        "Block {target_block} requires {} of {total} contracts",
+        state.contracts.len()
+    );
+    Ok(state)
+}
+
+/// Creates and stores the parcel for a block, with only the contracts that the block
+/// executes or reads.
+///
+/// Returns the sizes and counts of the stored parcel.
+pub fn create_minimal_transferrable_proof_in<S: BlockStore>(
+    store: &S,
+    target_block: u64,
+) -> Result<ParcelStats, MinimalError> {
+    let state = minimal_transferrable_state_in(store, target_block)?;
+    Ok(save_transferrable_data(store, target_block, state)?)
+}
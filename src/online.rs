@@ -0,0 +1,49 @@
+//! For tracing against state fetched from a node on demand, without a locally cached parcel.
+//!
+//! Useful for ad-hoc debugging of a single transaction. The node must support
+//! debug_traceBlockByNumber (prestate and default tracers), and the proof node must
+//! support eth_getProof for the block of interest.
+
+use archors_inventory::cache::{
+    fetch_block_with_transactions, fetch_required_block_state, CacheError,
+};
+use archors_tracer::trace::{BlockExecutor, PostExecutionProof, TraceError};
+use archors_types::state::RequiredBlockState;
+use log::info;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum OnlineError {
+    #[error("Cache error {0}")]
+    CacheError(#[from] CacheError),
+    #[error("Trace error {0}")]
+    TraceError(#[from] TraceError),
+    #[error("Transaction index {index} not in block with {tx_count} transactions")]
+    TxIndexOutOfRange { index: usize, tx_count: usize },
+}
+
+/// Fetches the block and the state it requires, then traces a single transaction.
+///
+/// The state is held in memory only. The whole block is executed, but only the
+/// transaction at the given index is inspected (EIP-3155 trace sent to stdout).
+pub async fn trace_transaction_online(
+    url: &str,
+    get_proof_url: &str,
+    block_number: u64,
+    tx_index: usize,
+) -> Result<RequiredBlockState, OnlineError> {
+    info!("Fetching block {block_number} with transactions");
+    let block = fetch_block_with_transactions(url, block_number).await?;
+    let tx_count = block.transactions.len();
+    if tx_index >= tx_count {
+        return Err(OnlineError::TxIndexOutOfRange {
+            index: tx_index,
+            tx_count,
+        });
+    }
+    info!("Fetching state required for block {block_number}");
+    let state = fetch_required_block_state(url, get_proof_url, block_number).await?;
+    let executor = BlockExecutor::load(block, state, PostExecutionProof::Ignore)?;
+    let post_state = executor.trace_transaction(tx_index)?;
+    Ok(post_state)
+}
@@ -0,0 +1,38 @@
+use std::path::PathBuf;
+
+use archors::minimal::minimal_transferrable_state_in;
+use archors_inventory::{
+    cache::{get_block_from_store, transferrable_from_store},
+    store::FileStore,
+};
+use archors_tracer::trace::{BlockExecutor, PostExecutionProof};
+
+const BLOCK: u64 = 17190873;
+
+/// Re-executes the block from the minimal parcel and compares the post-block state with
+/// execution from the full parcel.
+///
+/// Every contract the block loads must be kept, otherwise execution fails with missing
+/// code. Contracts that were only accessed in other ways (e.g., BALANCE) are left out.
+#[test]
+fn test_minimal_contracts_re_execute_block() {
+    let store = FileStore::new(PathBuf::from("data/blocks"));
+    let full = transferrable_from_store(&store, BLOCK).unwrap();
+    let minimal = minimal_transferrable_state_in(&store, BLOCK).unwrap();
+
+    assert!(!minimal.contracts.is_empty());
+    assert!(minimal.contracts.len() <= full.contracts.len());
+    // Account state is unaffected.
+    assert_eq!(minimal.compact_eip1186_proofs, full.compact_eip1186_proofs);
+
+    let block = get_block_from_store(&store, BLOCK).unwrap();
+    let (_, expected) = BlockExecutor::load(block.clone(), full, PostExecutionProof::Ignore)
+        .unwrap()
+        .execute_block_with_snapshot()
+        .unwrap();
+    let (_, snapshot) = BlockExecutor::load(block, minimal, PostExecutionProof::Ignore)
+        .unwrap()
+        .execute_block_with_snapshot()
+        .unwrap();
+    assert_eq!(snapshot, expected);
+}
@@ -0,0 +1,206 @@
+use std::{collections::HashMap, str::FromStr};
+
+use archors::online::trace_transaction_online;
+use archors_inventory::{
+    cache::{prepare_blocks, request_proofs},
+    types::BlockStateAccesses,
+};
+use archors_multiproof::StateForEvm;
+use ethers::{
+    types::{Block, EIP1186ProofResponse, Transaction, H160, H256, U256, U64},
+    utils::keccak256,
+};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+const BLOCK_NUMBER: u64 = 1_000;
+const SENDER: &str = "0x0300000000000000000000000000000000000000";
+const RECIPIENT: &str = "0x0200000000000000000000000000000000000000";
+
+/// Traces a single transfer transaction using state fetched from a mock node.
+#[tokio::test]
+async fn test_trace_transfer_against_mock_node() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(serve_mock_node(listener));
+
+    let post_state = trace_transaction_online(&url, &url, BLOCK_NUMBER, 0)
+        .await
+        .unwrap();
+    let sender = H160::from_str(SENDER).unwrap();
+    assert!(post_state.addresses().contains(&sender.0.into()));
+
+    // Requesting a transaction that is not in the block is an error.
+    assert!(trace_transaction_online(&url, &url, BLOCK_NUMBER, 1)
+        .await
+        .is_err());
+}
+
+/// Prepares parcels for a range of blocks from a mock node, two blocks at a time.
+#[tokio::test]
+async fn test_prepare_block_range_against_mock_node() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(serve_mock_node(listener));
+
+    let results = prepare_blocks(&url, BLOCK_NUMBER..=BLOCK_NUMBER + 2, 2).await;
+    let blocks: Vec<u64> = results.iter().map(|(block, _)| *block).collect();
+    assert_eq!(
+        blocks,
+        vec![BLOCK_NUMBER, BLOCK_NUMBER + 1, BLOCK_NUMBER + 2]
+    );
+    let sender = H160::from_str(SENDER).unwrap();
+    for (_, state) in results {
+        let state = state.unwrap();
+        assert!(state.parcel_addresses().contains(&sender));
+    }
+}
+
+/// Requests proofs from a mock node that supports JSON-RPC batch requests, and from one
+/// that does not (each request is then sent individually).
+#[tokio::test]
+async fn test_batched_proofs_against_mock_node() {
+    let accessed_state = serde_json::from_value(json!({
+        SENDER: {"balance": "0xde0b6b3a7640000", "nonce": 0},
+        RECIPIENT: {"balance": "0x0"},
+    }))
+    .unwrap();
+    let mut accesses = BlockStateAccesses::new();
+    accesses.include_new_state_accesses_for_tx(&accessed_state);
+
+    for batching in [true, false] {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve_mock_node_with(listener, batching));
+
+        let block_proofs = request_proofs(&url, &accesses, BLOCK_NUMBER).await.unwrap();
+        assert_eq!(block_proofs.proofs.len(), 2);
+        for address in [SENDER, RECIPIENT] {
+            let address = H160::from_str(address).unwrap();
+            assert_eq!(block_proofs.proofs[&address], account_proof(address));
+        }
+    }
+}
+
+/// Accepts connections and answers each JSON-RPC request once.
+async fn serve_mock_node(listener: TcpListener) {
+    serve_mock_node_with(listener, true).await
+}
+
+/// Accepts connections. Batch requests are rejected if batching is not supported.
+async fn serve_mock_node_with(listener: TcpListener, batching: bool) {
+    loop {
+        let (socket, _) = listener.accept().await.unwrap();
+        tokio::spawn(handle_connection(socket, batching));
+    }
+}
+
+async fn handle_connection(mut socket: TcpStream, batching: bool) {
+    let mut buf: Vec<u8> = vec![];
+    let mut chunk = [0u8; 4096];
+    let body = loop {
+        let n = socket.read(&mut chunk).await.unwrap();
+        if n == 0 {
+            return;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        let Some(header_end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+            continue;
+        };
+        let headers = String::from_utf8_lossy(&buf[..header_end]).to_lowercase();
+        let content_length: usize = headers
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length:"))
+            .map(|len| len.trim().parse().unwrap())
+            .unwrap_or_default();
+        let body_start = header_end + 4;
+        if buf.len() >= body_start + content_length {
+            break buf[body_start..body_start + content_length].to_vec();
+        }
+    };
+    let request: Value = serde_json::from_slice(&body).unwrap();
+    let response = match (request.as_array(), batching) {
+        (Some(batch), true) => Value::Array(
+            batch
+                .iter()
+                .map(|call| json!({"id": call["id"], "jsonrpc": "2.0", "result": respond(call)}))
+                .collect(),
+        ),
+        (Some(_), false) => json!({
+            "id": null,
+            "jsonrpc": "2.0",
+            "error": {"code": -32600, "message": "batch requests not supported"}
+        }),
+        (None, _) => json!({"id": 1, "jsonrpc": "2.0", "result": respond(&request)}),
+    }
+    .to_string();
+    let http = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.len(),
+        response
+    );
+    socket.write_all(http.as_bytes()).await.unwrap();
+    socket.shutdown().await.unwrap();
+}
+
+/// Produces the result for a JSON-RPC request.
+fn respond(request: &Value) -> Value {
+    let params = &request["params"];
+    match request["method"].as_str().unwrap() {
+        "eth_getBlockByNumber" => serde_json::to_value(transfer_block()).unwrap(),
+        "debug_traceBlockByNumber" => match params[1].get("tracer") {
+            Some(_) => json!([{"result": {
+                SENDER: {"balance": "0xde0b6b3a7640000", "nonce": 0},
+                RECIPIENT: {"balance": "0x0"},
+            }}]),
+            None => json!([{"result": {"structLogs": []}}]),
+        },
+        "eth_getProof" => {
+            let address = H160::from_str(params[0].as_str().unwrap()).unwrap();
+            serde_json::to_value(account_proof(address)).unwrap()
+        }
+        method => panic!("mock node does not support {method}"),
+    }
+}
+
+fn account_balances() -> HashMap<H160, U256> {
+    let mut balances = HashMap::new();
+    balances.insert(H160::from_str(SENDER).unwrap(), U256::exp10(18));
+    balances.insert(H160::from_str(RECIPIENT).unwrap(), U256::zero());
+    balances
+}
+
+fn account_proof(address: H160) -> EIP1186ProofResponse {
+    EIP1186ProofResponse {
+        address,
+        balance: account_balances()
+            .get(&address)
+            .copied()
+            .unwrap_or_default(),
+        code_hash: H256::from(keccak256([])),
+        ..Default::default()
+    }
+}
+
+fn transfer_block() -> Block<Transaction> {
+    let tx = Transaction {
+        from: H160::from_str(SENDER).unwrap(),
+        to: Some(H160::from_str(RECIPIENT).unwrap()),
+        value: U256::from(1),
+        gas: U256::from(21_000),
+        gas_price: Some(U256::zero()),
+        transaction_index: Some(U64::zero()),
+        block_number: Some(BLOCK_NUMBER.into()),
+        ..Default::default()
+    };
+    Block {
+        author: Some(H160::default()),
+        number: Some(BLOCK_NUMBER.into()),
+        gas_limit: U256::from(30_000_000),
+        transactions: vec![tx],
+        ..Default::default()
+    }
+}